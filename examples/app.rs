@@ -0,0 +1,52 @@
+//! The sawtooth from `hello.rs`, written as a struct implementing `BelaApp`
+//! rather than as closures over `AppData`, and started with the same
+//! command-line options as a C++ Bela project.
+extern crate bela;
+
+use bela::*;
+use std::process;
+
+struct Sawtooth {
+    frequency: f32,
+    gain: f32,
+    idx: usize,
+}
+
+impl BelaApp for Sawtooth {
+    fn setup(&mut self, _context: &mut Context) -> Result<(), error::Error> {
+        println!("Setting up");
+        Ok(())
+    }
+
+    fn render(&mut self, context: &mut Context) {
+        let period = context.audio_sample_rate() / self.frequency;
+        for samp in context.audio_out().iter_mut() {
+            *samp = self.gain * (2. * (self.idx as f32 / period) - 1.);
+            self.idx += 1;
+            if self.idx as f32 > period {
+                self.idx = 0;
+            }
+        }
+    }
+
+    fn cleanup(&mut self, _context: &mut Context) {
+        println!("Cleaning up");
+    }
+}
+
+fn main() {
+    let app = Sawtooth {
+        frequency: 110.,
+        gain: 0.5,
+        idx: 0,
+    };
+    let (mut settings, _args) = match InitSettings::from_args() {
+        Ok(parsed) => parsed,
+        Err(ArgsError::Help) => process::exit(0),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    Bela::new(app).run(&mut settings).unwrap();
+}
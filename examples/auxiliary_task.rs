@@ -1,7 +1,10 @@
 //! Produces a sine wave while printing "this is a string" repeatedly,
 //! appending "LOL" to every iteration.
 //!
-//! There's an example here for both the stack-allocated and a Boxed closure.
+//! There's an example here for both the stack-allocated and a Boxed closure,
+//! for a task fed with the frame index through a channel, which replies
+//! with a message picked up by render, and for a task that sums a block of
+//! frame indices handed over from render, whose result render polls for.
 //!
 extern crate bela;
 extern crate sample;
@@ -11,9 +14,11 @@ use bela::*;
 struct MyData {
     frame_index: usize,
     tasks: Vec<CreatedTask>,
+    frames: Option<(channel::TaskSender<usize>, channel::Receiver<String>)>,
+    sums: Option<channel::AuxTask<[usize; 16], usize>>,
 }
 
-type BelaApp<'a> = Bela<AppData<'a, MyData>>;
+type App<'a> = Bela<AppData<'a, MyData>>;
 
 fn main() {
     go().unwrap();
@@ -30,16 +35,30 @@ fn go() -> Result<(), error::Error> {
             println!("this is another string");
         });
 
-        user_data.tasks.push(BelaApp::create_auxiliary_task(
+        user_data.tasks.push(App::create_auxiliary_task(
             print_task,
             10,
             &std::ffi::CString::new("printing_stuff").unwrap(),
         ));
-        user_data.tasks.push(BelaApp::create_auxiliary_task(
+        user_data.tasks.push(App::create_auxiliary_task(
             another_print_task,
             10,
             &std::ffi::CStr::from_bytes_with_nul(b"printing_more_stuff\0").unwrap(),
         ));
+        user_data.frames = Some(App::create_channel_task(
+            16,
+            |frame_index: usize, replies: &mut channel::Sender<String>| {
+                println!("render reached frame {}", frame_index);
+                let _ = replies.try_send(format!("LOL {}", frame_index));
+            },
+            10,
+            &std::ffi::CString::new("printing_frames").unwrap(),
+        ));
+        user_data.sums = Some(App::create_aux_task(
+            |block: [usize; 16]| block.iter().sum(),
+            10,
+            &std::ffi::CString::new("summing_frames").unwrap(),
+        ));
         Ok(())
     };
 
@@ -50,7 +69,31 @@ fn go() -> Result<(), error::Error> {
     let mut render = |_context: &mut Context, user_data: &mut MyData| {
         if user_data.frame_index % 1024 == 0 {
             for task in user_data.tasks.iter() {
-                BelaApp::schedule_auxiliary_task(task).unwrap();
+                App::schedule_auxiliary_task(task).unwrap();
+            }
+        }
+
+        if let Some((ref mut frames, ref mut replies)) = user_data.frames {
+            if user_data.frame_index % 4096 == 0 {
+                let _ = frames.try_send(user_data.frame_index);
+            }
+            // Dropping the reply frees a String on the audio thread, which is
+            // fine for an example, but not for real-time code.
+            for reply in replies {
+                println!("{}", reply);
+            }
+        }
+
+        if let Some(ref mut sums) = user_data.sums {
+            if let Some(sum) = sums.poll() {
+                println!("sum of 16 frame indices: {}", sum);
+            }
+            if user_data.frame_index % 8192 == 0 && !sums.is_busy() {
+                let mut block = [0; 16];
+                for (idx, frame) in block.iter_mut().enumerate() {
+                    *frame = user_data.frame_index + idx;
+                }
+                let _ = sums.schedule(block);
             }
         }
 
@@ -60,6 +103,8 @@ fn go() -> Result<(), error::Error> {
     let my_data = MyData {
         tasks: Vec::new(),
         frame_index: 0,
+        frames: None,
+        sums: None,
     };
 
     let user_data = AppData::new(my_data, &mut render, Some(&mut setup), Some(&mut cleanup));
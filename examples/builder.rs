@@ -0,0 +1,35 @@
+//! Builds the sawtooth from `hello.rs` from owned closures, in a function
+//! that returns the whole app, rather than from closures borrowed by
+//! `AppData`.
+extern crate bela;
+
+use bela::*;
+
+struct Phasor {
+    idx: usize,
+}
+
+fn sawtooth(frequency: f32) -> Bela<OwnedAppData<Phasor>> {
+    AppBuilder::new(Phasor { idx: 0 })
+        .setup(|_context, _phasor| {
+            println!("Setting up");
+            Ok(())
+        })
+        .render(move |context, phasor| {
+            let period = context.audio_sample_rate() / frequency;
+            for samp in context.audio_out().iter_mut() {
+                *samp = 0.5 * (2. * (phasor.idx as f32 / period) - 1.);
+                phasor.idx += 1;
+                if phasor.idx as f32 > period {
+                    phasor.idx = 0;
+                }
+            }
+        })
+        .cleanup(|_context, _phasor| println!("Cleaning up"))
+        .build()
+}
+
+fn main() {
+    let mut settings = InitSettings::default();
+    sawtooth(110.).run(&mut settings).unwrap();
+}
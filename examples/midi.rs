@@ -0,0 +1,69 @@
+//! Plays a sine wave at the pitch of the last note received on the MIDI port
+//! of the Bela cape, and echoes every note back out of it.
+extern crate bela;
+
+use bela::midi::{self, Message, Midi};
+use bela::*;
+
+struct Synth {
+    midi: Midi,
+    phase: f32,
+    frequency: f32,
+    gain: f32,
+}
+
+fn main() {
+    go().unwrap();
+}
+
+fn go() -> Result<(), error::Error> {
+    let mut render = |context: &mut Context, synth: &mut Synth| {
+        while let Some(message) = synth.midi.read() {
+            let (channel, note, velocity) = match message {
+                Message::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                } => (channel, note, velocity),
+                Message::NoteOff { channel, note, .. } => (channel, note, 0),
+                _ => continue,
+            };
+            if velocity > 0 {
+                synth.frequency = 440. * 2f32.powf((f32::from(note) - 69.) / 12.);
+                synth.gain = f32::from(velocity) / 127.;
+            } else {
+                synth.gain = 0.;
+            }
+            synth.midi.write(&Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            });
+        }
+
+        let step = 2. * std::f32::consts::PI * synth.frequency / context.audio_sample_rate();
+        for mut frame in context.audio_out_frames() {
+            let value = synth.phase.sin() * synth.gain * 0.5;
+            frame.fill(value);
+            synth.phase = (synth.phase + step) % (2. * std::f32::consts::PI);
+        }
+    };
+
+    let midi = Midi::open(midi::DEFAULT_PORT).map_err(|err| {
+        error::Error::new(error::ErrorKind::Init)
+            .with_detail("could not open the MIDI port")
+            .with_source(err)
+    })?;
+    let synth = Synth {
+        midi,
+        phase: 0.,
+        frequency: 440.,
+        gain: 0.,
+    };
+
+    let user_data = AppData::new(synth, &mut render, None, None);
+
+    let mut bela_app = Bela::new(user_data);
+    let mut settings = InitSettings::default();
+    bela_app.run(&mut settings)
+}
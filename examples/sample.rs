@@ -25,13 +25,7 @@ fn go() -> Result<(), error::Error> {
     // Generates a sine wave with the period of whatever the audio frame
     // size is.
     let mut render = |context: &mut Context, synth: &mut Option<Box<dyn Signal<Frame = f64>>>| {
-        let audio_out_channels = context.audio_out_channels();
-        assert_eq!(audio_out_channels, 2);
-        let audio_out = context.audio_out();
-        let audio_out_frames: &mut [[f32; 2]] =
-            sample::slice::to_frame_slice_mut(audio_out).unwrap();
-
-        for frame in audio_out_frames.iter_mut() {
+        for mut frame in context.audio_out_frames() {
             for samp in frame.iter_mut() {
                 let val = synth.as_mut().unwrap().next();
                 *samp = val as f32;
@@ -0,0 +1,52 @@
+//! Runs the sawtooth from `hello.rs` on the software backend, so it can be
+//! run on any machine without libbela. Renders one second of audio as fast as
+//! possible and prints the peak output level.
+extern crate bela;
+
+use bela::*;
+
+struct Phasor {
+    idx: usize,
+    peak: f32,
+}
+
+fn main() {
+    go().unwrap();
+}
+
+fn go() -> Result<(), error::Error> {
+    let mut setup = |_context: &mut Context, _user_data: &mut Phasor| -> Result<(), error::Error> {
+        println!("Setting up");
+        Ok(())
+    };
+
+    let mut cleanup = |_context: &mut Context, phasor: &mut Phasor| {
+        println!("Cleaning up, peak level was {}", phasor.peak);
+    };
+
+    // Generates a non-bandlimited sawtooth at 110Hz.
+    let mut render = |context: &mut Context, phasor: &mut Phasor| {
+        for samp in context.audio_out().iter_mut() {
+            let gain = 0.5;
+            *samp = 2. * (phasor.idx as f32 * 110. / 44100.) - 1.;
+            *samp *= gain;
+            phasor.peak = phasor.peak.max(samp.abs());
+            phasor.idx += 1;
+            if phasor.idx as f32 > 44100. / 110. {
+                phasor.idx = 0;
+            }
+        }
+    };
+
+    let phasor = Phasor { idx: 0, peak: 0. };
+
+    let user_data = AppData::new(phasor, &mut render, Some(&mut setup), Some(&mut cleanup));
+
+    let mut settings = InitSettings::software_default();
+    let mut backend = backend::Software::new();
+    backend.set_realtime(false);
+    backend.set_max_blocks(Some(44_100 / (2 * settings.period_size() as u64)));
+
+    let mut bela_app = Bela::with_backend(user_data, backend);
+    bela_app.run(&mut settings)
+}
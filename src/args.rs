@@ -0,0 +1,675 @@
+//! Reads `InitSettings` from the command-line options understood by Bela's
+//! `Bela_getopt_long`, so that Rust programs can be started by the same run
+//! scripts as C++ ones.
+
+use std::ffi::OsString;
+use std::{env, error, fmt};
+
+use error as bela_error;
+use settings::InvalidSettings;
+use {BelaHw, InitSettings, InitSettingsBuilder};
+
+/// An option of the standard Bela set.
+struct Opt {
+    long: &'static str,
+    short: Option<char>,
+    /// Name of the value the option takes, if it takes one.
+    value: Option<&'static str>,
+    help: &'static str,
+}
+
+const OPTS: &[Opt] = &[
+    Opt {
+        long: "period",
+        short: Some('p'),
+        value: Some("frames"),
+        help: "Set the hardware period (buffer) size in analog frames",
+    },
+    Opt {
+        long: "dac-level",
+        short: Some('D'),
+        value: Some("dBs"),
+        help: "Set the DAC output level (0dB max; -63.5dB min)",
+    },
+    Opt {
+        long: "adc-level",
+        short: Some('A'),
+        value: Some("dBs"),
+        help: "Set the ADC input level (0dB max; -12dB min)",
+    },
+    Opt {
+        long: "pga-gain",
+        short: None,
+        value: Some("dBs"),
+        help: "Set the PGA gain of both channels (59.5dB max; 0dB min)",
+    },
+    Opt {
+        long: "pga-gain-left",
+        short: None,
+        value: Some("dBs"),
+        help: "Set the PGA gain of the left channel",
+    },
+    Opt {
+        long: "pga-gain-right",
+        short: None,
+        value: Some("dBs"),
+        help: "Set the PGA gain of the right channel",
+    },
+    Opt {
+        long: "hp-level",
+        short: Some('H'),
+        value: Some("dBs"),
+        help: "Set the headphone output level (0dB max; -63.5dB min)",
+    },
+    Opt {
+        long: "mute-speaker",
+        short: Some('M'),
+        value: Some("0|1"),
+        help: "Set whether to mute the speaker initially",
+    },
+    Opt {
+        long: "use-analog",
+        short: Some('N'),
+        value: Some("0|1"),
+        help: "Set whether to use the analog inputs and outputs",
+    },
+    Opt {
+        long: "use-digital",
+        short: Some('G'),
+        value: Some("0|1"),
+        help: "Set whether to use the digital channels",
+    },
+    Opt {
+        long: "analog-channels",
+        short: Some('C'),
+        value: Some("num"),
+        help: "Set the number of analog input and output channels",
+    },
+    Opt {
+        long: "digital-channels",
+        short: Some('B'),
+        value: Some("num"),
+        help: "Set the number of digital channels",
+    },
+    Opt {
+        long: "mux-channels",
+        short: Some('X'),
+        value: Some("num"),
+        help: "Set the number of multiplexer channels",
+    },
+    Opt {
+        long: "audio-expander-inputs",
+        short: Some('Y'),
+        value: Some("list"),
+        help: "Set the analog inputs used by the audio expander (comma-separated)",
+    },
+    Opt {
+        long: "audio-expander-outputs",
+        short: Some('Z'),
+        value: Some("list"),
+        help: "Set the analog outputs used by the audio expander (comma-separated)",
+    },
+    Opt {
+        long: "pru-file",
+        short: None,
+        value: Some("path"),
+        help: "Load the PRU code from a file rather than the built-in code",
+    },
+    Opt {
+        long: "pru-number",
+        short: None,
+        value: Some("0|1"),
+        help: "Set which PRU to run the code on",
+    },
+    Opt {
+        long: "detect-underruns",
+        short: None,
+        value: Some("0|1"),
+        help: "Set whether to report underruns",
+    },
+    Opt {
+        long: "disable-led",
+        short: None,
+        value: None,
+        help: "Do not blink the LED while audio is running",
+    },
+    Opt {
+        long: "disable-cape-button-monitoring",
+        short: None,
+        value: None,
+        help: "Do not stop the program when the button on the cape is pressed",
+    },
+    Opt {
+        long: "stop-button-pin",
+        short: None,
+        value: Some("pin"),
+        help: "Set the pin of the stop button, or -1 for none",
+    },
+    Opt {
+        long: "amp-mute-pin",
+        short: None,
+        value: Some("pin"),
+        help: "Set the pin that mutes the amplifier, or -1 for none",
+    },
+    Opt {
+        long: "high-performance-mode",
+        short: Some('6'),
+        value: None,
+        help: "Give less CPU time to the Linux side, and more to audio",
+    },
+    Opt {
+        long: "uniform-sample-rate",
+        short: None,
+        value: None,
+        help: "Run the analog channels at the audio sample rate",
+    },
+    Opt {
+        long: "board",
+        short: None,
+        value: Some("name"),
+        help: "Use the given board rather than the detected one",
+    },
+    Opt {
+        long: "verbose",
+        short: Some('v'),
+        value: None,
+        help: "Print information about the settings and the hardware",
+    },
+    Opt {
+        long: "help",
+        short: Some('h'),
+        value: None,
+        help: "Print this listing",
+    },
+];
+
+const BOARDS: &[(&str, BelaHw)] = &[
+    ("NoHw", BelaHw::NoHw),
+    ("Bela", BelaHw::Bela),
+    ("BelaMini", BelaHw::BelaMini),
+    ("Salt", BelaHw::Salt),
+    ("CtagFace", BelaHw::CtagFace),
+    ("CtagBeast", BelaHw::CtagBeast),
+    ("CtagFaceBela", BelaHw::CtagFaceBela),
+    ("CtagBeastBela", BelaHw::CtagBeastBela),
+];
+
+/// Why command-line options could not be turned into `InitSettings`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArgsError {
+    /// `--help` was given. Displays as the listing of the Bela options.
+    Help,
+    /// An option that takes a value was last on the command line.
+    MissingValue(&'static str),
+    /// The value of an option could not be parsed.
+    InvalidValue { option: &'static str, value: String },
+    /// The options parsed, but the settings they make are invalid.
+    Invalid(InvalidSettings),
+    /// An argument was not valid Unicode.
+    NotUnicode(OsString),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ArgsError::Help => write!(f, "{}", InitSettings::help()),
+            ArgsError::MissingValue(option) => write!(f, "--{} requires a value", option),
+            ArgsError::InvalidValue { option, ref value } => {
+                write!(f, "invalid value for --{}: {:?}", option, value)
+            }
+            ArgsError::Invalid(ref invalid) => write!(f, "{}", invalid),
+            ArgsError::NotUnicode(ref arg) => write!(f, "argument is not valid Unicode: {:?}", arg),
+        }
+    }
+}
+
+impl error::Error for ArgsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ArgsError::Invalid(ref invalid) => Some(invalid),
+            _ => None,
+        }
+    }
+}
+
+impl From<InvalidSettings> for ArgsError {
+    fn from(invalid: InvalidSettings) -> ArgsError {
+        ArgsError::Invalid(invalid)
+    }
+}
+
+impl From<ArgsError> for bela_error::Error {
+    fn from(err: ArgsError) -> bela_error::Error {
+        bela_error::Error::new(bela_error::ErrorKind::Init).with_source(err)
+    }
+}
+
+impl InitSettings {
+    /// Read settings from the arguments the program was started with.
+    ///
+    /// Like `from_iter`, but on `--help` the listing is also printed, so that
+    /// the program only has to print its own options before exiting. An
+    /// argument that is not valid Unicode gives `ArgsError::NotUnicode`.
+    ///
+    /// ```rust,ignore
+    /// let (mut settings, args) = match InitSettings::from_args() {
+    ///     Ok(parsed) => parsed,
+    ///     Err(ArgsError::Help) => process::exit(0),
+    ///     Err(err) => panic!("{}", err),
+    /// };
+    /// ```
+    pub fn from_args() -> Result<(InitSettings, Vec<String>), ArgsError> {
+        let parsed = InitSettings::from_iter(unicode_args(env::args_os().skip(1))?);
+        if let Err(ArgsError::Help) = parsed {
+            println!("{}", InitSettings::help());
+        }
+        parsed
+    }
+
+    /// Read settings from command-line arguments, not including the program
+    /// name, starting from the default settings.
+    ///
+    /// Options are written as `-p 16`, `-p16`, `--period 16` or
+    /// `--period=16`. Arguments that are not Bela options are returned in
+    /// order for the program to parse, as is everything after `--`.
+    pub fn from_iter<I, S>(args: I) -> Result<(InitSettings, Vec<String>), ArgsError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        parse(InitSettings::builder(), args)
+    }
+
+    /// The listing of the options read by `from_args`, as printed for
+    /// `--help`.
+    pub fn help() -> String {
+        let usages: Vec<String> = OPTS
+            .iter()
+            .map(|opt| {
+                let mut usage = format!("--{}", opt.long);
+                if let Some(short) = opt.short {
+                    usage.push_str(&format!(" [-{}]", short));
+                }
+                if let Some(value) = opt.value {
+                    usage.push_str(&format!(" <{}>", value));
+                }
+                usage
+            })
+            .collect();
+        let width = usages.iter().map(String::len).max().unwrap_or(0);
+
+        let mut help = String::from("Bela options:\n");
+        for (usage, opt) in usages.iter().zip(OPTS) {
+            help.push_str(&format!(
+                "  {:width$}  {}\n",
+                usage,
+                opt.help,
+                width = width
+            ));
+        }
+        let boards: Vec<&str> = BOARDS.iter().map(|&(name, _)| name).collect();
+        help.push_str(&format!("Boards: {}", boards.join(", ")));
+        help
+    }
+}
+
+/// The arguments as strings, or an error for the first that is not valid
+/// Unicode.
+fn unicode_args<I: IntoIterator<Item = OsString>>(args: I) -> Result<Vec<String>, ArgsError> {
+    args.into_iter()
+        .map(|arg| arg.into_string().map_err(ArgsError::NotUnicode))
+        .collect()
+}
+
+/// Apply the options among `args` to `builder`, as for `from_iter`.
+fn parse<I, S>(
+    mut builder: InitSettingsBuilder,
+    args: I,
+) -> Result<(InitSettings, Vec<String>), ArgsError>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut args = args.into_iter().map(Into::into);
+    let mut rest = Vec::new();
+    let mut help = false;
+
+    while let Some(arg) = args.next() {
+        let (opt, inline) = match find(&arg) {
+            Some(found) => found,
+            None => {
+                if arg == "--" {
+                    rest.extend(args.by_ref());
+                } else {
+                    rest.push(arg);
+                }
+                continue;
+            }
+        };
+        if opt.long == "help" {
+            help = true;
+            continue;
+        }
+        let value = match (opt.value, inline) {
+            (None, _) => String::new(),
+            (Some(_), Some(value)) => value,
+            (Some(_), None) => match args.next() {
+                Some(value) => value,
+                None => return Err(ArgsError::MissingValue(opt.long)),
+            },
+        };
+        builder = apply(builder, opt.long, &value)?;
+    }
+
+    if help {
+        return Err(ArgsError::Help);
+    }
+    Ok((builder.build()?, rest))
+}
+
+/// The Bela option `arg` names, along with a value given in the same
+/// argument.
+fn find(arg: &str) -> Option<(&'static Opt, Option<String>)> {
+    if let Some(long) = arg.strip_prefix("--") {
+        let mut parts = long.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let inline = parts.next().map(String::from);
+        let opt = OPTS.iter().find(|opt| opt.long == name)?;
+        match (opt.value, inline) {
+            (None, Some(_)) => None,
+            (_, inline) => Some((opt, inline)),
+        }
+    } else if let Some(short) = arg.strip_prefix('-') {
+        let mut chars = short.chars();
+        let short = chars.next()?;
+        let opt = OPTS.iter().find(|opt| opt.short == Some(short))?;
+        let attached = chars.as_str();
+        match (opt.value, attached.is_empty()) {
+            (_, true) => Some((opt, None)),
+            (Some(_), false) => Some((opt, Some(attached.to_owned()))),
+            (None, false) => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn apply(
+    builder: InitSettingsBuilder,
+    option: &'static str,
+    value: &str,
+) -> Result<InitSettingsBuilder, ArgsError> {
+    let invalid = || ArgsError::InvalidValue {
+        option,
+        value: value.to_owned(),
+    };
+    let num = || value.parse::<usize>().map_err(|_| invalid());
+    let level = || value.parse::<f32>().map_err(|_| invalid());
+    let flag = || match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(invalid()),
+    };
+    let pin = || match value.parse::<i8>() {
+        Ok(pin) if pin < 0 => Ok(None),
+        Ok(pin) => Ok(Some(pin)),
+        Err(_) => Err(invalid()),
+    };
+    let mask = || {
+        let mut mask = 0usize;
+        for channel in value.split(',').filter(|channel| !channel.is_empty()) {
+            match channel
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .and_then(|ch| 1usize.checked_shl(ch))
+            {
+                Some(bit) => mask |= bit,
+                None => return Err(invalid()),
+            }
+        }
+        Ok(mask)
+    };
+
+    let builder = match option {
+        "period" => builder.period_size(num()?),
+        "dac-level" => builder.dac_level(level()?),
+        "adc-level" => builder.adc_level(level()?),
+        "pga-gain" => {
+            let gain = level()?;
+            builder.pga_gain([gain, gain])
+        }
+        "pga-gain-left" | "pga-gain-right" => {
+            let mut gain = builder.settings().pga_gain();
+            gain[if option == "pga-gain-left" { 0 } else { 1 }] = level()?;
+            builder.pga_gain(gain)
+        }
+        "hp-level" => builder.headphone_level(level()?),
+        "mute-speaker" => builder.begin_muted(flag()?),
+        "use-analog" => builder.use_analog(flag()?),
+        "use-digital" => builder.use_digital(flag()?),
+        "analog-channels" => builder.num_analog_channels(num()?),
+        "digital-channels" => builder.num_digital_channels(num()?),
+        "mux-channels" => builder.num_mux_channels(num()?),
+        "audio-expander-inputs" => builder.audio_expander_inputs(mask()?),
+        "audio-expander-outputs" => builder.audio_expander_outputs(mask()?),
+        "pru-file" => builder.pru_file(value).ok_or_else(invalid)?,
+        "pru-number" => builder.pru_number(num()?),
+        "detect-underruns" => builder.detect_underruns(flag()?),
+        "disable-led" => builder.enable_led(false),
+        "disable-cape-button-monitoring" => builder.stop_button_pin(None),
+        "stop-button-pin" => builder.stop_button_pin(pin()?),
+        "amp-mute-pin" => builder.amp_mute_pin(pin()?),
+        "high-performance-mode" => builder.high_performance_mode(true),
+        "uniform-sample-rate" => builder.uniform_sample_rate(true),
+        "board" => {
+            let board = BOARDS
+                .iter()
+                .find(|&&(name, _)| name.eq_ignore_ascii_case(value))
+                .ok_or_else(invalid)?;
+            builder.board(board.1)
+        }
+        "verbose" => builder.verbose(true),
+        _ => unreachable!("option without a setting: {}", option),
+    };
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<(InitSettings, Vec<String>), ArgsError> {
+        let builder = InitSettingsBuilder::from_settings(InitSettings::software_default());
+        parse(builder, args.iter().cloned())
+    }
+
+    fn settings(args: &[&str]) -> InitSettings {
+        parse_args(args).unwrap().0
+    }
+
+    fn error(args: &[&str]) -> ArgsError {
+        match parse_args(args) {
+            Ok(_) => panic!("{:?} parsed", args),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn value_forms() {
+        for args in &[
+            &["-p16"][..],
+            &["-p", "16"],
+            &["--period", "16"],
+            &["--period=16"],
+        ] {
+            assert_eq!(settings(args).period_size(), 16, "{:?}", args);
+        }
+        assert_eq!(settings(&["-p", "32"]).period_size(), 32);
+        assert_eq!(settings(&["--adc-level=-3.5"]).adc_level(), -3.5);
+        // The last of repeated options wins
+        assert_eq!(settings(&["-p", "8", "--period=64"]).period_size(), 64);
+    }
+
+    #[test]
+    fn flags_and_levels() {
+        let settings = settings(&[
+            "-6",
+            "--disable-led",
+            "--uniform-sample-rate",
+            "-M1",
+            "--use-digital=no",
+            "--pga-gain=10",
+            "--pga-gain-right",
+            "20",
+            "--stop-button-pin=-1",
+            "--amp-mute-pin",
+            "61",
+        ]);
+        assert!(settings.high_performance_mode());
+        assert!(!settings.enable_led());
+        assert!(settings.uniform_sample_rate());
+        assert!(settings.begin_muted());
+        assert!(!settings.use_digital());
+        assert_eq!(settings.pga_gain(), [10., 20.]);
+        assert_eq!(settings.stop_button_pin(), None);
+        assert_eq!(settings.amp_mute_pin(), Some(61));
+    }
+
+    #[test]
+    fn other_arguments_are_returned() {
+        let (settings, rest) = parse_args(&[
+            "input.wav",
+            "-p",
+            "32",
+            "--gain",
+            "3",
+            "-q",
+            "--",
+            "-p",
+            "64",
+            "--",
+        ])
+        .unwrap();
+        assert_eq!(settings.period_size(), 32);
+        assert_eq!(rest, ["input.wav", "--gain", "3", "-q", "-p", "64", "--"]);
+        // Flags given a value, or with characters attached, are not Bela's
+        let (_, rest) = parse_args(&["--verbose=1", "-vx"]).unwrap();
+        assert_eq!(rest, ["--verbose=1", "-vx"]);
+    }
+
+    #[test]
+    fn help() {
+        assert_eq!(error(&["-p", "16", "--help"]), ArgsError::Help);
+        assert_eq!(error(&["-h"]), ArgsError::Help);
+        // Unless it is meant for the program
+        assert!(parse_args(&["--", "--help"]).is_ok());
+        let listing = ArgsError::Help.to_string();
+        assert!(listing.starts_with("Bela options:\n"));
+        assert!(listing.contains("--period [-p] <frames>"));
+        assert!(listing.ends_with(
+            "Boards: NoHw, Bela, BelaMini, Salt, CtagFace, CtagBeast, CtagFaceBela, CtagBeastBela"
+        ));
+    }
+
+    #[test]
+    fn invalid_values() {
+        let cases: &[(&[&str], &str, &str)] = &[
+            (&["-p", "many"], "period", "many"),
+            (&["--period="], "period", ""),
+            (&["-D", "loud"], "dac-level", "loud"),
+            (&["--use-analog", "2"], "use-analog", "2"),
+            (&["--stop-button-pin=200"], "stop-button-pin", "200"),
+            (
+                &["--audio-expander-inputs=1,x"],
+                "audio-expander-inputs",
+                "1,x",
+            ),
+            (
+                &["--audio-expander-inputs=70"],
+                "audio-expander-inputs",
+                "70",
+            ),
+            (&["--board", "Bella"], "board", "Bella"),
+        ];
+        for &(args, option, value) in cases {
+            assert_eq!(
+                error(args),
+                ArgsError::InvalidValue {
+                    option,
+                    value: value.to_owned()
+                },
+                "{:?}",
+                args
+            );
+        }
+        assert_eq!(
+            error(&["-p", "many"]).to_string(),
+            "invalid value for --period: \"many\""
+        );
+    }
+
+    #[test]
+    fn missing_values() {
+        assert_eq!(error(&["-p"]), ArgsError::MissingValue("period"));
+        assert_eq!(error(&["--board"]), ArgsError::MissingValue("board"));
+        assert_eq!(
+            ArgsError::MissingValue("board").to_string(),
+            "--board requires a value"
+        );
+    }
+
+    #[test]
+    fn invalid_settings() {
+        match error(&["-p", "7", "-C", "3"]) {
+            ArgsError::Invalid(invalid) => {
+                let fields: Vec<&str> = invalid.fields.iter().map(|field| field.field).collect();
+                assert_eq!(
+                    fields,
+                    [
+                        "period_size",
+                        "num_analog_in_channels",
+                        "num_analog_out_channels"
+                    ]
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn board_ignores_case() {
+        for name in &["BelaMini", "belamini", "BELAMINI"] {
+            // The mini has no analog outputs
+            let settings = settings(&["--board", name, "-N0"]);
+            assert_eq!(settings.try_board(), Some(BelaHw::BelaMini));
+        }
+        assert_eq!(
+            settings(&["--board=ctagfacebela"]).try_board(),
+            Some(BelaHw::CtagFaceBela)
+        );
+    }
+
+    #[test]
+    fn audio_expander_masks() {
+        let expander = settings(&["-Y", "0,2", "--audio-expander-outputs=1, 3,"]);
+        assert_eq!(expander.audio_expander_inputs(), 0b101);
+        assert_eq!(expander.audio_expander_outputs(), 0b1010);
+        assert_eq!(settings(&["-Y", ""]).audio_expander_inputs(), 0);
+    }
+
+    #[test]
+    fn arguments_must_be_unicode() {
+        let args = vec![OsString::from("-p"), OsString::from("16")];
+        assert_eq!(unicode_args(args).unwrap(), ["-p", "16"]);
+        let bad = OsString::from_vec(vec![b'-', 0xFF]);
+        let args = vec![OsString::from("-p"), bad.clone()];
+        assert_eq!(unicode_args(args), Err(ArgsError::NotUnicode(bad)));
+        assert_eq!(
+            ArgsError::NotUnicode(OsString::from_vec(vec![0xFF])).to_string(),
+            "argument is not valid Unicode: \"\\xFF\""
+        );
+    }
+}
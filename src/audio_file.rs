@@ -0,0 +1,780 @@
+//! Plays audio files too long to load into memory, streaming them from disk.
+//!
+//! An `AudioFile` is opened in `setup`, which decodes the head of the file
+//! into memory. `Bela::create_audio_file_stream` then creates the auxiliary
+//! task decoding the rest of the file into two buffers, and the
+//! `AudioFileStream` render plays them from. Playback starts from the head,
+//! so the task has the length of the head to fill the first buffer, and so
+//! do loops and seeks within the head.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let file = AudioFile::open("loop.wav", 44_100)?;
+//! let mut stream = App::create_audio_file_stream(
+//!     file,
+//!     8192,
+//!     5,
+//!     &CString::new("loop-stream").unwrap(),
+//! );
+//! stream.set_looping(true);
+//! // In render, with interleaved buffers and a stereo file:
+//! stream.fill(context.audio_out());
+//! ```
+//!
+//! When a buffer has not been filled by the time render needs it, `fill`
+//! outputs silence for the rest of the block, and counts an underflow,
+//! rather than playing stale audio. WAV files are read with hound, and FLAC
+//! files with claxon when the `flac` feature is enabled.
+
+use std::cell::UnsafeCell;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+#[cfg(feature = "flac")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "flac")]
+use claxon;
+use hound;
+
+use files::to_io_error;
+use {error, CreatedTask};
+
+/// A decoder of interleaved samples.
+trait Decoder: Send {
+    /// Move to `frame`.
+    fn seek(&mut self, frame: u64) -> io::Result<()>;
+    /// Read whole frames into `out`, returning the number of frames read,
+    /// which is only short at the end of the file.
+    fn read(&mut self, out: &mut [f32]) -> io::Result<usize>;
+}
+
+struct Wav {
+    reader: hound::WavReader<BufReader<File>>,
+    channels: usize,
+}
+
+impl Decoder for Wav {
+    fn seek(&mut self, frame: u64) -> io::Result<()> {
+        let frame = frame.min(u64::from(self.reader.duration()));
+        self.reader.seek(frame as u32)
+    }
+
+    fn read(&mut self, out: &mut [f32]) -> io::Result<usize> {
+        let spec = self.reader.spec();
+        let mut read = 0;
+        match spec.sample_format {
+            hound::SampleFormat::Float => {
+                for (samp, value) in out.iter_mut().zip(self.reader.samples::<f32>()) {
+                    *samp = value.map_err(to_io_error)?;
+                    read += 1;
+                }
+            }
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                for (samp, value) in out.iter_mut().zip(self.reader.samples::<i32>()) {
+                    *samp = value.map_err(to_io_error)? as f32 / scale;
+                    read += 1;
+                }
+            }
+        }
+        Ok(read / self.channels)
+    }
+}
+
+#[cfg(feature = "flac")]
+struct Flac {
+    path: PathBuf,
+    reader: claxon::FlacReader<File>,
+    channels: usize,
+    scale: f32,
+    block: Option<claxon::Block>,
+    /// Next frame to read from `block`.
+    offset: u32,
+}
+
+#[cfg(feature = "flac")]
+impl Flac {
+    fn open(path: &Path) -> io::Result<(Flac, claxon::metadata::StreamInfo)> {
+        let reader = claxon::FlacReader::open(path).map_err(flac_error)?;
+        let info = reader.streaminfo();
+        let flac = Flac {
+            path: path.to_owned(),
+            reader,
+            channels: info.channels as usize,
+            scale: (1u64 << (info.bits_per_sample - 1)) as f32,
+            block: None,
+            offset: 0,
+        };
+        Ok((flac, info))
+    }
+
+    /// Make sure `block` has frames left, returning `false` at the end of the
+    /// file.
+    fn next_block(&mut self) -> io::Result<bool> {
+        if let Some(ref block) = self.block {
+            if self.offset < block.duration() {
+                return Ok(true);
+            }
+        }
+        let buffer = self
+            .block
+            .take()
+            .map(claxon::Block::into_buffer)
+            .unwrap_or_default();
+        self.block = self
+            .reader
+            .blocks()
+            .read_next_or_eof(buffer)
+            .map_err(flac_error)?;
+        self.offset = 0;
+        Ok(self.block.is_some())
+    }
+}
+
+#[cfg(feature = "flac")]
+impl Decoder for Flac {
+    /// FLAC files are seeked by decoding them again from the start.
+    fn seek(&mut self, frame: u64) -> io::Result<()> {
+        let (flac, _) = Flac::open(&self.path)?;
+        *self = flac;
+        let mut skipped = 0;
+        while skipped < frame && self.next_block()? {
+            let left = match self.block {
+                Some(ref block) => u64::from(block.duration() - self.offset),
+                None => 0,
+            };
+            let skip = left.min(frame - skipped);
+            self.offset += skip as u32;
+            skipped += skip;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, out: &mut [f32]) -> io::Result<usize> {
+        let frames = out.len() / self.channels;
+        let mut read = 0;
+        while read < frames && self.next_block()? {
+            if let Some(ref block) = self.block {
+                while read < frames && self.offset < block.duration() {
+                    for channel in 0..self.channels {
+                        out[read * self.channels + channel] =
+                            block.sample(channel as u32, self.offset) as f32 / self.scale;
+                    }
+                    self.offset += 1;
+                    read += 1;
+                }
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "flac")]
+fn flac_error(err: claxon::Error) -> io::Error {
+    match err {
+        claxon::Error::IoError(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// An audio file opened for streaming, with its head decoded.
+pub struct AudioFile {
+    decoder: Box<dyn Decoder>,
+    channels: usize,
+    sample_rate: u32,
+    frames: u64,
+    /// Interleaved samples of the first frames of the file.
+    head: Vec<f32>,
+}
+
+impl AudioFile {
+    /// Open a `.wav` file, or a `.flac` file with the `flac` feature, and
+    /// decode its first `head_frames` frames.
+    pub fn open<P: AsRef<Path>>(path: P, head_frames: usize) -> io::Result<AudioFile> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(OsStr::to_str);
+        let (decoder, channels, sample_rate, frames): (Box<dyn Decoder>, _, _, _) = match extension
+        {
+            Some("wav") => {
+                let reader = hound::WavReader::open(path).map_err(to_io_error)?;
+                let spec = reader.spec();
+                let frames = u64::from(reader.duration());
+                let channels = spec.channels as usize;
+                let wav = Wav { reader, channels };
+                (Box::new(wav), channels, spec.sample_rate, frames)
+            }
+            #[cfg(feature = "flac")]
+            Some("flac") => {
+                let (flac, info) = Flac::open(path)?;
+                let frames = info.samples.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} does not give its length", path.display()),
+                    )
+                })?;
+                (
+                    Box::new(flac),
+                    info.channels as usize,
+                    info.sample_rate,
+                    frames,
+                )
+            }
+            _ => return Err(unsupported(path)),
+        };
+        if channels == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has no channels", path.display()),
+            ));
+        }
+
+        let mut file = AudioFile {
+            decoder,
+            channels,
+            sample_rate,
+            frames,
+            head: Vec::new(),
+        };
+        let head_frames = (head_frames as u64).min(frames) as usize;
+        file.head.resize(head_frames * channels, 0.);
+        let read = file.decoder.read(&mut file.head)?;
+        file.head.truncate(read * channels);
+        if read < head_frames {
+            // The file is shorter than its header says.
+            file.frames = read as u64;
+        }
+        Ok(file)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Length of the file, in frames.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Number of frames decoded into memory.
+    pub fn head_frames(&self) -> usize {
+        self.head.len() / self.channels
+    }
+}
+
+fn unsupported(path: &Path) -> io::Error {
+    let hint = if cfg!(feature = "flac") {
+        ""
+    } else {
+        ", or .flac with the flac feature"
+    };
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not a .wav file{}", path.display(), hint),
+    )
+}
+
+const EMPTY: usize = 0;
+const FULL: usize = 1;
+
+/// Frames decoded by the task. While the state of its slot is `EMPTY` it
+/// belongs to the task, and while it is `FULL` to render.
+struct Chunk {
+    samples: Vec<f32>,
+    start: u64,
+    frames: usize,
+    /// Seek generation the chunk was decoded for.
+    generation: usize,
+}
+
+struct Slot {
+    state: AtomicUsize,
+    chunk: UnsafeCell<Chunk>,
+}
+
+/// State shared between an `AudioFileStream` and its task.
+struct Shared {
+    slots: [Slot; 2],
+    /// Bumped by render on every seek, after storing `target`.
+    generation: AtomicUsize,
+    /// Frame the task decodes from after a seek.
+    target: AtomicU64,
+    /// Length of the file, lowered by the task if it ends early.
+    end: AtomicU64,
+    failed: AtomicBool,
+}
+
+// Chunks are handed between the ends by the release stores of their state.
+unsafe impl Sync for Shared {}
+
+/// Decodes the file into empty buffers, run by the auxiliary task.
+pub(crate) struct Filler {
+    decoder: Box<dyn Decoder>,
+    shared: Arc<Shared>,
+    head_frames: u64,
+    frames: u64,
+    generation: usize,
+    position: u64,
+}
+
+impl Filler {
+    pub(crate) fn run(&mut self) {
+        let generation = self.shared.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            self.generation = generation;
+            // The head is played from memory.
+            let target = self.shared.target.load(Ordering::Acquire);
+            self.position = target.max(self.head_frames).min(self.frames);
+            if self.decoder.seek(self.position).is_err() {
+                self.fail();
+                return;
+            }
+        }
+
+        for slot in self.shared.slots.iter() {
+            if self.position >= self.frames {
+                return;
+            }
+            if slot.state.load(Ordering::Acquire) != EMPTY {
+                continue;
+            }
+            let chunk = unsafe { &mut *slot.chunk.get() };
+            let frames = match self.decoder.read(&mut chunk.samples) {
+                Ok(frames) => frames,
+                Err(_) => {
+                    self.fail();
+                    return;
+                }
+            };
+            if frames == 0 {
+                // The file is shorter than its header says.
+                self.frames = self.position;
+                self.shared.end.store(self.position, Ordering::Release);
+                return;
+            }
+            chunk.start = self.position;
+            chunk.frames = frames;
+            chunk.generation = self.generation;
+            self.position += frames as u64;
+            slot.state.store(FULL, Ordering::Release);
+        }
+    }
+
+    /// Stop decoding for good. Render finishes once it has played the chunks
+    /// already decoded.
+    fn fail(&mut self) {
+        self.frames = 0;
+        self.shared.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Split `file` into the stream played by render and the filler of its
+/// buffers of `buffer_frames` frames, which are filled before returning.
+pub(crate) fn split(file: AudioFile, buffer_frames: usize) -> (Stream, Filler) {
+    let samples = buffer_frames.max(1) * file.channels;
+    let slot = || Slot {
+        state: AtomicUsize::new(EMPTY),
+        chunk: UnsafeCell::new(Chunk {
+            samples: vec![0.; samples],
+            start: 0,
+            frames: 0,
+            generation: 0,
+        }),
+    };
+    let shared = Arc::new(Shared {
+        slots: [slot(), slot()],
+        generation: AtomicUsize::new(0),
+        target: AtomicU64::new(0),
+        end: AtomicU64::new(file.frames),
+        failed: AtomicBool::new(false),
+    });
+    let head_frames = (file.head.len() / file.channels) as u64;
+    let mut filler = Filler {
+        decoder: file.decoder,
+        shared: shared.clone(),
+        head_frames,
+        frames: file.frames,
+        generation: 0,
+        position: head_frames,
+    };
+    filler.run();
+    let stream = Stream {
+        shared,
+        head: file.head,
+        channels: file.channels,
+        sample_rate: file.sample_rate,
+        frames: file.frames,
+    };
+    (stream, filler)
+}
+
+/// The render end of a stream, until its task is created.
+pub(crate) struct Stream {
+    shared: Arc<Shared>,
+    head: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    frames: u64,
+}
+
+/// An audio file played from the render thread, as returned by
+/// `Bela::create_audio_file_stream`.
+pub struct AudioFileStream {
+    stream: Stream,
+    position: u64,
+    generation: usize,
+    looping: bool,
+    finished: bool,
+    underflows: u64,
+    task: CreatedTask,
+    schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+}
+
+impl AudioFileStream {
+    pub(crate) fn new(
+        stream: Stream,
+        task: CreatedTask,
+        schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+    ) -> AudioFileStream {
+        AudioFileStream {
+            stream,
+            position: 0,
+            generation: 0,
+            looping: false,
+            finished: false,
+            underflows: 0,
+            task,
+            schedule,
+        }
+    }
+
+    /// Fill `out` with the next interleaved frames of the file. Never blocks
+    /// or allocates.
+    ///
+    /// Past the end of a file that is not looping, and for the rest of the
+    /// block after an underflow, `out` is filled with silence. Returns the
+    /// number of frames played from the file.
+    pub fn fill(&mut self, out: &mut [f32]) -> usize {
+        let channels = self.stream.channels;
+        let frames = out.len() / channels;
+        let head_frames = (self.stream.head.len() / channels) as u64;
+        let end = self.stream.shared.end.load(Ordering::Acquire);
+        let mut done = 0;
+        while done < frames {
+            if self.position >= end {
+                if !self.looping || end == 0 {
+                    self.finished = true;
+                    break;
+                }
+                self.seek(0);
+            }
+
+            let left = (frames - done) as u64;
+            let (slot, start, count) = if self.position < head_frames {
+                (None, 0, head_frames)
+            } else {
+                match self.current_chunk() {
+                    Some((slot, start, count)) => (Some(slot), start, count),
+                    None if self.has_failed() => {
+                        self.finished = true;
+                        break;
+                    }
+                    None => {
+                        self.underflows += 1;
+                        self.schedule();
+                        break;
+                    }
+                }
+            };
+            let offset = (self.position - start) as usize;
+            let n = left.min(start + count - self.position) as usize;
+            let samples = match slot {
+                Some(slot) => unsafe { &(*self.stream.shared.slots[slot].chunk.get()).samples },
+                None => &self.stream.head,
+            };
+            out[done * channels..(done + n) * channels]
+                .copy_from_slice(&samples[offset * channels..(offset + n) * channels]);
+            self.position += n as u64;
+            done += n;
+        }
+        for samp in out[done * channels..].iter_mut() {
+            *samp = 0.;
+        }
+        done
+    }
+
+    /// The slot, start and length of the chunk holding the current position,
+    /// releasing every chunk that will not be played.
+    fn current_chunk(&self) -> Option<(usize, u64, u64)> {
+        let mut current = None;
+        let mut released = false;
+        for (idx, slot) in self.stream.shared.slots.iter().enumerate() {
+            if slot.state.load(Ordering::Acquire) != FULL {
+                continue;
+            }
+            let chunk = unsafe { &*slot.chunk.get() };
+            let end = chunk.start + chunk.frames as u64;
+            if chunk.generation != self.generation || end <= self.position {
+                slot.state.store(EMPTY, Ordering::Release);
+                released = true;
+            } else if chunk.start <= self.position {
+                current = Some((idx, chunk.start, chunk.frames as u64));
+            }
+        }
+        if released {
+            self.schedule();
+        }
+        current
+    }
+
+    fn schedule(&self) {
+        let _ = (self.schedule)(&self.task);
+    }
+
+    /// Play from `frame` on. Seeking within the head is seamless; elsewhere,
+    /// playback underflows until the task has decoded the new position.
+    pub fn seek(&mut self, frame: u64) {
+        self.position = frame.min(self.stream.frames);
+        self.finished = false;
+        self.generation = self.generation.wrapping_add(1);
+        let shared = &self.stream.shared;
+        // Chunks decoded before the seek are of no use, so they are handed
+        // back for the task to decode the new position into right away.
+        for slot in shared.slots.iter() {
+            if slot.state.load(Ordering::Acquire) == FULL {
+                slot.state.store(EMPTY, Ordering::Release);
+            }
+        }
+        shared.target.store(self.position, Ordering::Release);
+        shared.generation.store(self.generation, Ordering::Release);
+        self.schedule();
+    }
+
+    /// Frame played next.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Whether to start again from the head at the end of the file.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping
+    }
+
+    /// Whether the end of the file has been reached without looping.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Number of blocks cut short because the task had not filled the next
+    /// buffer in time.
+    pub fn underflows(&self) -> u64 {
+        self.underflows
+    }
+
+    /// Whether decoding has failed. The stream then finishes once the frames
+    /// already decoded have been played.
+    pub fn has_failed(&self) -> bool {
+        self.stream.shared.failed.load(Ordering::SeqCst)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.stream.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.stream.sample_rate
+    }
+
+    /// Length of the file, in frames.
+    pub fn frames(&self) -> u64 {
+        self.stream.frames
+    }
+
+    /// The task decoding the file.
+    pub fn task(&self) -> &CreatedTask {
+        &self.task
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::ops::Range;
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    use super::*;
+    use backend::Offline;
+    use {Bela, OwnedAppData};
+
+    type App = Bela<OwnedAppData<()>, Offline>;
+
+    /// A file of `frames` frames, where channel `c` of frame `n` is
+    /// `n + 1000 * c`.
+    struct Ramp(PathBuf);
+
+    impl Ramp {
+        fn new(name: &str, channels: usize, frames: usize) -> Ramp {
+            let path =
+                env::temp_dir().join(format!("bela-audio-file-{}-{}.wav", process::id(), name));
+            let spec = hound::WavSpec {
+                channels: channels as u16,
+                sample_rate: 44_100,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for frame in 0..frames {
+                for channel in 0..channels {
+                    writer.write_sample(value(frame, channel)).unwrap();
+                }
+            }
+            writer.finalize().unwrap();
+            Ramp(path)
+        }
+
+        fn open(&self, head_frames: usize) -> AudioFile {
+            AudioFile::open(&self.0, head_frames).unwrap()
+        }
+    }
+
+    impl Drop for Ramp {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn value(frame: usize, channel: usize) -> f32 {
+        (frame + 1000 * channel) as f32
+    }
+
+    fn stream(file: AudioFile, buffer_frames: usize) -> AudioFileStream {
+        App::create_audio_file_stream(file, buffer_frames, 0, &CString::new("test").unwrap())
+    }
+
+    /// Play `blocks` blocks of `frames` frames of a mono stream.
+    fn play(stream: &mut AudioFileStream, blocks: usize, frames: usize) -> Vec<f32> {
+        let mut out = vec![0.; frames];
+        let mut played = Vec::new();
+        for _ in 0..blocks {
+            stream.fill(&mut out);
+            played.extend_from_slice(&out);
+        }
+        played
+    }
+
+    fn ramp(frames: Range<usize>) -> Vec<f32> {
+        frames.map(|frame| value(frame, 0)).collect()
+    }
+
+    #[test]
+    fn open_decodes_head() {
+        let ramp = Ramp::new("head", 2, 10);
+        let file = ramp.open(4);
+        assert_eq!((file.channels(), file.sample_rate()), (2, 44_100));
+        assert_eq!((file.frames(), file.head_frames()), (10, 4));
+        assert_eq!(ramp.open(100).head_frames(), 10);
+    }
+
+    #[test]
+    fn open_refuses_other_formats() {
+        let err = AudioFile::open("sample.mp3", 4).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn plays_through_head_and_buffers() {
+        let ramp = Ramp::new("play", 1, 20);
+        let mut stream = stream(ramp.open(4), 3);
+        let mut played = play(&mut stream, 5, 5);
+        assert!(stream.is_finished());
+        assert_eq!(stream.underflows(), 0);
+        assert_eq!(played.split_off(20), vec![0.; 5]);
+        assert_eq!(played, self::ramp(0..20));
+    }
+
+    #[test]
+    fn fill_returns_frames_played() {
+        let ramp = Ramp::new("frames", 1, 6);
+        let mut stream = stream(ramp.open(2), 2);
+        let mut out = [1.; 4];
+        assert_eq!(stream.fill(&mut out), 4);
+        assert_eq!(stream.fill(&mut out), 2);
+        assert_eq!(out, [4., 5., 0., 0.]);
+        assert_eq!(stream.fill(&mut out), 0);
+        assert_eq!(out, [0.; 4]);
+    }
+
+    #[test]
+    fn interleaves_channels() {
+        let ramp = Ramp::new("stereo", 2, 6);
+        let mut stream = stream(ramp.open(2), 2);
+        let mut out = [0.; 12];
+        assert_eq!(stream.fill(&mut out), 6);
+        let expected: Vec<f32> = (0..6)
+            .flat_map(|frame| vec![value(frame, 0), value(frame, 1)])
+            .collect();
+        assert_eq!(&out[..], &expected[..]);
+    }
+
+    #[test]
+    fn loops_from_the_head() {
+        let ramp = Ramp::new("loop", 1, 7);
+        let mut stream = stream(ramp.open(3), 2);
+        stream.set_looping(true);
+        let played = play(&mut stream, 4, 4);
+        assert!(!stream.is_finished());
+        let expected: Vec<f32> = (0..16).map(|frame| value(frame % 7, 0)).collect();
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn seeks() {
+        let ramp = Ramp::new("seek", 1, 20);
+        let mut stream = stream(ramp.open(4), 3);
+        stream.seek(12);
+        assert_eq!(play(&mut stream, 1, 4), self::ramp(12..16));
+        stream.seek(1);
+        assert_eq!(play(&mut stream, 1, 6), self::ramp(1..7));
+        stream.seek(100);
+        assert_eq!(stream.position(), 20);
+        assert_eq!(play(&mut stream, 1, 2), vec![0.; 2]);
+        assert!(stream.is_finished());
+    }
+
+    fn never_schedule(_task: &CreatedTask) -> Result<(), error::Error> {
+        Ok(())
+    }
+
+    #[test]
+    fn underflows_until_filled() {
+        let ramp = Ramp::new("underflow", 1, 20);
+        let (inner, mut filler) = split(ramp.open(4), 3);
+        let task = App::create_auxiliary_task(Box::new(|| {}), 0, &CString::new("test").unwrap());
+        let mut stream = AudioFileStream::new(inner, task, never_schedule);
+
+        // The head and both buffers, filled up front, then nothing
+        let mut out = [0.; 12];
+        assert_eq!(stream.fill(&mut out), 10);
+        assert_eq!(&out[..10], &self::ramp(0..10)[..]);
+        assert_eq!(&out[10..], &[0., 0.]);
+        assert_eq!(stream.underflows(), 1);
+        assert!(!stream.is_finished());
+
+        filler.run();
+        let mut out = [0.; 4];
+        assert_eq!(stream.fill(&mut out), 4);
+        assert_eq!(&out[..], &self::ramp(10..14)[..]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::{AuxiliaryTaskFn, Backend, TaskClosure, TaskHandle};
+use codec::Codec;
+use error;
+use {CreatedTask, InitSettings};
+
+/// Backend that runs on the Bela board by calling into libbela.
+///
+/// libbela cannot delete a single auxiliary task, so the closure of every task
+/// is kept from its creation until `cleanup_audio` deletes every task. Deleting
+/// a task then only marks it as deleted, which never locks or allocates.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LibBela;
+
+/// Incremented every time libbela deletes all auxiliary tasks.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Closures of the tasks whose threads are still registered with libbela.
+static CLOSURES: Mutex<Vec<TaskClosure>> = Mutex::new(Vec::new());
+
+/// Number of tasks deleted since the last `cleanup_audio`.
+static DELETED: AtomicUsize = AtomicUsize::new(0);
+
+impl LibBela {
+    /// Number of deleted tasks whose closures are kept until the next
+    /// `cleanup_audio`.
+    pub fn pending_tasks() -> usize {
+        DELETED.load(Ordering::SeqCst)
+    }
+}
+
+/// Keep the closure of a task created in `generation` until its thread is
+/// gone.
+pub(crate) fn keep(closure: TaskClosure, generation: usize) {
+    if generation == GENERATION.load(Ordering::SeqCst) {
+        super::lock(&CLOSURES).push(closure);
+    }
+}
+
+/// Record that a task created in `generation` was deleted.
+pub(crate) fn deleted(generation: usize) {
+    if generation == GENERATION.load(Ordering::SeqCst) {
+        DELETED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Backend for LibBela {
+    unsafe fn init_audio(
+        &mut self,
+        settings: &mut InitSettings,
+        user_data: *mut c_void,
+    ) -> Result<(), error::Error> {
+        error::clear_errno();
+        let out = bela_sys::Bela_initAudio(settings.settings_ptr(), user_data);
+
+        match out {
+            0 => Ok(()),
+            _ => Err(error::Error::from_code(error::ErrorKind::Init, out).with_settings(settings)),
+        }
+    }
+
+    fn start_audio(&mut self) -> Result<(), error::Error> {
+        error::clear_errno();
+        let out = unsafe { bela_sys::Bela_startAudio() };
+
+        match out {
+            0 => Ok(()),
+            _ => Err(error::Error::from_code(error::ErrorKind::Start, out)),
+        }
+    }
+
+    fn should_stop(&self) -> bool {
+        unsafe { bela_sys::Bela_stopRequested() != 0 }
+    }
+
+    fn stop_audio(&mut self) {
+        unsafe {
+            bela_sys::Bela_stopAudio();
+        }
+    }
+
+    fn cleanup_audio(&mut self) {
+        unsafe {
+            bela_sys::Bela_cleanupAudio();
+            bela_sys::Bela_deleteAllAuxiliaryTasks();
+        }
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        DELETED.store(0, Ordering::SeqCst);
+        super::lock(&CLOSURES).clear();
+    }
+
+    fn codec(&self) -> Codec {
+        Codec::libbela()
+    }
+
+    unsafe fn create_auxiliary_task(
+        callback: AuxiliaryTaskFn,
+        arg: *mut c_void,
+        priority: i32,
+        name: &CStr,
+    ) -> CreatedTask {
+        let aux_task =
+            bela_sys::Bela_createAuxiliaryTask(Some(callback), priority, name.as_ptr(), arg);
+
+        CreatedTask::new(TaskHandle::Bela(
+            aux_task,
+            GENERATION.load(Ordering::SeqCst),
+        ))
+    }
+
+    fn schedule_auxiliary_task(task: &CreatedTask) -> Result<(), error::Error> {
+        if task.0.is_deleted() {
+            return Err(super::deleted_error());
+        }
+        let res = match task.0.handle {
+            TaskHandle::Bela(_, generation) if generation != GENERATION.load(Ordering::SeqCst) => {
+                return Err(error::Error::new(error::ErrorKind::Task)
+                    .with_detail("the task was deleted by cleanup_audio"))
+            }
+            TaskHandle::Bela(aux_task, _) => unsafe {
+                error::clear_errno();
+                bela_sys::Bela_scheduleAuxiliaryTask(aux_task)
+            },
+            _ => {
+                return Err(error::Error::new(error::ErrorKind::Task)
+                    .with_detail("the task was not created by libbela"))
+            }
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(error::Error::from_code(error::ErrorKind::Task, res)),
+        }
+    }
+}
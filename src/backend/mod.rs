@@ -0,0 +1,351 @@
+//! Backends that drive the `Bela` lifecycle.
+//!
+//! `Bela<T, B>` never calls into libbela directly. Instead, every lifecycle
+//! call (`init_audio`, `start_audio`, `should_stop`, `stop_audio` and
+//! `cleanup_audio`) and every auxiliary task call is dispatched through a
+//! `Backend`. `LibBela` is the backend used on the board, and `Software` runs
+//! the same setup, render and cleanup callbacks from a plain thread, so that
+//! render code can be run off the board. `Offline` renders from and to files
+//! as fast as possible. `LibBela` is only usable with the `libbela` feature,
+//! without which the crate does not link against libbela.
+
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use codec::{Codec, Levels};
+use error;
+use {CreatedTask, InitSettings};
+
+#[cfg(feature = "libbela")]
+mod libbela;
+mod offline;
+pub(crate) mod session;
+mod software;
+
+#[cfg(feature = "libbela")]
+pub use self::libbela::LibBela;
+pub use self::offline::Offline;
+pub use self::software::Software;
+
+/// Stands in for the libbela backend without the `libbela` feature, so that
+/// `Bela` keeps its default backend type. It does not implement `Backend`.
+#[cfg(not(feature = "libbela"))]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LibBela;
+
+/// Callback invoked on the auxiliary task thread with the task argument.
+pub type AuxiliaryTaskFn = extern "C" fn(*mut c_void);
+
+/// Audio lifecycle used by `Bela`.
+pub trait Backend {
+    /// Initialise audio, calling the setup callback.
+    ///
+    /// `settings` has the setup, render and cleanup trampolines already filled
+    /// in, and `user_data` is the pointer to pass back to them.
+    ///
+    /// # Safety
+    ///
+    /// `user_data` must be valid for the trampolines in `settings` and must
+    /// outlive the call to `cleanup_audio`.
+    unsafe fn init_audio(
+        &mut self,
+        settings: &mut InitSettings,
+        user_data: *mut c_void,
+    ) -> Result<(), error::Error>;
+
+    fn start_audio(&mut self) -> Result<(), error::Error>;
+
+    fn should_stop(&self) -> bool;
+
+    fn stop_audio(&mut self);
+
+    fn cleanup_audio(&mut self);
+
+    /// Handle to the codec levels of this backend.
+    ///
+    /// The default is a no-op: it returns a fresh handle that validates and
+    /// records levels, but that the backend never reads, so they have no
+    /// effect on the audio. `LibBela` overrides it to control the codec, and
+    /// `Software` and `Offline` to hand out the levels they apply.
+    fn codec(&self) -> Codec {
+        Codec::software(Arc::new(Levels::default()))
+    }
+
+    /// Register `callback` to be run with `arg` on a lower-priority thread
+    /// whenever the returned task is scheduled.
+    ///
+    /// # Safety
+    ///
+    /// `arg` must be valid for `callback` for as long as the task exists, and
+    /// must be safe to use from another thread.
+    unsafe fn create_auxiliary_task(
+        callback: AuxiliaryTaskFn,
+        arg: *mut c_void,
+        priority: i32,
+        name: &CStr,
+    ) -> CreatedTask;
+
+    fn schedule_auxiliary_task(task: &CreatedTask) -> Result<(), error::Error>;
+}
+
+/// Backend-specific representation of a `CreatedTask`
+pub(crate) enum TaskHandle {
+    /// A libbela task, and the generation of libbela tasks it belongs to.
+    #[cfg(feature = "libbela")]
+    Bela(bela_sys::AuxiliaryTask, usize),
+    /// Wakes the task thread, which is joined when the task is deleted.
+    Software(
+        mpsc::SyncSender<software::Wake>,
+        Mutex<Option<JoinHandle<()>>>,
+    ),
+    /// Run on the scheduling thread as soon as it is scheduled
+    Inline(AuxiliaryTaskFn, *mut c_void),
+}
+
+/// The closure an auxiliary task runs, freed by `drop` along with it.
+pub(crate) struct TaskClosure {
+    ptr: *mut c_void,
+    drop: unsafe fn(*mut c_void),
+}
+
+impl TaskClosure {
+    /// # Safety
+    ///
+    /// `drop` must free `ptr`, and `ptr` must not be used once the task the
+    /// closure belongs to has stopped.
+    pub(crate) unsafe fn new(ptr: *mut c_void, drop: unsafe fn(*mut c_void)) -> TaskClosure {
+        TaskClosure { ptr, drop }
+    }
+}
+
+impl Drop for TaskClosure {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.ptr) };
+    }
+}
+
+// The closure is `Send`, and is only called from the task thread.
+unsafe impl Send for TaskClosure {}
+
+/// An auxiliary task, shared by every clone of its `CreatedTask`.
+pub(crate) struct Task {
+    pub(crate) handle: TaskHandle,
+    closure: Mutex<Option<TaskClosure>>,
+    deleted: AtomicBool,
+}
+
+impl Task {
+    pub(crate) fn new(handle: TaskHandle) -> Task {
+        Task {
+            handle,
+            closure: Mutex::new(None),
+            deleted: AtomicBool::new(false),
+        }
+    }
+
+    /// Hand the closure the task runs over to the task, to be freed once the
+    /// task is deleted.
+    pub(crate) fn set_closure(&self, closure: TaskClosure) {
+        match self.handle {
+            // Kept by libbela, so that deleting the task needs no lock
+            #[cfg(feature = "libbela")]
+            TaskHandle::Bela(_, generation) => libbela::keep(closure, generation),
+            _ => *lock(&self.closure) = Some(closure),
+        }
+    }
+
+    pub(crate) fn is_deleted(&self) -> bool {
+        self.deleted.load(Ordering::SeqCst)
+    }
+
+    /// Stop the task and free its closure, as far as the backend allows.
+    pub(crate) fn delete(&self) {
+        if self.deleted.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        match self.handle {
+            #[cfg(feature = "libbela")]
+            TaskHandle::Bela(_, generation) => libbela::deleted(generation),
+            TaskHandle::Software(ref wake, ref thread) => {
+                let closure = lock(&self.closure).take();
+                if !software::stop_task(wake, thread) {
+                    // Deleted from its own thread, which is still running
+                    // the closure.
+                    mem::forget(closure);
+                }
+            }
+            TaskHandle::Inline(..) => drop(lock(&self.closure).take()),
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.delete();
+    }
+}
+
+/// The error for scheduling a task that has been deleted.
+pub(crate) fn deleted_error() -> error::Error {
+    error::Error::new(error::ErrorKind::Task).with_detail("the task has been deleted")
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::sync::atomic::AtomicUsize;
+    use std::{thread, time};
+
+    use super::*;
+    use {Bela, OwnedAppData};
+
+    type SoftwareApp = Bela<OwnedAppData<()>, Software>;
+    type OfflineApp = Bela<OwnedAppData<()>, Offline>;
+
+    /// Counts the closures that captured it and have since been freed.
+    struct Freed(Arc<AtomicUsize>);
+
+    impl Drop for Freed {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// A task closure that only holds a `Freed`, and the count it adds to.
+    fn closure() -> (Box<impl FnMut() + Send>, Arc<AtomicUsize>) {
+        let freed = Arc::new(AtomicUsize::new(0));
+        let held = Freed(freed.clone());
+        (
+            Box::new(move || {
+                let _ = &held;
+            }),
+            freed,
+        )
+    }
+
+    /// A `CreatedTask` moved into its own closure.
+    struct Own(CreatedTask);
+
+    // Software handles hold only a channel and a mutex
+    unsafe impl Send for Own {}
+
+    fn name() -> CString {
+        CString::new("test").unwrap()
+    }
+
+    fn software() -> Software {
+        let mut backend = Software::new();
+        backend.set_realtime(false);
+        backend.set_max_blocks(Some(1));
+        backend
+    }
+
+    #[test]
+    fn last_handle_frees_the_closure() {
+        let (task, freed) = closure();
+        let created = SoftwareApp::create_auxiliary_task(task, 0, &name());
+        let other = created.clone();
+        drop(created);
+        assert_eq!(freed.load(Ordering::SeqCst), 0);
+        assert!(SoftwareApp::schedule_auxiliary_task(&other).is_ok());
+        drop(other);
+        assert_eq!(freed.load(Ordering::SeqCst), 1);
+
+        let (task, freed) = closure();
+        drop(OfflineApp::create_auxiliary_task(task, 0, &name()));
+        assert_eq!(freed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn delete_frees_the_closure() {
+        let (task, freed) = closure();
+        let created = SoftwareApp::create_auxiliary_task(task, 0, &name());
+        created.delete();
+        assert_eq!(freed.load(Ordering::SeqCst), 1);
+        assert!(created.is_deleted());
+        assert!(SoftwareApp::schedule_auxiliary_task(&created).is_err());
+        // Deleting again, or dropping the handle, frees nothing more
+        created.delete();
+        drop(created);
+        assert_eq!(freed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn delete_joins_the_software_thread() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let created = {
+            let runs = runs.clone();
+            SoftwareApp::create_auxiliary_task(
+                Box::new(move || {
+                    thread::sleep(time::Duration::from_millis(50));
+                    runs.fetch_add(1, Ordering::SeqCst);
+                }),
+                0,
+                &name(),
+            )
+        };
+        SoftwareApp::schedule_auxiliary_task(&created).unwrap();
+        created.delete();
+        // The pending run finished before the thread was joined
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        match created.0.handle {
+            TaskHandle::Software(_, ref thread) => assert!(lock(thread).is_none()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn task_can_delete_itself() {
+        let slot: Arc<Mutex<Option<Own>>> = Arc::default();
+        let (done_tx, done_rx) = mpsc::channel();
+        let created = {
+            let slot = slot.clone();
+            SoftwareApp::create_auxiliary_task(
+                Box::new(move || {
+                    if let Some(Own(task)) = lock(&slot).take() {
+                        task.delete();
+                        let _ = done_tx.send(());
+                    }
+                }),
+                0,
+                &name(),
+            )
+        };
+        *lock(&slot) = Some(Own(created.clone()));
+        SoftwareApp::schedule_auxiliary_task(&created).unwrap();
+        done_rx
+            .recv_timeout(time::Duration::from_secs(5))
+            .expect("the task deadlocked deleting itself");
+        assert!(created.is_deleted());
+        assert!(SoftwareApp::schedule_auxiliary_task(&created).is_err());
+    }
+
+    #[test]
+    fn cleanup_deletes_scoped_tasks() {
+        let mut bela = Bela::with_backend(OwnedAppData::new((), |_, _| ()), software());
+        let (task, scoped_freed) = closure();
+        let scoped = bela.create_scoped_auxiliary_task(task, 0, &name());
+        let (task, kept_freed) = closure();
+        let kept = SoftwareApp::create_auxiliary_task(task, 0, &name());
+
+        bela.run(&mut InitSettings::software_default()).unwrap();
+        assert!(scoped.is_deleted());
+        assert_eq!(scoped_freed.load(Ordering::SeqCst), 1);
+        // Other tasks outlive the run
+        assert!(!kept.is_deleted());
+        assert!(SoftwareApp::schedule_auxiliary_task(&kept).is_ok());
+        assert_eq!(kept_freed.load(Ordering::SeqCst), 0);
+        assert!(bela.scoped_tasks.is_empty());
+    }
+}
@@ -0,0 +1,652 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time;
+
+use hound;
+
+use super::session::Session;
+use super::{AuxiliaryTaskFn, Backend, TaskHandle};
+use codec::{Codec, Levels};
+use error;
+use files::{check_wav_spec, create_wav, to_io_error, write_wav, DigitalCsv, WavWriter};
+use {Context, ContextBuilder, CreatedTask, InitSettings, Layout};
+
+/// Backend that renders a fixed duration as fast as possible, reading inputs
+/// from and writing outputs to files.
+///
+/// Audio and analog inputs are read from WAV files, and audio and analog
+/// outputs are written to 32-bit float WAV files at the audio and analog
+/// sample rates. Digital pin values are written to a CSV file with one row
+/// per digital frame. Any input that is not given, or that runs out before
+/// the end of the render, reads as silence.
+///
+/// The whole render happens within `start_audio`, after which `should_stop`
+/// returns `true`, so `Bela::run` returns as soon as the files are written.
+/// Auxiliary tasks are run synchronously when they are scheduled.
+///
+/// ```rust,ignore
+/// let mut backend = backend::Offline::new(time::Duration::from_secs(10));
+/// backend.set_audio_in("voice.wav");
+/// backend.set_audio_out("processed.wav");
+/// Bela::with_backend(user_data, backend).run(&mut settings)
+/// ```
+pub struct Offline {
+    audio_in_channels: usize,
+    audio_out_channels: usize,
+    audio_sample_rate: f32,
+    duration: time::Duration,
+    audio_in: Option<PathBuf>,
+    analog_in: Option<PathBuf>,
+    audio_out: Option<PathBuf>,
+    analog_out: Option<PathBuf>,
+    digital_out: Option<PathBuf>,
+    session: Option<Session>,
+    inputs: Inputs,
+    finished: bool,
+    levels: Arc<Levels>,
+}
+
+impl Offline {
+    /// Create a backend that renders `duration` worth of audio, rounded up to
+    /// a whole number of blocks.
+    pub fn new(duration: time::Duration) -> Offline {
+        Offline {
+            audio_in_channels: 2,
+            audio_out_channels: 2,
+            audio_sample_rate: 44_100.,
+            duration,
+            audio_in: None,
+            analog_in: None,
+            audio_out: None,
+            analog_out: None,
+            digital_out: None,
+            session: None,
+            inputs: Inputs::default(),
+            finished: false,
+            levels: Arc::new(Levels::default()),
+        }
+    }
+
+    pub fn audio_in_channels(&self) -> usize {
+        self.audio_in_channels
+    }
+
+    pub fn set_audio_in_channels(&mut self, num: usize) {
+        self.audio_in_channels = num;
+    }
+
+    pub fn audio_out_channels(&self) -> usize {
+        self.audio_out_channels
+    }
+
+    pub fn set_audio_out_channels(&mut self, num: usize) {
+        self.audio_out_channels = num;
+    }
+
+    pub fn audio_sample_rate(&self) -> f32 {
+        self.audio_sample_rate
+    }
+
+    pub fn set_audio_sample_rate(&mut self, rate: f32) {
+        self.audio_sample_rate = rate;
+    }
+
+    pub fn duration(&self) -> time::Duration {
+        self.duration
+    }
+
+    pub fn set_duration(&mut self, duration: time::Duration) {
+        self.duration = duration;
+    }
+
+    /// Set the WAV file read into the audio input. Its sample rate must match
+    /// the audio sample rate.
+    pub fn set_audio_in<P: AsRef<Path>>(&mut self, path: P) {
+        self.audio_in = Some(path.as_ref().to_owned());
+    }
+
+    /// Set the WAV file read into the analog input. Its sample rate must match
+    /// the analog sample rate.
+    pub fn set_analog_in<P: AsRef<Path>>(&mut self, path: P) {
+        self.analog_in = Some(path.as_ref().to_owned());
+    }
+
+    /// Set the WAV file the audio output is written to.
+    pub fn set_audio_out<P: AsRef<Path>>(&mut self, path: P) {
+        self.audio_out = Some(path.as_ref().to_owned());
+    }
+
+    /// Set the WAV file the analog output is written to.
+    pub fn set_analog_out<P: AsRef<Path>>(&mut self, path: P) {
+        self.analog_out = Some(path.as_ref().to_owned());
+    }
+
+    /// Set the CSV file the digital pin values are written to.
+    pub fn set_digital_out<P: AsRef<Path>>(&mut self, path: P) {
+        self.digital_out = Some(path.as_ref().to_owned());
+    }
+
+    /// Refuse a context the render loop or the output files cannot handle,
+    /// before setup runs.
+    fn check_outputs(&self, context: &Context) -> Result<(), error::Error> {
+        if context.audio_frames() == 0 {
+            return Err(error::Error::new(error::ErrorKind::Init)
+                .with_detail("the period size must be at least one frame"));
+        }
+
+        let outputs = [
+            (
+                &self.audio_out,
+                context.audio_out_channels(),
+                context.audio_sample_rate(),
+            ),
+            (
+                &self.analog_out,
+                context.analog_out_channels(),
+                context.analog_sample_rate(),
+            ),
+        ];
+        for &(path, channels, sample_rate) in &outputs {
+            if let Some(ref path) = *path {
+                check_wav_spec(channels, sample_rate).map_err(|err| {
+                    error::Error::new(error::ErrorKind::Init)
+                        .with_detail(format!("cannot write {}", path.display()))
+                        .with_source(err)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&mut self) -> io::Result<()> {
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => return Ok(()),
+        };
+
+        let (audio_frames, audio_sample_rate, analog_sample_rate) = {
+            let context = &session.context;
+            (
+                context.audio_frames(),
+                context.audio_sample_rate(),
+                context.analog_sample_rate(),
+            )
+        };
+        let total_frames = (self.duration.as_secs_f64() * f64::from(audio_sample_rate)).ceil();
+        let blocks = (total_frames as usize).div_ceil(audio_frames);
+
+        let mut outputs = Outputs::create(
+            &session.context,
+            self.audio_out.as_deref(),
+            self.analog_out.as_deref(),
+            self.digital_out.as_deref(),
+            audio_sample_rate,
+            analog_sample_rate,
+        )?;
+
+        for _ in 0..blocks {
+            self.inputs.read(&mut session.context)?;
+            session.render();
+            outputs.write(&mut session.context)?;
+        }
+
+        outputs.finalize()
+    }
+}
+
+impl Backend for Offline {
+    unsafe fn init_audio(
+        &mut self,
+        settings: &mut InitSettings,
+        user_data: *mut c_void,
+    ) -> Result<(), error::Error> {
+        if self.session.is_some() {
+            return Err(error::Error::new(error::ErrorKind::Init)
+                .with_detail("audio is already initialized"));
+        }
+
+        let context = ContextBuilder::from_settings(
+            settings,
+            self.audio_in_channels,
+            self.audio_out_channels,
+            self.audio_sample_rate,
+        )
+        .build()?;
+        self.check_outputs(&context)?;
+        let mut session = Session::new(settings, context, user_data, self.levels.clone());
+
+        let (audio_sample_rate, analog_sample_rate) = {
+            let context = &session.context;
+            (context.audio_sample_rate(), context.analog_sample_rate())
+        };
+        self.inputs = Inputs {
+            audio: match self.audio_in {
+                Some(ref path) => Some(InputFile::open(path, audio_sample_rate)?),
+                None => None,
+            },
+            analog: match self.analog_in {
+                Some(ref path) => Some(InputFile::open(path, analog_sample_rate)?),
+                None => None,
+            },
+        };
+
+        if !session.setup() {
+            return Err(error::Error::new(error::ErrorKind::Init)
+                .with_detail("setup failed")
+                .with_settings(settings));
+        }
+
+        self.finished = false;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    fn start_audio(&mut self) -> Result<(), error::Error> {
+        if self.session.is_none() {
+            return Err(
+                error::Error::new(error::ErrorKind::Start).with_detail("audio is not initialized")
+            );
+        }
+
+        let res = self.render();
+        self.finished = true;
+        res.map_err(|err| {
+            error::Error::new(error::ErrorKind::Start)
+                .with_detail("could not render to the output files")
+                .with_source(err)
+        })
+    }
+
+    fn should_stop(&self) -> bool {
+        self.finished
+    }
+
+    fn stop_audio(&mut self) {
+        self.finished = true;
+    }
+
+    fn cleanup_audio(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            session.cleanup();
+        }
+        self.inputs = Inputs::default();
+    }
+
+    fn codec(&self) -> Codec {
+        Codec::software(self.levels.clone())
+    }
+
+    unsafe fn create_auxiliary_task(
+        callback: AuxiliaryTaskFn,
+        arg: *mut c_void,
+        _priority: i32,
+        _name: &CStr,
+    ) -> CreatedTask {
+        CreatedTask::new(TaskHandle::Inline(callback, arg))
+    }
+
+    fn schedule_auxiliary_task(task: &CreatedTask) -> Result<(), error::Error> {
+        if task.0.is_deleted() {
+            return Err(super::deleted_error());
+        }
+        match task.0.handle {
+            TaskHandle::Inline(callback, arg) => {
+                callback(arg);
+                Ok(())
+            }
+            _ => Err(error::Error::new(error::ErrorKind::Task)
+                .with_detail("the task was not created by the offline backend")),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inputs {
+    audio: Option<InputFile>,
+    analog: Option<InputFile>,
+}
+
+impl Inputs {
+    fn read(&mut self, context: &mut Context) -> io::Result<()> {
+        let layout = context.layout();
+        if let Some(ref mut audio) = self.audio {
+            let channels = context.audio_in_channels();
+            audio.read(context.audio_in_mut(), channels, layout)?;
+        }
+        if let Some(ref mut analog) = self.analog {
+            let channels = context.analog_in_channels();
+            analog.read(context.analog_in_mut(), channels, layout)?;
+        }
+        Ok(())
+    }
+}
+
+/// A WAV file read frame by frame into an input buffer.
+struct InputFile {
+    reader: hound::WavReader<BufReader<File>>,
+    frame: Vec<f32>,
+}
+
+impl InputFile {
+    fn open(path: &Path, sample_rate: f32) -> Result<InputFile, error::Error> {
+        let reader = hound::WavReader::open(path).map_err(|err| {
+            error::Error::new(error::ErrorKind::Init)
+                .with_detail(format!("could not open {}", path.display()))
+                .with_source(err)
+        })?;
+        let spec = reader.spec();
+        if spec.sample_rate as f32 != sample_rate {
+            return Err(
+                error::Error::new(error::ErrorKind::Init).with_detail(format!(
+                    "{} has a sample rate of {}Hz rather than {}Hz",
+                    path.display(),
+                    spec.sample_rate,
+                    sample_rate
+                )),
+            );
+        }
+
+        Ok(InputFile {
+            reader,
+            frame: vec![0.; spec.channels as usize],
+        })
+    }
+
+    /// Fill `buf` with the next block of frames. Channels the file does not
+    /// have, and frames past its end, are filled with silence.
+    fn read(&mut self, buf: &mut [f32], channels: usize, layout: Layout) -> io::Result<()> {
+        if channels == 0 {
+            return Ok(());
+        }
+
+        let frames = buf.len() / channels;
+        for frame in 0..frames {
+            self.next_frame()?;
+            for channel in 0..channels {
+                buf[layout.index(frame, channel, frames, channels)] =
+                    self.frame.get(channel).cloned().unwrap_or(0.);
+            }
+        }
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> io::Result<()> {
+        let spec = self.reader.spec();
+        match spec.sample_format {
+            hound::SampleFormat::Float => {
+                let mut samples = self.reader.samples::<f32>();
+                for samp in self.frame.iter_mut() {
+                    *samp = match samples.next() {
+                        Some(s) => s.map_err(to_io_error)?,
+                        None => 0.,
+                    };
+                }
+            }
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                let mut samples = self.reader.samples::<i32>();
+                for samp in self.frame.iter_mut() {
+                    *samp = match samples.next() {
+                        Some(s) => s.map_err(to_io_error)? as f32 / scale,
+                        None => 0.,
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files the outputs are written to, block by block.
+struct Outputs {
+    audio: Option<WavWriter>,
+    analog: Option<WavWriter>,
+    digital: Option<DigitalCsv>,
+    scratch: Vec<f32>,
+}
+
+impl Outputs {
+    fn create(
+        context: &Context,
+        audio: Option<&Path>,
+        analog: Option<&Path>,
+        digital: Option<&Path>,
+        audio_sample_rate: f32,
+        analog_sample_rate: f32,
+    ) -> io::Result<Outputs> {
+        let audio = match audio {
+            Some(path) => Some(create_wav(
+                path,
+                context.audio_out_channels(),
+                audio_sample_rate,
+            )?),
+            None => None,
+        };
+        let analog = match analog {
+            Some(path) => Some(create_wav(
+                path,
+                context.analog_out_channels(),
+                analog_sample_rate,
+            )?),
+            None => None,
+        };
+        let digital = match digital {
+            Some(path) => Some(DigitalCsv::create(path, context.digital_channels())?),
+            None => None,
+        };
+
+        Ok(Outputs {
+            audio,
+            analog,
+            digital,
+            scratch: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, context: &mut Context) -> io::Result<()> {
+        let layout = context.layout();
+        if let Some(ref mut audio) = self.audio {
+            let channels = context.audio_out_channels();
+            write_wav(
+                audio,
+                context.audio_out(),
+                channels,
+                layout,
+                &mut self.scratch,
+            )?;
+        }
+        if let Some(ref mut analog) = self.analog {
+            let channels = context.analog_out_channels();
+            write_wav(
+                analog,
+                context.analog_out(),
+                channels,
+                layout,
+                &mut self.scratch,
+            )?;
+        }
+        if let Some(ref mut digital) = self.digital {
+            // The elapsed count has already moved on to the next block
+            let first = context.audio_frames_elapsed() - context.audio_frames();
+            for (frame, word) in context.digital().iter().enumerate() {
+                digital.write((first + frame) as u64, word >> 16)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> io::Result<()> {
+        if let Some(audio) = self.audio {
+            audio.finalize().map_err(to_io_error)?;
+        }
+        if let Some(analog) = self.analog {
+            analog.finalize().map_err(to_io_error)?;
+        }
+        if let Some(digital) = self.digital {
+            digital.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use {Bela, BelaApp, DigitalDirection};
+
+    /// Doubles the audio input, holds the analog outputs at their channel
+    /// number over 10, and sets digital pin 0 halfway through every block.
+    #[derive(Default)]
+    struct Doubler {
+        setups: usize,
+    }
+
+    impl BelaApp for Doubler {
+        fn setup(&mut self, _context: &mut Context) -> Result<(), error::Error> {
+            self.setups += 1;
+            Ok(())
+        }
+
+        fn render(&mut self, context: &mut Context) {
+            let input = context.audio_in().to_vec();
+            for (out, samp) in context.audio_out().iter_mut().zip(input) {
+                *out = samp * 2.;
+            }
+            for frame in 0..context.analog_frames() {
+                for channel in 0..context.analog_out_channels() {
+                    context.analog_write(frame, channel, channel as f32 / 10.);
+                }
+            }
+            context.pin_mode(0, 0, DigitalDirection::OUTPUT);
+            let half = context.digital_frames() / 2;
+            context.digital_write(half, 0, true);
+        }
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bela-offline-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The samples of a 32-bit float WAV file, and its spec.
+    fn read_wav(path: &Path) -> (hound::WavSpec, Vec<f32>) {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let samples = reader.samples::<f32>().map(Result::unwrap).collect();
+        (reader.spec(), samples)
+    }
+
+    #[test]
+    fn renders_between_files() {
+        let dir = dir("round-trip");
+        let input: Vec<f32> = (0..100).map(|n| n as f32 / 1000.).collect();
+        {
+            let spec = hound::WavSpec {
+                channels: 2,
+                sample_rate: 44_100,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            let mut writer = hound::WavWriter::create(dir.join("in.wav"), spec).unwrap();
+            for &samp in &input {
+                writer.write_sample(samp).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+
+        // 44.1 frames, rounded up to two blocks of 32
+        let mut backend = Offline::new(time::Duration::from_millis(1));
+        backend.set_audio_in(dir.join("in.wav"));
+        backend.set_audio_out(dir.join("audio.wav"));
+        backend.set_analog_out(dir.join("analog.wav"));
+        backend.set_digital_out(dir.join("digital.csv"));
+        let mut bela = Bela::with_backend(Doubler::default(), backend);
+        bela.run(&mut InitSettings::software_default()).unwrap();
+        assert_eq!(bela.user_data.app.setups, 1);
+
+        let (spec, audio) = read_wav(&dir.join("audio.wav"));
+        assert_eq!((spec.channels, spec.sample_rate), (2, 44_100));
+        assert_eq!(audio.len(), 2 * 64);
+        // The input runs out after 50 frames, and then reads as silence
+        let expected: Vec<f32> = (0..128)
+            .map(|n| input.get(n).map_or(0., |samp| samp * 2.))
+            .collect();
+        assert_eq!(audio, expected);
+
+        let (spec, analog) = read_wav(&dir.join("analog.wav"));
+        assert_eq!((spec.channels, spec.sample_rate), (8, 22_050));
+        assert_eq!(analog.len(), 8 * 32);
+        assert_eq!(&analog[..8], &[0., 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7]);
+
+        let digital = fs::read_to_string(dir.join("digital.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let lines: Vec<&str> = digital.lines().collect();
+        assert_eq!(lines.len(), 1 + 64);
+        assert_eq!(lines[0], "frame,0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15");
+        assert_eq!(lines[1], "0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        assert_eq!(lines[17], "16,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        // The second block starts low again and goes high halfway through
+        assert!(lines[33].starts_with("32,0,"));
+        assert!(lines[49].starts_with("48,1,"));
+    }
+
+    #[test]
+    fn refuses_empty_period() {
+        let mut settings = InitSettings::software_default();
+        settings.set_period_size(0);
+        let backend = Offline::new(time::Duration::from_millis(1));
+        let mut bela = Bela::with_backend(Doubler::default(), backend);
+        let err = bela.init_audio(&mut settings).unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Init);
+        assert_eq!(
+            err.detail(),
+            Some("the period size must be at least one frame")
+        );
+        assert_eq!(bela.user_data.app.setups, 0);
+    }
+
+    #[test]
+    fn refuses_unwritable_output() {
+        let mut backend = Offline::new(time::Duration::from_millis(1));
+        backend.set_audio_out_channels(0);
+        backend.set_audio_out("never-written.wav");
+        let mut bela = Bela::with_backend(Doubler::default(), backend);
+        let err = bela
+            .init_audio(&mut InitSettings::software_default())
+            .unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Init);
+        assert_eq!(err.detail(), Some("cannot write never-written.wav"));
+        assert_eq!(bela.user_data.app.setups, 0);
+        assert!(!Path::new("never-written.wav").exists());
+    }
+
+    #[test]
+    fn refuses_input_at_other_rate() {
+        let dir = dir("rate");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        hound::WavWriter::create(dir.join("in.wav"), spec)
+            .unwrap()
+            .finalize()
+            .unwrap();
+        let mut backend = Offline::new(time::Duration::from_millis(1));
+        backend.set_audio_in(dir.join("in.wav"));
+        let mut bela = Bela::with_backend(Doubler::default(), backend);
+        let err = bela
+            .init_audio(&mut InitSettings::software_default())
+            .unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(err.kind(), error::ErrorKind::Init);
+        assert_eq!(bela.user_data.app.setups, 0);
+    }
+}
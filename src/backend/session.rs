@@ -0,0 +1,108 @@
+use std::os::raw::c_void;
+use std::sync::Arc;
+
+use sys::{self, BelaContext};
+
+use codec::Levels;
+use {Context, InitSettings, Layout};
+
+type SetupFn = unsafe extern "C" fn(*mut BelaContext, *mut c_void) -> bool;
+type RenderFn = unsafe extern "C" fn(*mut BelaContext, *mut c_void);
+
+/// The callbacks registered in `InitSettings` by `Bela::init_audio`, together
+/// with the context they render into and the codec levels applied to it.
+pub(crate) struct Session {
+    pub(crate) context: Context,
+    setup: Option<SetupFn>,
+    render: Option<RenderFn>,
+    cleanup: Option<RenderFn>,
+    user_data: *mut c_void,
+    levels: Arc<Levels>,
+}
+
+// The context owns its buffers, and the user data is only accessed from one
+// thread at a time: setup runs before the audio thread is spawned and cleanup
+// runs after it has been joined.
+unsafe impl Send for Session {}
+
+impl Session {
+    pub(crate) fn new(
+        settings: &InitSettings,
+        context: Context,
+        user_data: *mut c_void,
+        levels: Arc<Levels>,
+    ) -> Session {
+        levels.reset(settings);
+        Session {
+            context,
+            setup: settings.settings.setup,
+            render: settings.settings.render,
+            cleanup: settings.settings.cleanup,
+            user_data,
+            levels,
+        }
+    }
+
+    pub(crate) fn setup(&mut self) -> bool {
+        match self.setup {
+            Some(setup) => unsafe { setup(self.context.context_mut_ptr(), self.user_data) },
+            None => true,
+        }
+    }
+
+    /// Render a single block, resetting the outputs beforehand and advancing
+    /// the elapsed frame count afterwards. The codec levels are applied to the
+    /// audio inputs before render, and to the audio outputs after it.
+    pub(crate) fn render(&mut self) {
+        begin_block(&mut self.context);
+        self.levels.apply_inputs(&mut self.context);
+        if let Some(render) = self.render {
+            unsafe { render(self.context.context_mut_ptr(), self.user_data) };
+        }
+        self.levels.apply_outputs(&mut self.context);
+        end_block(&mut self.context);
+    }
+
+    pub(crate) fn cleanup(&mut self) {
+        if let Some(cleanup) = self.cleanup {
+            unsafe { cleanup(self.context.context_mut_ptr(), self.user_data) };
+        }
+    }
+}
+
+/// Reset the outputs the way libbela does before every call to render: audio
+/// outputs are cleared, and analog outputs either hold the last value of the
+/// previous block or are cleared.
+pub(crate) fn begin_block(context: &mut Context) {
+    for samp in context.audio_out().iter_mut() {
+        *samp = 0.;
+    }
+
+    let flags = context.flags();
+    let frames = context.analog_frames();
+    let channels = context.analog_out_channels();
+    let analog_out = context.analog_out();
+    if flags & sys::BELA_FLAG_ANALOG_OUTPUTS_PERSIST == 0 || frames == 0 {
+        for samp in analog_out.iter_mut() {
+            *samp = 0.;
+        }
+        return;
+    }
+
+    let layout = Layout::from_flags(flags);
+    for channel in 0..channels {
+        let index = |frame: usize| layout.index(frame, channel, frames, channels);
+        let last = analog_out[index(frames - 1)];
+        for frame in 0..frames {
+            analog_out[index(frame)] = last;
+        }
+    }
+}
+
+/// Advance the elapsed frame count past the block that was just rendered.
+pub(crate) fn end_block(context: &mut Context) {
+    unsafe {
+        let context = context.context_mut_ptr();
+        (*context).audioFramesElapsed += (*context).audioFrames as u64;
+    }
+}
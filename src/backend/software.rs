@@ -0,0 +1,420 @@
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time;
+
+use super::session::Session;
+use super::{AuxiliaryTaskFn, Backend, TaskHandle};
+use codec::{Codec, Levels};
+use error;
+use {ContextBuilder, CreatedTask, InitSettings};
+
+/// Backend that runs the Bela lifecycle in-process, without libbela.
+///
+/// Setup and cleanup are called from the thread calling `init_audio` and
+/// `cleanup_audio`, and render is called block by block from a plain thread
+/// spawned by `start_audio`. Audio and analog inputs read as silence, and
+/// outputs are discarded. Buffer sizes are taken from the `InitSettings` in
+/// the same way libbela derives them.
+///
+/// ```rust,ignore
+/// let mut backend = backend::Software::new();
+/// backend.set_max_blocks(Some(1000));
+/// Bela::with_backend(user_data, backend).run(&mut settings)
+/// ```
+pub struct Software {
+    audio_in_channels: usize,
+    audio_out_channels: usize,
+    audio_sample_rate: f32,
+    realtime: bool,
+    max_blocks: Option<u64>,
+    stop: Arc<AtomicBool>,
+    levels: Arc<Levels>,
+    session: Option<Session>,
+    thread: Option<JoinHandle<Session>>,
+}
+
+impl Software {
+    pub fn new() -> Software {
+        Software {
+            audio_in_channels: 2,
+            audio_out_channels: 2,
+            audio_sample_rate: 44_100.,
+            realtime: true,
+            max_blocks: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            levels: Arc::new(Levels::default()),
+            session: None,
+            thread: None,
+        }
+    }
+
+    pub fn audio_in_channels(&self) -> usize {
+        self.audio_in_channels
+    }
+
+    pub fn set_audio_in_channels(&mut self, num: usize) {
+        self.audio_in_channels = num;
+    }
+
+    pub fn audio_out_channels(&self) -> usize {
+        self.audio_out_channels
+    }
+
+    pub fn set_audio_out_channels(&mut self, num: usize) {
+        self.audio_out_channels = num;
+    }
+
+    pub fn audio_sample_rate(&self) -> f32 {
+        self.audio_sample_rate
+    }
+
+    pub fn set_audio_sample_rate(&mut self, rate: f32) {
+        self.audio_sample_rate = rate;
+    }
+
+    /// Get whether blocks are paced to the audio sample rate. When `false`,
+    /// blocks are rendered as fast as possible.
+    pub fn realtime(&self) -> bool {
+        self.realtime
+    }
+
+    /// Set whether blocks are paced to the audio sample rate. When `false`,
+    /// blocks are rendered as fast as possible.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// Get the number of blocks after which a stop is requested.
+    pub fn max_blocks(&self) -> Option<u64> {
+        self.max_blocks
+    }
+
+    /// Set the number of blocks after which a stop is requested. `None` runs
+    /// until `request_stop` is called.
+    pub fn set_max_blocks(&mut self, blocks: Option<u64>) {
+        self.max_blocks = blocks;
+    }
+
+    /// Request that audio stops, as pressing the button on the board would.
+    pub fn request_stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Default for Software {
+    fn default() -> Software {
+        Software::new()
+    }
+}
+
+impl Backend for Software {
+    unsafe fn init_audio(
+        &mut self,
+        settings: &mut InitSettings,
+        user_data: *mut c_void,
+    ) -> Result<(), error::Error> {
+        if self.session.is_some() || self.thread.is_some() {
+            return Err(error::Error::new(error::ErrorKind::Init)
+                .with_detail("audio is already initialized"));
+        }
+
+        let context = ContextBuilder::from_settings(
+            settings,
+            self.audio_in_channels,
+            self.audio_out_channels,
+            self.audio_sample_rate,
+        )
+        .build()?;
+        let mut session = Session::new(settings, context, user_data, self.levels.clone());
+
+        if !session.setup() {
+            return Err(error::Error::new(error::ErrorKind::Init)
+                .with_detail("setup failed")
+                .with_settings(settings));
+        }
+
+        self.stop.store(false, Ordering::SeqCst);
+        self.session = Some(session);
+        Ok(())
+    }
+
+    fn start_audio(&mut self) -> Result<(), error::Error> {
+        let mut session = match self.session.take() {
+            Some(session) => session,
+            None => {
+                return Err(error::Error::new(error::ErrorKind::Start)
+                    .with_detail("audio is not initialized, or already running"))
+            }
+        };
+
+        let stop = self.stop.clone();
+        let realtime = self.realtime;
+        let max_blocks = self.max_blocks;
+        let thread = thread::Builder::new()
+            .name("bela-audio".into())
+            .spawn(move || {
+                let period = {
+                    let context = &session.context;
+                    time::Duration::from_secs_f64(
+                        context.audio_frames() as f64 / f64::from(context.audio_sample_rate()),
+                    )
+                };
+                let mut deadline = time::Instant::now();
+                let mut blocks = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    session.render();
+                    blocks += 1;
+                    if max_blocks == Some(blocks) {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    if realtime {
+                        deadline += period;
+                        let now = time::Instant::now();
+                        if deadline > now {
+                            thread::sleep(deadline - now);
+                        }
+                    }
+                }
+                session
+            })
+            .map_err(|err| {
+                error::Error::new(error::ErrorKind::Start)
+                    .with_detail("could not spawn the audio thread")
+                    .with_source(err)
+            })?;
+
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    fn should_stop(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    fn stop_audio(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            // A panic in the render callback has already been reported on the
+            // audio thread, and takes the session down with it.
+            self.session = thread.join().ok();
+        }
+    }
+
+    fn cleanup_audio(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            session.cleanup();
+        }
+    }
+
+    fn codec(&self) -> Codec {
+        Codec::software(self.levels.clone())
+    }
+
+    unsafe fn create_auxiliary_task(
+        callback: AuxiliaryTaskFn,
+        arg: *mut c_void,
+        _priority: i32,
+        name: &CStr,
+    ) -> CreatedTask {
+        // A single pending slot: scheduling a task that has not run yet is a
+        // no-op, as with Bela_scheduleAuxiliaryTask.
+        let (tx, rx) = mpsc::sync_channel::<Wake>(1);
+        let arg = TaskArg(arg);
+        // If the thread cannot be spawned, the receiver is dropped and
+        // scheduling the task reports an error.
+        let thread = thread::Builder::new()
+            .name(name.to_string_lossy().into_owned())
+            .spawn(move || {
+                let arg = arg;
+                while let Ok(Wake::Run) = rx.recv() {
+                    callback(arg.0);
+                }
+            })
+            .ok();
+
+        CreatedTask::new(TaskHandle::Software(tx, Mutex::new(thread)))
+    }
+
+    fn schedule_auxiliary_task(task: &CreatedTask) -> Result<(), error::Error> {
+        if task.0.is_deleted() {
+            return Err(super::deleted_error());
+        }
+        match task.0.handle {
+            TaskHandle::Software(ref tx, _) => match tx.try_send(Wake::Run) {
+                Ok(()) | Err(mpsc::TrySendError::Full(_)) => Ok(()),
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    Err(error::Error::new(error::ErrorKind::Task)
+                        .with_detail("the task thread is not running"))
+                }
+            },
+            _ => Err(error::Error::new(error::ErrorKind::Task)
+                .with_detail("the task was not created by the software backend")),
+        }
+    }
+}
+
+/// Message to an auxiliary task thread.
+pub(crate) enum Wake {
+    Run,
+    Stop,
+}
+
+/// Stop a task thread and wait for it to finish. Returns `false` if called
+/// from the task thread itself, which cannot be joined.
+pub(crate) fn stop_task(
+    wake: &mpsc::SyncSender<Wake>,
+    thread: &Mutex<Option<JoinHandle<()>>>,
+) -> bool {
+    let thread = super::lock(thread).take();
+    match thread {
+        Some(ref handle) if handle.thread().id() == thread::current().id() => {
+            // The thread stops once the closure returns, or once `wake` is
+            // dropped along with the task.
+            let _ = wake.try_send(Wake::Stop);
+            false
+        }
+        Some(handle) => {
+            // Waits for a pending run to be picked up first.
+            let _ = wake.send(Wake::Stop);
+            let _ = handle.join();
+            true
+        }
+        None => true,
+    }
+}
+
+impl Drop for Software {
+    fn drop(&mut self) {
+        self.stop_audio();
+    }
+}
+
+/// Argument of an auxiliary task, handed over to the task thread.
+struct TaskArg(*mut c_void);
+
+// The argument is a `Send` closure which is only ever called from the task
+// thread, and is only freed once the thread has been joined.
+unsafe impl Send for TaskArg {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use {Bela, BelaApp, Context};
+
+    /// Counts the callbacks it gets, and the frames render was asked for.
+    #[derive(Default)]
+    struct Counter {
+        setups: usize,
+        renders: usize,
+        /// The renders so far, read while audio is running.
+        rendered: Arc<AtomicUsize>,
+        cleanups: usize,
+        frames: usize,
+        fail_setup: bool,
+    }
+
+    impl BelaApp for Counter {
+        fn setup(&mut self, _context: &mut Context) -> Result<(), error::Error> {
+            self.setups += 1;
+            if self.fail_setup {
+                return Err(error::Error::new(error::ErrorKind::Init).with_detail("no"));
+            }
+            Ok(())
+        }
+
+        fn render(&mut self, context: &mut Context) {
+            self.renders += 1;
+            self.rendered.fetch_add(1, Ordering::SeqCst);
+            self.frames = context.audio_frames();
+            for samp in context.audio_out().iter_mut() {
+                *samp = 1.;
+            }
+        }
+
+        fn cleanup(&mut self, _context: &mut Context) {
+            self.cleanups += 1;
+        }
+    }
+
+    fn backend(blocks: Option<u64>) -> Software {
+        let mut backend = Software::new();
+        backend.set_realtime(false);
+        backend.set_max_blocks(blocks);
+        backend
+    }
+
+    #[test]
+    fn run_calls_every_callback() {
+        let mut bela = Bela::with_backend(Counter::default(), backend(Some(25)));
+        let mut settings = InitSettings::software_default();
+        settings.set_period_size(32);
+        bela.run(&mut settings).unwrap();
+
+        let app = &bela.user_data.app;
+        assert_eq!((app.setups, app.renders, app.cleanups), (1, 25, 1));
+        // Two audio frames per analog frame, with 8 analog channels
+        assert_eq!(app.frames, 64);
+    }
+
+    #[test]
+    fn runs_again_after_cleanup() {
+        let mut bela = Bela::with_backend(Counter::default(), backend(Some(3)));
+        let mut settings = InitSettings::software_default();
+        bela.run(&mut settings).unwrap();
+        bela.run(&mut settings).unwrap();
+
+        let app = &bela.user_data.app;
+        assert_eq!((app.setups, app.renders, app.cleanups), (2, 6, 2));
+    }
+
+    #[test]
+    fn failed_setup_does_not_render() {
+        let app = Counter {
+            fail_setup: true,
+            ..Counter::default()
+        };
+        let mut bela = Bela::with_backend(app, backend(Some(3)));
+        let err = bela.run(&mut InitSettings::software_default()).unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Init);
+        assert_eq!(err.detail(), Some("no"));
+
+        let app = &bela.user_data.app;
+        assert_eq!((app.setups, app.renders, app.cleanups), (1, 0, 0));
+    }
+
+    #[test]
+    fn renders_until_stop_is_requested() {
+        let app = Counter::default();
+        let rendered = app.rendered.clone();
+        let mut bela = Bela::with_backend(app, backend(None));
+        let mut settings = InitSettings::software_default();
+        bela.init_audio(&mut settings).unwrap();
+        assert!(bela.init_audio(&mut settings).is_err());
+        bela.start_audio().unwrap();
+        assert!(bela.start_audio().is_err());
+        while rendered.load(Ordering::SeqCst) < 3 {
+            thread::yield_now();
+        }
+        assert!(!bela.should_stop());
+        bela.backend().request_stop();
+        assert!(bela.should_stop());
+        bela.stop_audio();
+        bela.cleanup_audio();
+
+        let app = &bela.user_data.app;
+        assert!(app.renders >= 3);
+        assert_eq!((app.setups, app.cleanups), (1, 1));
+    }
+
+    #[test]
+    fn start_needs_init() {
+        let mut bela = Bela::with_backend(Counter::default(), backend(Some(1)));
+        let err = bela.start_audio().unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Start);
+    }
+}
@@ -0,0 +1,423 @@
+use std::{cmp, mem, ptr};
+
+use sys::{self, BelaContext};
+
+use {error, Context, InitSettings};
+
+/// Builds a `Context` backed by buffers allocated on the Rust side, rather
+/// than by a `BelaContext` handed over by libbela.
+///
+/// This is what the software backends render into, and it allows calling
+/// render code directly, with synthetic input written through
+/// `Context::audio_in_mut` and friends, without any hardware.
+///
+/// ```rust,ignore
+/// let mut context = ContextBuilder::new()
+///     .audio_frames(64)
+///     .audio_out_channels(1)
+///     .build()
+///     .unwrap();
+/// render(&mut context, &mut state);
+/// assert!(context.audio_out().iter().all(|s| s.abs() <= 1.));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ContextBuilder {
+    audio_frames: usize,
+    audio_in_channels: usize,
+    audio_out_channels: usize,
+    audio_sample_rate: f32,
+    analog_frames: usize,
+    analog_in_channels: usize,
+    analog_out_channels: usize,
+    analog_sample_rate: f32,
+    digital_frames: usize,
+    digital_channels: usize,
+    digital_sample_rate: f32,
+    audio_frames_elapsed: u64,
+    multiplexer_channels: usize,
+    multiplexer_starting_channel: usize,
+    audio_expander_enabled: u32,
+    flags: u32,
+}
+
+impl ContextBuilder {
+    /// Start from the layout of a Bela with default settings: 16 frames of
+    /// stereo audio at 44.1kHz, 8 frames of 8 analog channels at 22.05kHz and
+    /// 16 digital channels, interleaved, with persistent analog outputs.
+    pub fn new() -> ContextBuilder {
+        ContextBuilder {
+            audio_frames: 16,
+            audio_in_channels: 2,
+            audio_out_channels: 2,
+            audio_sample_rate: 44_100.,
+            analog_frames: 8,
+            analog_in_channels: 8,
+            analog_out_channels: 8,
+            analog_sample_rate: 22_050.,
+            digital_frames: 16,
+            digital_channels: 16,
+            digital_sample_rate: 44_100.,
+            audio_frames_elapsed: 0,
+            multiplexer_channels: 0,
+            multiplexer_starting_channel: 0,
+            audio_expander_enabled: 0,
+            flags: sys::BELA_FLAG_INTERLEAVED | sys::BELA_FLAG_ANALOG_OUTPUTS_PERSIST,
+        }
+    }
+
+    /// Start from the layout libbela would use for `settings`, with the given
+    /// audio channel counts and sample rate.
+    pub fn from_settings(
+        settings: &InitSettings,
+        audio_in_channels: usize,
+        audio_out_channels: usize,
+        audio_sample_rate: f32,
+    ) -> ContextBuilder {
+        let period_size = settings.period_size();
+        let analog_channels = if settings.use_analog() {
+            cmp::max(
+                settings.num_analog_in_channels(),
+                settings.num_analog_out_channels(),
+            )
+        } else {
+            0
+        };
+
+        // The period size counts analog frames. Without uniform sample rates,
+        // 8 analog channels run at half the audio rate, 4 at the audio rate
+        // and 2 at twice the audio rate.
+        let (audio_frames, analog_frames, analog_sample_rate) = if analog_channels == 0 {
+            (period_size, 0, 0.)
+        } else if settings.uniform_sample_rate() {
+            (period_size, period_size, audio_sample_rate)
+        } else {
+            let rounded = cmp::max(analog_channels.next_power_of_two(), 2);
+            (
+                period_size * rounded / 4,
+                period_size,
+                audio_sample_rate * 4. / rounded as f32,
+            )
+        };
+        let (analog_in_channels, analog_out_channels) = if analog_channels == 0 {
+            (0, 0)
+        } else {
+            (
+                settings.num_analog_in_channels(),
+                settings.num_analog_out_channels(),
+            )
+        };
+        let digital_channels = if settings.use_digital() {
+            settings.num_digital_channels()
+        } else {
+            0
+        };
+        let digital_frames = if digital_channels == 0 {
+            0
+        } else {
+            audio_frames
+        };
+        let multiplexer_channels = if analog_channels == 0 {
+            0
+        } else {
+            settings.num_mux_channels()
+        };
+
+        let mut flags = 0;
+        if settings.interleave() {
+            flags |= sys::BELA_FLAG_INTERLEAVED;
+        }
+        if settings.analog_outputs_persist() {
+            flags |= sys::BELA_FLAG_ANALOG_OUTPUTS_PERSIST;
+        }
+        if settings.detect_underruns() {
+            flags |= sys::BELA_FLAG_DETECT_UNDERRUNS;
+        }
+
+        ContextBuilder {
+            audio_frames,
+            audio_in_channels,
+            audio_out_channels,
+            audio_sample_rate,
+            analog_frames,
+            analog_in_channels,
+            analog_out_channels,
+            analog_sample_rate,
+            digital_frames,
+            digital_channels,
+            digital_sample_rate: audio_sample_rate,
+            audio_frames_elapsed: 0,
+            multiplexer_channels,
+            multiplexer_starting_channel: 0,
+            audio_expander_enabled: 0,
+            flags,
+        }
+    }
+
+    pub fn audio_frames(mut self, frames: usize) -> ContextBuilder {
+        self.audio_frames = frames;
+        self
+    }
+
+    pub fn audio_in_channels(mut self, channels: usize) -> ContextBuilder {
+        self.audio_in_channels = channels;
+        self
+    }
+
+    pub fn audio_out_channels(mut self, channels: usize) -> ContextBuilder {
+        self.audio_out_channels = channels;
+        self
+    }
+
+    pub fn audio_sample_rate(mut self, rate: f32) -> ContextBuilder {
+        self.audio_sample_rate = rate;
+        self
+    }
+
+    pub fn analog_frames(mut self, frames: usize) -> ContextBuilder {
+        self.analog_frames = frames;
+        self
+    }
+
+    pub fn analog_in_channels(mut self, channels: usize) -> ContextBuilder {
+        self.analog_in_channels = channels;
+        self
+    }
+
+    pub fn analog_out_channels(mut self, channels: usize) -> ContextBuilder {
+        self.analog_out_channels = channels;
+        self
+    }
+
+    pub fn analog_sample_rate(mut self, rate: f32) -> ContextBuilder {
+        self.analog_sample_rate = rate;
+        self
+    }
+
+    pub fn digital_frames(mut self, frames: usize) -> ContextBuilder {
+        self.digital_frames = frames;
+        self
+    }
+
+    pub fn digital_channels(mut self, channels: usize) -> ContextBuilder {
+        self.digital_channels = channels;
+        self
+    }
+
+    pub fn digital_sample_rate(mut self, rate: f32) -> ContextBuilder {
+        self.digital_sample_rate = rate;
+        self
+    }
+
+    pub fn audio_frames_elapsed(mut self, frames: u64) -> ContextBuilder {
+        self.audio_frames_elapsed = frames;
+        self
+    }
+
+    pub fn multiplexer_channels(mut self, channels: usize) -> ContextBuilder {
+        self.multiplexer_channels = channels;
+        self
+    }
+
+    pub fn multiplexer_starting_channel(mut self, channel: usize) -> ContextBuilder {
+        self.multiplexer_starting_channel = channel;
+        self
+    }
+
+    pub fn audio_expander_enabled(mut self, enabled: u32) -> ContextBuilder {
+        self.audio_expander_enabled = enabled;
+        self
+    }
+
+    /// Set the raw `BELA_FLAG_*` bits.
+    pub fn flags(mut self, flags: u32) -> ContextBuilder {
+        self.flags = flags;
+        self
+    }
+
+    /// Set or clear `BELA_FLAG_INTERLEAVED`.
+    pub fn interleaved(mut self, interleaved: bool) -> ContextBuilder {
+        if interleaved {
+            self.flags |= sys::BELA_FLAG_INTERLEAVED;
+        } else {
+            self.flags &= !sys::BELA_FLAG_INTERLEAVED;
+        }
+        self
+    }
+
+    /// Set or clear `BELA_FLAG_ANALOG_OUTPUTS_PERSIST`.
+    pub fn analog_outputs_persist(mut self, persist: bool) -> ContextBuilder {
+        if persist {
+            self.flags |= sys::BELA_FLAG_ANALOG_OUTPUTS_PERSIST;
+        } else {
+            self.flags &= !sys::BELA_FLAG_ANALOG_OUTPUTS_PERSIST;
+        }
+        self
+    }
+
+    /// Allocate zeroed buffers and return a `Context` that owns them. All
+    /// digital pins start out as inputs, reading low.
+    ///
+    /// Fails if more than 16 digital channels are asked for, since a frame
+    /// of digital pins is a single word.
+    pub fn build(&self) -> Result<Context, error::Error> {
+        if self.digital_channels > 16 {
+            return Err(
+                error::Error::new(error::ErrorKind::Init).with_detail(format!(
+                    "a Context holds at most 16 digital channels, not {}",
+                    self.digital_channels
+                )),
+            );
+        }
+
+        let mut buffers = Box::new(Buffers {
+            context: unsafe { mem::zeroed() },
+            audio_in: vec![0.; self.audio_frames * self.audio_in_channels],
+            audio_out: vec![0.; self.audio_frames * self.audio_out_channels],
+            analog_in: vec![0.; self.analog_frames * self.analog_in_channels],
+            analog_out: vec![0.; self.analog_frames * self.analog_out_channels],
+            digital: vec![0xffff; self.digital_frames],
+            multiplexer_analog_in: vec![0.; self.analog_frames * self.multiplexer_channels],
+        });
+
+        {
+            let Buffers {
+                ref mut context,
+                ref mut audio_in,
+                ref mut audio_out,
+                ref mut analog_in,
+                ref mut analog_out,
+                ref mut digital,
+                ref mut multiplexer_analog_in,
+            } = *buffers;
+            context.audioIn = audio_in.as_mut_ptr();
+            context.audioOut = audio_out.as_mut_ptr();
+            context.analogIn = analog_in.as_mut_ptr();
+            context.analogOut = analog_out.as_mut_ptr();
+            context.digital = digital.as_mut_ptr();
+            context.audioFrames = self.audio_frames as _;
+            context.audioInChannels = self.audio_in_channels as _;
+            context.audioOutChannels = self.audio_out_channels as _;
+            context.audioSampleRate = self.audio_sample_rate;
+            context.analogFrames = self.analog_frames as _;
+            context.analogInChannels = self.analog_in_channels as _;
+            context.analogOutChannels = self.analog_out_channels as _;
+            context.analogSampleRate = self.analog_sample_rate;
+            context.digitalFrames = self.digital_frames as _;
+            context.digitalChannels = self.digital_channels as _;
+            context.digitalSampleRate = self.digital_sample_rate;
+            context.audioFramesElapsed = self.audio_frames_elapsed as _;
+            context.multiplexerChannels = self.multiplexer_channels as _;
+            context.multiplexerStartingChannel = self.multiplexer_starting_channel as _;
+            context.multiplexerAnalogIn = multiplexer_analog_in.as_mut_ptr();
+            context.audioExpanderEnabled = self.audio_expander_enabled;
+            context.flags = self.flags;
+        }
+
+        let buffers = Box::into_raw(buffers);
+        Ok(Context {
+            context: unsafe { ptr::addr_of_mut!((*buffers).context) },
+            buffers,
+        })
+    }
+}
+
+impl Default for ContextBuilder {
+    fn default() -> ContextBuilder {
+        ContextBuilder::new()
+    }
+}
+
+/// Storage behind a `Context` built by `ContextBuilder`.
+///
+/// Once built, the vectors are only ever accessed through the pointers held
+/// by `context`, and are freed along with the `Context`.
+pub(crate) struct Buffers {
+    context: BelaContext,
+    audio_in: Vec<f32>,
+    audio_out: Vec<f32>,
+    analog_in: Vec<f32>,
+    analog_out: Vec<f32>,
+    digital: Vec<u32>,
+    multiplexer_analog_in: Vec<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Layout;
+
+    fn settings(analog_channels: usize, uniform: bool) -> InitSettings {
+        let mut settings = InitSettings::software_default();
+        settings.set_period_size(16);
+        settings.set_num_analog_in_channels(analog_channels);
+        settings.set_num_analog_out_channels(analog_channels);
+        settings.set_uniform_sample_rate(uniform);
+        settings
+    }
+
+    #[test]
+    fn new_matches_default_bela_layout() {
+        let mut context = ContextBuilder::new().build().unwrap();
+        assert_eq!(context.audio_frames(), 16);
+        assert_eq!(context.audio_in().len(), 32);
+        assert_eq!(context.audio_out().len(), 32);
+        assert_eq!(context.analog_frames(), 8);
+        assert_eq!(context.analog_in().len(), 64);
+        assert_eq!(context.digital_frames(), 16);
+        assert_eq!(context.digital().len(), 16);
+        assert_eq!(context.layout(), Layout::Interleaved);
+    }
+
+    #[test]
+    fn digital_pins_start_as_low_inputs() {
+        let context = ContextBuilder::new().build().unwrap();
+        assert!(context.digital().iter().all(|&word| word == 0xffff));
+        assert!(!context.digital_read(0, 3));
+    }
+
+    #[test]
+    fn analog_rate_follows_channel_count() {
+        let context = ContextBuilder::from_settings(&settings(8, false), 2, 2, 44_100.)
+            .build()
+            .unwrap();
+        assert_eq!((context.audio_frames(), context.analog_frames()), (32, 16));
+        assert_eq!(context.analog_sample_rate(), 22_050.);
+
+        let context = ContextBuilder::from_settings(&settings(4, false), 2, 2, 44_100.)
+            .build()
+            .unwrap();
+        assert_eq!((context.audio_frames(), context.analog_frames()), (16, 16));
+        assert_eq!(context.analog_sample_rate(), 44_100.);
+
+        let context = ContextBuilder::from_settings(&settings(2, false), 2, 2, 44_100.)
+            .build()
+            .unwrap();
+        assert_eq!((context.audio_frames(), context.analog_frames()), (8, 16));
+        assert_eq!(context.analog_sample_rate(), 88_200.);
+    }
+
+    #[test]
+    fn uniform_sample_rate_matches_audio() {
+        let context = ContextBuilder::from_settings(&settings(8, true), 2, 2, 44_100.)
+            .build()
+            .unwrap();
+        assert_eq!((context.audio_frames(), context.analog_frames()), (16, 16));
+        assert_eq!(context.analog_sample_rate(), 44_100.);
+    }
+
+    #[test]
+    fn non_interleaved_layout() {
+        let mut settings = settings(8, false);
+        settings.set_interleave(false);
+        let context = ContextBuilder::from_settings(&settings, 2, 2, 44_100.)
+            .build()
+            .unwrap();
+        assert_eq!(context.layout(), Layout::NonInterleaved);
+    }
+
+    #[test]
+    fn too_many_digital_channels() {
+        assert!(ContextBuilder::new().digital_channels(16).build().is_ok());
+        assert!(ContextBuilder::new().digital_channels(17).build().is_err());
+    }
+}
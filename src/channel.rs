@@ -0,0 +1,384 @@
+//! Bounded, lock-free channels between the render callback and auxiliary
+//! tasks.
+//!
+//! Every channel has a single sender and a single receiver. Sending and
+//! receiving never block or allocate, so both ends can be used from the
+//! render thread. `Bela::create_channel_task` ties a channel to an auxiliary
+//! task: values sent from render are drained by the task, which is scheduled
+//! on every send, and the task replies through a second channel polled from
+//! render.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let (readings, results) = App::create_channel_task(
+//!     64,
+//!     |reading: f32, results: &mut Sender<f32>| {
+//!         let _ = results.try_send(expensive_analysis(reading));
+//!     },
+//!     10,
+//!     &CString::new("analysis").unwrap(),
+//! );
+//! // In render:
+//! let _ = readings.try_send(context.analog_in()[0]);
+//! while let Some(result) = results.try_recv() {
+//!     // ...
+//! }
+//! ```
+//!
+//! `Bela::create_aux_task` ties a task to a single slot instead: render hands
+//! the task one value at a time, and polls for its result on a later block.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let mut analysis = App::create_aux_task(
+//!     |block: [f32; 1024]| spectral_centroid(&block),
+//!     10,
+//!     &CString::new("centroid").unwrap(),
+//! );
+//! // In render:
+//! if let Some(centroid) = analysis.poll() {
+//!     // ...
+//! }
+//! if !analysis.is_busy() {
+//!     let _ = analysis.schedule(block);
+//! }
+//! ```
+
+use std::fmt;
+
+use ring;
+use {error, CreatedTask};
+
+/// Create a channel holding up to `capacity` values in flight.
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (producer, consumer) = ring::channel(capacity);
+    (Sender(producer), Receiver(consumer))
+}
+
+/// Sending end of a channel.
+pub struct Sender<T>(ring::Producer<T>);
+
+impl<T> Sender<T> {
+    /// Send `value`, or hand it back if the channel is full.
+    pub fn try_send(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        self.0.push(value).map_err(TrySendError::Full)
+    }
+
+    /// Number of values that can be sent before the channel is full.
+    pub fn free(&self) -> usize {
+        self.0.free()
+    }
+}
+
+/// Receiving end of a channel.
+pub struct Receiver<T>(ring::Consumer<T>);
+
+impl<T> Receiver<T> {
+    /// Receive the oldest value sent, if any.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+
+    /// Number of values waiting to be received.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    /// Receive the oldest value sent, stopping once the channel is empty.
+    fn next(&mut self) -> Option<T> {
+        self.try_recv()
+    }
+}
+
+/// Sending end of a channel drained by an auxiliary task, as returned by
+/// `Bela::create_channel_task`.
+pub struct TaskSender<T> {
+    sender: Sender<T>,
+    task: CreatedTask,
+    schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+}
+
+impl<T> TaskSender<T> {
+    pub(crate) fn new(
+        sender: Sender<T>,
+        task: CreatedTask,
+        schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+    ) -> TaskSender<T> {
+        TaskSender {
+            sender,
+            task,
+            schedule,
+        }
+    }
+
+    /// Send `value` and schedule the task to drain the channel.
+    ///
+    /// The task is scheduled even if the channel is full, so that it makes
+    /// room for later values.
+    pub fn try_send(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        let sent = self.sender.try_send(value);
+        let scheduled = (self.schedule)(&self.task);
+        sent?;
+        scheduled.map_err(TrySendError::Task)
+    }
+
+    /// Number of values that can be sent before the channel is full.
+    pub fn free(&self) -> usize {
+        self.sender.free()
+    }
+
+    /// The task draining the channel.
+    pub fn task(&self) -> &CreatedTask {
+        &self.task
+    }
+}
+
+/// Error returned when a value could not be sent.
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    /// The channel is full. The value is handed back.
+    Full(T),
+    /// The task draining the channel could not be scheduled. The value was
+    /// queued, and is received the next time the task runs.
+    Task(error::Error),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            TrySendError::Full(_) => write!(f, "channel is full"),
+            TrySendError::Task(ref err) => write!(f, "could not schedule task: {}", err),
+        }
+    }
+}
+
+impl<T: fmt::Debug> ::std::error::Error for TrySendError<T> {}
+
+/// An auxiliary task that is handed one value at a time from render, and hands
+/// a result back, as returned by `Bela::create_aux_task`.
+///
+/// The value is moved into a slot allocated when the task is created, so
+/// neither scheduling nor polling allocates. Only one value is in flight at a
+/// time: the next one can be scheduled once the result of the last one has
+/// been taken with `poll`.
+pub struct AuxTask<In, Out> {
+    input: Sender<In>,
+    /// `None` for a value the task panicked on.
+    output: Receiver<Option<Out>>,
+    task: CreatedTask,
+    schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+    busy: bool,
+}
+
+impl<In, Out> AuxTask<In, Out> {
+    pub(crate) fn new(
+        input: Sender<In>,
+        output: Receiver<Option<Out>>,
+        task: CreatedTask,
+        schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+    ) -> AuxTask<In, Out> {
+        AuxTask {
+            input,
+            output,
+            task,
+            schedule,
+            busy: false,
+        }
+    }
+
+    /// Hand `value` to the task and schedule it.
+    ///
+    /// If the task could not be scheduled, the value stays in its slot and
+    /// the task is still busy; `reschedule` tries again.
+    pub fn schedule(&mut self, value: In) -> Result<(), ScheduleError<In>> {
+        if self.busy {
+            return Err(ScheduleError::Busy(value));
+        }
+        // The slot is free whenever the task is not busy.
+        self.input.try_send(value).map_err(|err| match err {
+            TrySendError::Full(value) => ScheduleError::Busy(value),
+            TrySendError::Task(err) => ScheduleError::Task(err),
+        })?;
+        self.busy = true;
+        self.reschedule().map_err(ScheduleError::Task)
+    }
+
+    /// Schedule the task again for the value it was last handed.
+    pub fn reschedule(&mut self) -> Result<(), error::Error> {
+        (self.schedule)(&self.task)
+    }
+
+    /// Take the result for the last value scheduled, if the task is done
+    /// with it.
+    ///
+    /// A task that panics on a value has no result for it: `poll` returns
+    /// `None`, and the task is no longer busy.
+    pub fn poll(&mut self) -> Option<Out> {
+        let result = self.output.try_recv()?;
+        self.busy = false;
+        result
+    }
+
+    /// Whether a value has been scheduled and its result not yet taken.
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// The task the values are handed to.
+    pub fn task(&self) -> &CreatedTask {
+        &self.task
+    }
+}
+
+/// Error returned when a value could not be handed to an `AuxTask`.
+#[derive(Debug)]
+pub enum ScheduleError<T> {
+    /// The task is still busy with the last value, or its result has not
+    /// been taken yet. The value is handed back.
+    Busy(T),
+    /// The task could not be scheduled. The value was handed over, and is
+    /// taken the next time the task runs.
+    Task(error::Error),
+}
+
+impl<T> fmt::Display for ScheduleError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ScheduleError::Busy(_) => write!(f, "task is busy"),
+            ScheduleError::Task(ref err) => write!(f, "could not schedule task: {}", err),
+        }
+    }
+}
+
+impl<T: fmt::Debug> ::std::error::Error for ScheduleError<T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use backend::Offline;
+    use {Bela, OwnedAppData};
+
+    type App = Bela<OwnedAppData<()>, Offline>;
+
+    fn name() -> CString {
+        CString::new("test").unwrap()
+    }
+
+    #[test]
+    fn channel_is_bounded() {
+        let (mut tx, mut rx) = channel(2);
+        assert!(tx.try_send(1).is_ok());
+        assert!(tx.try_send(2).is_ok());
+        assert_eq!(tx.free(), 0);
+        match tx.try_send(3) {
+            Err(TrySendError::Full(3)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(rx.len(), 2);
+        assert_eq!(rx.by_ref().collect::<Vec<_>>(), vec![1, 2]);
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn channel_task_drains_on_send() {
+        let (mut sender, replies) = App::create_channel_task(
+            4,
+            |value: u32, replies: &mut Sender<u32>| {
+                let _ = replies.try_send(value * 2);
+            },
+            10,
+            &name(),
+        );
+        for value in 0..3 {
+            sender.try_send(value).unwrap();
+        }
+        assert_eq!(replies.collect::<Vec<_>>(), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn aux_task_returns_result() {
+        let mut task = App::create_aux_task(|value: u32| value + 1, 10, &name());
+        assert!(task.poll().is_none());
+        task.schedule(41).unwrap();
+        assert!(task.is_busy());
+        assert_eq!(task.poll(), Some(42));
+        assert!(!task.is_busy());
+        task.schedule(1).unwrap();
+        assert_eq!(task.poll(), Some(2));
+    }
+
+    #[test]
+    fn aux_task_is_busy_until_polled() {
+        let mut task = App::create_aux_task(|value: u32| value, 10, &name());
+        task.schedule(1).unwrap();
+        match task.schedule(2) {
+            Err(ScheduleError::Busy(2)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(task.poll(), Some(1));
+        task.schedule(2).unwrap();
+        assert_eq!(task.poll(), Some(2));
+    }
+
+    #[test]
+    fn aux_task_waits_for_the_task() {
+        let (input, mut values) = channel(1);
+        let (mut results, output) = channel(1);
+        let created = App::create_aux_task(|()| (), 10, &name()).task().clone();
+        // Never runs the task, so that the value stays in flight
+        let mut task = AuxTask::new(input, output, created, |_| Ok(()));
+
+        task.schedule(7).unwrap();
+        assert!(task.poll().is_none());
+        assert!(task.is_busy());
+
+        let value: u32 = values.try_recv().unwrap();
+        results.try_send(Some(value * 3)).unwrap();
+        assert_eq!(task.poll(), Some(21));
+        assert!(!task.is_busy());
+    }
+
+    #[test]
+    fn aux_task_panic_has_no_result() {
+        let mut task = App::create_aux_task(
+            |value: u32| {
+                if value == 0 {
+                    panic!("no result for zero");
+                }
+                value
+            },
+            10,
+            &name(),
+        );
+        task.schedule(0).unwrap();
+        assert!(task.poll().is_none());
+        assert!(!task.is_busy());
+        task.schedule(5).unwrap();
+        assert_eq!(task.poll(), Some(5));
+    }
+
+    #[test]
+    fn aux_task_deleted() {
+        let mut task = App::create_aux_task(|value: u32| value, 10, &name());
+        task.task().delete();
+        match task.schedule(1) {
+            Err(ScheduleError::Task(err)) => assert_eq!(err.kind(), error::ErrorKind::Task),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(task.is_busy());
+        assert!(task.reschedule().is_err());
+        assert!(task.poll().is_none());
+    }
+}
@@ -0,0 +1,391 @@
+//! Changes the codec levels while audio is running.
+//!
+//! A `Codec` is obtained from the backend with `Bela::codec` or
+//! `Backend::codec`, before or after audio is initialized. It can be cloned
+//! and moved into auxiliary tasks, which is where it should be used: on the
+//! board, every change is a write to the codec over I2C, which must not
+//! happen in render.
+//!
+//! ```rust,ignore
+//! // Before running, or in setup with a codec moved into the user data:
+//! let codec = bela.codec();
+//! let quieter = App::create_auxiliary_task(
+//!     Box::new(move || {
+//!         codec.set_headphone_level(-12.).unwrap();
+//!     }),
+//!     10,
+//!     &CString::new("quieter").unwrap(),
+//! );
+//! // In render, when the button is pressed:
+//! if context.digital_read(0, 1) {
+//!     let _ = App::schedule_auxiliary_task(&quieter);
+//! }
+//! ```
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use error;
+use settings::{self, ADC_LEVEL, DAC_LEVEL, HEADPHONE_LEVEL, PGA_GAIN};
+use {Context, InitSettings};
+
+/// Handle to the codec of the backend it was obtained from.
+#[derive(Clone)]
+pub struct Codec(Handle);
+
+#[derive(Clone)]
+enum Handle {
+    /// Levels are set by libbela.
+    #[cfg(feature = "libbela")]
+    Bela,
+    /// Levels are applied to the buffers by the backend.
+    Software(Arc<Levels>),
+}
+
+impl Codec {
+    /// A codec controlled through libbela.
+    #[cfg(feature = "libbela")]
+    pub(crate) fn libbela() -> Codec {
+        Codec(Handle::Bela)
+    }
+
+    /// A codec whose levels are applied in software through `levels`.
+    pub(crate) fn software(levels: Arc<Levels>) -> Codec {
+        Codec(Handle::Software(levels))
+    }
+
+    /// Set the level of the DAC, in dB.
+    pub fn set_dac_level(&self, decibels: f32) -> Result<(), error::Error> {
+        check("dac_level", decibels, DAC_LEVEL)?;
+        match self.0 {
+            #[cfg(feature = "libbela")]
+            Handle::Bela => libbela(unsafe { bela_sys::Bela_setDACLevel(decibels) }),
+            Handle::Software(ref levels) => {
+                store(&levels.dac, decibels);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the level of the ADC, in dB.
+    pub fn set_adc_level(&self, decibels: f32) -> Result<(), error::Error> {
+        check("adc_level", decibels, ADC_LEVEL)?;
+        match self.0 {
+            #[cfg(feature = "libbela")]
+            Handle::Bela => libbela(unsafe { bela_sys::Bela_setADCLevel(decibels) }),
+            Handle::Software(ref levels) => {
+                store(&levels.adc, decibels);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the gain of the programmable gain amplifier of one input channel,
+    /// 0 or 1, in dB.
+    pub fn set_pga_gain(&self, decibels: f32, channel: usize) -> Result<(), error::Error> {
+        check("pga_gain", decibels, PGA_GAIN)?;
+        if channel > 1 {
+            return Err(error::Error::new(error::ErrorKind::Codec)
+                .with_detail(format!("the PGA has no channel {}", channel)));
+        }
+        match self.0 {
+            #[cfg(feature = "libbela")]
+            Handle::Bela => libbela(unsafe { bela_sys::Bela_setPgaGain(decibels, channel as _) }),
+            Handle::Software(ref levels) => {
+                store(&levels.pga[channel], decibels);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the level of the headphone output, in dB.
+    pub fn set_headphone_level(&self, decibels: f32) -> Result<(), error::Error> {
+        check("headphone_level", decibels, HEADPHONE_LEVEL)?;
+        match self.0 {
+            #[cfg(feature = "libbela")]
+            Handle::Bela => libbela(unsafe { bela_sys::Bela_setHeadphoneLevel(decibels) }),
+            Handle::Software(ref levels) => {
+                store(&levels.headphone, decibels);
+                Ok(())
+            }
+        }
+    }
+
+    /// Mute or unmute the speaker amplifiers.
+    pub fn mute_speakers(&self, mute: bool) -> Result<(), error::Error> {
+        match self.0 {
+            #[cfg(feature = "libbela")]
+            Handle::Bela => libbela(unsafe { bela_sys::Bela_muteSpeakers(mute as _) }),
+            Handle::Software(ref levels) => {
+                levels.muted.store(mute, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn check(field: &'static str, value: f32, range: (f32, f32)) -> Result<(), error::Error> {
+    settings::check_level(field, value, range)
+        .map_err(|invalid| error::Error::new(error::ErrorKind::Codec).with_source(invalid))
+}
+
+#[cfg(feature = "libbela")]
+fn libbela(code: i32) -> Result<(), error::Error> {
+    match code {
+        0 => Ok(()),
+        _ => Err(error::Error::from_code(error::ErrorKind::Codec, code)),
+    }
+}
+
+/// Codec levels of a software backend, shared with the audio thread.
+///
+/// Buffers are scaled by how far each level has moved from the level audio
+/// was initialized with, so that renders are unchanged until a level is
+/// changed. Outputs follow the DAC and headphone levels together, as heard on
+/// the headphone output, and are silenced while the speakers are muted. The
+/// first two audio inputs follow the ADC level and the PGA gain of their
+/// channel.
+#[derive(Default)]
+pub(crate) struct Levels {
+    dac: AtomicU32,
+    adc: AtomicU32,
+    pga: [AtomicU32; 2],
+    headphone: AtomicU32,
+    muted: AtomicBool,
+    initial: [AtomicU32; 5],
+}
+
+impl Levels {
+    /// Take the levels in `settings` as the ones audio is initialized with.
+    pub(crate) fn reset(&self, settings: &InitSettings) {
+        let pga = settings.pga_gain();
+        let levels = [
+            settings.dac_level(),
+            settings.headphone_level(),
+            settings.adc_level(),
+            pga[0],
+            pga[1],
+        ];
+        for (initial, &level) in self.initial.iter().zip(&levels) {
+            store(initial, level);
+        }
+        store(&self.dac, levels[0]);
+        store(&self.headphone, levels[1]);
+        store(&self.adc, levels[2]);
+        store(&self.pga[0], levels[3]);
+        store(&self.pga[1], levels[4]);
+        self.muted.store(false, Ordering::Relaxed);
+    }
+
+    /// Scale the audio inputs, before render.
+    pub(crate) fn apply_inputs(&self, context: &mut Context) {
+        let layout = context.layout();
+        let frames = context.audio_frames();
+        let channels = context.audio_in_channels();
+        let adc = load(&self.adc) - load(&self.initial[2]);
+        let audio_in = context.audio_in_mut();
+        for channel in 0..channels.min(2) {
+            let pga = load(&self.pga[channel]) - load(&self.initial[3 + channel]);
+            let gain = db_to_gain(adc + pga);
+            if gain != 1. {
+                for frame in 0..frames {
+                    audio_in[layout.index(frame, channel, frames, channels)] *= gain;
+                }
+            }
+        }
+    }
+
+    /// Scale the audio outputs, after render.
+    pub(crate) fn apply_outputs(&self, context: &mut Context) {
+        let gain = if self.muted.load(Ordering::Relaxed) {
+            0.
+        } else {
+            let dac = load(&self.dac) - load(&self.initial[0]);
+            let headphone = load(&self.headphone) - load(&self.initial[1]);
+            db_to_gain(dac + headphone)
+        };
+        if gain != 1. {
+            for samp in context.audio_out().iter_mut() {
+                *samp *= gain;
+            }
+        }
+    }
+}
+
+fn db_to_gain(decibels: f32) -> f32 {
+    10f32.powf(decibels / 20.)
+}
+
+fn store(level: &AtomicU32, decibels: f32) {
+    level.store(decibels.to_bits(), Ordering::Relaxed);
+}
+
+fn load(level: &AtomicU32) -> f32 {
+    f32::from_bits(level.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+
+    use super::*;
+    use settings::InvalidSettings;
+    use ContextBuilder;
+
+    /// A software codec initialized with the default levels: ADC -6dB, PGA
+    /// 16dB, DAC 0dB and headphone -6dB.
+    fn codec() -> (Codec, Arc<Levels>) {
+        let levels = Arc::new(Levels::default());
+        levels.reset(&InitSettings::software_default());
+        (Codec::software(levels.clone()), levels)
+    }
+
+    /// Four frames of three channels of ones, in and out.
+    fn ones(interleaved: bool) -> Context {
+        let mut context = ContextBuilder::new()
+            .audio_frames(4)
+            .audio_in_channels(3)
+            .audio_out_channels(2)
+            .interleaved(interleaved)
+            .build()
+            .unwrap();
+        for samp in context.audio_in_mut().iter_mut() {
+            *samp = 1.;
+        }
+        for samp in context.audio_out().iter_mut() {
+            *samp = 1.;
+        }
+        context
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn unchanged_until_a_level_is_set() {
+        let (_, levels) = codec();
+        let mut context = ones(false);
+        levels.apply_inputs(&mut context);
+        levels.apply_outputs(&mut context);
+        assert!(context.audio_in().iter().all(|&samp| samp == 1.));
+        assert!(context.audio_out().iter().all(|&samp| samp == 1.));
+    }
+
+    #[test]
+    fn outputs_follow_dac_and_headphone() {
+        let (codec, levels) = codec();
+        codec.set_dac_level(-6.).unwrap();
+        let mut context = ones(true);
+        levels.apply_outputs(&mut context);
+        for &samp in context.audio_out().iter() {
+            assert_near(samp, 0.501_187);
+        }
+
+        // Raising the headphone level by as much cancels it out
+        codec.set_headphone_level(0.).unwrap();
+        let mut context = ones(true);
+        levels.apply_outputs(&mut context);
+        assert!(context.audio_out().iter().all(|&samp| samp == 1.));
+        // Inputs are left alone
+        levels.apply_inputs(&mut context);
+        assert!(context.audio_in().iter().all(|&samp| samp == 1.));
+    }
+
+    #[test]
+    fn inputs_follow_adc_and_pga() {
+        for &interleaved in &[true, false] {
+            let (codec, levels) = codec();
+            codec.set_adc_level(0.).unwrap();
+            codec.set_pga_gain(22., 0).unwrap();
+            codec.set_pga_gain(10., 1).unwrap();
+            let mut context = ones(interleaved);
+            levels.apply_inputs(&mut context);
+            for frame in 0..4 {
+                // +12dB, 0dB, and the third channel has no PGA
+                assert_near(context.audio_read(frame, 0), 3.981_072);
+                assert_near(context.audio_read(frame, 1), 1.);
+                assert_eq!(context.audio_read(frame, 2), 1.);
+            }
+            levels.apply_outputs(&mut context);
+            assert!(context.audio_out().iter().all(|&samp| samp == 1.));
+        }
+    }
+
+    #[test]
+    fn mute_silences_outputs() {
+        let (codec, levels) = codec();
+        codec.mute_speakers(true).unwrap();
+        let mut context = ones(false);
+        levels.apply_outputs(&mut context);
+        assert!(context.audio_out().iter().all(|&samp| samp == 0.));
+
+        codec.mute_speakers(false).unwrap();
+        let mut context = ones(false);
+        levels.apply_outputs(&mut context);
+        assert!(context.audio_out().iter().all(|&samp| samp == 1.));
+    }
+
+    #[test]
+    fn reset_forgets_changes() {
+        let (codec, levels) = codec();
+        codec.set_dac_level(-20.).unwrap();
+        codec.set_adc_level(-12.).unwrap();
+        codec.mute_speakers(true).unwrap();
+        levels.reset(&InitSettings::software_default());
+        let mut context = ones(false);
+        levels.apply_inputs(&mut context);
+        levels.apply_outputs(&mut context);
+        assert!(context.audio_in().iter().all(|&samp| samp == 1.));
+        assert!(context.audio_out().iter().all(|&samp| samp == 1.));
+    }
+
+    #[test]
+    fn levels_out_of_range() {
+        let (codec, levels) = codec();
+        let errors = [
+            ("dac_level", codec.set_dac_level(0.5).unwrap_err()),
+            ("adc_level", codec.set_adc_level(-12.5).unwrap_err()),
+            ("pga_gain", codec.set_pga_gain(60., 0).unwrap_err()),
+            (
+                "headphone_level",
+                codec.set_headphone_level(-64.).unwrap_err(),
+            ),
+            ("dac_level", codec.set_dac_level(f32::NAN).unwrap_err()),
+        ];
+        for &(field, ref err) in &errors {
+            assert_eq!(err.kind(), error::ErrorKind::Codec);
+            let invalid = err
+                .source()
+                .and_then(|source| source.downcast_ref::<InvalidSettings>())
+                .unwrap();
+            assert_eq!(invalid.fields.len(), 1);
+            assert_eq!(invalid.fields[0].field, field);
+        }
+        assert_eq!(
+            errors[0].1.source().unwrap().to_string(),
+            "invalid settings: dac_level = 0.5: must be between -63.5dB and 0dB"
+        );
+
+        // Nothing was changed
+        let mut context = ones(false);
+        levels.apply_inputs(&mut context);
+        levels.apply_outputs(&mut context);
+        assert!(context.audio_in().iter().all(|&samp| samp == 1.));
+        assert!(context.audio_out().iter().all(|&samp| samp == 1.));
+    }
+
+    #[test]
+    fn pga_has_two_channels() {
+        let (codec, _) = codec();
+        assert!(codec.set_pga_gain(0., 1).is_ok());
+        let err = codec.set_pga_gain(0., 2).unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Codec);
+        assert_eq!(err.detail(), Some("the PGA has no channel 2"));
+    }
+}
@@ -0,0 +1,390 @@
+//! Reads `InitSettings` from TOML or JSON files, so that a deployed program
+//! can be reconfigured without recompiling it. Requires the `serde` feature.
+//!
+//! Fields left out of a file keep the default value of libbela, and unknown
+//! fields are rejected so that typos are caught:
+//!
+//! ```toml
+//! period_size = 32
+//! num_analog_in_channels = 4
+//! num_analog_out_channels = 4
+//! dac_level = -6.0
+//! board = "Bela"
+//! ```
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::{error, fmt, fs, io};
+
+use serde_json;
+use toml;
+
+use error as bela_error;
+use settings::InvalidSettings;
+use {BelaHw, InitSettings, InitSettingsBuilder};
+
+/// Every field of `InitSettings`, as plain data.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub period_size: usize,
+    pub use_analog: bool,
+    pub use_digital: bool,
+    pub num_analog_in_channels: usize,
+    pub num_analog_out_channels: usize,
+    pub num_digital_channels: usize,
+    pub begin_muted: bool,
+    pub dac_level: f32,
+    pub adc_level: f32,
+    pub pga_gain: [f32; 2],
+    pub headphone_level: f32,
+    pub num_mux_channels: usize,
+    pub audio_expander_inputs: usize,
+    pub audio_expander_outputs: usize,
+    pub pru_number: usize,
+    /// Path to the PRU code, or empty for the built-in code.
+    pub pru_filename: String,
+    pub detect_underruns: bool,
+    pub verbose: bool,
+    pub enable_led: bool,
+    pub stop_button_pin: Option<i8>,
+    pub high_performance_mode: bool,
+    pub interleave: bool,
+    pub analog_outputs_persist: bool,
+    pub uniform_sample_rate: bool,
+    pub audio_thread_stack_size: usize,
+    pub auxiliary_task_stack_size: usize,
+    pub amp_mute_pin: Option<i8>,
+    pub board: BelaHw,
+}
+
+impl Config {
+    /// Validate the configuration, and turn it into settings for libbela.
+    pub fn to_settings(&self) -> Result<InitSettings, ConfigError> {
+        let builder = InitSettingsBuilder::new()
+            .pru_file(&self.pru_filename)
+            .ok_or(ConfigError::PruFilename)?;
+        let settings = builder
+            .period_size(self.period_size)
+            .use_analog(self.use_analog)
+            .use_digital(self.use_digital)
+            .num_analog_in_channels(self.num_analog_in_channels)
+            .num_analog_out_channels(self.num_analog_out_channels)
+            .num_digital_channels(self.num_digital_channels)
+            .begin_muted(self.begin_muted)
+            .dac_level(self.dac_level)
+            .adc_level(self.adc_level)
+            .pga_gain(self.pga_gain)
+            .headphone_level(self.headphone_level)
+            .num_mux_channels(self.num_mux_channels)
+            .audio_expander_inputs(self.audio_expander_inputs)
+            .audio_expander_outputs(self.audio_expander_outputs)
+            .pru_number(self.pru_number)
+            .detect_underruns(self.detect_underruns)
+            .verbose(self.verbose)
+            .enable_led(self.enable_led)
+            .stop_button_pin(self.stop_button_pin)
+            .high_performance_mode(self.high_performance_mode)
+            .interleave(self.interleave)
+            .analog_outputs_persist(self.analog_outputs_persist)
+            .uniform_sample_rate(self.uniform_sample_rate)
+            .audio_thread_stack_size(self.audio_thread_stack_size)
+            .auxiliary_task_stack_size(self.auxiliary_task_stack_size)
+            .amp_mute_pin(self.amp_mute_pin)
+            .board(self.board)
+            .build()?;
+        Ok(settings)
+    }
+}
+
+impl Config {
+    fn with_board(settings: &InitSettings, board: BelaHw) -> Config {
+        let filename = settings.pru_filename();
+        let len = filename
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(filename.len());
+        Config {
+            period_size: settings.period_size(),
+            use_analog: settings.use_analog(),
+            use_digital: settings.use_digital(),
+            num_analog_in_channels: settings.num_analog_in_channels(),
+            num_analog_out_channels: settings.num_analog_out_channels(),
+            num_digital_channels: settings.num_digital_channels(),
+            begin_muted: settings.begin_muted(),
+            dac_level: settings.dac_level(),
+            adc_level: settings.adc_level(),
+            pga_gain: settings.pga_gain(),
+            headphone_level: settings.headphone_level(),
+            num_mux_channels: settings.num_mux_channels(),
+            audio_expander_inputs: settings.audio_expander_inputs(),
+            audio_expander_outputs: settings.audio_expander_outputs(),
+            pru_number: settings.pru_number(),
+            pru_filename: String::from_utf8_lossy(&filename[..len]).into_owned(),
+            detect_underruns: settings.detect_underruns(),
+            verbose: settings.verbose(),
+            enable_led: settings.enable_led(),
+            stop_button_pin: settings.stop_button_pin(),
+            high_performance_mode: settings.high_performance_mode(),
+            interleave: settings.interleave(),
+            analog_outputs_persist: settings.analog_outputs_persist(),
+            uniform_sample_rate: settings.uniform_sample_rate(),
+            audio_thread_stack_size: settings.audio_thread_stack_size(),
+            auxiliary_task_stack_size: settings.auxiliary_task_stack_size(),
+            amp_mute_pin: settings.amp_mute_pin(),
+            board,
+        }
+    }
+}
+
+/// Fails with `ConfigError::UnknownBoard` if the settings select a board this
+/// crate does not know, which could not be written back to a file.
+impl<'a> TryFrom<&'a InitSettings> for Config {
+    type Error = ConfigError;
+
+    fn try_from(settings: &'a InitSettings) -> Result<Config, ConfigError> {
+        match settings.try_board() {
+            Some(board) => Ok(Config::with_board(settings, board)),
+            None => Err(ConfigError::UnknownBoard(settings.settings.board)),
+        }
+    }
+}
+
+impl Default for Config {
+    /// The default settings of libbela, with the board left for libbela to
+    /// detect if it is not one this crate knows.
+    fn default() -> Config {
+        let settings = InitSettings::default();
+        let board = settings.try_board().unwrap_or(BelaHw::NoHw);
+        Config::with_board(&settings, board)
+    }
+}
+
+/// Why a configuration file could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    /// The file extension is neither `.toml` nor `.json`.
+    UnknownFormat(PathBuf),
+    /// The PRU filename is too long, or contains a nul byte.
+    PruFilename,
+    /// The raw `BelaHw` value of settings turned into a `Config` is not one
+    /// this crate knows.
+    UnknownBoard(i32),
+    Invalid(InvalidSettings),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::Io(ref err) => write!(f, "could not read the configuration: {}", err),
+            ConfigError::Toml(ref err) => write!(f, "invalid TOML configuration: {}", err),
+            ConfigError::Json(ref err) => write!(f, "invalid JSON configuration: {}", err),
+            ConfigError::UnknownFormat(ref path) => write!(
+                f,
+                "{} is not a .toml or .json configuration file",
+                path.display()
+            ),
+            ConfigError::PruFilename => write!(
+                f,
+                "pru_filename must be shorter than 256 bytes, without nul bytes"
+            ),
+            ConfigError::UnknownBoard(board) => write!(f, "board {} is not a known board", board),
+            ConfigError::Invalid(ref invalid) => write!(f, "{}", invalid),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ConfigError::Io(ref err) => Some(err),
+            ConfigError::Toml(ref err) => Some(err),
+            ConfigError::Json(ref err) => Some(err),
+            ConfigError::Invalid(ref invalid) => Some(invalid),
+            ConfigError::UnknownFormat(_)
+            | ConfigError::PruFilename
+            | ConfigError::UnknownBoard(_) => None,
+        }
+    }
+}
+
+impl From<InvalidSettings> for ConfigError {
+    fn from(invalid: InvalidSettings) -> ConfigError {
+        ConfigError::Invalid(invalid)
+    }
+}
+
+impl From<ConfigError> for bela_error::Error {
+    fn from(err: ConfigError) -> bela_error::Error {
+        bela_error::Error::new(bela_error::ErrorKind::Init).with_source(err)
+    }
+}
+
+impl InitSettings {
+    /// Load settings from a `.toml` or `.json` file, validating them against
+    /// the board they select.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<InitSettings, ConfigError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(OsStr::to_str);
+        let config: Config = match extension {
+            Some("toml") => {
+                let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+                toml::from_str(&text).map_err(ConfigError::Toml)?
+            }
+            Some("json") => {
+                let file = fs::File::open(path).map_err(ConfigError::Io)?;
+                serde_json::from_reader(io::BufReader::new(file)).map_err(ConfigError::Json)?
+            }
+            _ => return Err(ConfigError::UnknownFormat(path.to_owned())),
+        };
+        config.to_settings()
+    }
+}
+
+// With the `libbela` feature, the defaults come from libbela itself, which is
+// only there on the board.
+#[cfg(all(test, not(feature = "libbela")))]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Load `text` from a temporary file named `name`.
+    fn load(name: &str, text: &str) -> Result<InitSettings, ConfigError> {
+        let path = env::temp_dir().join(format!("bela-config-{}-{}", process::id(), name));
+        fs::write(&path, text).unwrap();
+        let res = InitSettings::load(&path);
+        fs::remove_file(&path).unwrap();
+        res
+    }
+
+    fn config(settings: &InitSettings) -> Config {
+        Config::try_from(settings).unwrap()
+    }
+
+    // The example of the module documentation, which has to be valid
+    #[test]
+    fn loads_toml() {
+        let settings = load(
+            "full.toml",
+            "period_size = 32\n\
+             num_analog_in_channels = 4\n\
+             num_analog_out_channels = 4\n\
+             dac_level = -6.0\n\
+             board = \"Bela\"\n",
+        )
+        .unwrap();
+        assert_eq!(settings.period_size(), 32);
+        assert_eq!(settings.num_analog_in_channels(), 4);
+        assert_eq!(settings.num_analog_out_channels(), 4);
+        assert_eq!(settings.dac_level(), -6.);
+        assert_eq!(settings.try_board(), Some(BelaHw::Bela));
+    }
+
+    #[test]
+    fn loads_json() {
+        let settings = load(
+            "full.json",
+            r#"{"period_size": 64, "use_digital": false, "pga_gain": [10.0, 20.5], "stop_button_pin": 2}"#,
+        )
+        .unwrap();
+        assert_eq!(settings.period_size(), 64);
+        assert!(!settings.use_digital());
+        assert_eq!(settings.pga_gain(), [10., 20.5]);
+        assert_eq!(settings.stop_button_pin(), Some(2));
+    }
+
+    #[test]
+    fn missing_fields_keep_defaults() {
+        let settings = load("partial.toml", "verbose = true\n").unwrap();
+        let expected = Config {
+            verbose: true,
+            ..Config::default()
+        };
+        assert_eq!(config(&settings), expected);
+        assert_eq!(
+            config(&load("empty.json", "{}").unwrap()),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        match load("typo.toml", "perod_size = 32\n") {
+            Err(ConfigError::Toml(err)) => {
+                assert!(err.to_string().contains("unknown field `perod_size`"))
+            }
+            other => panic!("unexpected {:?}", other.map(|settings| config(&settings))),
+        }
+        match load("typo.json", r#"{"perod_size": 32}"#) {
+            Err(ConfigError::Json(err)) => {
+                assert!(err.to_string().contains("unknown field `perod_size`"))
+            }
+            other => panic!("unexpected {:?}", other.map(|settings| config(&settings))),
+        }
+    }
+
+    #[test]
+    fn unknown_format() {
+        for name in &["settings.yaml", "settings"] {
+            let path = Path::new(name);
+            match InitSettings::load(path) {
+                Err(ConfigError::UnknownFormat(ref unknown)) => assert_eq!(unknown, path),
+                other => panic!("unexpected {:?}", other.map(|settings| config(&settings))),
+            }
+        }
+        assert_eq!(
+            ConfigError::UnknownFormat(PathBuf::from("a.yaml")).to_string(),
+            "a.yaml is not a .toml or .json configuration file"
+        );
+    }
+
+    #[test]
+    fn invalid_settings() {
+        match load("invalid.toml", "period_size = 7\nadc_level = 3.0\n") {
+            Err(ConfigError::Invalid(invalid)) => {
+                let fields: Vec<&str> = invalid.fields.iter().map(|field| field.field).collect();
+                assert_eq!(fields, ["period_size", "adc_level"]);
+            }
+            other => panic!("unexpected {:?}", other.map(|settings| config(&settings))),
+        }
+        let long = format!("pru_filename = \"{}\"\n", "a".repeat(300));
+        match load("pru.toml", &long) {
+            Err(ConfigError::PruFilename) => {}
+            other => panic!("unexpected {:?}", other.map(|settings| config(&settings))),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let settings = InitSettingsBuilder::from_settings(InitSettings::software_default())
+            .period_size(64)
+            .headphone_level(-12.)
+            .pru_file("/root/pru.bin")
+            .unwrap()
+            .amp_mute_pin(None)
+            .board(BelaHw::Bela)
+            .build()
+            .unwrap();
+        let written = config(&settings);
+        assert_eq!(written.pru_filename, "/root/pru.bin");
+        assert_eq!(config(&written.to_settings().unwrap()), written);
+
+        let json = serde_json::to_string(&written).unwrap();
+        assert_eq!(config(&load("round-trip.json", &json).unwrap()), written);
+    }
+
+    #[test]
+    fn unknown_board() {
+        let mut settings = InitSettings::software_default();
+        settings.settings.board = 99;
+        match Config::try_from(&settings) {
+            Err(ConfigError::UnknownBoard(99)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
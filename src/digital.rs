@@ -0,0 +1,445 @@
+//! Handles for single digital pins, which read and write a whole block at a
+//! time.
+//!
+//! The digital buffer of a `Context` holds one word per frame: the lower 16
+//! bits are the pin directions, and the upper 16 bits are the pin values.
+//! A `DigitalPin` hides that layout, reading a block of a pin into `Bits` and
+//! writing `Bits` back, and `DigitalInput` turns the reads into rising and
+//! falling edges with their frame within the block.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let button = context.digital_pin(0).expect("no digital pin 0");
+//! let led = context.digital_pin(1).expect("no digital pin 1");
+//! let mut input = DigitalInput::new(button).with_debounce(441);
+//! // In render:
+//! button.set_mode(context, DigitalDirection::INPUT);
+//! led.set_mode(context, DigitalDirection::OUTPUT);
+//! for (frame, edge) in input.process(context) {
+//!     if edge == Edge::Rising {
+//!         led.write(context, frame, true);
+//!     }
+//! }
+//! ```
+
+use {Context, DigitalDirection};
+
+/// Most frames a `Bits` holds.
+pub const MAX_FRAMES: usize = 256;
+
+/// A digital channel, checked against the channels of a context.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DigitalPin {
+    channel: usize,
+}
+
+impl DigitalPin {
+    /// The pin for `channel`, if the context has that many digital channels.
+    pub fn new(context: &Context, channel: usize) -> Option<DigitalPin> {
+        if channel < context.digital_channels() {
+            Some(DigitalPin { channel })
+        } else {
+            None
+        }
+    }
+
+    pub fn channel(self) -> usize {
+        self.channel
+    }
+
+    /// Set the direction of the pin for the whole block.
+    pub fn set_mode(self, context: &mut Context, mode: DigitalDirection) {
+        context.pin_mode(0, self.channel, mode);
+    }
+
+    /// Read the pin at `frame`.
+    pub fn read(self, context: &Context, frame: usize) -> bool {
+        context.digital_read(frame, self.channel)
+    }
+
+    /// Read the pin for every frame of the block.
+    ///
+    /// Panics if the block has more than `MAX_FRAMES` frames.
+    pub fn read_block(self, context: &Context) -> Bits {
+        let digital = context.digital();
+        let mut bits = Bits::new(digital.len());
+        for (frame, &word) in digital.iter().enumerate() {
+            bits.set(frame, word & self.value_mask() != 0);
+        }
+        bits
+    }
+
+    /// Write `value` to the pin from `frame` to the end of the block.
+    pub fn write(self, context: &mut Context, frame: usize, value: bool) {
+        context.digital_write(frame, self.channel, value);
+    }
+
+    /// Write `value` to the pin at `frame` only.
+    pub fn write_once(self, context: &mut Context, frame: usize, value: bool) {
+        context.digital_write_once(frame, self.channel, value);
+    }
+
+    /// Write a pattern to the pin, one bit per frame, from the start of the
+    /// block. Frames past the end of `bits` are left as they are.
+    pub fn write_block(self, context: &mut Context, bits: &Bits) {
+        let mask = self.value_mask();
+        for (word, value) in context.digital_mut().iter_mut().zip(bits.iter()) {
+            if value {
+                *word |= mask;
+            } else {
+                *word &= !mask;
+            }
+        }
+    }
+
+    fn value_mask(self) -> u32 {
+        1 << (self.channel + 16)
+    }
+}
+
+/// One bit per frame of a block, without allocating.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bits {
+    words: [u64; MAX_FRAMES / 64],
+    len: usize,
+}
+
+impl Bits {
+    /// `len` bits, all clear.
+    ///
+    /// Panics if `len` is more than `MAX_FRAMES`.
+    pub fn new(len: usize) -> Bits {
+        assert!(len <= MAX_FRAMES, "{} frames do not fit in Bits", len);
+        Bits {
+            words: [0; MAX_FRAMES / 64],
+            len,
+        }
+    }
+
+    /// `len` bits, each set to `f(frame)`.
+    pub fn from_fn<F: FnMut(usize) -> bool>(len: usize, mut f: F) -> Bits {
+        let mut bits = Bits::new(len);
+        for frame in 0..len {
+            bits.set(frame, f(frame));
+        }
+        bits
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, frame: usize) -> bool {
+        assert!(frame < self.len, "frame {} out of {}", frame, self.len);
+        self.words[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    pub fn set(&mut self, frame: usize, value: bool) {
+        assert!(frame < self.len, "frame {} out of {}", frame, self.len);
+        if value {
+            self.words[frame / 64] |= 1 << (frame % 64);
+        } else {
+            self.words[frame / 64] &= !(1 << (frame % 64));
+        }
+    }
+
+    /// Number of frames whose bit is set.
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(move |frame| self.get(frame))
+    }
+
+    /// The edges within the block, given the value of the bit before it.
+    pub fn edges(&self, previous: bool) -> Edges {
+        Edges {
+            bits: *self,
+            previous,
+            frame: 0,
+        }
+    }
+}
+
+/// A change in the value of a pin.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// The edges in a block, with the frame each one happens at.
+pub struct Edges {
+    bits: Bits,
+    previous: bool,
+    frame: usize,
+}
+
+impl Iterator for Edges {
+    type Item = (usize, Edge);
+
+    fn next(&mut self) -> Option<(usize, Edge)> {
+        while self.frame < self.bits.len() {
+            let frame = self.frame;
+            let value = self.bits.get(frame);
+            self.frame += 1;
+            if value != self.previous {
+                self.previous = value;
+                let edge = if value { Edge::Rising } else { Edge::Falling };
+                return Some((frame, edge));
+            }
+        }
+        None
+    }
+}
+
+/// An input pin whose edges are tracked from one block to the next, with
+/// optional debouncing.
+pub struct DigitalInput {
+    pin: DigitalPin,
+    /// `None` until the first block is read.
+    value: Option<bool>,
+    debounce: usize,
+    /// Number of frames the raw value has differed from `value`.
+    pending: usize,
+}
+
+impl DigitalInput {
+    pub fn new(pin: DigitalPin) -> DigitalInput {
+        DigitalInput {
+            pin,
+            value: None,
+            debounce: 0,
+            pending: 0,
+        }
+    }
+
+    /// Only accept a change once the pin has held its new value for `frames`
+    /// more frames after the one it changed at. The edge is reported at the
+    /// frame the change is accepted.
+    ///
+    /// With 0, the default, every change is accepted at the frame it happens.
+    pub fn with_debounce(mut self, frames: usize) -> DigitalInput {
+        self.debounce = frames;
+        self
+    }
+
+    pub fn pin(&self) -> DigitalPin {
+        self.pin
+    }
+
+    /// The value of the pin at the end of the last block processed, or
+    /// `false` before the first one.
+    pub fn value(&self) -> bool {
+        self.value.unwrap_or(false)
+    }
+
+    /// Read the block, and return the edges it contains.
+    ///
+    /// The first block read starts from the value of its first frame, so a
+    /// pin that is already high does not report a rising edge.
+    pub fn process(&mut self, context: &Context) -> Edges {
+        let raw = self.pin.read_block(context);
+        let mut value = match self.value {
+            Some(value) => value,
+            None if raw.is_empty() => return raw.edges(false),
+            None => raw.get(0),
+        };
+        let previous = value;
+        let mut accepted = Bits::new(raw.len());
+        for frame in 0..raw.len() {
+            if raw.get(frame) == value {
+                self.pending = 0;
+            } else {
+                self.pending += 1;
+                if self.pending > self.debounce {
+                    value = !value;
+                    self.pending = 0;
+                }
+            }
+            accepted.set(frame, value);
+        }
+        self.value = Some(value);
+        accepted.edges(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ContextBuilder;
+
+    fn context(frames: usize) -> Context {
+        ContextBuilder::new()
+            .digital_frames(frames)
+            .digital_channels(16)
+            .build()
+            .unwrap()
+    }
+
+    /// Set the value of `pin` for each frame of the block from `values`.
+    fn drive(context: &mut Context, pin: DigitalPin, values: &[u8]) {
+        let bits = Bits::from_fn(values.len(), |frame| values[frame] != 0);
+        pin.write_block(context, &bits);
+    }
+
+    #[test]
+    fn bits_get_and_set() {
+        let mut bits = Bits::new(MAX_FRAMES);
+        assert_eq!(bits.len(), MAX_FRAMES);
+        assert_eq!(bits.count_ones(), 0);
+        for &frame in &[0, 63, 64, 200, MAX_FRAMES - 1] {
+            bits.set(frame, true);
+            assert!(bits.get(frame));
+        }
+        assert_eq!(bits.count_ones(), 5);
+        bits.set(64, false);
+        assert!(!bits.get(64));
+        assert!(!bits.get(65));
+        assert_eq!(bits.count_ones(), 4);
+        assert!(Bits::new(0).is_empty());
+    }
+
+    #[test]
+    fn bits_from_fn() {
+        let bits = Bits::from_fn(10, |frame| frame % 3 == 0);
+        assert_eq!(
+            bits.iter().collect::<Vec<_>>(),
+            vec![true, false, false, true, false, false, true, false, false, true]
+        );
+        assert_eq!(bits.count_ones(), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bits_out_of_range() {
+        Bits::new(4).get(4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bits_too_long() {
+        Bits::new(MAX_FRAMES + 1);
+    }
+
+    #[test]
+    fn edges_follow_previous_value() {
+        let bits = Bits::from_fn(6, |frame| (2..5).contains(&frame));
+        assert_eq!(
+            bits.edges(false).collect::<Vec<_>>(),
+            vec![(2, Edge::Rising), (5, Edge::Falling)]
+        );
+        assert_eq!(
+            bits.edges(true).collect::<Vec<_>>(),
+            vec![(0, Edge::Falling), (2, Edge::Rising), (5, Edge::Falling)]
+        );
+        assert_eq!(Bits::new(6).edges(false).count(), 0);
+    }
+
+    #[test]
+    fn pin_checks_channels() {
+        let context = ContextBuilder::new().digital_channels(4).build().unwrap();
+        assert!(DigitalPin::new(&context, 3).is_some());
+        assert!(DigitalPin::new(&context, 4).is_none());
+    }
+
+    #[test]
+    fn pin_reads_and_writes_blocks() {
+        let mut context = context(8);
+        let pin = context.digital_pin(2).unwrap();
+        let other = context.digital_pin(3).unwrap();
+        let pattern = Bits::from_fn(8, |frame| frame % 2 == 1);
+        pin.write_block(&mut context, &pattern);
+        assert_eq!(pin.read_block(&context), pattern);
+        assert_eq!(other.read_block(&context).count_ones(), 0);
+        // The directions in the lower bits are untouched
+        assert!(context
+            .digital()
+            .iter()
+            .all(|&word| word & 0xffff == 0xffff));
+
+        pin.write(&mut context, 6, true);
+        assert!(pin.read(&context, 6) && pin.read(&context, 7));
+        other.write_once(&mut context, 4, true);
+        assert_eq!(other.read_block(&context).count_ones(), 1);
+    }
+
+    #[test]
+    fn input_starts_from_first_read() {
+        let mut context = context(4);
+        let pin = context.digital_pin(0).unwrap();
+        let mut input = DigitalInput::new(pin);
+        drive(&mut context, pin, &[1, 1, 1, 1]);
+        assert_eq!(input.process(&context).count(), 0);
+        assert!(input.value());
+
+        drive(&mut context, pin, &[1, 0, 0, 1]);
+        assert_eq!(
+            input.process(&context).collect::<Vec<_>>(),
+            vec![(1, Edge::Falling), (3, Edge::Rising)]
+        );
+    }
+
+    #[test]
+    fn input_tracks_edges_across_blocks() {
+        let mut context = context(4);
+        let pin = context.digital_pin(0).unwrap();
+        let mut input = DigitalInput::new(pin);
+        drive(&mut context, pin, &[0, 0, 0, 1]);
+        assert_eq!(
+            input.process(&context).collect::<Vec<_>>(),
+            vec![(3, Edge::Rising)]
+        );
+        drive(&mut context, pin, &[1, 1, 0, 0]);
+        assert_eq!(
+            input.process(&context).collect::<Vec<_>>(),
+            vec![(2, Edge::Falling)]
+        );
+        assert!(!input.value());
+    }
+
+    #[test]
+    fn input_debounces() {
+        let mut context = context(8);
+        let pin = context.digital_pin(0).unwrap();
+        drive(&mut context, pin, &[0, 1, 0, 1, 1, 1, 0, 0]);
+
+        let edges = |debounce| {
+            let mut input = DigitalInput::new(pin).with_debounce(debounce);
+            input.process(&context).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            edges(0),
+            vec![
+                (1, Edge::Rising),
+                (2, Edge::Falling),
+                (3, Edge::Rising),
+                (6, Edge::Falling),
+            ]
+        );
+        assert_eq!(edges(1), vec![(4, Edge::Rising), (7, Edge::Falling)]);
+        assert_eq!(edges(2), vec![(5, Edge::Rising)]);
+    }
+
+    #[test]
+    fn input_debounce_spans_blocks() {
+        let mut context = context(4);
+        let pin = context.digital_pin(0).unwrap();
+        let mut input = DigitalInput::new(pin).with_debounce(2);
+        drive(&mut context, pin, &[0, 0, 0, 1]);
+        assert_eq!(input.process(&context).count(), 0);
+        drive(&mut context, pin, &[1, 1, 1, 1]);
+        assert_eq!(
+            input.process(&context).collect::<Vec<_>>(),
+            vec![(1, Edge::Rising)]
+        );
+    }
+}
@@ -1,28 +1,424 @@
-use std::{error, fmt};
+//! The error returned by every step of the Bela lifecycle.
+//!
+//! # Migrating from the `Error` enum
+//!
+//! `Error` used to be a fieldless enum with one variant per lifecycle step.
+//! Those variants are now the `ErrorKind` of an `Error` struct, which also
+//! carries the return code, `errno`, settings, detail and source of the
+//! failure. Code matching on the enum moves to `Error::kind`, and code
+//! building one goes through `From<ErrorKind>`:
+//!
+//! ```rust,ignore
+//! // Before
+//! match err {
+//!     Error::Init => retry(),
+//!     _ => return Err(Error::Start),
+//! }
+//! // After
+//! match err.kind() {
+//!     ErrorKind::Init => retry(),
+//!     _ => return Err(ErrorKind::Start.into()),
+//! }
+//! ```
+//!
+//! An `Error` can also be compared against an `ErrorKind` directly, as in
+//! `err == ErrorKind::Init`. The enum never implemented `PartialEq`, so this
+//! has no equivalent in the code being migrated.
 
-#[derive(Copy, Clone, Debug)]
-pub enum Error {
+use std::{error, fmt, io};
+
+#[cfg(feature = "libbela")]
+use libc;
+
+use InitSettings;
+
+/// The step of the Bela lifecycle that failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
     Init,
     Start,
     Stop,
     Cleanup,
     Task,
+    /// Changing a codec level or muting the speakers while audio is running.
+    Codec,
+    /// Detecting the board, or reading its configuration.
+    Detect,
+    /// A callback panicked, with the panic contained by the `PanicPolicy`.
+    Panic,
+}
+
+impl ErrorKind {
+    fn description(self) -> &'static str {
+        match self {
+            ErrorKind::Init => "could not initialize audio",
+            ErrorKind::Start => "could not start audio",
+            ErrorKind::Stop => "could not stop audio",
+            ErrorKind::Cleanup => "could not clean up audio",
+            ErrorKind::Task => "could not schedule an auxiliary task",
+            ErrorKind::Codec => "could not control the codec",
+            ErrorKind::Detect => "could not detect the board",
+            ErrorKind::Panic => "a callback panicked",
+        }
+    }
+
+    /// What usually causes this kind of error.
+    fn hint(self) -> &'static str {
+        match self {
+            ErrorKind::Init => {
+                "check that no other Bela program is running, that setup returned Ok, \
+                 and that the period size and channel counts are supported by the board"
+            }
+            ErrorKind::Start => {
+                "check that init_audio succeeded, and that the audio thread could be \
+                 created with real-time priority"
+            }
+            ErrorKind::Stop | ErrorKind::Cleanup => "audio may not have been running",
+            ErrorKind::Task => {
+                "check that the task was created by the same backend, and that its \
+                 thread is still running"
+            }
+            ErrorKind::Codec => {
+                "check that the level is within the range of the codec, and that the \
+                 board has the codec being controlled"
+            }
+            ErrorKind::Detect => {
+                "check that the board is powered and its cape is seated, or select \
+                 the board in ~/.bela/belaconfig"
+            }
+            ErrorKind::Panic => "set RUST_BACKTRACE=1 to find where the callback panicked",
+        }
+    }
+}
+
+/// The settings audio was initialized with, as recorded in an `Error`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub period_size: usize,
+    pub use_analog: bool,
+    pub use_digital: bool,
+    pub num_analog_in_channels: usize,
+    pub num_analog_out_channels: usize,
+    pub num_digital_channels: usize,
+    pub num_mux_channels: usize,
+    pub uniform_sample_rate: bool,
+    pub interleave: bool,
+    /// The raw `BelaHw` value, which may not be one this crate knows.
+    pub board: i32,
+}
+
+impl<'a> From<&'a InitSettings> for Settings {
+    fn from(settings: &'a InitSettings) -> Settings {
+        Settings {
+            period_size: settings.period_size(),
+            use_analog: settings.use_analog(),
+            use_digital: settings.use_digital(),
+            num_analog_in_channels: settings.num_analog_in_channels(),
+            num_analog_out_channels: settings.num_analog_out_channels(),
+            num_digital_channels: settings.num_digital_channels(),
+            num_mux_channels: settings.num_mux_channels(),
+            uniform_sample_rate: settings.uniform_sample_rate(),
+            interleave: settings.interleave(),
+            board: settings.settings.board,
+        }
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "period size {}", self.period_size)?;
+        if self.use_analog {
+            write!(
+                f,
+                ", {} analog in, {} analog out",
+                self.num_analog_in_channels, self.num_analog_out_channels
+            )?;
+            if self.num_mux_channels > 0 {
+                write!(f, ", {} multiplexer channels", self.num_mux_channels)?;
+            }
+        } else {
+            write!(f, ", no analog")?;
+        }
+        if self.use_digital {
+            write!(f, ", {} digital", self.num_digital_channels)?;
+        } else {
+            write!(f, ", no digital")?;
+        }
+        write!(
+            f,
+            ", {} sample rates, {}, board {}",
+            if self.uniform_sample_rate {
+                "uniform"
+            } else {
+                "native"
+            },
+            if self.interleave {
+                "interleaved"
+            } else {
+                "non-interleaved"
+            },
+            self.board
+        )
+    }
+}
+
+/// An error from a step of the Bela lifecycle.
+///
+/// Besides its `kind`, an error carries whatever is known about its cause:
+/// the code returned by libbela, `errno` right after the failing call, the
+/// settings in use, a detail message, and an underlying error.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    code: Option<i32>,
+    errno: Option<i32>,
+    settings: Option<Settings>,
+    detail: Option<String>,
+    source: Option<Box<dyn error::Error + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            code: None,
+            errno: None,
+            settings: None,
+            detail: None,
+            source: None,
+        }
+    }
+
+    /// An error for a libbela call that returned `code`, recording the
+    /// current `errno` if it is set.
+    pub fn from_code(kind: ErrorKind, code: i32) -> Error {
+        let errno = io::Error::last_os_error()
+            .raw_os_error()
+            .filter(|&errno| errno != 0);
+        Error {
+            code: Some(code),
+            errno,
+            ..Error::new(kind)
+        }
+    }
+
+    /// Record the settings in use when the error happened.
+    pub fn with_settings(mut self, settings: &InitSettings) -> Error {
+        self.settings = Some(Settings::from(settings));
+        self
+    }
+
+    /// Add a message describing what went wrong.
+    pub fn with_detail<S: Into<String>>(mut self, detail: S) -> Error {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Record the error that caused this one.
+    pub fn with_source<E>(mut self, source: E) -> Error
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The code returned by libbela, if the error came from a libbela call.
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+
+    /// The value of `errno` right after the failing libbela call, if set.
+    pub fn errno(&self) -> Option<i32> {
+        self.errno
+    }
+
+    pub fn settings(&self) -> Option<&Settings> {
+        self.settings.as_ref()
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_ref().map(|detail| &detail[..])
+    }
+}
+
+/// Clear `errno` ahead of a libbela call, so that `Error::from_code` only
+/// picks up values set by that call.
+///
+/// On platforms where the location of `errno` is not known, this does
+/// nothing, and an error may report an `errno` left over from an earlier call.
+#[cfg(feature = "libbela")]
+pub(crate) fn clear_errno() {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "emscripten"))]
+    unsafe {
+        *libc::__errno_location() = 0
+    };
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly"
+    ))]
+    unsafe {
+        *libc::__error() = 0
+    };
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::new(kind)
+    }
+}
+
+impl PartialEq<ErrorKind> for Error {
+    fn eq(&self, kind: &ErrorKind) -> bool {
+        self.kind == *kind
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Error: {:?}.", self)
+        write!(f, "{}", self.kind.description())?;
+        if let Some(ref detail) = self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        if let Some(code) = self.code {
+            write!(f, " (libbela returned {}", code)?;
+            if let Some(errno) = self.errno {
+                write!(f, ", {}", io::Error::from_raw_os_error(errno))?;
+            }
+            write!(f, ")")?;
+        }
+        if let Some(ref settings) = self.settings {
+            write!(f, " with {}", settings)?;
+        }
+        if let Some(ref source) = self.source {
+            write!(f, ", caused by: {}", source)?;
+        }
+        // A detail message already says what went wrong, unless libbela was
+        // the one failing.
+        if self.detail.is_none() || self.code.is_some() || self.kind == ErrorKind::Panic {
+            write!(f, ". Hint: {}", self.kind.hint())?;
+        }
+        write!(f, ".")
     }
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        match self {
-            Error::Init => "Bela_initAudio error",
-            Error::Start => "Bela_startAudio error",
-            Error::Stop => "Bela_stopAudio error",
-            Error::Cleanup => "Bela_cleanupAudio error",
-            Error::Task => "Bela_scheduleAuxiliaryTask error",
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.source {
+            Some(ref source) => Some(&**source),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+    use std::fs;
+
+    use libc;
+
+    use super::*;
+
+    #[test]
+    fn display_adds_hint() {
+        assert_eq!(
+            Error::new(ErrorKind::Stop).to_string(),
+            "could not stop audio. Hint: audio may not have been running."
+        );
+        // A detail explains the error in its place
+        assert_eq!(
+            Error::new(ErrorKind::Stop)
+                .with_detail("not started")
+                .to_string(),
+            "could not stop audio: not started."
+        );
+        // Unless the detail is about a panic, or libbela failed
+        let panic = Error::new(ErrorKind::Panic).with_detail("render panicked");
+        assert!(panic
+            .to_string()
+            .starts_with("a callback panicked: render panicked. Hint: set RUST_BACKTRACE=1"));
+        let code = Error {
+            code: Some(-1),
+            ..Error::new(ErrorKind::Stop)
         }
+        .with_detail("not started");
+        assert_eq!(
+            code.to_string(),
+            "could not stop audio: not started (libbela returned -1). \
+             Hint: audio may not have been running."
+        );
+    }
+
+    #[test]
+    fn display_settings_and_source() {
+        let mut settings = InitSettings::software_default();
+        settings.set_use_digital(false);
+        let err = Error::new(ErrorKind::Init)
+            .with_settings(&settings)
+            .with_source(io::Error::other("no codec"));
+        let message = err.to_string();
+        assert!(
+            message.starts_with("could not initialize audio with period size 16, "),
+            "{}",
+            message
+        );
+        assert!(message.contains(", no digital, "), "{}", message);
+        assert!(
+            message.contains(", caused by: no codec. Hint: "),
+            "{}",
+            message
+        );
+        assert_eq!(err.settings().unwrap().period_size, 16);
+    }
+
+    #[test]
+    fn source_is_chained() {
+        let io = io::Error::other("no codec");
+        let inner = Error::new(ErrorKind::Detect).with_source(io);
+        let outer = Error::new(ErrorKind::Init).with_source(inner);
+        let inner = outer.source().unwrap();
+        assert!(inner
+            .to_string()
+            .starts_with("could not detect the board, caused by: no codec"));
+        assert_eq!(inner.source().unwrap().to_string(), "no codec");
+        assert!(Error::new(ErrorKind::Init).source().is_none());
+    }
+
+    #[test]
+    fn from_code_records_errno() {
+        assert!(fs::File::open("/nonexistent/bela").is_err());
+        let err = Error::from_code(ErrorKind::Start, -1);
+        assert_eq!(err.code(), Some(-1));
+        assert_eq!(err.errno(), Some(libc::ENOENT));
+        assert!(err
+            .to_string()
+            .starts_with("could not start audio (libbela returned -1, No such file or directory"));
+    }
+
+    #[cfg(feature = "libbela")]
+    #[test]
+    fn from_code_skips_cleared_errno() {
+        clear_errno();
+        let err = Error::from_code(ErrorKind::Start, -1);
+        assert_eq!(err.errno(), None);
+        assert!(err
+            .to_string()
+            .starts_with("could not start audio (libbela returned -1). Hint: "));
+    }
+
+    #[test]
+    fn compares_with_kind() {
+        let err = Error::from(ErrorKind::Init);
+        assert!(err == ErrorKind::Init);
+        assert!(err != ErrorKind::Start);
+        assert_eq!(err.kind(), ErrorKind::Init);
+        assert_eq!((err.code(), err.errno(), err.detail()), (None, None, None));
     }
 }
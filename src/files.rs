@@ -0,0 +1,115 @@
+//! The WAV and CSV files written by the offline backend and the golden
+//! harness, and the `hound` error conversion shared by every module reading
+//! or writing WAV files.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use hound;
+
+use Layout;
+
+pub(crate) type WavWriter = hound::WavWriter<BufWriter<File>>;
+
+/// Turn a `hound` error into the `io::Error` it wraps, or an `InvalidData`
+/// error for a malformed file.
+pub(crate) fn to_io_error(err: hound::Error) -> io::Error {
+    match err {
+        hound::Error::IoError(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// Check that a WAV file can hold `channels` channels at `sample_rate`.
+pub(crate) fn check_wav_spec(channels: usize, sample_rate: f32) -> io::Result<()> {
+    if channels == 0 || channels > usize::from(u16::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a WAV file cannot hold {} channels", channels),
+        ));
+    }
+    if !(1. ..=u32::MAX as f32).contains(&sample_rate) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a WAV file cannot have a sample rate of {}Hz", sample_rate),
+        ));
+    }
+    Ok(())
+}
+
+/// Create a 32-bit float WAV file.
+pub(crate) fn create_wav(path: &Path, channels: usize, sample_rate: f32) -> io::Result<WavWriter> {
+    check_wav_spec(channels, sample_rate)?;
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    hound::WavWriter::create(path, spec).map_err(to_io_error)
+}
+
+/// Append the frames of `buf` to `writer`, interleaving them into `scratch`
+/// first when `layout` is not interleaved.
+pub(crate) fn write_wav(
+    writer: &mut WavWriter,
+    buf: &[f32],
+    channels: usize,
+    layout: Layout,
+    scratch: &mut Vec<f32>,
+) -> io::Result<()> {
+    if channels == 0 {
+        return Ok(());
+    }
+
+    let samples = if layout == Layout::Interleaved {
+        buf
+    } else {
+        let frames = buf.len() / channels;
+        scratch.clear();
+        for frame in 0..frames {
+            for channel in 0..channels {
+                scratch.push(buf[layout.index(frame, channel, frames, channels)]);
+            }
+        }
+        &scratch[..]
+    };
+
+    for &samp in samples {
+        writer.write_sample(samp).map_err(to_io_error)?;
+    }
+    Ok(())
+}
+
+/// A CSV file with a `frame` column followed by the value, 0 or 1, of every
+/// digital channel.
+pub(crate) struct DigitalCsv {
+    file: BufWriter<File>,
+    channels: usize,
+}
+
+impl DigitalCsv {
+    pub(crate) fn create(path: &Path, channels: usize) -> io::Result<DigitalCsv> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write!(file, "frame")?;
+        for channel in 0..channels {
+            write!(file, ",{}", channel)?;
+        }
+        writeln!(file)?;
+        Ok(DigitalCsv { file, channels })
+    }
+
+    /// Write one row, with the value of channel `n` in bit `n` of `values`.
+    pub(crate) fn write(&mut self, frame: u64, values: u32) -> io::Result<()> {
+        write!(self.file, "{}", frame)?;
+        for channel in 0..self.channels {
+            write!(self.file, ",{}", (values >> channel) & 1)?;
+        }
+        writeln!(self.file)
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
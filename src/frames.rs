@@ -0,0 +1,632 @@
+//! Buffer layouts, and views of single frames and channels in the `Context`
+//! buffers.
+//!
+//! Depending on the `Layout`, either the samples of a frame or the samples of
+//! a channel are contiguous in memory. `Samples` and `SamplesMut` hide that
+//! difference: they view every `stride`th sample of a buffer, so frames and
+//! channels are accessed in the same way for both layouts.
+
+use std::iter::{StepBy, Take};
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+use std::slice;
+
+use sys;
+
+/// Order of the samples in the audio and analog buffers of a `Context`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// The samples of each frame are contiguous: `[l0, r0, l1, r1, ...]`.
+    /// This is the layout when `BELA_FLAG_INTERLEAVED` is set.
+    Interleaved,
+    /// The samples of each channel are contiguous: `[l0, l1, ..., r0, r1, ...]`.
+    NonInterleaved,
+}
+
+impl Layout {
+    /// Layout described by the `BELA_FLAG_*` bits of a context.
+    pub fn from_flags(flags: u32) -> Layout {
+        if flags & sys::BELA_FLAG_INTERLEAVED != 0 {
+            Layout::Interleaved
+        } else {
+            Layout::NonInterleaved
+        }
+    }
+
+    /// Index of the sample of `channel` at `frame`, in a buffer of `frames`
+    /// frames of `channels` channels.
+    pub fn index(self, frame: usize, channel: usize, frames: usize, channels: usize) -> usize {
+        match self {
+            Layout::Interleaved => frame * channels + channel,
+            Layout::NonInterleaved => channel * frames + frame,
+        }
+    }
+
+    /// Start and stride of the samples of `frame`.
+    pub(crate) fn frame(self, frame: usize, frames: usize, channels: usize) -> (usize, usize) {
+        match self {
+            Layout::Interleaved => (frame * channels, 1),
+            Layout::NonInterleaved => (frame, frames),
+        }
+    }
+
+    /// Start and stride of the samples of `channel`.
+    pub(crate) fn channel(self, channel: usize, frames: usize, channels: usize) -> (usize, usize) {
+        match self {
+            Layout::Interleaved => (channel, channels),
+            Layout::NonInterleaved => (channel * frames, 1),
+        }
+    }
+}
+
+/// Read-only view of the samples of one frame or one channel.
+#[derive(Copy, Clone, Debug)]
+pub struct Samples<'a> {
+    data: &'a [f32],
+    len: usize,
+    stride: usize,
+}
+
+impl<'a> Samples<'a> {
+    /// View `len` samples of `data`, starting at `start`, `stride` apart.
+    pub(crate) fn new(data: &'a [f32], start: usize, len: usize, stride: usize) -> Samples<'a> {
+        let data = if len == 0 { &data[..0] } else { &data[start..] };
+        assert!(len == 0 || (len - 1) * stride < data.len());
+        Samples {
+            data,
+            len,
+            stride: stride.max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<f32> {
+        if idx < self.len {
+            Some(self.data[idx * self.stride])
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> Take<StepBy<slice::Iter<'a, f32>>> {
+        self.data.iter().step_by(self.stride).take(self.len)
+    }
+
+    /// Copy the samples into `out`, which must have the same length.
+    pub fn copy_to_slice(&self, out: &mut [f32]) {
+        assert_eq!(out.len(), self.len);
+        for (out, samp) in out.iter_mut().zip(self.iter()) {
+            *out = *samp;
+        }
+    }
+}
+
+impl<'a> Index<usize> for Samples<'a> {
+    type Output = f32;
+
+    fn index(&self, idx: usize) -> &f32 {
+        assert!(
+            idx < self.len,
+            "index {} out of range for {} samples",
+            idx,
+            self.len
+        );
+        &self.data[idx * self.stride]
+    }
+}
+
+impl<'a> IntoIterator for Samples<'a> {
+    type Item = &'a f32;
+    type IntoIter = Take<StepBy<slice::Iter<'a, f32>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Mutable view of the samples of one frame or one channel.
+///
+/// Views handed out by `FramesMut` interleave in memory for the
+/// non-interleaved layout, so they hold a raw pointer rather than a slice.
+#[derive(Debug)]
+pub struct SamplesMut<'a> {
+    ptr: *mut f32,
+    len: usize,
+    stride: usize,
+    _marker: PhantomData<&'a mut [f32]>,
+}
+
+impl<'a> SamplesMut<'a> {
+    /// View `len` samples of `data`, starting at `start`, `stride` apart.
+    pub(crate) fn new(
+        data: &'a mut [f32],
+        start: usize,
+        len: usize,
+        stride: usize,
+    ) -> SamplesMut<'a> {
+        assert!(len == 0 || start + (len - 1) * stride < data.len());
+        let start = if len == 0 { 0 } else { start };
+        unsafe { SamplesMut::from_raw(data.as_mut_ptr().add(start), len, stride) }
+    }
+
+    /// View `len` samples starting at `ptr`, `stride` apart.
+    ///
+    /// The samples must be valid for `'a`, and not be accessed through any
+    /// other reference for as long as the view exists.
+    unsafe fn from_raw(ptr: *mut f32, len: usize, stride: usize) -> SamplesMut<'a> {
+        SamplesMut {
+            ptr,
+            len,
+            stride,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<f32> {
+        if idx < self.len {
+            Some(unsafe { *self.ptr.add(idx * self.stride) })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut f32> {
+        if idx < self.len {
+            Some(unsafe { &mut *self.ptr.add(idx * self.stride) })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            ptr: self.ptr,
+            len: self.len,
+            stride: self.stride,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_> {
+        IterMut {
+            ptr: self.ptr,
+            len: self.len,
+            stride: self.stride,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Set every sample to `value`.
+    pub fn fill(&mut self, value: f32) {
+        for samp in self.iter_mut() {
+            *samp = value;
+        }
+    }
+
+    /// Copy the samples from `src`, which must have the same length.
+    pub fn copy_from_slice(&mut self, src: &[f32]) {
+        assert_eq!(src.len(), self.len);
+        for (samp, value) in self.iter_mut().zip(src.iter()) {
+            *samp = *value;
+        }
+    }
+}
+
+impl<'a> Index<usize> for SamplesMut<'a> {
+    type Output = f32;
+
+    fn index(&self, idx: usize) -> &f32 {
+        assert!(
+            idx < self.len,
+            "index {} out of range for {} samples",
+            idx,
+            self.len
+        );
+        unsafe { &*self.ptr.add(idx * self.stride) }
+    }
+}
+
+impl<'a> IndexMut<usize> for SamplesMut<'a> {
+    fn index_mut(&mut self, idx: usize) -> &mut f32 {
+        assert!(
+            idx < self.len,
+            "index {} out of range for {} samples",
+            idx,
+            self.len
+        );
+        unsafe { &mut *self.ptr.add(idx * self.stride) }
+    }
+}
+
+impl<'a> IntoIterator for SamplesMut<'a> {
+    type Item = &'a mut f32;
+    type IntoIter = IterMut<'a>;
+
+    fn into_iter(self) -> IterMut<'a> {
+        IterMut {
+            ptr: self.ptr,
+            len: self.len,
+            stride: self.stride,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Iterator over the samples of a `SamplesMut`.
+pub struct Iter<'a> {
+    ptr: *const f32,
+    len: usize,
+    stride: usize,
+    _marker: PhantomData<&'a f32>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a f32;
+
+    fn next(&mut self) -> Option<&'a f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let samp = unsafe { &*self.ptr };
+        self.len -= 1;
+        if self.len > 0 {
+            self.ptr = unsafe { self.ptr.add(self.stride) };
+        }
+        Some(samp)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+/// Mutable iterator over the samples of a `SamplesMut`.
+pub struct IterMut<'a> {
+    ptr: *mut f32,
+    len: usize,
+    stride: usize,
+    _marker: PhantomData<&'a mut f32>,
+}
+
+impl<'a> Iterator for IterMut<'a> {
+    type Item = &'a mut f32;
+
+    fn next(&mut self) -> Option<&'a mut f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let samp = unsafe { &mut *self.ptr };
+        self.len -= 1;
+        if self.len > 0 {
+            self.ptr = unsafe { self.ptr.add(self.stride) };
+        }
+        Some(samp)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a> ExactSizeIterator for IterMut<'a> {}
+
+/// Iterator over the frames of a buffer.
+pub struct Frames<'a> {
+    data: &'a [f32],
+    next: usize,
+    frames: usize,
+    channels: usize,
+    layout: Layout,
+}
+
+impl<'a> Frames<'a> {
+    pub(crate) fn new(
+        data: &'a [f32],
+        frames: usize,
+        channels: usize,
+        layout: Layout,
+    ) -> Frames<'a> {
+        Frames {
+            data,
+            next: 0,
+            frames,
+            channels,
+            layout,
+        }
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Samples<'a>;
+
+    fn next(&mut self) -> Option<Samples<'a>> {
+        if self.next >= self.frames {
+            return None;
+        }
+        let (start, stride) = self.layout.frame(self.next, self.frames, self.channels);
+        self.next += 1;
+        Some(Samples::new(self.data, start, self.channels, stride))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.frames - self.next;
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for Frames<'a> {}
+
+/// Iterator over mutable views of the frames of a buffer.
+pub struct FramesMut<'a> {
+    ptr: *mut f32,
+    next: usize,
+    frames: usize,
+    channels: usize,
+    layout: Layout,
+    _marker: PhantomData<&'a mut [f32]>,
+}
+
+impl<'a> FramesMut<'a> {
+    pub(crate) fn new(
+        data: &'a mut [f32],
+        frames: usize,
+        channels: usize,
+        layout: Layout,
+    ) -> FramesMut<'a> {
+        assert!(frames * channels <= data.len());
+        FramesMut {
+            ptr: data.as_mut_ptr(),
+            next: 0,
+            frames,
+            channels,
+            layout,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a> Iterator for FramesMut<'a> {
+    type Item = SamplesMut<'a>;
+
+    fn next(&mut self) -> Option<SamplesMut<'a>> {
+        if self.next >= self.frames {
+            return None;
+        }
+        let (start, stride) = self.layout.frame(self.next, self.frames, self.channels);
+        self.next += 1;
+        // The buffer was checked to hold every frame, and every frame covers a
+        // distinct set of samples, so the views handed out never alias.
+        Some(unsafe { SamplesMut::from_raw(self.ptr.add(start), self.channels, stride) })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.frames - self.next;
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for FramesMut<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Context, ContextBuilder};
+
+    fn context(interleaved: bool) -> Context {
+        let mut context = ContextBuilder::new()
+            .audio_frames(4)
+            .audio_in_channels(2)
+            .audio_out_channels(2)
+            .analog_frames(2)
+            .analog_in_channels(3)
+            .analog_out_channels(3)
+            .interleaved(interleaved)
+            .build()
+            .unwrap();
+        let layout = context.layout();
+        for (idx, samp) in context.audio_in_mut().iter_mut().enumerate() {
+            // Store frame * 10 + channel, wherever the layout puts it
+            let (frame, channel) = match layout {
+                Layout::Interleaved => (idx / 2, idx % 2),
+                Layout::NonInterleaved => (idx % 4, idx / 4),
+            };
+            *samp = (frame * 10 + channel) as f32;
+        }
+        context
+    }
+
+    // The same render code, run against either layout
+    fn render(context: &mut Context) {
+        for frame in 0..context.audio_frames() {
+            let left = context.audio_read(frame, 0);
+            let right = context.audio_read(frame, 1);
+            context.audio_write(frame, 0, right);
+            context.audio_write(frame, 1, left);
+        }
+        for (frame, mut samples) in context.analog_out_frames().enumerate() {
+            for (channel, samp) in samples.iter_mut().enumerate() {
+                *samp = (frame * 10 + channel) as f32;
+            }
+        }
+    }
+
+    fn outputs(context: &mut Context) -> (Vec<f32>, Vec<f32>) {
+        let mut audio = Vec::new();
+        for frame in context.audio_out_frames() {
+            audio.extend(frame.iter());
+        }
+        let mut analog = Vec::new();
+        for channel in 0..context.analog_out_channels() {
+            analog.extend(context.analog_out_channel_mut(channel).iter());
+        }
+        (audio, analog)
+    }
+
+    #[test]
+    fn index() {
+        assert_eq!(Layout::Interleaved.index(3, 1, 4, 2), 7);
+        assert_eq!(Layout::NonInterleaved.index(3, 1, 4, 2), 7);
+        assert_eq!(Layout::Interleaved.index(1, 0, 4, 2), 2);
+        assert_eq!(Layout::NonInterleaved.index(1, 0, 4, 2), 1);
+        assert_eq!(Layout::NonInterleaved.index(0, 1, 4, 2), 4);
+    }
+
+    #[test]
+    fn from_flags() {
+        assert_eq!(
+            Layout::from_flags(sys::BELA_FLAG_INTERLEAVED),
+            Layout::Interleaved
+        );
+        assert_eq!(Layout::from_flags(0), Layout::NonInterleaved);
+    }
+
+    #[test]
+    fn layouts_render_identically() {
+        let mut interleaved = context(true);
+        let mut non_interleaved = context(false);
+        assert_ne!(interleaved.audio_in(), non_interleaved.audio_in());
+
+        render(&mut interleaved);
+        render(&mut non_interleaved);
+        let (audio, analog) = outputs(&mut interleaved);
+        assert_eq!(
+            (audio.clone(), analog.clone()),
+            outputs(&mut non_interleaved)
+        );
+        assert_eq!(audio, vec![1., 0., 11., 10., 21., 20., 31., 30.]);
+        assert_eq!(analog, vec![0., 10., 1., 11., 2., 12.]);
+    }
+
+    #[test]
+    fn channel_views() {
+        for &interleaved in &[true, false] {
+            let mut context = context(interleaved);
+            let right: Vec<f32> = context.audio_in_channel(1).iter().cloned().collect();
+            assert_eq!(right, vec![1., 11., 21., 31.]);
+
+            context.audio_out_channel_mut(0).fill(0.5);
+            for frame in context.audio_out_frames() {
+                assert_eq!(frame[0], 0.5);
+                assert_eq!(frame[1], 0.);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_stride() {
+        let data = [0., 1., 2., 3., 4., 5., 6.];
+        let samples = Samples::new(&data, 1, 3, 2);
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[2], 5.);
+        assert_eq!(samples.get(1), Some(3.));
+        assert_eq!(samples.get(3), None);
+        assert_eq!(samples.iter().cloned().collect::<Vec<_>>(), [1., 3., 5.]);
+        let mut out = [0.; 3];
+        samples.copy_to_slice(&mut out);
+        assert_eq!(out, [1., 3., 5.]);
+
+        let empty = Samples::new(&data, 7, 0, 2);
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "index 3 out of range for 3 samples")]
+    fn samples_index_out_of_range() {
+        let data = [0.; 6];
+        let _ = Samples::new(&data, 0, 3, 2)[3];
+    }
+
+    #[test]
+    #[should_panic]
+    fn samples_past_the_buffer() {
+        let data = [0.; 6];
+        Samples::new(&data, 1, 3, 3);
+    }
+
+    #[test]
+    fn samples_mut_stride() {
+        let mut data = [0.; 6];
+        {
+            let mut samples = SamplesMut::new(&mut data, 2, 2, 3);
+            assert_eq!(samples.len(), 2);
+            samples[0] = 1.;
+            *samples.get_mut(1).unwrap() = 2.;
+            assert!(samples.get_mut(2).is_none());
+            assert_eq!(samples.get(1), Some(2.));
+            assert_eq!(samples.iter().len(), 2);
+        }
+        assert_eq!(data, [0., 0., 1., 0., 0., 2.]);
+
+        SamplesMut::new(&mut data, 0, 3, 2).fill(7.);
+        assert_eq!(data, [7., 0., 7., 0., 7., 2.]);
+        SamplesMut::new(&mut data, 1, 3, 2).copy_from_slice(&[3., 4., 5.]);
+        assert_eq!(data, [7., 3., 7., 4., 7., 5.]);
+        for samp in SamplesMut::new(&mut data, 0, 2, 1) {
+            *samp = -1.;
+        }
+        assert_eq!(data, [-1., -1., 7., 4., 7., 5.]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn samples_mut_copy_needs_same_length() {
+        let mut data = [0.; 4];
+        SamplesMut::new(&mut data, 0, 2, 2).copy_from_slice(&[1.]);
+    }
+
+    #[test]
+    fn frames_follow_the_layout() {
+        // Two frames of three channels, holding frame * 10 + channel
+        let interleaved = [0., 1., 2., 10., 11., 12.];
+        let non_interleaved = [0., 10., 1., 11., 2., 12.];
+        for &(data, layout) in &[
+            (&interleaved, Layout::Interleaved),
+            (&non_interleaved, Layout::NonInterleaved),
+        ] {
+            let frames = Frames::new(data, 2, 3, layout);
+            assert_eq!(frames.len(), 2);
+            let frames: Vec<Vec<f32>> = frames
+                .map(|frame| frame.iter().cloned().collect())
+                .collect();
+            assert_eq!(frames, [[0., 1., 2.], [10., 11., 12.]]);
+        }
+    }
+
+    #[test]
+    fn frames_mut_follow_the_layout() {
+        for &layout in &[Layout::Interleaved, Layout::NonInterleaved] {
+            let mut data = [0.; 6];
+            let mut frames = FramesMut::new(&mut data, 2, 3, layout);
+            assert_eq!(frames.len(), 2);
+            // Views of both frames can be held at once
+            let mut first = frames.next().unwrap();
+            let mut second = frames.next().unwrap();
+            assert!(frames.next().is_none());
+            first.copy_from_slice(&[0., 1., 2.]);
+            second.copy_from_slice(&[10., 11., 12.]);
+            let mut read: Vec<f32> = Vec::new();
+            for frame in Frames::new(&data, 2, 3, layout) {
+                read.extend(frame.iter());
+            }
+            assert_eq!(read, [0., 1., 2., 10., 11., 12.]);
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Golden-output regression tests for render callbacks.
+//!
+//! A `Harness` runs the setup, render and cleanup functions of a `BelaApp`
+//! against a `Context` built by `ContextBuilder`, records every block of
+//! audio, analog and digital output, and compares the recording against one
+//! stored in a directory of golden files:
+//!
+//! * `audio_out.wav` and `analog_out.wav`, as interleaved 32-bit float WAV
+//! * `digital.csv`, with the value of every digital pin for each frame
+//!
+//! Recordings are always interleaved, so the same golden files apply whether
+//! or not the context is built with `BELA_FLAG_INTERLEAVED`.
+//!
+//! Missing golden files are an error. Set the `BELA_UPDATE_GOLDEN`
+//! environment variable to `1` or `true` to write (or overwrite) them from
+//! the current output.
+//!
+//! ```rust,ignore
+//! #[test]
+//! fn saw_is_unchanged() {
+//!     let mut user_data = AppData::new(Phasor { idx: 0 }, &mut render, None, None);
+//!     golden::Harness::new(ContextBuilder::new(), 64)
+//!         .tolerance(1e-5)
+//!         .check(&mut user_data, "tests/golden/saw")
+//!         .unwrap();
+//! }
+//! ```
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::{env, fmt};
+
+use hound;
+
+use backend::session;
+use files::{create_wav, to_io_error, write_wav, DigitalCsv};
+use {error, BelaApp, Context, ContextBuilder, Layout};
+
+/// Environment variable which, when set to `1`, `true`, `yes` or `on`, makes
+/// `Harness::check` write the golden files instead of comparing against them.
+/// Any other value, such as `0`, leaves them alone.
+pub const UPDATE_ENV: &str = "BELA_UPDATE_GOLDEN";
+
+type InputFn = dyn FnMut(&mut Context);
+
+/// Renders a `BelaApp` for a fixed number of blocks and compares the result
+/// against golden files.
+pub struct Harness {
+    builder: ContextBuilder,
+    blocks: usize,
+    tolerance: f32,
+    input: Option<Box<InputFn>>,
+}
+
+impl Harness {
+    /// Render `blocks` blocks into contexts laid out by `builder`.
+    pub fn new(builder: ContextBuilder, blocks: usize) -> Harness {
+        Harness {
+            builder,
+            blocks,
+            tolerance: 0.,
+            input: None,
+        }
+    }
+
+    /// Set the largest absolute difference allowed between an audio or
+    /// analog output sample and its golden value. Defaults to 0.
+    pub fn tolerance(mut self, tolerance: f32) -> Harness {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Set a function called before every block to fill in the inputs, for
+    /// instance through `Context::audio_in_mut`. Without it, inputs stay
+    /// silent.
+    pub fn input<F>(mut self, input: F) -> Harness
+    where
+        F: FnMut(&mut Context) + 'static,
+    {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Run setup, every block of render, and cleanup, recording the outputs.
+    pub fn record<T: BelaApp>(&mut self, user_data: &mut T) -> Result<Recording, error::Error> {
+        let mut context = self.builder.build()?;
+        user_data.setup(&mut context)?;
+
+        let mut recording = Recording::new(&context);
+        for _ in 0..self.blocks {
+            if let Some(ref mut input) = self.input {
+                input(&mut context);
+            }
+            session::begin_block(&mut context);
+            user_data.render(&mut context);
+            recording.push(&mut context);
+            session::end_block(&mut context);
+        }
+
+        user_data.cleanup(&mut context);
+        Ok(recording)
+    }
+
+    /// Record `user_data` and compare the outputs against the golden files in
+    /// `dir`, or write them there if `BELA_UPDATE_GOLDEN` is set to a true
+    /// value.
+    pub fn check<T: BelaApp, P: AsRef<Path>>(
+        &mut self,
+        user_data: &mut T,
+        dir: P,
+    ) -> Result<(), Failure> {
+        let dir = dir.as_ref();
+        let recording = self.record(user_data).map_err(Failure::Setup)?;
+
+        if update_requested(env::var_os(UPDATE_ENV).as_deref()) {
+            return recording.save(dir).map_err(Failure::Io);
+        }
+
+        let golden = Recording::load(dir).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => Failure::Missing(dir.to_owned()),
+            _ => Failure::Io(err),
+        })?;
+
+        recording
+            .compare(&golden, self.tolerance)
+            .map_err(Failure::Mismatch)
+    }
+}
+
+/// Outputs recorded over every block of a render, interleaved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    audio_sample_rate: f32,
+    audio_out_channels: usize,
+    audio_out: Vec<f32>,
+    analog_sample_rate: f32,
+    analog_out_channels: usize,
+    analog_out: Vec<f32>,
+    digital_channels: usize,
+    digital: Vec<u32>,
+}
+
+impl Recording {
+    fn new(context: &Context) -> Recording {
+        Recording {
+            audio_sample_rate: context.audio_sample_rate(),
+            audio_out_channels: context.audio_out_channels(),
+            audio_out: Vec::new(),
+            analog_sample_rate: context.analog_sample_rate(),
+            analog_out_channels: context.analog_out_channels(),
+            analog_out: Vec::new(),
+            digital_channels: context.digital_channels(),
+            digital: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, context: &mut Context) {
+        let layout = context.layout();
+        let channels = self.audio_out_channels;
+        push_interleaved(&mut self.audio_out, context.audio_out(), channels, layout);
+        let channels = self.analog_out_channels;
+        push_interleaved(&mut self.analog_out, context.analog_out(), channels, layout);
+        for word in context.digital() {
+            self.digital.push(word >> 16);
+        }
+    }
+
+    /// Recorded audio output, interleaved.
+    pub fn audio_out(&self) -> &[f32] {
+        &self.audio_out
+    }
+
+    pub fn audio_out_channels(&self) -> usize {
+        self.audio_out_channels
+    }
+
+    /// Recorded analog output, interleaved.
+    pub fn analog_out(&self) -> &[f32] {
+        &self.analog_out
+    }
+
+    pub fn analog_out_channels(&self) -> usize {
+        self.analog_out_channels
+    }
+
+    /// Recorded digital pin values, one word per frame with the value of
+    /// channel `n` in bit `n`.
+    pub fn digital(&self) -> &[u32] {
+        &self.digital
+    }
+
+    pub fn digital_channels(&self) -> usize {
+        self.digital_channels
+    }
+
+    /// Compare against a `golden` recording, allowing audio and analog
+    /// samples to differ by up to `tolerance`.
+    pub fn compare(&self, golden: &Recording, tolerance: f32) -> Result<(), Mismatch> {
+        compare_samples(
+            "audio_out",
+            &self.audio_out,
+            self.audio_out_channels,
+            &golden.audio_out,
+            golden.audio_out_channels,
+            tolerance,
+        )?;
+        compare_samples(
+            "analog_out",
+            &self.analog_out,
+            self.analog_out_channels,
+            &golden.analog_out,
+            golden.analog_out_channels,
+            tolerance,
+        )?;
+        compare_digital(
+            &self.digital,
+            self.digital_channels,
+            &golden.digital,
+            golden.digital_channels,
+        )
+    }
+
+    /// Write the recording as golden files into `dir`, creating it if needed.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        save_wav(
+            &dir.join("audio_out.wav"),
+            &self.audio_out,
+            self.audio_out_channels,
+            self.audio_sample_rate,
+        )?;
+        save_wav(
+            &dir.join("analog_out.wav"),
+            &self.analog_out,
+            self.analog_out_channels,
+            self.analog_sample_rate,
+        )?;
+
+        let mut csv = DigitalCsv::create(&dir.join("digital.csv"), self.digital_channels)?;
+        for (frame, &word) in self.digital.iter().enumerate() {
+            csv.write(frame as u64, word)?;
+        }
+        csv.finish()
+    }
+
+    /// Read a recording from the golden files in `dir`.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Recording> {
+        let dir = dir.as_ref();
+        let (audio_out, audio_out_channels, audio_sample_rate) =
+            load_wav(&dir.join("audio_out.wav"))?;
+        let (analog_out, analog_out_channels, analog_sample_rate) =
+            load_wav(&dir.join("analog_out.wav"))?;
+
+        let file = BufReader::new(File::open(dir.join("digital.csv"))?);
+        let mut lines = file.lines();
+        let digital_channels = match lines.next() {
+            Some(header) => header?.split(',').count() - 1,
+            None => 0,
+        };
+        let mut digital = Vec::new();
+        for line in lines {
+            let mut word = 0;
+            for (channel, value) in line?.split(',').skip(1).enumerate() {
+                match value.trim() {
+                    "0" => (),
+                    "1" => word |= 1 << channel,
+                    _ => return Err(invalid_data("digital.csv holds a value other than 0 or 1")),
+                }
+            }
+            digital.push(word);
+        }
+
+        Ok(Recording {
+            audio_sample_rate,
+            audio_out_channels,
+            audio_out,
+            analog_sample_rate,
+            analog_out_channels,
+            analog_out,
+            digital_channels,
+            digital,
+        })
+    }
+}
+
+/// Why `Harness::check` failed.
+#[derive(Debug)]
+pub enum Failure {
+    /// The setup function returned an error.
+    Setup(error::Error),
+    /// The golden files could not be found.
+    Missing(PathBuf),
+    /// The golden files could not be read or written.
+    Io(io::Error),
+    /// The outputs differ from the golden files.
+    Mismatch(Mismatch),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Setup(ref err) => write!(f, "setup failed: {}", err),
+            Failure::Missing(ref dir) => write!(
+                f,
+                "no golden files in {}; run with {}=1 to create them",
+                dir.display(),
+                UPDATE_ENV
+            ),
+            Failure::Io(ref err) => write!(f, "could not access golden files: {}", err),
+            Failure::Mismatch(ref mismatch) => mismatch.fmt(f),
+        }
+    }
+}
+
+impl ::std::error::Error for Failure {}
+
+/// Where a recording first differs from its golden counterpart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    /// The buffer that differs: `audio_out`, `analog_out` or `digital`.
+    pub buffer: &'static str,
+    /// Number of (frames, channels) recorded and in the golden files.
+    pub shape: ((usize, usize), (usize, usize)),
+    /// First differing frame, counted from the start of the render.
+    pub frame: usize,
+    pub channel: usize,
+    pub expected: f32,
+    pub actual: f32,
+    /// Largest absolute difference over the whole buffer.
+    pub max_abs_error: f32,
+    /// Number of samples differing by more than the tolerance.
+    pub count: usize,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ((frames, channels), (golden_frames, golden_channels)) = self.shape;
+        if frames != golden_frames || channels != golden_channels {
+            return write!(
+                f,
+                "{} has {} frames of {} channels, golden has {} frames of {} channels",
+                self.buffer, frames, channels, golden_frames, golden_channels
+            );
+        }
+
+        write!(
+            f,
+            "{} differs from golden at frame {}, channel {}: expected {}, got {} \
+             ({} samples differ, max abs error {})",
+            self.buffer,
+            self.frame,
+            self.channel,
+            self.expected,
+            self.actual,
+            self.count,
+            self.max_abs_error
+        )
+    }
+}
+
+fn update_requested(value: Option<&OsStr>) -> bool {
+    let value = match value.and_then(OsStr::to_str) {
+        Some(value) => value.trim().to_ascii_lowercase(),
+        None => return false,
+    };
+    matches!(value.as_str(), "1" | "true" | "yes" | "on")
+}
+
+fn push_interleaved(out: &mut Vec<f32>, buf: &[f32], channels: usize, layout: Layout) {
+    if layout == Layout::Interleaved || channels == 0 {
+        out.extend_from_slice(buf);
+        return;
+    }
+
+    let frames = buf.len() / channels;
+    for frame in 0..frames {
+        for channel in 0..channels {
+            out.push(buf[layout.index(frame, channel, frames, channels)]);
+        }
+    }
+}
+
+fn frames(len: usize, channels: usize) -> usize {
+    len.checked_div(channels).unwrap_or(0)
+}
+
+fn compare_samples(
+    buffer: &'static str,
+    actual: &[f32],
+    channels: usize,
+    expected: &[f32],
+    golden_channels: usize,
+    tolerance: f32,
+) -> Result<(), Mismatch> {
+    let mut mismatch = Mismatch {
+        buffer,
+        shape: (
+            (frames(actual.len(), channels), channels),
+            (frames(expected.len(), golden_channels), golden_channels),
+        ),
+        frame: 0,
+        channel: 0,
+        expected: 0.,
+        actual: 0.,
+        max_abs_error: 0.,
+        count: 0,
+    };
+    if actual.is_empty() && expected.is_empty() {
+        return Ok(());
+    }
+    if actual.len() != expected.len() || channels != golden_channels {
+        return Err(mismatch);
+    }
+
+    for (idx, (&a, &e)) in actual.iter().zip(expected.iter()).enumerate() {
+        let error = (a - e).abs();
+        // NaN never compares greater, so check it explicitly
+        if error > tolerance || (a.is_nan() != e.is_nan()) {
+            if mismatch.count == 0 {
+                mismatch.frame = idx / channels;
+                mismatch.channel = idx % channels;
+                mismatch.expected = e;
+                mismatch.actual = a;
+            }
+            mismatch.count += 1;
+        }
+        if error > mismatch.max_abs_error {
+            mismatch.max_abs_error = error;
+        }
+    }
+
+    match mismatch.count {
+        0 => Ok(()),
+        _ => Err(mismatch),
+    }
+}
+
+fn compare_digital(
+    actual: &[u32],
+    channels: usize,
+    expected: &[u32],
+    golden_channels: usize,
+) -> Result<(), Mismatch> {
+    let mut mismatch = Mismatch {
+        buffer: "digital",
+        shape: ((actual.len(), channels), (expected.len(), golden_channels)),
+        frame: 0,
+        channel: 0,
+        expected: 0.,
+        actual: 0.,
+        max_abs_error: 0.,
+        count: 0,
+    };
+    if actual.len() != expected.len() || channels != golden_channels {
+        return Err(mismatch);
+    }
+
+    for (frame, (&a, &e)) in actual.iter().zip(expected.iter()).enumerate() {
+        for channel in 0..channels {
+            let (a, e) = ((a >> channel) & 1, (e >> channel) & 1);
+            if a != e {
+                if mismatch.count == 0 {
+                    mismatch.frame = frame;
+                    mismatch.channel = channel;
+                    mismatch.expected = e as f32;
+                    mismatch.actual = a as f32;
+                }
+                mismatch.count += 1;
+                mismatch.max_abs_error = 1.;
+            }
+        }
+    }
+
+    match mismatch.count {
+        0 => Ok(()),
+        _ => Err(mismatch),
+    }
+}
+
+fn save_wav(path: &Path, samples: &[f32], channels: usize, sample_rate: f32) -> io::Result<()> {
+    // WAV files need at least one channel
+    let mut writer = create_wav(path, channels.max(1), sample_rate.max(1.))?;
+    write_wav(
+        &mut writer,
+        samples,
+        channels.max(1),
+        Layout::Interleaved,
+        &mut Vec::new(),
+    )?;
+    writer.finalize().map_err(to_io_error)
+}
+
+fn load_wav(path: &Path) -> io::Result<(Vec<f32>, usize, f32)> {
+    let mut reader = hound::WavReader::open(path).map_err(to_io_error)?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Float || spec.bits_per_sample != 32 {
+        return Err(invalid_data("golden WAV files must hold 32-bit floats"));
+    }
+
+    let samples = reader
+        .samples::<f32>()
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(to_io_error)?;
+    // An empty buffer is saved with a single channel
+    let channels = if samples.is_empty() {
+        0
+    } else {
+        spec.channels as usize
+    };
+    Ok((samples, channels, spec.sample_rate as f32))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn recording() -> Recording {
+        Recording {
+            audio_sample_rate: 44_100.,
+            audio_out_channels: 2,
+            audio_out: vec![0., 0.5, -0.5, 1.],
+            analog_sample_rate: 22_050.,
+            analog_out_channels: 0,
+            analog_out: Vec::new(),
+            digital_channels: 3,
+            digital: vec![0b101, 0b010],
+        }
+    }
+
+    #[test]
+    fn update_needs_a_true_value() {
+        assert!(!update_requested(None));
+        for value in &["1", "true", "TRUE", "yes", " on "] {
+            assert!(update_requested(Some(OsStr::new(value))), "{}", value);
+        }
+        for value in &["", "0", "false", "no", "off"] {
+            assert!(!update_requested(Some(OsStr::new(value))), "{}", value);
+        }
+    }
+
+    #[test]
+    fn save_then_load() {
+        let dir = env::temp_dir().join(format!("bela-golden-{}", process::id()));
+        let recording = recording();
+        recording.save(&dir).unwrap();
+        let loaded = Recording::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.unwrap(), recording);
+    }
+
+    #[test]
+    fn compare_reports_first_difference() {
+        let golden = recording();
+        let mut actual = recording();
+        actual.audio_out[2] = -0.4;
+        actual.audio_out[3] = 0.;
+
+        assert!(actual.compare(&golden, 0.2).is_err());
+        let mismatch = actual.compare(&golden, 0.15).unwrap_err();
+        assert_eq!(mismatch.buffer, "audio_out");
+        assert_eq!((mismatch.frame, mismatch.channel), (1, 1));
+        assert_eq!(mismatch.count, 1);
+        assert_eq!(mismatch.max_abs_error, 1.);
+        assert!(actual.compare(&golden, 1.).is_ok());
+    }
+
+    #[test]
+    fn compare_digital_and_shape() {
+        let golden = recording();
+        let mut actual = recording();
+        actual.digital[1] = 0b011;
+        let mismatch = actual.compare(&golden, 0.).unwrap_err();
+        assert_eq!(mismatch.buffer, "digital");
+        assert_eq!((mismatch.frame, mismatch.channel), (1, 0));
+
+        let mut actual = recording();
+        actual.audio_out.truncate(2);
+        let mismatch = actual.compare(&golden, 1.).unwrap_err();
+        assert_eq!(mismatch.shape, ((1, 2), (2, 2)));
+    }
+}
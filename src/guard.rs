@@ -0,0 +1,446 @@
+//! Keeps panics in user callbacks from unwinding into libbela.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use {error, BelaApp, Context};
+
+/// What happens once setup, render, cleanup or an auxiliary task panics.
+///
+/// The panic is caught before it reaches libbela, and its message is returned
+/// as an `ErrorKind::Panic` error by `Bela::run`, or by `Bela::init_audio` for
+/// a panic in setup. Neither render nor the task that panicked are called
+/// again after a panic.
+///
+/// Auxiliary tasks follow the policy of the `Bela` whose setup created them,
+/// or that created them through `Bela::create_scoped_auxiliary_task`, as it
+/// was when they were created.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Mute the audio and analog outputs, and request that audio stops.
+    #[default]
+    Stop,
+    /// Mute the audio and analog outputs, and keep running in silence until
+    /// audio is stopped as usual.
+    Silence,
+    /// Abort the process right away.
+    Abort,
+}
+
+/// Whether a callback panicked, shared between the audio thread and `Bela`.
+#[derive(Default)]
+pub(crate) struct PanicStatus {
+    panicked: AtomicBool,
+    stop: AtomicBool,
+    message: Mutex<Option<String>>,
+}
+
+impl PanicStatus {
+    /// Forget about panics from an earlier run.
+    pub(crate) fn reset(&self) {
+        self.panicked.store(false, Ordering::SeqCst);
+        self.stop.store(false, Ordering::SeqCst);
+        let _ = self.take_error();
+    }
+
+    /// Whether a panic asked for audio to stop.
+    pub(crate) fn should_stop(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    /// Record a panic caught in a callback or task run under `policy`.
+    fn caught(&self, policy: PanicPolicy, payload: Box<dyn Any + Send>) {
+        if policy == PanicPolicy::Abort {
+            process::abort();
+        }
+
+        if !self.panicked.swap(true, Ordering::SeqCst) {
+            let message = payload_message(&*payload);
+            match self.message.lock() {
+                Ok(mut slot) => *slot = Some(message),
+                Err(poisoned) => *poisoned.into_inner() = Some(message),
+            }
+        }
+        if policy == PanicPolicy::Stop {
+            self.stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Take the error describing the first panic caught, if any.
+    pub(crate) fn take_error(&self) -> Option<error::Error> {
+        let message = match self.message.lock() {
+            Ok(mut message) => message.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        message.map(|message| error::Error::new(error::ErrorKind::Panic).with_detail(message))
+    }
+}
+
+/// A `BelaApp` whose callbacks are run with panics caught, as handed to the
+/// trampolines.
+pub(crate) struct Guarded<T> {
+    pub(crate) app: T,
+    pub(crate) policy: PanicPolicy,
+    pub(crate) status: Arc<PanicStatus>,
+    /// The error returned by the last call to setup.
+    pub(crate) setup_error: Option<error::Error>,
+}
+
+impl<T: BelaApp> Guarded<T> {
+    pub(crate) fn new(app: T) -> Guarded<T> {
+        Guarded {
+            app,
+            policy: PanicPolicy::default(),
+            status: Arc::new(PanicStatus::default()),
+            setup_error: None,
+        }
+    }
+
+    /// The guard for auxiliary tasks created on behalf of this app.
+    pub(crate) fn task_guard(&self) -> TaskGuard {
+        TaskGuard {
+            policy: self.policy,
+            status: self.status.clone(),
+        }
+    }
+
+    pub(crate) fn setup(&mut self, context: &mut Context) -> bool {
+        // Tasks created by setup report their panics here
+        let previous = SETUP_GUARD.with(|guard| guard.replace(Some(self.task_guard())));
+        let app = &mut self.app;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| app.setup(context)));
+        SETUP_GUARD.with(|guard| guard.replace(previous));
+
+        match res {
+            Ok(Ok(())) => {
+                self.setup_error = None;
+                true
+            }
+            Ok(Err(err)) => {
+                self.setup_error = Some(err);
+                false
+            }
+            Err(payload) => {
+                self.caught(payload);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn render(&mut self, context: &mut Context) {
+        if !self.status.panicked.load(Ordering::Relaxed) {
+            let app = &mut self.app;
+            match panic::catch_unwind(AssertUnwindSafe(|| app.render(context))) {
+                Ok(()) => return,
+                Err(payload) => self.caught(payload),
+            }
+        }
+        mute(context);
+    }
+
+    pub(crate) fn cleanup(&mut self, context: &mut Context) {
+        let app = &mut self.app;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| app.cleanup(context))) {
+            self.caught(payload);
+        }
+    }
+
+    fn caught(&mut self, payload: Box<dyn Any + Send>) {
+        self.status.caught(self.policy, payload);
+    }
+}
+
+thread_local! {
+    /// The guard of the app whose setup is running on this thread, if any.
+    static SETUP_GUARD: RefCell<Option<TaskGuard>> = const { RefCell::new(None) };
+}
+
+/// The panic policy and status an auxiliary task reports its panics to.
+#[derive(Clone)]
+pub(crate) struct TaskGuard {
+    policy: PanicPolicy,
+    status: Arc<PanicStatus>,
+}
+
+impl TaskGuard {
+    /// The guard of the app whose setup is running on this thread, if any.
+    pub(crate) fn current() -> Option<TaskGuard> {
+        SETUP_GUARD.with(|guard| guard.borrow().clone())
+    }
+}
+
+/// An auxiliary task run with panics caught. A task that panicked is not run
+/// again.
+pub(crate) struct GuardedTask<F> {
+    task: F,
+    guard: Option<TaskGuard>,
+    panicked: bool,
+}
+
+impl<F: FnMut()> GuardedTask<F> {
+    pub(crate) fn new(task: F, guard: Option<TaskGuard>) -> GuardedTask<F> {
+        GuardedTask {
+            task,
+            guard,
+            panicked: false,
+        }
+    }
+
+    pub(crate) fn run(&mut self) {
+        if self.panicked {
+            return;
+        }
+        let task = &mut self.task;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(task)) {
+            self.panicked = true;
+            // Without a guard, the panic hook has already reported the panic
+            if let Some(ref guard) = self.guard {
+                guard.status.caught(guard.policy, payload);
+            }
+        }
+    }
+}
+
+fn mute(context: &mut Context) {
+    for samp in context.audio_out().iter_mut() {
+        *samp = 0.;
+    }
+    for samp in context.analog_out().iter_mut() {
+        *samp = 0.;
+    }
+}
+
+/// The message a panic was raised with.
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("callback panicked: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("callback panicked: {}", message)
+    } else {
+        "callback panicked".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::{env, fs, process, thread, time};
+
+    use hound;
+
+    use super::*;
+    use backend::{Backend, Offline, Software};
+    use {Bela, ContextBuilder, InitSettings};
+
+    /// Fills its outputs with ones, and panics in setup, or in its nth render.
+    #[derive(Default)]
+    struct Panicky {
+        setup_panics: bool,
+        panic_at: Option<usize>,
+        renders: Arc<AtomicUsize>,
+        cleanups: Arc<AtomicUsize>,
+    }
+
+    impl BelaApp for Panicky {
+        fn setup(&mut self, _context: &mut Context) -> Result<(), error::Error> {
+            if self.setup_panics {
+                panic!("setup failed");
+            }
+            Ok(())
+        }
+
+        fn render(&mut self, context: &mut Context) {
+            for samp in context.audio_out().iter_mut() {
+                *samp = 1.;
+            }
+            for samp in context.analog_out().iter_mut() {
+                *samp = 1.;
+            }
+            let renders = self.renders.fetch_add(1, Ordering::SeqCst) + 1;
+            if Some(renders) == self.panic_at {
+                panic!("render failed");
+            }
+        }
+
+        fn cleanup(&mut self, _context: &mut Context) {
+            self.cleanups.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn software(blocks: Option<u64>) -> Software {
+        let mut backend = Software::new();
+        backend.set_realtime(false);
+        backend.set_max_blocks(blocks);
+        backend
+    }
+
+    fn assert_render_panic(res: Result<(), error::Error>) {
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Panic);
+        assert_eq!(err.detail(), Some("callback panicked: render failed"));
+    }
+
+    /// Renders five blocks of 32 frames offline under `policy`, panicking in
+    /// the second, and returns the audio and analog outputs.
+    fn render_offline(policy: PanicPolicy, name: &str) -> (Vec<f32>, Vec<f32>) {
+        let dir = env::temp_dir().join(format!("bela-guard-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let mut backend = Offline::new(time::Duration::from_millis(3));
+        backend.set_audio_out(dir.join("audio.wav"));
+        backend.set_analog_out(dir.join("analog.wav"));
+        let app = Panicky {
+            panic_at: Some(2),
+            ..Panicky::default()
+        };
+        let renders = app.renders.clone();
+        let cleanups = app.cleanups.clone();
+        let mut bela = Bela::with_backend(app, backend);
+        bela.set_panic_policy(policy);
+        assert_render_panic(bela.run(&mut InitSettings::software_default()));
+        // Render is not called again after the panic, but cleanup still is
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+
+        let read = |name: &str| -> Vec<f32> {
+            let mut reader = hound::WavReader::open(dir.join(name)).unwrap();
+            reader.samples().map(Result::unwrap).collect()
+        };
+        let outputs = (read("audio.wav"), read("analog.wav"));
+        fs::remove_dir_all(&dir).unwrap();
+        outputs
+    }
+
+    #[test]
+    fn render_panic_mutes_outputs() {
+        for &(policy, name) in &[
+            (PanicPolicy::Stop, "stop"),
+            (PanicPolicy::Silence, "silence"),
+        ] {
+            let (audio, analog) = render_offline(policy, name);
+            // Two channels of 160 frames, the first block of which rendered
+            assert_eq!(audio.len(), 2 * 160);
+            assert!(audio[..2 * 32].iter().all(|&samp| samp == 1.));
+            assert!(audio[2 * 32..].iter().all(|&samp| samp == 0.));
+            assert_eq!(analog.len(), 8 * 80);
+            assert!(analog[..8 * 16].iter().all(|&samp| samp == 1.));
+            assert!(analog[8 * 16..].iter().all(|&samp| samp == 0.));
+        }
+    }
+
+    #[test]
+    fn render_panic_stops_audio() {
+        let app = Panicky {
+            panic_at: Some(3),
+            ..Panicky::default()
+        };
+        let renders = app.renders.clone();
+        let cleanups = app.cleanups.clone();
+        // Would run forever unless the panic stops it
+        let mut bela = Bela::with_backend(app, software(None));
+        assert_eq!(bela.panic_policy(), PanicPolicy::Stop);
+        assert_render_panic(bela.run(&mut InitSettings::software_default()));
+        assert_eq!(renders.load(Ordering::SeqCst), 3);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn render_panic_keeps_running_silenced() {
+        let app = Panicky {
+            panic_at: Some(2),
+            ..Panicky::default()
+        };
+        let renders = app.renders.clone();
+        let mut bela = Bela::with_backend(app, software(Some(10)));
+        bela.set_panic_policy(PanicPolicy::Silence);
+        let mut settings = InitSettings::software_default();
+        bela.init_audio(&mut settings).unwrap();
+        bela.start_audio().unwrap();
+        // Audio carries on until the backend has run all its blocks
+        while !bela.backend().should_stop() {
+            assert!(!bela.should_stop());
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        bela.stop_audio();
+        bela.cleanup_audio();
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+        let err = bela.user_data.status.take_error().unwrap();
+        assert_eq!(err.detail(), Some("callback panicked: render failed"));
+    }
+
+    #[test]
+    fn setup_panic_fails_init() {
+        let app = Panicky {
+            setup_panics: true,
+            ..Panicky::default()
+        };
+        let renders = app.renders.clone();
+        let mut bela = Bela::with_backend(app, software(Some(1)));
+        let err = bela
+            .init_audio(&mut InitSettings::software_default())
+            .unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Panic);
+        assert_eq!(err.detail(), Some("callback panicked: setup failed"));
+
+        let err = bela.run(&mut InitSettings::software_default()).unwrap_err();
+        assert_eq!(err.kind(), error::ErrorKind::Panic);
+        assert_eq!(renders.load(Ordering::SeqCst), 0);
+    }
+
+    fn guard(policy: PanicPolicy) -> TaskGuard {
+        TaskGuard {
+            policy,
+            status: Arc::new(PanicStatus::default()),
+        }
+    }
+
+    #[test]
+    fn task_panic_stops_audio() {
+        let guard = guard(PanicPolicy::Stop);
+        let mut runs = 0;
+        {
+            let mut task = GuardedTask::new(
+                || {
+                    runs += 1;
+                    panic!("task failed");
+                },
+                Some(guard.clone()),
+            );
+            task.run();
+            task.run();
+        }
+        assert_eq!(runs, 1);
+        assert!(guard.status.should_stop());
+        let err = guard.status.take_error().unwrap();
+        assert_eq!(err.kind(), error::ErrorKind::Panic);
+        assert_eq!(err.detail(), Some("callback panicked: task failed"));
+    }
+
+    #[test]
+    fn task_panic_silences_render() {
+        let guard = guard(PanicPolicy::Silence);
+        GuardedTask::new(|| panic!("task failed"), Some(guard.clone())).run();
+        assert!(!guard.status.should_stop());
+        assert!(guard.status.panicked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn setup_guards_tasks_it_creates() {
+        struct App;
+        impl BelaApp for App {
+            fn setup(&mut self, _context: &mut Context) -> Result<(), error::Error> {
+                assert!(TaskGuard::current().is_some());
+                Ok(())
+            }
+            fn render(&mut self, _context: &mut Context) {}
+        }
+
+        let mut context = ContextBuilder::new().build().unwrap();
+        assert!(TaskGuard::current().is_none());
+        assert!(Guarded::new(App).setup(&mut context));
+        assert!(TaskGuard::current().is_none());
+    }
+}
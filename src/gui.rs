@@ -0,0 +1,721 @@
+//! Exchanges data buffers with a browser sketch, like the `Gui` library of
+//! C++ Bela projects. Requires the `gui` feature.
+//!
+//! A `Gui` is set up in `setup`, which starts a websocket server on its own
+//! thread, and buffers are added to it in the order the sketch expects them.
+//! Each `GuiBuffer` is then read and written from `render` without blocking
+//! or allocating: both directions go through lock-free exchanges that only
+//! keep the latest data.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let mut gui = Gui::setup("my-project", gui::DEFAULT_PORT)?;
+//! let mut sliders = gui.add_buffer::<f32>(4);
+//! let mut meters = gui.add_buffer::<f32>(2);
+//! // In render:
+//! let gain = sliders.read().get(0).cloned().unwrap_or(1.);
+//! meters.write(&[peak_left, peak_right]);
+//! ```
+//!
+//! The sketch connects to `/gui_control` and `/gui_data` on the port of the
+//! `Gui`. On `/gui_control`, the server greets each client with
+//! `{"event":"connection","projectName":...}` and the client answers with
+//! `{"event":"connection-reply"}`; other JSON messages are passed on through
+//! `Gui::receive_control` and `Gui::send_control`. On `/gui_data`, each buffer
+//! is sent, in either direction, as a 12-byte header of the buffer id as a
+//! `u32`, the type tag (`'f'`, `'i'` or `'c'`) padded to 4 bytes and the
+//! number of values as a `u32`, followed by a message of the values. All
+//! numbers are little-endian.
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time;
+
+use serde_json::{self, Map, Value};
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::http::StatusCode;
+use tungstenite::{self, Message, WebSocket};
+
+use triple;
+
+/// Port of the C++ `Gui`.
+pub const DEFAULT_PORT: u16 = 5555;
+
+const CONTROL_PATH: &str = "/gui_control";
+const DATA_PATH: &str = "/gui_data";
+const HEADER_LEN: usize = 12;
+/// How long a new connection has to complete its handshake.
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// A type a buffer can hold, with the tag the sketch knows it by.
+pub trait BufferType: Copy + Default + Send + 'static {
+    const TAG: u8;
+    const SIZE: usize;
+
+    fn read_le(bytes: &[u8]) -> Self;
+    fn write_le(self, out: &mut Vec<u8>);
+}
+
+impl BufferType for f32 {
+    const TAG: u8 = b'f';
+    const SIZE: usize = 4;
+
+    fn read_le(bytes: &[u8]) -> f32 {
+        f32::from_bits(u32::read_le(bytes))
+    }
+
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl BufferType for i32 {
+    const TAG: u8 = b'i';
+    const SIZE: usize = 4;
+
+    fn read_le(bytes: &[u8]) -> i32 {
+        u32::read_le(bytes) as i32
+    }
+
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl BufferType for u8 {
+    const TAG: u8 = b'c';
+    const SIZE: usize = 1;
+
+    fn read_le(bytes: &[u8]) -> u8 {
+        bytes[0]
+    }
+
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.push(self);
+    }
+}
+
+trait ReadLe {
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+impl ReadLe for u32 {
+    fn read_le(bytes: &[u8]) -> u32 {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[..4]);
+        u32::from_le_bytes(word)
+    }
+}
+
+/// A websocket server exchanging buffers and control messages with sketches.
+pub struct Gui {
+    project: String,
+    local_addr: SocketAddr,
+    buffers: Arc<Mutex<Vec<Box<dyn ServerBuffer>>>>,
+    connected: Arc<AtomicBool>,
+    control_in: Receiver<Value>,
+    control_out: Sender<Value>,
+    stop: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>,
+}
+
+impl Gui {
+    /// Start serving `project_name` on `port` of every interface.
+    pub fn setup(project_name: &str, port: u16) -> io::Result<Gui> {
+        Gui::bind(
+            project_name,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into(),
+        )
+    }
+
+    /// Start serving `project_name` on `addr`. Port 0 picks a free port,
+    /// which `Gui::local_addr` then reports.
+    pub fn bind(project_name: &str, addr: SocketAddr) -> io::Result<Gui> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let buffers = Arc::new(Mutex::new(Vec::new()));
+        let connected = Arc::new(AtomicBool::new(false));
+        let (control_in_tx, control_in) = mpsc::channel();
+        let (control_out, control_out_rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = {
+            let mut server = Server {
+                project: project_name.to_owned(),
+                listener,
+                handshakes: Vec::new(),
+                clients: Vec::new(),
+                buffers: buffers.clone(),
+                connected: connected.clone(),
+                control_in: control_in_tx,
+                control_out: control_out_rx,
+            };
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("bela-gui".to_owned())
+                .spawn(move || server.run(&stop))?
+        };
+
+        Ok(Gui {
+            project: project_name.to_owned(),
+            local_addr,
+            buffers,
+            connected,
+            control_in,
+            control_out,
+            stop,
+            server: Some(server),
+        })
+    }
+
+    pub fn project_name(&self) -> &str {
+        &self.project
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Whether a sketch that answered the connection message is still
+    /// connected.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Add a buffer of up to `capacity` values. Buffers are numbered in the
+    /// order they are added, starting from 0.
+    pub fn add_buffer<T: BufferType>(&mut self, capacity: usize) -> GuiBuffer<T> {
+        let empty = || Vec::with_capacity(capacity);
+        let (incoming_tx, incoming_rx) = triple::exchange(empty);
+        let (outgoing_tx, outgoing_rx) = triple::exchange(empty);
+        let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+        let id = buffers.len();
+        buffers.push(Box::new(Ends {
+            capacity,
+            incoming: incoming_tx,
+            outgoing: outgoing_rx,
+        }));
+        GuiBuffer {
+            id,
+            capacity,
+            incoming: incoming_rx,
+            outgoing: outgoing_tx,
+        }
+    }
+
+    /// Take the next control message received from a sketch, other than
+    /// connection replies. This allocates, so it is not for `render`.
+    pub fn receive_control(&self) -> Option<Value> {
+        self.control_in.try_recv().ok()
+    }
+
+    /// Send a control message to every connected sketch. This allocates, so
+    /// it is not for `render`.
+    pub fn send_control(&self, message: Value) {
+        let _ = self.control_out.send(message);
+    }
+}
+
+impl Drop for Gui {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            let _ = server.join();
+        }
+    }
+}
+
+/// A buffer shared with sketches, read and written from the render thread.
+pub struct GuiBuffer<T> {
+    id: usize,
+    capacity: usize,
+    incoming: triple::Reader<Vec<T>>,
+    outgoing: triple::Writer<Vec<T>>,
+}
+
+impl<T: BufferType> GuiBuffer<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The latest values received from a sketch, which are empty until the
+    /// first ones arrive. Never blocks or allocates.
+    pub fn read(&mut self) -> &[T] {
+        self.incoming.update();
+        self.incoming.get()
+    }
+
+    /// Send `values` to the sketches, replacing any not sent yet. Values past
+    /// the capacity of the buffer are ignored. Never blocks or allocates.
+    pub fn write(&mut self, values: &[T]) {
+        let len = values.len().min(self.capacity);
+        let slot = self.outgoing.slot();
+        slot.clear();
+        slot.extend_from_slice(&values[..len]);
+        self.outgoing.publish();
+    }
+}
+
+/// The server side of a `GuiBuffer`, with its type erased.
+trait ServerBuffer: Send {
+    /// Store values received from a sketch, returning `false` if they do not
+    /// match the buffer.
+    fn receive(&mut self, tag: u8, count: usize, bytes: &[u8]) -> bool;
+    /// Encode values written since the last call into `header` and `values`.
+    fn take(&mut self, id: usize, header: &mut Vec<u8>, values: &mut Vec<u8>) -> bool;
+}
+
+struct Ends<T> {
+    capacity: usize,
+    incoming: triple::Writer<Vec<T>>,
+    outgoing: triple::Reader<Vec<T>>,
+}
+
+impl<T: BufferType> ServerBuffer for Ends<T> {
+    fn receive(&mut self, tag: u8, count: usize, bytes: &[u8]) -> bool {
+        if tag != T::TAG || count > self.capacity || bytes.len() != count * T::SIZE {
+            return false;
+        }
+        let slot = self.incoming.slot();
+        slot.clear();
+        slot.extend(bytes.chunks(T::SIZE).map(T::read_le));
+        self.incoming.publish();
+        true
+    }
+
+    fn take(&mut self, id: usize, header: &mut Vec<u8>, values: &mut Vec<u8>) -> bool {
+        if !self.outgoing.update() {
+            return false;
+        }
+        let outgoing = self.outgoing.get();
+        header.clear();
+        header.extend_from_slice(&(id as u32).to_le_bytes());
+        header.extend_from_slice(&[T::TAG, 0, 0, 0]);
+        header.extend_from_slice(&(outgoing.len() as u32).to_le_bytes());
+        values.clear();
+        for &value in outgoing {
+            value.write_le(values);
+        }
+        true
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Path {
+    Control,
+    Data,
+}
+
+/// Records the path a new connection asks for, and refuses any other than
+/// the control and data paths.
+struct Route(Arc<Mutex<Option<Path>>>);
+
+impl Callback for Route {
+    // The size of the error response is set by tungstenite.
+    #[allow(clippy::result_large_err)]
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let path = match request.uri().path() {
+            CONTROL_PATH => Path::Control,
+            DATA_PATH => Path::Data,
+            _ => {
+                let mut error = ErrorResponse::new(None);
+                *error.status_mut() = StatusCode::NOT_FOUND;
+                return Err(error);
+            }
+        };
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = Some(path);
+        Ok(response)
+    }
+}
+
+/// A connection whose handshake is waiting for the socket.
+struct Handshake {
+    handshake: MidHandshake<ServerHandshake<TcpStream, Route>>,
+    path: Arc<Mutex<Option<Path>>>,
+    started: time::Instant,
+}
+
+struct Client {
+    socket: WebSocket<TcpStream>,
+    path: Path,
+    /// Whether the sketch answered the connection message.
+    replied: bool,
+    /// Buffer id, type tag and count of the data message expected next.
+    header: Option<(usize, u8, usize)>,
+    open: bool,
+}
+
+impl Client {
+    fn send(&mut self, message: Message) {
+        match self.socket.send(message) {
+            Ok(()) => {}
+            // Queued, and flushed on a later pass.
+            Err(tungstenite::Error::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => self.open = false,
+        }
+    }
+
+    fn flush(&mut self) {
+        match self.socket.flush() {
+            Ok(()) => {}
+            Err(tungstenite::Error::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => self.open = false,
+        }
+    }
+}
+
+struct Server {
+    project: String,
+    listener: TcpListener,
+    handshakes: Vec<Handshake>,
+    clients: Vec<Client>,
+    buffers: Arc<Mutex<Vec<Box<dyn ServerBuffer>>>>,
+    connected: Arc<AtomicBool>,
+    control_in: Sender<Value>,
+    control_out: Receiver<Value>,
+}
+
+impl Server {
+    fn run(&mut self, stop: &AtomicBool) {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        let mut values = Vec::new();
+        while !stop.load(Ordering::SeqCst) {
+            while let Ok((stream, _)) = self.listener.accept() {
+                self.accept(stream);
+            }
+            self.handshake();
+
+            while let Ok(message) = self.control_out.try_recv() {
+                let text = message.to_string();
+                for client in self.clients.iter_mut().filter(|c| c.path == Path::Control) {
+                    client.send(Message::Text(text.clone()));
+                }
+            }
+
+            {
+                let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+                for (id, buffer) in buffers.iter_mut().enumerate() {
+                    if buffer.take(id, &mut header, &mut values) {
+                        for client in self.clients.iter_mut().filter(|c| c.path == Path::Data) {
+                            client.send(Message::Binary(header.clone()));
+                            client.send(Message::Binary(values.clone()));
+                        }
+                    }
+                }
+            }
+
+            for idx in 0..self.clients.len() {
+                self.read(idx);
+                let client = &mut self.clients[idx];
+                if client.open {
+                    client.flush();
+                }
+            }
+            self.clients.retain(|client| client.open);
+            let connected = self
+                .clients
+                .iter()
+                .any(|client| client.path == Path::Control && client.replied);
+            self.connected.store(connected, Ordering::Relaxed);
+
+            thread::sleep(time::Duration::from_millis(5));
+        }
+    }
+
+    /// Start the websocket handshake of a new connection. The socket stays
+    /// non-blocking, and the handshake is carried on by `handshake`.
+    fn accept(&mut self, stream: TcpStream) {
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let path = Arc::new(Mutex::new(None));
+        let started = time::Instant::now();
+        match tungstenite::accept_hdr(stream, Route(path.clone())) {
+            Ok(socket) => self.connect(socket, &path),
+            Err(HandshakeError::Interrupted(handshake)) => self.handshakes.push(Handshake {
+                handshake,
+                path,
+                started,
+            }),
+            Err(HandshakeError::Failure(_)) => {}
+        }
+    }
+
+    /// Carry on the handshakes waiting for their sockets, dropping those
+    /// that take longer than `HANDSHAKE_TIMEOUT`.
+    fn handshake(&mut self) {
+        for pending in mem::take(&mut self.handshakes) {
+            let Handshake {
+                handshake,
+                path,
+                started,
+            } = pending;
+            match handshake.handshake() {
+                Ok(socket) => self.connect(socket, &path),
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    if started.elapsed() < HANDSHAKE_TIMEOUT {
+                        self.handshakes.push(Handshake {
+                            handshake,
+                            path,
+                            started,
+                        });
+                    }
+                }
+                Err(HandshakeError::Failure(_)) => {}
+            }
+        }
+    }
+
+    /// Add the client of a completed handshake, greeting it on the control
+    /// path.
+    fn connect(&mut self, socket: WebSocket<TcpStream>, path: &Mutex<Option<Path>>) {
+        let path = match *path.lock().unwrap_or_else(|err| err.into_inner()) {
+            Some(path) => path,
+            None => return,
+        };
+        let mut client = Client {
+            socket,
+            path,
+            replied: false,
+            header: None,
+            open: true,
+        };
+        if client.path == Path::Control {
+            let mut greeting = Map::new();
+            greeting.insert("event".to_owned(), Value::from("connection"));
+            greeting.insert("projectName".to_owned(), Value::from(self.project.clone()));
+            client.send(Message::Text(Value::Object(greeting).to_string()));
+        }
+        self.clients.push(client);
+    }
+
+    /// Handle every message waiting on client `idx`.
+    fn read(&mut self, idx: usize) {
+        loop {
+            let message = match self.clients[idx].socket.read() {
+                Ok(message) => message,
+                Err(tungstenite::Error::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return;
+                }
+                Err(_) => {
+                    self.clients[idx].open = false;
+                    return;
+                }
+            };
+            match (self.clients[idx].path, message) {
+                (Path::Control, Message::Text(text)) => self.control(idx, &text),
+                (Path::Data, Message::Binary(bytes)) => self.data(idx, &bytes),
+                _ => {}
+            }
+        }
+    }
+
+    fn control(&mut self, idx: usize, text: &str) {
+        let message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        if message["event"] == "connection-reply" {
+            self.clients[idx].replied = true;
+            self.connected.store(true, Ordering::Relaxed);
+        } else {
+            let _ = self.control_in.send(message);
+        }
+    }
+
+    fn data(&mut self, idx: usize, bytes: &[u8]) {
+        let client = &mut self.clients[idx];
+        match client.header.take() {
+            None => {
+                // A header that is not 12 bytes long is ignored, so that the
+                // next message is taken as a header again.
+                if bytes.len() == HEADER_LEN {
+                    let id = u32::read_le(&bytes[0..4]) as usize;
+                    let count = u32::read_le(&bytes[8..12]) as usize;
+                    client.header = Some((id, bytes[4], count));
+                }
+            }
+            Some((id, tag, count)) => {
+                let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+                if let Some(buffer) = buffers.get_mut(id) {
+                    buffer.receive(tag, count, bytes);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Socket = WebSocket<TcpStream>;
+
+    fn gui() -> Gui {
+        Gui::bind("test", SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0).into()).unwrap()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn connect(gui: &Gui, path: &str) -> Result<Socket, tungstenite::Error> {
+        let stream = TcpStream::connect(gui.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .unwrap();
+        let url = format!("ws://{}{}", gui.local_addr(), path);
+        tungstenite::client(url, stream)
+            .map(|(socket, _)| socket)
+            .map_err(|err| match err {
+                HandshakeError::Failure(err) => err,
+                HandshakeError::Interrupted(_) => panic!("blocking handshake interrupted"),
+            })
+    }
+
+    /// Wait up to 5 seconds for `done`.
+    fn wait_for<F: FnMut() -> bool>(mut done: F) {
+        let started = time::Instant::now();
+        while !done() {
+            assert!(
+                started.elapsed() < time::Duration::from_secs(5),
+                "timed out"
+            );
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    /// Read the next message, if one arrives within the read timeout.
+    fn receive(socket: &mut Socket) -> Option<Message> {
+        match socket.read() {
+            Ok(message) => Some(message),
+            Err(tungstenite::Error::Io(ref err))
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                None
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn receive_json(socket: &mut Socket) -> Value {
+        let mut message = None;
+        wait_for(|| {
+            message = receive(socket);
+            message.is_some()
+        });
+        match message {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn header(id: u32, tag: u8, count: u32) -> Vec<u8> {
+        let mut header = id.to_le_bytes().to_vec();
+        header.extend_from_slice(&[tag, 0, 0, 0]);
+        header.extend_from_slice(&count.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn control_connection() {
+        let gui = gui();
+        let mut client = connect(&gui, CONTROL_PATH).unwrap();
+        let greeting = receive_json(&mut client);
+        assert_eq!(greeting["event"], "connection");
+        assert_eq!(greeting["projectName"], "test");
+        assert!(!gui.is_connected());
+
+        client
+            .send(Message::Text(r#"{"event":"connection-reply"}"#.to_owned()))
+            .unwrap();
+        wait_for(|| gui.is_connected());
+
+        client
+            .send(Message::Text(r#"{"event":"slider","value":3}"#.to_owned()))
+            .unwrap();
+        let mut received = None;
+        wait_for(|| {
+            received = gui.receive_control();
+            received.is_some()
+        });
+        assert_eq!(received.unwrap()["value"], 3);
+
+        let mut message = Map::new();
+        message.insert("event".to_owned(), Value::from("reset"));
+        gui.send_control(Value::Object(message));
+        assert_eq!(receive_json(&mut client)["event"], "reset");
+
+        drop(client);
+        wait_for(|| !gui.is_connected());
+    }
+
+    #[test]
+    fn data_buffers() {
+        let mut gui = gui();
+        let mut sliders = gui.add_buffer::<f32>(4);
+        let mut counts = gui.add_buffer::<i32>(2);
+        assert_eq!((sliders.id(), counts.id()), (0, 1));
+        let mut client = connect(&gui, DATA_PATH).unwrap();
+
+        // Written until the server has registered the client
+        let mut message = None;
+        wait_for(|| {
+            counts.write(&[7, -1, 5]);
+            message = receive(&mut client);
+            message.is_some()
+        });
+        assert_eq!(message, Some(Message::Binary(header(1, b'i', 2))));
+        let mut values = 7i32.to_le_bytes().to_vec();
+        values.extend_from_slice(&(-1i32).to_le_bytes());
+        assert_eq!(receive(&mut client), Some(Message::Binary(values)));
+
+        let mut values = 0.5f32.to_le_bytes().to_vec();
+        values.extend_from_slice(&(-2f32).to_le_bytes());
+        client.send(Message::Binary(header(0, b'f', 2))).unwrap();
+        client.send(Message::Binary(values)).unwrap();
+        wait_for(|| !sliders.read().is_empty());
+        assert_eq!(sliders.read(), &[0.5, -2.]);
+
+        // Values of the wrong type are ignored
+        client.send(Message::Binary(header(1, b'f', 1))).unwrap();
+        client
+            .send(Message::Binary(1f32.to_le_bytes().to_vec()))
+            .unwrap();
+        client.send(Message::Binary(header(1, b'i', 1))).unwrap();
+        client
+            .send(Message::Binary(3i32.to_le_bytes().to_vec()))
+            .unwrap();
+        wait_for(|| counts.read() == [3]);
+    }
+
+    #[test]
+    fn unknown_path_is_refused() {
+        let gui = gui();
+        match connect(&gui, "/elsewhere") {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::NOT_FOUND)
+            }
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn stalled_handshake_does_not_block_others() {
+        let gui = gui();
+        // Connects, but never sends its handshake
+        let _stalled = TcpStream::connect(gui.local_addr()).unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        let mut client = connect(&gui, CONTROL_PATH).unwrap();
+        assert_eq!(receive_json(&mut client)["event"], "connection");
+    }
+}
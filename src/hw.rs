@@ -0,0 +1,282 @@
+//! Detects the board libbela is running on, and describes what each board
+//! provides, so that an app can adapt its channel routing at startup.
+//!
+//! ```rust,ignore
+//! let hw = match Bela::detect_hw() {
+//!     Ok(hw) => hw,
+//!     Err(err) => {
+//!         eprintln!("{}", err);
+//!         process::exit(1);
+//!     }
+//! };
+//! let mut settings = InitSettings::builder()
+//!     .num_analog_out_channels(hw.analog_out_channels)
+//!     .build()?;
+//! ```
+
+use std::fmt;
+
+#[cfg(feature = "libbela")]
+use error;
+use BelaHw;
+
+/// Where `detect_board` looks for the board.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DetectMode {
+    /// Probe the I2C buses for the codecs. This must not be done while audio
+    /// is running.
+    Scan,
+    /// Read the board cached in `/run/bela/belaconfig`, or scan and cache the
+    /// result if there is none.
+    Cache,
+    /// Only read the cached board.
+    CacheOnly,
+    /// Read the board set in `~/.bela/belaconfig`, or fall back to `Cache`.
+    User,
+    /// Only read the board set in `~/.bela/belaconfig`.
+    UserOnly,
+}
+
+#[cfg(feature = "libbela")]
+impl DetectMode {
+    fn to_raw(self) -> bela_sys::BelaHwDetectMode {
+        match self {
+            DetectMode::Scan => bela_sys::BelaHwDetectMode_BelaHwDetectMode_Scan,
+            DetectMode::Cache => bela_sys::BelaHwDetectMode_BelaHwDetectMode_Cache,
+            DetectMode::CacheOnly => bela_sys::BelaHwDetectMode_BelaHwDetectMode_CacheOnly,
+            DetectMode::User => bela_sys::BelaHwDetectMode_BelaHwDetectMode_User,
+            DetectMode::UserOnly => bela_sys::BelaHwDetectMode_BelaHwDetectMode_UserOnly,
+        }
+    }
+}
+
+/// An audio codec found on a board.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CodecKind {
+    /// The stereo codec of the Bela cape, BelaMini and Salt.
+    Tlv320Aic3104,
+    /// The multichannel codec of the CTAG FACE and BEAST.
+    Ad1938,
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CodecKind::Tlv320Aic3104 => write!(f, "TLV320AIC3104"),
+            CodecKind::Ad1938 => write!(f, "AD1938"),
+        }
+    }
+}
+
+/// What a board provides.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HwConfig {
+    pub board: BelaHw,
+    pub audio_sample_rate: f32,
+    /// Sample rate of the analog channels when all of them are used, or 0 for
+    /// a board without analog channels.
+    pub analog_sample_rate: f32,
+    pub audio_in_channels: usize,
+    pub audio_out_channels: usize,
+    pub analog_in_channels: usize,
+    pub analog_out_channels: usize,
+    pub digital_channels: usize,
+    pub codecs: &'static [CodecKind],
+}
+
+impl HwConfig {
+    /// The configuration of `board`, as documented for it. Unlike `query`,
+    /// this does not need libbela, so it can be used off the board.
+    pub fn for_board(board: BelaHw) -> HwConfig {
+        const TLV: &[CodecKind] = &[CodecKind::Tlv320Aic3104];
+        const AD: &[CodecKind] = &[CodecKind::Ad1938];
+        const BOTH: &[CodecKind] = &[CodecKind::Tlv320Aic3104, CodecKind::Ad1938];
+
+        // (audio rate, audio in, audio out, analog in, analog out, codecs)
+        let (rate, audio_in, audio_out, analog_in, analog_out, codecs) = match board {
+            BelaHw::NoHw => (44_100., 0, 0, 0, 0, &[][..]),
+            BelaHw::Bela | BelaHw::Salt => (44_100., 2, 2, 8, 8, TLV),
+            BelaHw::BelaMini => (44_100., 2, 2, 8, 0, TLV),
+            BelaHw::CtagFace => (48_000., 4, 8, 0, 0, AD),
+            BelaHw::CtagBeast => (48_000., 8, 16, 0, 0, AD),
+            BelaHw::CtagFaceBela => (48_000., 6, 10, 8, 8, BOTH),
+            BelaHw::CtagBeastBela => (48_000., 10, 18, 8, 8, BOTH),
+        };
+        HwConfig {
+            board,
+            audio_sample_rate: rate,
+            // Eight analog channels run at half the audio sample rate.
+            analog_sample_rate: if analog_in > 0 { rate / 2. } else { 0. },
+            audio_in_channels: audio_in,
+            audio_out_channels: audio_out,
+            analog_in_channels: analog_in,
+            analog_out_channels: analog_out,
+            digital_channels: if board == BelaHw::NoHw { 0 } else { 16 },
+            codecs,
+        }
+    }
+
+    /// The configuration of `board`, as reported by libbela.
+    #[cfg(feature = "libbela")]
+    pub fn query(board: BelaHw) -> Result<HwConfig, error::Error> {
+        let ptr = unsafe { bela_sys::Bela_HwConfig_new(board as _) };
+        if ptr.is_null() {
+            return Err(error::Error::new(error::ErrorKind::Detect)
+                .with_detail(format!("libbela has no configuration for {:?}", board)));
+        }
+
+        let mut config = HwConfig::for_board(board);
+        unsafe {
+            let raw = &*ptr;
+            config.audio_sample_rate = raw.audioSampleRate;
+            config.analog_sample_rate = if raw.analogInChannels > 0 {
+                raw.audioSampleRate / 2.
+            } else {
+                0.
+            };
+            config.audio_in_channels = raw.audioInChannels as usize;
+            config.audio_out_channels = raw.audioOutChannels as usize;
+            config.analog_in_channels = raw.analogInChannels as usize;
+            config.analog_out_channels = raw.analogOutChannels as usize;
+            config.digital_channels = raw.digitalChannels as usize;
+            bela_sys::Bela_HwConfig_delete(ptr);
+        }
+        Ok(config)
+    }
+}
+
+/// Detect the board, using the cached result if there is one, and read its
+/// configuration from libbela. Returns an error rather than panicking on
+/// hardware this crate does not know.
+///
+/// Fails if libbela has no configuration for the board it detected. To fall
+/// back to the documented configuration instead, use
+/// `HwConfig::for_board(detect_board(DetectMode::Cache)?)`.
+#[cfg(feature = "libbela")]
+pub fn detect() -> Result<HwConfig, error::Error> {
+    HwConfig::query(detect_board(DetectMode::Cache)?)
+}
+
+/// Detect the board libbela is running on.
+///
+/// Fails rather than panicking when libbela reports a board this crate does
+/// not know, with the raw value as the error code.
+#[cfg(feature = "libbela")]
+pub fn detect_board(mode: DetectMode) -> Result<BelaHw, error::Error> {
+    error::clear_errno();
+    let raw = unsafe { bela_sys::Bela_detectHw(mode.to_raw()) };
+    match BelaHw::from_i32(raw) {
+        Some(BelaHw::NoHw) => Err(error::Error::new(error::ErrorKind::Detect)
+            .with_detail(format!("no board found ({:?})", mode))),
+        Some(board) => Ok(board),
+        None => Err(error::Error::from_code(error::ErrorKind::Detect, raw)
+            .with_detail("libbela reported an unknown board")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOARDS: [BelaHw; 8] = [
+        BelaHw::NoHw,
+        BelaHw::Bela,
+        BelaHw::BelaMini,
+        BelaHw::Salt,
+        BelaHw::CtagFace,
+        BelaHw::CtagBeast,
+        BelaHw::CtagFaceBela,
+        BelaHw::CtagBeastBela,
+    ];
+
+    fn channels(config: &HwConfig) -> [usize; 5] {
+        [
+            config.audio_in_channels,
+            config.audio_out_channels,
+            config.analog_in_channels,
+            config.analog_out_channels,
+            config.digital_channels,
+        ]
+    }
+
+    #[test]
+    fn bela_boards() {
+        let bela = HwConfig::for_board(BelaHw::Bela);
+        assert_eq!(bela.board, BelaHw::Bela);
+        assert_eq!(channels(&bela), [2, 2, 8, 8, 16]);
+        assert_eq!(
+            (bela.audio_sample_rate, bela.analog_sample_rate),
+            (44_100., 22_050.)
+        );
+        assert_eq!(bela.codecs, [CodecKind::Tlv320Aic3104]);
+        assert_eq!(
+            HwConfig::for_board(BelaHw::Salt),
+            HwConfig {
+                board: BelaHw::Salt,
+                ..bela
+            }
+        );
+
+        let mini = HwConfig::for_board(BelaHw::BelaMini);
+        assert_eq!(channels(&mini), [2, 2, 8, 0, 16]);
+        assert_eq!(mini.analog_sample_rate, 22_050.);
+    }
+
+    #[test]
+    fn ctag_boards() {
+        let face = HwConfig::for_board(BelaHw::CtagFace);
+        assert_eq!(channels(&face), [4, 8, 0, 0, 16]);
+        assert_eq!(
+            (face.audio_sample_rate, face.analog_sample_rate),
+            (48_000., 0.)
+        );
+        assert_eq!(face.codecs, [CodecKind::Ad1938]);
+        assert_eq!(
+            channels(&HwConfig::for_board(BelaHw::CtagBeast)),
+            [8, 16, 0, 0, 16]
+        );
+
+        // With a Bela cape, the cape adds its own codec and analog channels
+        let face_bela = HwConfig::for_board(BelaHw::CtagFaceBela);
+        assert_eq!(channels(&face_bela), [6, 10, 8, 8, 16]);
+        assert_eq!(face_bela.analog_sample_rate, 24_000.);
+        assert_eq!(
+            face_bela.codecs,
+            [CodecKind::Tlv320Aic3104, CodecKind::Ad1938]
+        );
+        assert_eq!(
+            channels(&HwConfig::for_board(BelaHw::CtagBeastBela)),
+            [10, 18, 8, 8, 16]
+        );
+    }
+
+    #[test]
+    fn no_board() {
+        let none = HwConfig::for_board(BelaHw::NoHw);
+        assert_eq!(channels(&none), [0; 5]);
+        assert_eq!(none.analog_sample_rate, 0.);
+        assert!(none.codecs.is_empty());
+    }
+
+    #[test]
+    fn every_board_is_consistent() {
+        for &board in &BOARDS {
+            let config = HwConfig::for_board(board);
+            assert_eq!(config.board, board);
+            // Analog channels run at half the audio rate, when there are any
+            if config.analog_in_channels > 0 {
+                assert_eq!(config.analog_sample_rate * 2., config.audio_sample_rate);
+            } else {
+                assert_eq!(config.analog_sample_rate, 0.);
+            }
+            // Every board with audio has a codec for it
+            assert_eq!(config.codecs.is_empty(), config.audio_out_channels == 0);
+        }
+    }
+
+    #[test]
+    fn codec_names() {
+        assert_eq!(CodecKind::Tlv320Aic3104.to_string(), "TLV320AIC3104");
+        assert_eq!(CodecKind::Ad1938.to_string(), "AD1938");
+    }
+}
@@ -1,40 +1,89 @@
+#[cfg(feature = "libbela")]
 extern crate bela_sys;
+#[cfg(feature = "flac")]
+extern crate claxon;
+extern crate hound;
+extern crate libc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(any(feature = "serde", feature = "gui"))]
+extern crate serde_json;
+#[cfg(feature = "serde")]
+extern crate toml;
+#[cfg(feature = "gui")]
+extern crate tungstenite;
 
-use bela_sys::{BelaContext, BelaInitSettings};
 use std::convert::TryInto;
-use std::{mem, slice};
+#[cfg(feature = "libbela")]
+use std::mem;
+use std::{ptr, slice};
 use std::{thread, time};
 
+mod args;
+pub mod audio_file;
+pub mod backend;
+mod builder;
+pub mod channel;
+pub mod codec;
+#[cfg(feature = "serde")]
+pub mod config;
+pub mod digital;
 pub mod error;
+mod files;
+pub mod frames;
+pub mod golden;
+#[cfg(feature = "gui")]
+pub mod gui;
+mod guard;
+pub mod hw;
+pub mod midi;
+mod ring;
+pub mod scope;
+mod settings;
+mod sys;
+pub mod trill;
+mod triple;
+pub mod write_file;
+
+pub use args::ArgsError;
+pub use backend::Backend;
+pub use builder::ContextBuilder;
+pub use frames::Layout;
+pub use guard::PanicPolicy;
+pub use settings::{InitSettingsBuilder, InvalidField, InvalidSettings};
+use sys::{BelaContext, BelaInitSettings};
 
 pub enum DigitalDirection {
     INPUT,
     OUTPUT,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub enum BelaHw {
-    NoHw = bela_sys::BelaHw_BelaHw_NoHw as isize,
-    Bela = bela_sys::BelaHw_BelaHw_Bela as isize,
-    BelaMini = bela_sys::BelaHw_BelaHw_BelaMini as isize,
-    Salt = bela_sys::BelaHw_BelaHw_Salt as isize,
-    CtagFace = bela_sys::BelaHw_BelaHw_CtagFace as isize,
-    CtagBeast = bela_sys::BelaHw_BelaHw_CtagBeast as isize,
-    CtagFaceBela = bela_sys::BelaHw_BelaHw_CtagFaceBela as isize,
-    CtagBeastBela = bela_sys::BelaHw_BelaHw_CtagBeastBela as isize,
+    NoHw = sys::BelaHw_BelaHw_NoHw as isize,
+    Bela = sys::BelaHw_BelaHw_Bela as isize,
+    BelaMini = sys::BelaHw_BelaHw_BelaMini as isize,
+    Salt = sys::BelaHw_BelaHw_Salt as isize,
+    CtagFace = sys::BelaHw_BelaHw_CtagFace as isize,
+    CtagBeast = sys::BelaHw_BelaHw_CtagBeast as isize,
+    CtagFaceBela = sys::BelaHw_BelaHw_CtagFaceBela as isize,
+    CtagBeastBela = sys::BelaHw_BelaHw_CtagBeastBela as isize,
 }
 
 impl BelaHw {
     fn from_i32(v: i32) -> Option<BelaHw> {
         match v {
-            bela_sys::BelaHw_BelaHw_NoHw => Some(BelaHw::NoHw),
-            bela_sys::BelaHw_BelaHw_Bela => Some(BelaHw::Bela),
-            bela_sys::BelaHw_BelaHw_BelaMini => Some(BelaHw::BelaMini),
-            bela_sys::BelaHw_BelaHw_Salt => Some(BelaHw::Salt),
-            bela_sys::BelaHw_BelaHw_CtagFace => Some(BelaHw::CtagFace),
-            bela_sys::BelaHw_BelaHw_CtagBeast => Some(BelaHw::CtagBeast),
-            bela_sys::BelaHw_BelaHw_CtagFaceBela => Some(BelaHw::CtagFaceBela),
-            bela_sys::BelaHw_BelaHw_CtagBeastBela => Some(BelaHw::CtagBeastBela),
+            sys::BelaHw_BelaHw_NoHw => Some(BelaHw::NoHw),
+            sys::BelaHw_BelaHw_Bela => Some(BelaHw::Bela),
+            sys::BelaHw_BelaHw_BelaMini => Some(BelaHw::BelaMini),
+            sys::BelaHw_BelaHw_Salt => Some(BelaHw::Salt),
+            sys::BelaHw_BelaHw_CtagFace => Some(BelaHw::CtagFace),
+            sys::BelaHw_BelaHw_CtagBeast => Some(BelaHw::CtagBeast),
+            sys::BelaHw_BelaHw_CtagFaceBela => Some(BelaHw::CtagFaceBela),
+            sys::BelaHw_BelaHw_CtagBeastBela => Some(BelaHw::CtagBeastBela),
             _ => None,
         }
     }
@@ -66,55 +115,144 @@ impl BelaHw {
 /// ```rust
 /// pub type CleanupFn = FnOnce(&mut Context, T) -> bool;
 /// ```
-pub struct Bela<T> {
+///
+/// Besides `UserData`, `<T>` can be any `BelaApp`.
+///
+/// The lifecycle itself is dispatched through a `Backend`. By default this is
+/// `LibBela`, which calls into the C library on the board, but any other
+/// `Backend` (such as `backend::Software`) can be supplied through
+/// `Bela::with_backend`. Without the `libbela` feature, only the other
+/// backends are available.
+pub struct Bela<T, B = backend::LibBela> {
     initialized: bool,
-    user_data: T,
+    user_data: guard::Guarded<T>,
+    backend: B,
+    /// Tasks deleted by `cleanup_audio`.
+    scoped_tasks: Vec<CreatedTask>,
 }
 
-extern "C" fn render_trampoline<'a, T>(
-    context: *mut BelaContext,
-    user_data: *mut std::os::raw::c_void,
-) where
-    T: UserData<'a> + 'a,
+extern "C" fn render_trampoline<T>(context: *mut BelaContext, user_data: *mut std::os::raw::c_void)
+where
+    T: BelaApp,
 {
     let mut context = Context::new(context);
-    let user_data = unsafe { &mut *(user_data as *mut T) };
-    user_data.render_fn(&mut context);
+    let user_data = unsafe { &mut *(user_data as *mut guard::Guarded<T>) };
+    user_data.render(&mut context);
 }
 
-extern "C" fn setup_trampoline<'a, T>(
+extern "C" fn setup_trampoline<T>(
     context: *mut BelaContext,
     user_data: *mut std::os::raw::c_void,
 ) -> bool
 where
-    T: UserData<'a> + 'a,
+    T: BelaApp,
 {
     let mut context = Context::new(context);
-    let user_data = unsafe { &mut *(user_data as *mut T) };
-    user_data.setup_fn(&mut context).is_ok()
+    let user_data = unsafe { &mut *(user_data as *mut guard::Guarded<T>) };
+    user_data.setup(&mut context)
 }
 
-extern "C" fn cleanup_trampoline<'a, T>(
-    context: *mut BelaContext,
-    user_data: *mut std::os::raw::c_void,
-) where
-    T: UserData<'a> + 'a,
+extern "C" fn cleanup_trampoline<T>(context: *mut BelaContext, user_data: *mut std::os::raw::c_void)
+where
+    T: BelaApp,
 {
     let mut context = Context::new(context);
-    let user_data = unsafe { &mut *(user_data as *mut T) };
-    user_data.cleanup_fn(&mut context);
+    let user_data = unsafe { &mut *(user_data as *mut guard::Guarded<T>) };
+    user_data.cleanup(&mut context);
 }
 
-pub struct CreatedTask(bela_sys::AuxiliaryTask);
+/// Handle to an auxiliary task, as returned by `Bela::create_auxiliary_task`.
+/// The handle can only be scheduled by the backend that created it.
+///
+/// Handles can be cloned, and the task is deleted once the last one is
+/// dropped: its thread is stopped and joined, and its closure is freed. On the
+/// board, libbela cannot stop a single task, so its closure is kept until
+/// `cleanup_audio` deletes all of them.
+///
+/// On the board, deleting a task never locks or allocates, so handles can be
+/// dropped in render. The other backends join the task thread, so handles
+/// must be dropped outside of render there.
+#[derive(Clone)]
+pub struct CreatedTask(std::sync::Arc<backend::Task>);
 
-impl<'a, T: UserData<'a> + 'a> Bela<T> {
+impl CreatedTask {
+    // Handles reach the audio thread through the user data, which is not
+    // checked for `Send`, so the count must be atomic all the same.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn new(handle: backend::TaskHandle) -> CreatedTask {
+        CreatedTask(std::sync::Arc::new(backend::Task::new(handle)))
+    }
+
+    /// Delete the task now, rather than when the last handle is dropped.
+    /// Scheduling it afterwards returns an error.
+    pub fn delete(&self) {
+        self.0.delete();
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.0.is_deleted()
+    }
+}
+
+#[cfg(feature = "libbela")]
+impl<T: BelaApp> Bela<T> {
     pub fn new(user_data: T) -> Self {
+        Bela::with_backend(user_data, backend::LibBela)
+    }
+}
+
+impl Bela<OwnedAppData<()>> {
+    /// Start building an app from owned closures. Use `AppBuilder::new` to
+    /// share state between the callbacks.
+    pub fn builder() -> AppBuilder<()> {
+        AppBuilder::new(())
+    }
+
+    /// Detect the board and read its configuration from libbela, as
+    /// `hw::detect` does.
+    #[cfg(feature = "libbela")]
+    pub fn detect_hw() -> Result<hw::HwConfig, error::Error> {
+        hw::detect()
+    }
+}
+
+impl<T: BelaApp, B: Backend> Bela<T, B> {
+    /// Create a `Bela` whose lifecycle runs on the given backend.
+    pub fn with_backend(user_data: T, backend: B) -> Self {
         Bela {
             initialized: false,
-            user_data,
+            user_data: guard::Guarded::new(user_data),
+            backend,
+            scoped_tasks: Vec::new(),
         }
     }
 
+    pub fn panic_policy(&self) -> PanicPolicy {
+        self.user_data.policy
+    }
+
+    /// Set what happens when setup, render or cleanup panics. Defaults to
+    /// `PanicPolicy::Stop`.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.user_data.policy = policy;
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Handle to the codec, to change its levels while audio is running.
+    pub fn codec(&self) -> codec::Codec {
+        self.backend.codec()
+    }
+
+    /// Initialize and start audio, wait until a stop is requested, then stop
+    /// and clean up. Returns an `ErrorKind::Panic` error if a callback
+    /// panicked along the way.
     pub fn run(&mut self, settings: &mut InitSettings) -> Result<(), error::Error> {
         self.init_audio(settings)?;
         self.start_audio()?;
@@ -125,72 +263,57 @@ impl<'a, T: UserData<'a> + 'a> Bela<T> {
         self.stop_audio();
         self.cleanup_audio();
 
-        Ok(())
-    }
-
-    pub fn set_render<F: 'a>(&mut self, func: &'a mut F)
-    where
-        F: FnMut(&mut Context, T::Data),
-        for<'r, 's> F: FnMut(&'r mut Context, &'s mut T::Data),
-    {
-        self.user_data.set_render_fn(func);
-    }
-
-    pub fn set_setup<F: 'a>(&mut self, func: &'a mut F)
-    where
-        F: FnMut(&mut Context, T::Data) -> bool,
-        for<'r, 's> F: FnMut(&'r mut Context, &'s mut T::Data) -> Result<(), error::Error>,
-    {
-        self.user_data.set_setup_fn(Some(func));
-    }
-
-    pub fn set_cleanup<F: 'a>(&mut self, func: &'a mut F)
-    where
-        F: FnMut(&mut Context, T::Data),
-        for<'r, 's> F: FnMut(&'r mut Context, &'s mut T::Data),
-    {
-        self.user_data.set_cleanup_fn(Some(func));
+        match self.user_data.status.take_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
     pub fn init_audio(&mut self, settings: &mut InitSettings) -> Result<(), error::Error> {
         settings.settings.setup = Some(setup_trampoline::<T>);
         settings.settings.render = Some(render_trampoline::<T>);
         settings.settings.cleanup = Some(cleanup_trampoline::<T>);
-        let out = unsafe {
-            bela_sys::Bela_initAudio(
-                settings.settings_ptr(),
-                &mut self.user_data as *mut _ as *mut _,
-            )
+        self.user_data.status.reset();
+        let res = unsafe {
+            self.backend
+                .init_audio(settings, &mut self.user_data as *mut _ as *mut _)
         };
-
-        match out {
-            0 => {
-                self.initialized = true;
-                Ok(())
-            }
-            _ => Err(error::Error::Init),
+        if let Err(err) = res {
+            // Report why setup failed rather than that initialization did
+            return Err(self
+                .user_data
+                .status
+                .take_error()
+                .or_else(|| self.user_data.setup_error.take())
+                .unwrap_or(err));
         }
+        self.initialized = true;
+        Ok(())
     }
 
-    pub fn start_audio(&self) -> Result<(), error::Error> {
+    /// Start calling render. Like `stop_audio` and `cleanup_audio`, this
+    /// takes `&mut self` since it drives the backend; it took `&self` before
+    /// backends were introduced.
+    pub fn start_audio(&mut self) -> Result<(), error::Error> {
         if !self.initialized {
-            return Err(error::Error::Start);
+            return Err(error::Error::new(error::ErrorKind::Start)
+                .with_detail("init_audio has not been called"));
         }
 
-        let out = unsafe { bela_sys::Bela_startAudio() };
-
-        match out {
-            0 => Ok(()),
-            _ => Err(error::Error::Start),
-        }
+        self.backend.start_audio()
     }
 
     pub fn should_stop(&self) -> bool {
-        unsafe { bela_sys::Bela_stopRequested() != 0 }
+        self.backend.should_stop() || self.user_data.status.should_stop()
     }
 
     /// Create an auxiliary task that runs on a lower-priority thread
     /// `name` must be globally unique across all Xenomai processes!
+    ///
+    /// A panic in a task created during setup is handled by the
+    /// `PanicPolicy` of the `Bela` running that setup. A task created
+    /// anywhere else only has its panics reported by the panic hook. Either
+    /// way, a task that panicked is not run again.
     pub fn create_auxiliary_task<Auxiliary>(
         task: Box<Auxiliary>,
         priority: i32,
@@ -199,62 +322,227 @@ impl<'a, T: UserData<'a> + 'a> Bela<T> {
     where
         Auxiliary: FnMut() + Send + 'static,
     {
-        // TODO: Bela API does not currently offer an API to stop and unregister a task,
-        // so we can only leak the task. Otherwise, we could `Box::into_raw` here, store the
-        // raw pointer in `CreatedTask` and drop it after unregistering & joining the thread
-        // using `Box::from_raw`.
-        let task_ptr = Box::leak(task) as *mut _ as *mut _;
+        Self::create_guarded_task(task, guard::TaskGuard::current(), priority, name)
+    }
+
+    // Takes the box handed to the public functions, whose signature predates
+    // the guard
+    #[allow(clippy::boxed_local)]
+    fn create_guarded_task<Auxiliary>(
+        task: Box<Auxiliary>,
+        guard: Option<guard::TaskGuard>,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> CreatedTask
+    where
+        Auxiliary: FnMut() + Send + 'static,
+    {
+        let task = Box::new(guard::GuardedTask::new(*task, guard));
+        let task_ptr = Box::into_raw(task) as *mut std::os::raw::c_void;
 
         extern "C" fn auxiliary_task_trampoline<Auxiliary>(aux_ptr: *mut std::os::raw::c_void)
         where
             Auxiliary: FnMut() + Send + 'static,
         {
-            let task_ptr = unsafe { &mut *(aux_ptr as *mut Auxiliary) };
-            task_ptr();
+            let task = unsafe { &mut *(aux_ptr as *mut guard::GuardedTask<Auxiliary>) };
+            // Unwinding into the task thread is undefined behaviour, so the
+            // task catches its own panics.
+            task.run();
         }
 
-        let aux_task = unsafe {
-            bela_sys::Bela_createAuxiliaryTask(
-                Some(auxiliary_task_trampoline::<Auxiliary>),
-                priority,
-                name.as_ptr(),
+        unsafe fn drop_task<Auxiliary>(aux_ptr: *mut std::os::raw::c_void) {
+            drop(Box::from_raw(aux_ptr as *mut guard::GuardedTask<Auxiliary>));
+        }
+
+        unsafe {
+            let created = B::create_auxiliary_task(
+                auxiliary_task_trampoline::<Auxiliary>,
                 task_ptr,
-            )
-        };
+                priority,
+                name,
+            );
+            created
+                .0
+                .set_closure(backend::TaskClosure::new(task_ptr, drop_task::<Auxiliary>));
+            created
+        }
+    }
 
-        CreatedTask(aux_task)
+    /// Create an auxiliary task like `create_auxiliary_task`, which is also
+    /// deleted by `cleanup_audio`, so that tasks created for every run do not
+    /// pile up.
+    pub fn create_scoped_auxiliary_task<Auxiliary>(
+        &mut self,
+        task: Box<Auxiliary>,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> CreatedTask
+    where
+        Auxiliary: FnMut() + Send + 'static,
+    {
+        let guard = self.user_data.task_guard();
+        let created = Self::create_guarded_task(task, Some(guard), priority, name);
+        self.scoped_tasks.push(created.clone());
+        created
+    }
+
+    /// Create an auxiliary task fed through a channel of `capacity` values.
+    ///
+    /// Every value sent through the returned `TaskSender` schedules the task,
+    /// which calls `task` on each value waiting in the channel. `task` can
+    /// reply through the `Sender` it is given, whose values are received from
+    /// the returned `Receiver`, which has the same capacity.
+    pub fn create_channel_task<In, Out, F>(
+        capacity: usize,
+        mut task: F,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> (channel::TaskSender<In>, channel::Receiver<Out>)
+    where
+        In: Send + 'static,
+        Out: Send + 'static,
+        F: FnMut(In, &mut channel::Sender<Out>) + Send + 'static,
+    {
+        let (in_tx, mut in_rx) = channel::channel(capacity);
+        let (mut out_tx, out_rx) = channel::channel(capacity);
+        let drain = Box::new(move || {
+            while let Some(value) = in_rx.try_recv() {
+                task(value, &mut out_tx);
+            }
+        });
+        let created = Self::create_auxiliary_task(drain, priority, name);
+        (
+            channel::TaskSender::new(in_tx, created, B::schedule_auxiliary_task),
+            out_rx,
+        )
+    }
+
+    /// Create an auxiliary task that takes values from render one at a time,
+    /// and whose results render polls for on a later block.
+    pub fn create_aux_task<In, Out, F>(
+        mut task: F,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> channel::AuxTask<In, Out>
+    where
+        In: Send + 'static,
+        Out: Send + 'static,
+        F: FnMut(In) -> Out + Send + 'static,
+    {
+        let (in_tx, mut in_rx) = channel::channel(1);
+        let (mut out_tx, out_rx) = channel::channel(1);
+        let run = Box::new(move || {
+            while let Some(value) = in_rx.try_recv() {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| task(value)));
+                // Only one value is in flight, so there is always room.
+                let _ = out_tx.try_send(result.ok());
+            }
+        });
+        let created = Self::create_auxiliary_task(run, priority, name);
+        channel::AuxTask::new(in_tx, out_rx, created, B::schedule_auxiliary_task)
+    }
+
+    /// Create an auxiliary task writing `file`, and the `WriteFile` render
+    /// logs frames to through a queue of `frames` frames.
+    pub fn create_write_file(
+        file: write_file::FileWriter,
+        frames: usize,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> write_file::WriteFile {
+        let (queue, mut drain) = write_file::Drain::new(file, frames);
+        let created = Self::create_auxiliary_task(Box::new(move || drain.run()), priority, name);
+        write_file::WriteFile::new(queue, created, B::schedule_auxiliary_task)
+    }
+
+    /// Create an auxiliary task streaming `file` through two buffers of
+    /// `buffer_frames` frames, and the `AudioFileStream` render plays it from.
+    /// Both buffers are filled before this returns.
+    pub fn create_audio_file_stream(
+        file: audio_file::AudioFile,
+        buffer_frames: usize,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> audio_file::AudioFileStream {
+        let (stream, mut filler) = audio_file::split(file, buffer_frames);
+        let created = Self::create_auxiliary_task(Box::new(move || filler.run()), priority, name);
+        audio_file::AudioFileStream::new(stream, created, B::schedule_auxiliary_task)
+    }
+
+    /// Create an auxiliary task reading `trill` each time the returned
+    /// `TrillReader` is updated from render.
+    pub fn create_trill_reader<Bus>(
+        trill: trill::Trill<Bus>,
+        priority: i32,
+        name: &std::ffi::CStr,
+    ) -> trill::TrillReader
+    where
+        Bus: trill::I2cBus + Send + 'static,
+    {
+        let (latest, mut poll) = trill::split(trill);
+        let created = Self::create_auxiliary_task(Box::new(move || poll.run()), priority, name);
+        trill::TrillReader::new(latest, created, B::schedule_auxiliary_task)
     }
 
     pub fn schedule_auxiliary_task(task: &CreatedTask) -> Result<(), error::Error> {
-        let res = unsafe { bela_sys::Bela_scheduleAuxiliaryTask(task.0) };
+        B::schedule_auxiliary_task(task)
+    }
 
-        match res {
-            0 => Ok(()),
-            _ => Err(error::Error::Task),
-        }
+    pub fn stop_audio(&mut self) {
+        self.backend.stop_audio();
     }
 
-    pub fn stop_audio(&self) {
-        unsafe {
-            bela_sys::Bela_stopAudio();
+    pub fn cleanup_audio(&mut self) {
+        self.backend.cleanup_audio();
+        for task in self.scoped_tasks.drain(..) {
+            task.delete();
         }
+        self.initialized = false;
     }
+}
 
-    pub fn cleanup_audio(&self) {
-        unsafe {
-            bela_sys::Bela_cleanupAudio();
-        }
+impl<'a, T: UserData<'a> + 'a, B: Backend> Bela<T, B> {
+    pub fn set_render<F: 'a>(&mut self, func: &'a mut F)
+    where
+        F: FnMut(&mut Context, T::Data),
+        for<'r, 's> F: FnMut(&'r mut Context, &'s mut T::Data),
+    {
+        self.user_data.app.set_render_fn(func);
+    }
+
+    pub fn set_setup<F: 'a>(&mut self, func: &'a mut F)
+    where
+        F: FnMut(&mut Context, T::Data) -> bool,
+        for<'r, 's> F: FnMut(&'r mut Context, &'s mut T::Data) -> Result<(), error::Error>,
+    {
+        self.user_data.app.set_setup_fn(Some(func));
+    }
+
+    pub fn set_cleanup<F: 'a>(&mut self, func: &'a mut F)
+    where
+        F: FnMut(&mut Context, T::Data),
+        for<'r, 's> F: FnMut(&'r mut Context, &'s mut T::Data),
+    {
+        self.user_data.app.set_cleanup_fn(Some(func));
     }
 }
 
 /// Wraps `BelaContext`
+///
+/// A `Context` either borrows the `BelaContext` passed to the render, setup and
+/// cleanup callbacks, or owns Rust-allocated buffers when built through
+/// `ContextBuilder`.
 pub struct Context {
     context: *mut BelaContext,
+    buffers: *mut builder::Buffers,
 }
 
 impl Context {
     pub fn new(context: *mut BelaContext) -> Context {
-        Context { context }
+        Context {
+            context,
+            buffers: ptr::null_mut(),
+        }
     }
 
     pub fn context_mut_ptr(&mut self) -> *mut BelaContext {
@@ -295,13 +583,15 @@ impl Context {
     }
 
     /// Access the digital input/output slice immutably
+    ///
+    /// The digital buffer holds one word per frame: the lower 16 bits are the
+    /// pin directions and the upper 16 bits are the pin values.
     pub fn digital(&self) -> &[u32] {
         unsafe {
             let context = self.context_ptr();
             let n_frames = (*context).digitalFrames;
-            let n_channels = (*context).digitalChannels;
             let digital_ptr = (*context).digital;
-            slice::from_raw_parts(digital_ptr, (n_frames * n_channels) as usize)
+            slice::from_raw_parts(digital_ptr, n_frames as usize)
         }
     }
 
@@ -313,9 +603,8 @@ impl Context {
         unsafe {
             let context = self.context_ptr();
             let n_frames = (*context).digitalFrames;
-            let n_channels = (*context).digitalChannels;
             let digital_ptr = (*context).digital;
-            slice::from_raw_parts_mut(digital_ptr, (n_frames * n_channels) as usize)
+            slice::from_raw_parts_mut(digital_ptr, n_frames as usize)
         }
     }
 
@@ -343,6 +632,32 @@ impl Context {
         }
     }
 
+    /// Access the audio input slice mutably
+    ///
+    /// Inputs are only meant to be written when they are not fed by the
+    /// hardware, such as for a context built by `ContextBuilder`.
+    pub fn audio_in_mut(&mut self) -> &mut [f32] {
+        unsafe {
+            let n_frames = (*self.context).audioFrames;
+            let n_channels = (*self.context).audioInChannels;
+            let audio_in_ptr = (*self.context).audioIn as *mut f32;
+            slice::from_raw_parts_mut(audio_in_ptr, (n_frames * n_channels) as usize)
+        }
+    }
+
+    /// Access the analog input slice mutably
+    ///
+    /// Inputs are only meant to be written when they are not fed by the
+    /// hardware, such as for a context built by `ContextBuilder`.
+    pub fn analog_in_mut(&mut self) -> &mut [f32] {
+        unsafe {
+            let n_frames = (*self.context).analogFrames;
+            let n_channels = (*self.context).analogInChannels;
+            let analog_in_ptr = (*self.context).analogIn as *mut f32;
+            slice::from_raw_parts_mut(analog_in_ptr, (n_frames * n_channels) as usize)
+        }
+    }
+
     pub fn audio_frames(&self) -> usize {
         unsafe { (*self.context).audioFrames as usize }
     }
@@ -383,6 +698,12 @@ impl Context {
         unsafe { (*self.context).digitalChannels as usize }
     }
 
+    /// A handle to digital channel `channel`, or `None` if there is no such
+    /// channel.
+    pub fn digital_pin(&self, channel: usize) -> Option<digital::DigitalPin> {
+        digital::DigitalPin::new(self, channel)
+    }
+
     pub fn digital_sample_rate(&self) -> f32 {
         unsafe { (*self.context).digitalSampleRate }
     }
@@ -408,6 +729,19 @@ impl Context {
         }
     }
 
+    /// Access the multiplexer analog input slice mutably
+    ///
+    /// Inputs are only meant to be written when they are not fed by the
+    /// hardware, such as for a context built by `ContextBuilder`.
+    pub fn multiplexer_analog_in_mut(&mut self) -> &mut [f32] {
+        unsafe {
+            let n_frames = (*self.context).analogFrames;
+            let n_channels = (*self.context).multiplexerChannels;
+            let analog_in_ptr = (*self.context).multiplexerAnalogIn as *mut f32;
+            slice::from_raw_parts_mut(analog_in_ptr, (n_frames * n_channels) as usize)
+        }
+    }
+
     pub fn multiplexer_enabled(&self) -> u32 {
         unsafe { (*self.context).audioExpanderEnabled }
     }
@@ -416,7 +750,146 @@ impl Context {
         unsafe { (*self.context).flags }
     }
 
-    // Returns the value of a given digital input at the given frame number
+    /// Order of the samples in the audio and analog buffers, as given by
+    /// `BELA_FLAG_INTERLEAVED`.
+    pub fn layout(&self) -> Layout {
+        Layout::from_flags(self.flags())
+    }
+
+    /// Read the audio input of `channel` at `frame`, whatever the layout.
+    ///
+    /// Panics if `frame` or `channel` is out of range.
+    pub fn audio_read(&self, frame: usize, channel: usize) -> f32 {
+        let (n_frames, n_channels) = (self.audio_frames(), self.audio_in_channels());
+        assert!(frame < n_frames, "no audio frame {}", frame);
+        assert!(channel < n_channels, "no audio input channel {}", channel);
+        self.audio_in()[self.layout().index(frame, channel, n_frames, n_channels)]
+    }
+
+    /// Write the audio output of `channel` at `frame`, whatever the layout.
+    ///
+    /// Panics if `frame` or `channel` is out of range.
+    pub fn audio_write(&mut self, frame: usize, channel: usize, value: f32) {
+        let (n_frames, n_channels) = (self.audio_frames(), self.audio_out_channels());
+        assert!(frame < n_frames, "no audio frame {}", frame);
+        assert!(channel < n_channels, "no audio output channel {}", channel);
+        let idx = self.layout().index(frame, channel, n_frames, n_channels);
+        self.audio_out()[idx] = value;
+    }
+
+    /// Read the analog input of `channel` at `frame`, whatever the layout.
+    ///
+    /// Panics if `frame` or `channel` is out of range.
+    pub fn analog_read(&self, frame: usize, channel: usize) -> f32 {
+        let (n_frames, n_channels) = (self.analog_frames(), self.analog_in_channels());
+        assert!(frame < n_frames, "no analog frame {}", frame);
+        assert!(channel < n_channels, "no analog input channel {}", channel);
+        self.analog_in()[self.layout().index(frame, channel, n_frames, n_channels)]
+    }
+
+    /// Write the analog output of `channel` at `frame`, whatever the layout.
+    ///
+    /// Panics if `frame` or `channel` is out of range.
+    pub fn analog_write(&mut self, frame: usize, channel: usize, value: f32) {
+        let (n_frames, n_channels) = (self.analog_frames(), self.analog_out_channels());
+        assert!(frame < n_frames, "no analog frame {}", frame);
+        assert!(channel < n_channels, "no analog output channel {}", channel);
+        let idx = self.layout().index(frame, channel, n_frames, n_channels);
+        self.analog_out()[idx] = value;
+    }
+
+    /// Iterate over the frames of the audio input
+    pub fn audio_in_frames(&self) -> frames::Frames<'_> {
+        let (n_frames, n_channels) = (self.audio_frames(), self.audio_in_channels());
+        frames::Frames::new(self.audio_in(), n_frames, n_channels, self.layout())
+    }
+
+    /// View a single channel of the audio input
+    ///
+    /// Panics if `channel` is not below `audio_in_channels()`.
+    pub fn audio_in_channel(&self, channel: usize) -> frames::Samples<'_> {
+        let (n_frames, n_channels) = (self.audio_frames(), self.audio_in_channels());
+        assert!(channel < n_channels, "no audio input channel {}", channel);
+        let (start, stride) = self.layout().channel(channel, n_frames, n_channels);
+        frames::Samples::new(self.audio_in(), start, n_frames, stride)
+    }
+
+    /// Iterate mutably over the frames of the audio output
+    pub fn audio_out_frames(&mut self) -> frames::FramesMut<'_> {
+        let (n_frames, n_channels) = (self.audio_frames(), self.audio_out_channels());
+        let layout = self.layout();
+        frames::FramesMut::new(self.audio_out(), n_frames, n_channels, layout)
+    }
+
+    /// View a single channel of the audio output mutably
+    ///
+    /// Panics if `channel` is not below `audio_out_channels()`.
+    pub fn audio_out_channel_mut(&mut self, channel: usize) -> frames::SamplesMut<'_> {
+        let (n_frames, n_channels) = (self.audio_frames(), self.audio_out_channels());
+        assert!(channel < n_channels, "no audio output channel {}", channel);
+        let (start, stride) = self.layout().channel(channel, n_frames, n_channels);
+        frames::SamplesMut::new(self.audio_out(), start, n_frames, stride)
+    }
+
+    /// Iterate over the frames of the analog input
+    pub fn analog_in_frames(&self) -> frames::Frames<'_> {
+        let (n_frames, n_channels) = (self.analog_frames(), self.analog_in_channels());
+        frames::Frames::new(self.analog_in(), n_frames, n_channels, self.layout())
+    }
+
+    /// View a single channel of the analog input
+    ///
+    /// Panics if `channel` is not below `analog_in_channels()`.
+    pub fn analog_in_channel(&self, channel: usize) -> frames::Samples<'_> {
+        let (n_frames, n_channels) = (self.analog_frames(), self.analog_in_channels());
+        assert!(channel < n_channels, "no analog input channel {}", channel);
+        let (start, stride) = self.layout().channel(channel, n_frames, n_channels);
+        frames::Samples::new(self.analog_in(), start, n_frames, stride)
+    }
+
+    /// Iterate mutably over the frames of the analog output
+    pub fn analog_out_frames(&mut self) -> frames::FramesMut<'_> {
+        let (n_frames, n_channels) = (self.analog_frames(), self.analog_out_channels());
+        let layout = self.layout();
+        frames::FramesMut::new(self.analog_out(), n_frames, n_channels, layout)
+    }
+
+    /// View a single channel of the analog output mutably
+    ///
+    /// Panics if `channel` is not below `analog_out_channels()`.
+    pub fn analog_out_channel_mut(&mut self, channel: usize) -> frames::SamplesMut<'_> {
+        let (n_frames, n_channels) = (self.analog_frames(), self.analog_out_channels());
+        assert!(channel < n_channels, "no analog output channel {}", channel);
+        let (start, stride) = self.layout().channel(channel, n_frames, n_channels);
+        frames::SamplesMut::new(self.analog_out(), start, n_frames, stride)
+    }
+
+    /// Iterate over the frames of the multiplexer analog input
+    ///
+    /// The multiplexer buffer is always interleaved, whatever `layout()` is.
+    pub fn multiplexer_analog_in_frames(&self) -> frames::Frames<'_> {
+        let (n_frames, n_channels) = (self.analog_frames(), self.multiplexer_channels());
+        frames::Frames::new(
+            self.multiplexer_analog_in(),
+            n_frames,
+            n_channels,
+            Layout::Interleaved,
+        )
+    }
+
+    /// View a single channel of the multiplexer analog input
+    ///
+    /// Panics if `channel` is not below `multiplexer_channels()`.
+    pub fn multiplexer_analog_in_channel(&self, channel: usize) -> frames::Samples<'_> {
+        let (n_frames, n_channels) = (self.analog_frames(), self.multiplexer_channels());
+        assert!(channel < n_channels, "no multiplexer channel {}", channel);
+        let (start, stride) = Layout::Interleaved.channel(channel, n_frames, n_channels);
+        frames::Samples::new(self.multiplexer_analog_in(), start, n_frames, stride)
+    }
+
+    // Returns the value of a given digital input at the given frame number.
+    // The digital buffer holds one word per frame, so it reads the same for
+    // either layout.
     pub fn digital_read(&self, frame: usize, channel: usize) -> bool {
         let digital = self.digital();
         (digital[frame] >> (channel + 16)) & 1 != 0
@@ -473,6 +946,66 @@ impl Context {
     }
 }
 
+impl Drop for Context {
+    fn drop(&mut self) {
+        if !self.buffers.is_null() {
+            unsafe { drop(Box::from_raw(self.buffers)) };
+        }
+    }
+}
+
+/// The setup, render and cleanup functions run by `Bela`.
+///
+/// Larger apps can implement this on a struct holding all of their state,
+/// rather than splitting it between closures and the data of `AppData`.
+/// The calls are dispatched statically, without going through a `dyn FnMut`.
+///
+/// ```rust,ignore
+/// struct Sawtooth {
+///     phase: f32,
+/// }
+///
+/// impl BelaApp for Sawtooth {
+///     fn render(&mut self, context: &mut Context) {
+///         for samp in context.audio_out().iter_mut() {
+///             *samp = self.phase;
+///             self.phase = (self.phase + 0.01) % 1.;
+///         }
+///     }
+/// }
+///
+/// Bela::new(Sawtooth { phase: 0. }).run(&mut InitSettings::default())
+/// ```
+///
+/// Every `UserData`, such as `AppData`, is also a `BelaApp`.
+pub trait BelaApp {
+    /// Called once before audio starts. Returning an error aborts
+    /// initialization.
+    fn setup(&mut self, _context: &mut Context) -> Result<(), error::Error> {
+        Ok(())
+    }
+
+    /// Called for every block of audio.
+    fn render(&mut self, context: &mut Context);
+
+    /// Called once after audio has stopped.
+    fn cleanup(&mut self, _context: &mut Context) {}
+}
+
+impl<'a, T: UserData<'a>> BelaApp for T {
+    fn setup(&mut self, context: &mut Context) -> Result<(), error::Error> {
+        self.setup_fn(context)
+    }
+
+    fn render(&mut self, context: &mut Context) {
+        self.render_fn(context)
+    }
+
+    fn cleanup(&mut self, context: &mut Context) {
+        self.cleanup_fn(context)
+    }
+}
+
 pub trait UserData<'a> {
     type Data;
 
@@ -560,6 +1093,171 @@ impl<'a, D> UserData<'a> for AppData<'a, D> {
     }
 }
 
+type RenderFn<D> = Box<dyn FnMut(&mut Context, &mut D) + Send>;
+type SetupFn<D> = Box<dyn FnMut(&mut Context, &mut D) -> Result<(), error::Error> + Send>;
+type CleanupFn<D> = Box<dyn FnMut(&mut Context, &mut D) + Send>;
+
+/// User data that owns its callbacks, so that unlike `AppData` it does not
+/// borrow closures declared beforehand, and can be built in one function,
+/// returned, and stored in a struct. Usually built through `Bela::builder`.
+///
+/// The callbacks must be `Send`, so that `OwnedAppData` is `Send` whenever
+/// its data is, and can be built on one thread and run on another. It is a
+/// `BelaApp` itself rather than a `UserData`, as the borrowed callbacks taken
+/// by `UserData` need not be `Send`.
+pub struct OwnedAppData<D> {
+    pub data: D,
+    render: RenderFn<D>,
+    setup: Option<SetupFn<D>>,
+    cleanup: Option<CleanupFn<D>>,
+}
+
+impl<D> OwnedAppData<D> {
+    pub fn new<R>(data: D, render: R) -> OwnedAppData<D>
+    where
+        R: FnMut(&mut Context, &mut D) + Send + 'static,
+    {
+        OwnedAppData {
+            data,
+            render: Box::new(render),
+            setup: None,
+            cleanup: None,
+        }
+    }
+}
+
+impl<D> OwnedAppData<D> {
+    /// Replace the render callback. Unlike `UserData::set_render_fn`, the
+    /// closure is moved in, so it need not outlive the call.
+    pub fn set_render_fn<F>(&mut self, render: F)
+    where
+        F: FnMut(&mut Context, &mut D) + Send + 'static,
+    {
+        self.render = Box::new(render);
+    }
+
+    /// Replace the setup callback.
+    pub fn set_setup_fn<F>(&mut self, setup: F)
+    where
+        F: FnMut(&mut Context, &mut D) -> Result<(), error::Error> + Send + 'static,
+    {
+        self.setup = Some(Box::new(setup));
+    }
+
+    /// Replace the cleanup callback.
+    pub fn set_cleanup_fn<F>(&mut self, cleanup: F)
+    where
+        F: FnMut(&mut Context, &mut D) + Send + 'static,
+    {
+        self.cleanup = Some(Box::new(cleanup));
+    }
+}
+
+impl<D> BelaApp for OwnedAppData<D> {
+    fn setup(&mut self, context: &mut Context) -> Result<(), error::Error> {
+        match self.setup {
+            Some(ref mut f) => f(context, &mut self.data),
+            None => Ok(()),
+        }
+    }
+
+    fn render(&mut self, context: &mut Context) {
+        (self.render)(context, &mut self.data)
+    }
+
+    fn cleanup(&mut self, context: &mut Context) {
+        if let Some(ref mut f) = self.cleanup {
+            f(context, &mut self.data)
+        }
+    }
+}
+
+/// Builds a `Bela` running owned closures.
+///
+/// ```rust,ignore
+/// let mut phase = 0.;
+/// let mut bela = Bela::builder()
+///     .setup(|_context, _| {
+///         println!("Setting up");
+///         Ok(())
+///     })
+///     .render(move |context, _| {
+///         for samp in context.audio_out().iter_mut() {
+///             *samp = phase;
+///             phase = (phase + 0.01) % 1.;
+///         }
+///     })
+///     .build();
+/// bela.run(&mut InitSettings::default())
+/// ```
+///
+/// State shared by the callbacks is passed to `AppBuilder::new` instead, and
+/// handed to every callback along with the `Context`. Without a render
+/// callback, the outputs are left silent.
+pub struct AppBuilder<D> {
+    data: D,
+    render: Option<RenderFn<D>>,
+    setup: Option<SetupFn<D>>,
+    cleanup: Option<CleanupFn<D>>,
+}
+
+impl<D: 'static> AppBuilder<D> {
+    /// Start building an app whose callbacks share `data`.
+    pub fn new(data: D) -> AppBuilder<D> {
+        AppBuilder {
+            data,
+            render: None,
+            setup: None,
+            cleanup: None,
+        }
+    }
+
+    pub fn render<F>(mut self, render: F) -> AppBuilder<D>
+    where
+        F: FnMut(&mut Context, &mut D) + Send + 'static,
+    {
+        self.render = Some(Box::new(render));
+        self
+    }
+
+    pub fn setup<F>(mut self, setup: F) -> AppBuilder<D>
+    where
+        F: FnMut(&mut Context, &mut D) -> Result<(), error::Error> + Send + 'static,
+    {
+        self.setup = Some(Box::new(setup));
+        self
+    }
+
+    pub fn cleanup<F>(mut self, cleanup: F) -> AppBuilder<D>
+    where
+        F: FnMut(&mut Context, &mut D) + Send + 'static,
+    {
+        self.cleanup = Some(Box::new(cleanup));
+        self
+    }
+
+    /// Build the user data, to run on any backend through `Bela::with_backend`.
+    pub fn build_user_data(self) -> OwnedAppData<D> {
+        OwnedAppData {
+            data: self.data,
+            render: self.render.unwrap_or_else(|| Box::new(|_, _| ())),
+            setup: self.setup,
+            cleanup: self.cleanup,
+        }
+    }
+
+    /// Build a `Bela` running on libbela.
+    #[cfg(feature = "libbela")]
+    pub fn build(self) -> Bela<OwnedAppData<D>> {
+        Bela::new(self.build_user_data())
+    }
+
+    /// Build a `Bela` running on `backend`.
+    pub fn build_with_backend<B: Backend>(self, backend: B) -> Bela<OwnedAppData<D>, B> {
+        Bela::with_backend(self.build_user_data(), backend)
+    }
+}
+
 /// Safe wrapper for `BelaInitSettings`, which sets initial parameters for the
 /// Bela system.
 pub struct InitSettings {
@@ -567,6 +1265,47 @@ pub struct InitSettings {
 }
 
 impl InitSettings {
+    /// The default settings of libbela, filled in without calling into it,
+    /// for the backends that run off the board. The board is left for libbela
+    /// to detect, and the stack sizes, which only libbela uses, are 0.
+    pub fn software_default() -> InitSettings {
+        InitSettings {
+            settings: BelaInitSettings {
+                periodSize: 16,
+                useAnalog: 1,
+                useDigital: 1,
+                numAnalogInChannels: 8,
+                numAnalogOutChannels: 8,
+                numDigitalChannels: 16,
+                beginMuted: 0,
+                dacLevel: 0.,
+                adcLevel: -6.,
+                pgaGain: [16., 16.],
+                headphoneLevel: -6.,
+                numMuxChannels: 0,
+                audioExpanderInputs: 0,
+                audioExpanderOutputs: 0,
+                pruNumber: 1,
+                pruFilename: [0; 256],
+                detectUnderruns: 1,
+                verbose: 0,
+                enableLED: 1,
+                stopButtonPin: 115,
+                highPerformanceMode: 0,
+                interleave: 1,
+                analogOutputsPersist: 1,
+                uniformSampleRate: 0,
+                audioThreadStackSize: 0,
+                auxiliaryTaskStackSize: 0,
+                setup: None,
+                render: None,
+                cleanup: None,
+                ampMutePin: 61,
+                board: sys::BelaHw_BelaHw_NoHw,
+            },
+        }
+    }
+
     pub fn settings_ptr(&mut self) -> *mut BelaInitSettings {
         &mut self.settings
     }
@@ -810,8 +1549,16 @@ impl InitSettings {
     }
 
     /// Get user selected board to work with (as opposed to detected hardware).
+    ///
+    /// Panics if the board is not one this crate knows; see `try_board`.
     pub fn board(&self) -> BelaHw {
-        BelaHw::from_i32(self.settings.board).expect("unexpected board type")
+        self.try_board().expect("unexpected board type")
+    }
+
+    /// Get user selected board to work with, or `None` if it is not one this
+    /// crate knows.
+    pub fn try_board(&self) -> Option<BelaHw> {
+        BelaHw::from_i32(self.settings.board)
     }
 
     /// Set user selected board to work with (as opposed to detected hardware).
@@ -821,6 +1568,9 @@ impl InitSettings {
 }
 
 impl Default for InitSettings {
+    /// The default settings of libbela, or `software_default` without the
+    /// `libbela` feature.
+    #[cfg(feature = "libbela")]
     fn default() -> InitSettings {
         let settings = unsafe {
             let mut settings = mem::MaybeUninit::<BelaInitSettings>::uninit();
@@ -830,4 +1580,9 @@ impl Default for InitSettings {
 
         InitSettings { settings }
     }
+
+    #[cfg(not(feature = "libbela"))]
+    fn default() -> InitSettings {
+        InitSettings::software_default()
+    }
 }
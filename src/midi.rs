@@ -0,0 +1,804 @@
+//! MIDI input and output over ALSA rawmidi devices.
+//!
+//! A `Midi` port is opened in `setup`, or before the `Bela` is created, and
+//! then read and written from `render`. The device itself is read and written
+//! by two threads owned by the port, which exchange raw bytes with it through
+//! lock-free queues polled every millisecond, so `Midi::read` and
+//! `Midi::write` never block, allocate or make a system call.
+//!
+//! ```rust,ignore
+//! let mut midi = Midi::open(midi::DEFAULT_PORT)?;
+//! // In render:
+//! while let Some(message) = midi.read() {
+//!     if let Message::NoteOn { note, velocity, .. } = message {
+//!         // ...
+//!     }
+//! }
+//! ```
+//!
+//! `Midi::loopback` and `Midi::virtual_port` create ports without a device,
+//! for testing MIDI handling off the board.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time;
+
+use libc;
+
+use ring;
+
+/// The port of the MIDI input and output on the Bela cape, as used by the
+/// Bela core code.
+pub const DEFAULT_PORT: &str = "hw:1,0,0";
+
+const QUEUE_CAPACITY: usize = 1024;
+const SYSEX_CAPACITY: usize = 1024;
+
+/// A parsed MIDI message. Channels are numbered from 0 to 15.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    /// Sent with a velocity of 0, this usually means a note off.
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// The bend ranges from -8192 to 8191, with 0 at the center.
+    PitchBend {
+        channel: u8,
+        bend: i16,
+    },
+    /// The bytes between the `0xF0` and `0xF7` delimiters.
+    SysEx(&'a [u8]),
+}
+
+impl<'a> Message<'a> {
+    /// The channel of a channel message, or `None` for system exclusive.
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            Message::NoteOff { channel, .. }
+            | Message::NoteOn { channel, .. }
+            | Message::PolyPressure { channel, .. }
+            | Message::ControlChange { channel, .. }
+            | Message::ProgramChange { channel, .. }
+            | Message::ChannelPressure { channel, .. }
+            | Message::PitchBend { channel, .. } => Some(channel),
+            Message::SysEx(_) => None,
+        }
+    }
+
+    /// Encode a channel message into `out`, returning the number of bytes
+    /// used, or `None` for system exclusive.
+    fn encode(&self, out: &mut [u8; 3]) -> Option<usize> {
+        let (status, channel, data) = match *self {
+            Message::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (0x80, channel, [note, velocity]),
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (0x90, channel, [note, velocity]),
+            Message::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => (0xA0, channel, [note, pressure]),
+            Message::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (0xB0, channel, [controller, value]),
+            Message::ProgramChange { channel, program } => (0xC0, channel, [program, 0]),
+            Message::ChannelPressure { channel, pressure } => (0xD0, channel, [pressure, 0]),
+            Message::PitchBend { channel, bend } => {
+                let bend = (i32::from(bend) + 8192).clamp(0, 0x3FFF) as u16;
+                (0xE0, channel, [(bend & 0x7F) as u8, (bend >> 7) as u8])
+            }
+            Message::SysEx(_) => return None,
+        };
+        out[0] = status | (channel & 0x0F);
+        out[1] = data[0] & 0x7F;
+        out[2] = data[1] & 0x7F;
+        Some(1 + data_len(status))
+    }
+}
+
+/// Number of data bytes following a channel message status.
+fn data_len(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+/// Queue `message` on `output`, in full or not at all.
+fn push_message(output: &mut ring::Producer<u8>, message: &Message) -> bool {
+    let mut bytes = [0; 3];
+    match message.encode(&mut bytes) {
+        Some(len) => output.push_slice(&bytes[..len]),
+        None => {
+            let data = match *message {
+                Message::SysEx(data) => data,
+                _ => unreachable!(),
+            };
+            if output.free() < data.len() + 2 {
+                return false;
+            }
+            let _ = output.push(0xF0);
+            for &byte in data {
+                let _ = output.push(byte & 0x7F);
+            }
+            let _ = output.push(0xF7);
+            true
+        }
+    }
+}
+
+/// What the parser found after the last byte it was fed.
+#[derive(Copy, Clone)]
+enum Parsed {
+    Channel(u8, [u8; 2]),
+    SysEx,
+}
+
+/// Incremental parser handling running status, interleaved real-time bytes
+/// and system exclusive messages into a preallocated buffer.
+struct Parser {
+    status: u8,
+    data: [u8; 2],
+    count: usize,
+    sysex: Box<[u8]>,
+    sysex_len: usize,
+    in_sysex: bool,
+}
+
+impl Parser {
+    fn new() -> Parser {
+        Parser {
+            status: 0,
+            data: [0; 2],
+            count: 0,
+            sysex: vec![0; SYSEX_CAPACITY].into_boxed_slice(),
+            sysex_len: 0,
+            in_sysex: false,
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<Parsed> {
+        if byte >= 0xF8 {
+            // Real-time messages may appear anywhere, and are ignored.
+            return None;
+        }
+
+        if byte == 0xF7 {
+            let complete = self.in_sysex && self.sysex_len <= self.sysex.len();
+            self.in_sysex = false;
+            return if complete { Some(Parsed::SysEx) } else { None };
+        }
+
+        if byte & 0x80 != 0 {
+            // Any other status byte ends an unterminated system exclusive
+            // message, which is dropped.
+            self.in_sysex = byte == 0xF0;
+            self.sysex_len = 0;
+            self.count = 0;
+            // System common messages cancel running status, and are ignored
+            // along with their data bytes.
+            self.status = if byte < 0xF0 { byte } else { 0 };
+            return None;
+        }
+
+        if self.in_sysex {
+            // Messages that do not fit are counted past the end, and dropped.
+            if self.sysex_len < self.sysex.len() {
+                self.sysex[self.sysex_len] = byte;
+            }
+            self.sysex_len = self.sysex_len.saturating_add(1);
+            return None;
+        }
+
+        if self.status == 0 {
+            return None;
+        }
+        self.data[self.count] = byte;
+        self.count += 1;
+        if self.count < data_len(self.status) {
+            return None;
+        }
+        self.count = 0;
+        Some(Parsed::Channel(self.status, self.data))
+    }
+
+    fn message(&self, parsed: Parsed) -> Message<'_> {
+        let (status, data) = match parsed {
+            Parsed::Channel(status, data) => (status, data),
+            Parsed::SysEx => return Message::SysEx(&self.sysex[..self.sysex_len]),
+        };
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            0x80 => Message::NoteOff {
+                channel,
+                note: data[0],
+                velocity: data[1],
+            },
+            0x90 => Message::NoteOn {
+                channel,
+                note: data[0],
+                velocity: data[1],
+            },
+            0xA0 => Message::PolyPressure {
+                channel,
+                note: data[0],
+                pressure: data[1],
+            },
+            0xB0 => Message::ControlChange {
+                channel,
+                controller: data[0],
+                value: data[1],
+            },
+            0xC0 => Message::ProgramChange {
+                channel,
+                program: data[0],
+            },
+            0xD0 => Message::ChannelPressure {
+                channel,
+                pressure: data[0],
+            },
+            _ => Message::PitchBend {
+                channel,
+                bend: ((i16::from(data[1]) << 7) | i16::from(data[0])) - 8192,
+            },
+        }
+    }
+}
+
+/// A MIDI port, read and written from the render thread.
+pub struct Midi {
+    input: ring::Consumer<u8>,
+    output: ring::Producer<u8>,
+    parser: Parser,
+    device: Option<Device>,
+}
+
+/// The threads moving bytes between a device and the queues of a `Midi`.
+struct Device {
+    stop: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Midi {
+    /// Open an ALSA rawmidi port, either by its ALSA name, such as
+    /// `"hw:1,0,0"`, or by the path of its device node.
+    ///
+    /// Only subdevice 0 can be opened by name.
+    pub fn open(port: &str) -> io::Result<Midi> {
+        let path = device_path(port)?;
+        let input_file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)?;
+        // Non-blocking too, so that a stalled device cannot hang `Drop`
+        let output_file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)?;
+
+        let (input_tx, input_rx) = ring::channel(QUEUE_CAPACITY);
+        let (output_tx, output_rx) = ring::channel(QUEUE_CAPACITY);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name(format!("bela-midi-in {}", port))
+                .spawn(move || read_device(input_file, input_tx, &stop))?
+        };
+        let writer = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name(format!("bela-midi-out {}", port))
+                .spawn(move || write_device(output_file, output_rx, &stop))
+        };
+        let writer = match writer {
+            Ok(writer) => writer,
+            Err(err) => {
+                stop.store(true, Ordering::SeqCst);
+                let _ = reader.join();
+                return Err(err);
+            }
+        };
+
+        Ok(Midi {
+            input: input_rx,
+            output: output_tx,
+            parser: Parser::new(),
+            device: Some(Device {
+                stop,
+                reader,
+                writer,
+            }),
+        })
+    }
+
+    /// Create a port whose output is read back as its input.
+    pub fn loopback() -> Midi {
+        let (tx, rx) = ring::channel(QUEUE_CAPACITY);
+        Midi {
+            input: rx,
+            output: tx,
+            parser: Parser::new(),
+            device: None,
+        }
+    }
+
+    /// Create a port connected to a `VirtualDevice`, which plays the part of
+    /// the device on the other end of the cable.
+    pub fn virtual_port() -> (Midi, VirtualDevice) {
+        let (input_tx, input_rx) = ring::channel(QUEUE_CAPACITY);
+        let (output_tx, output_rx) = ring::channel(QUEUE_CAPACITY);
+        let midi = Midi {
+            input: input_rx,
+            output: output_tx,
+            parser: Parser::new(),
+            device: None,
+        };
+        let device = VirtualDevice {
+            input: input_tx,
+            output: output_rx,
+        };
+        (midi, device)
+    }
+
+    /// Read the next complete message received, if any. Never blocks.
+    ///
+    /// System exclusive messages longer than 1024 bytes, and system common and
+    /// real-time messages, are skipped.
+    pub fn read(&mut self) -> Option<Message<'_>> {
+        loop {
+            let byte = self.input.pop()?;
+            if let Some(parsed) = self.parser.feed(byte) {
+                return Some(self.parser.message(parsed));
+            }
+        }
+    }
+
+    /// Queue `message` to be sent. Never blocks.
+    ///
+    /// Returns `false`, without queueing anything, if there is no room left
+    /// for the whole message.
+    pub fn write(&mut self, message: &Message) -> bool {
+        push_message(&mut self.output, message)
+    }
+
+    /// Queue raw bytes to be sent as they are. Never blocks.
+    ///
+    /// Returns `false`, without queueing anything, if there is no room left
+    /// for all of `bytes`.
+    pub fn write_raw(&mut self, bytes: &[u8]) -> bool {
+        self.output.push_slice(bytes)
+    }
+}
+
+impl Drop for Midi {
+    fn drop(&mut self) {
+        if let Some(device) = self.device.take() {
+            device.stop.store(true, Ordering::SeqCst);
+            let _ = device.reader.join();
+            let _ = device.writer.join();
+        }
+    }
+}
+
+/// The other end of a port created by `Midi::virtual_port`.
+pub struct VirtualDevice {
+    input: ring::Producer<u8>,
+    output: ring::Consumer<u8>,
+}
+
+impl VirtualDevice {
+    /// Send `message` to the port, returning `false` if its input is full.
+    pub fn send(&mut self, message: &Message) -> bool {
+        push_message(&mut self.input, message)
+    }
+
+    /// Send raw bytes to the port, returning `false` if its input is full.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> bool {
+        self.input.push_slice(bytes)
+    }
+
+    /// Take the bytes written by the port into `buf`, returning how many were
+    /// taken.
+    pub fn receive(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buf.len() {
+            match self.output.pop() {
+                Some(byte) => buf[len] = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Map an ALSA port name to the device node of its rawmidi device.
+fn device_path(port: &str) -> io::Result<PathBuf> {
+    if !port.starts_with("hw:") {
+        return Ok(PathBuf::from(port));
+    }
+
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported MIDI port {:?}", port),
+        )
+    };
+    let mut numbers = port[3..].split(',').map(|n| n.trim().parse::<u32>());
+    let card = numbers.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+    let device = match numbers.next() {
+        Some(device) => device.map_err(|_| invalid())?,
+        None => 0,
+    };
+    match numbers.next() {
+        None | Some(Ok(0)) => {}
+        Some(_) => return Err(invalid()),
+    }
+    if numbers.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(PathBuf::from(format!("/dev/snd/midiC{}D{}", card, device)))
+}
+
+/// Queue bytes read from the device on `input`.
+///
+/// Bytes that do not fit are dropped, as the render thread is not keeping up
+/// anyway. Once a byte is dropped, everything up to the next status byte is
+/// dropped too, so that the parser never sees part of a message run into the
+/// next. A system exclusive end does not count, as it would complete a
+/// message missing some of its bytes.
+fn queue_received(input: &mut ring::Producer<u8>, bytes: &[u8], resync: &mut bool) {
+    for &byte in bytes {
+        if *resync {
+            let status = byte & 0x80 != 0 && byte != 0xF7 && byte < 0xF8;
+            if !status {
+                continue;
+            }
+            *resync = false;
+        }
+        if input.push(byte).is_err() {
+            *resync = true;
+        }
+    }
+}
+
+fn read_device(mut file: File, mut input: ring::Producer<u8>, stop: &AtomicBool) {
+    let mut buf = [0; 256];
+    let mut resync = false;
+    while !stop.load(Ordering::SeqCst) {
+        match file.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => queue_received(&mut input, &buf[..len], &mut resync),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// Polls the queue rather than being woken by `Midi::write`, so that render
+// never makes a system call.
+fn write_device(mut file: File, mut output: ring::Consumer<u8>, stop: &AtomicBool) {
+    let mut buf = [0; 256];
+    loop {
+        let mut len = 0;
+        while len < buf.len() {
+            match output.pop() {
+                Some(byte) => buf[len] = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        if len > 0 {
+            if !write_all(&mut file, &buf[..len], stop) {
+                return;
+            }
+            continue;
+        }
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(time::Duration::from_millis(1));
+    }
+}
+
+/// Write all of `buf` to the non-blocking `file`, waiting for the device to
+/// take it until `stop` is set. Returns `false` if it was not all written.
+fn write_all<W: Write>(file: &mut W, mut buf: &[u8], stop: &AtomicBool) -> bool {
+    while !buf.is_empty() {
+        match file.write(buf) {
+            Ok(0) => return false,
+            Ok(len) => buf = &buf[len..],
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if stop.load(Ordering::SeqCst) {
+                    return false;
+                }
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<String> {
+        let mut parser = Parser::new();
+        let mut messages = Vec::new();
+        for &byte in bytes {
+            if let Some(parsed) = parser.feed(byte) {
+                messages.push(format!("{:?}", parser.message(parsed)));
+            }
+        }
+        messages
+    }
+
+    fn debug(message: Message) -> String {
+        format!("{:?}", message)
+    }
+
+    #[test]
+    fn channel_messages() {
+        let messages = parse(&[0x91, 60, 100, 0xB2, 7, 127, 0xC3, 5, 0xE0, 0, 0x40]);
+        assert_eq!(
+            messages,
+            vec![
+                debug(Message::NoteOn {
+                    channel: 1,
+                    note: 60,
+                    velocity: 100
+                }),
+                debug(Message::ControlChange {
+                    channel: 2,
+                    controller: 7,
+                    value: 127
+                }),
+                debug(Message::ProgramChange {
+                    channel: 3,
+                    program: 5
+                }),
+                debug(Message::PitchBend {
+                    channel: 0,
+                    bend: 0
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn running_status_and_real_time() {
+        // A clock byte in the middle of a message, then two more notes
+        // without a status byte
+        let messages = parse(&[0x80, 60, 0xF8, 0, 61, 0, 62, 0]);
+        let notes: Vec<String> = (60..63)
+            .map(|note| {
+                debug(Message::NoteOff {
+                    channel: 0,
+                    note,
+                    velocity: 0,
+                })
+            })
+            .collect();
+        assert_eq!(messages, notes);
+    }
+
+    #[test]
+    fn system_common_cancels_running_status() {
+        // A song select, with its data byte, ends the running status
+        assert_eq!(parse(&[0x90, 60, 1, 0xF3, 2, 61, 1]).len(), 1);
+    }
+
+    #[test]
+    fn sysex() {
+        assert_eq!(
+            parse(&[0xF0, 1, 2, 3, 0xF7]),
+            vec![debug(Message::SysEx(&[1, 2, 3]))]
+        );
+        // Unterminated, then too long
+        assert!(parse(&[0xF0, 1, 2, 0x90]).is_empty());
+        let mut long = vec![0xF0];
+        long.extend(vec![1; SYSEX_CAPACITY + 1]);
+        long.push(0xF7);
+        assert!(parse(&long).is_empty());
+    }
+
+    #[test]
+    fn loopback_round_trip() {
+        let mut midi = Midi::loopback();
+        let sent = [
+            Message::PitchBend {
+                channel: 15,
+                bend: -8192,
+            },
+            Message::PitchBend {
+                channel: 0,
+                bend: 8191,
+            },
+            Message::ChannelPressure {
+                channel: 4,
+                pressure: 90,
+            },
+            Message::SysEx(&[0x7E, 0x01]),
+        ];
+        for message in &sent {
+            assert!(midi.write(message));
+        }
+        for message in &sent {
+            assert_eq!(midi.read().as_ref(), Some(message));
+        }
+        assert_eq!(midi.read(), None);
+    }
+
+    #[test]
+    fn virtual_port() {
+        let (mut midi, mut device) = Midi::virtual_port();
+        assert!(device.send_raw(&[0x95, 64]));
+        assert_eq!(midi.read(), None);
+        assert!(device.send_raw(&[127]));
+        assert_eq!(
+            midi.read(),
+            Some(Message::NoteOn {
+                channel: 5,
+                note: 64,
+                velocity: 127
+            })
+        );
+
+        assert!(midi.write_raw(&[0xFA]));
+        let mut buf = [0; 4];
+        assert_eq!(device.receive(&mut buf), 1);
+        assert_eq!(buf[0], 0xFA);
+    }
+
+    #[test]
+    fn full_queue_refuses_whole_messages() {
+        let mut midi = Midi::loopback();
+        while midi.write_raw(&[0xF8; 2]) {}
+        let note = Message::NoteOn {
+            channel: 0,
+            note: 60,
+            velocity: 1,
+        };
+        assert!(!midi.write(&note));
+    }
+
+    #[test]
+    fn port_names() {
+        assert_eq!(
+            device_path("hw:1,0,0").unwrap(),
+            PathBuf::from("/dev/snd/midiC1D0")
+        );
+        assert_eq!(
+            device_path("hw:2,3").unwrap(),
+            PathBuf::from("/dev/snd/midiC2D3")
+        );
+        assert_eq!(
+            device_path("/dev/midi1").unwrap(),
+            PathBuf::from("/dev/midi1")
+        );
+        assert!(device_path("hw:1,0,1").is_err());
+        assert!(device_path("hw:x").is_err());
+    }
+
+    #[test]
+    fn overflow_resyncs_on_status() {
+        let (mut tx, mut rx) = ring::channel(4);
+        let mut resync = false;
+        // The second note only half fits, and the rest of it is dropped along
+        // with the data of the third, sent with running status
+        queue_received(&mut tx, &[0x90, 60, 1, 0x91, 61, 1, 62, 1], &mut resync);
+        assert!(resync);
+        let mut parser = Parser::new();
+        let mut notes = Vec::new();
+        while let Some(byte) = rx.pop() {
+            if let Some(parsed) = parser.feed(byte) {
+                notes.push(format!("{:?}", parser.message(parsed)));
+            }
+        }
+        assert_eq!(notes.len(), 1);
+
+        // A clock byte or the end of a system exclusive message does not
+        // resync, the next status byte does
+        queue_received(&mut tx, &[0xF8, 0xF7, 63, 0x92, 64, 1], &mut resync);
+        assert!(!resync);
+        while let Some(byte) = rx.pop() {
+            if let Some(parsed) = parser.feed(byte) {
+                notes.push(format!("{:?}", parser.message(parsed)));
+            }
+        }
+        assert_eq!(
+            notes[1],
+            debug(Message::NoteOn {
+                channel: 2,
+                note: 64,
+                velocity: 1
+            })
+        );
+        assert_eq!(notes.len(), 2);
+    }
+
+    /// Takes at most one byte per write, and then blocks.
+    struct Stalled {
+        written: Vec<u8>,
+        budget: usize,
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.budget -= 1;
+            self.written.push(buf[0]);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_gives_up_on_stop() {
+        let stop = AtomicBool::new(false);
+        let mut device = Stalled {
+            written: Vec::new(),
+            budget: 3,
+        };
+        assert!(write_all(&mut device, &[1, 2, 3], &stop));
+        assert_eq!(device.written, [1, 2, 3]);
+
+        // A stalled device does not hold up a port being dropped
+        stop.store(true, Ordering::SeqCst);
+        device.budget = 1;
+        assert!(!write_all(&mut device, &[4, 5], &stop));
+        assert_eq!(device.written, [1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Bounded single-producer, single-consumer queue.
+//!
+//! Both ends are wait-free and never allocate after the queue is created, so
+//! either end can be used from the render thread.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    // Total number of values pushed and popped. Only the producer stores
+    // `head`, and only the consumer stores `tail`.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Each slot is only accessed by one end at a time, as handed over by the
+// release stores of `head` and `tail`.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn slot(&self, idx: usize) -> *mut MaybeUninit<T> {
+        self.slots[idx % self.slots.len()].get()
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let head = *self.head.get_mut();
+        for idx in tail..head {
+            unsafe { (*self.slot(idx)).as_mut_ptr().drop_in_place() };
+        }
+    }
+}
+
+/// Create a queue holding up to `capacity` values.
+pub(crate) fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "queue capacity must not be zero");
+    let slots = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+    let shared = Arc::new(Shared {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+/// Sending end of a queue.
+pub(crate) struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Producer<T> {
+    /// Number of values that can be pushed before the queue is full.
+    pub(crate) fn free(&self) -> usize {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        self.shared.slots.len() - head.wrapping_sub(tail)
+    }
+
+    /// Push `value`, or hand it back if the queue is full.
+    pub(crate) fn push(&mut self, value: T) -> Result<(), T> {
+        if self.free() == 0 {
+            return Err(value);
+        }
+        let head = self.shared.head.load(Ordering::Relaxed);
+        unsafe { (*self.shared.slot(head)).as_mut_ptr().write(value) };
+        self.shared
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T: Copy> Producer<T> {
+    /// Push all of `values`, or none of them if they do not fit.
+    pub(crate) fn push_slice(&mut self, values: &[T]) -> bool {
+        if self.free() < values.len() {
+            return false;
+        }
+        for &value in values {
+            let _ = self.push(value);
+        }
+        true
+    }
+}
+
+/// Receiving end of a queue.
+pub(crate) struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Consumer<T> {
+    /// Number of values waiting to be popped.
+    pub(crate) fn len(&self) -> usize {
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        head.wrapping_sub(tail)
+    }
+
+    /// Pop the oldest value, if any.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        if self.len() == 0 {
+            return None;
+        }
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let value = unsafe { (*self.shared.slot(tail)).as_ptr().read() };
+        self.shared
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fifo_and_capacity() {
+        let (mut tx, mut rx) = channel(3);
+        assert_eq!(tx.free(), 3);
+        for value in 0..3 {
+            assert_eq!(tx.push(value), Ok(()));
+        }
+        assert_eq!(tx.push(3), Err(3));
+        assert_eq!(rx.len(), 3);
+        assert_eq!(rx.pop(), Some(0));
+        assert_eq!(tx.free(), 1);
+
+        // Wrap around the end of the slots a few times
+        for value in 3..10 {
+            assert_eq!(tx.push(value), Ok(()));
+            assert_eq!(rx.pop(), Some(value - 2));
+        }
+        assert_eq!(rx.pop(), Some(8));
+        assert_eq!(rx.pop(), Some(9));
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn push_slice_is_all_or_nothing() {
+        let (mut tx, mut rx) = channel(4);
+        assert!(tx.push_slice(&[1, 2, 3]));
+        assert!(!tx.push_slice(&[4, 5]));
+        assert_eq!(rx.len(), 3);
+        assert!(tx.push_slice(&[4]));
+        let values: Vec<i32> = (0..4).filter_map(|_| rx.pop()).collect();
+        assert_eq!(values, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn unpopped_values_are_dropped() {
+        let value = Arc::new(());
+        {
+            let (mut tx, mut rx) = channel(4);
+            for _ in 0..3 {
+                tx.push(value.clone()).unwrap();
+            }
+            rx.pop();
+            assert_eq!(Arc::strong_count(&value), 3);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn across_threads() {
+        let (mut tx, mut rx) = channel(16);
+        let producer = thread::spawn(move || {
+            for value in 0..10_000u32 {
+                let mut value = value;
+                while let Err(back) = tx.push(value) {
+                    value = back;
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 10_000 {
+            match rx.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,501 @@
+//! Streams signals to the oscilloscope of the Bela IDE.
+//!
+//! A `Scope` is set up in `setup`, with the number of signals it shows, and
+//! then fed one frame at a time from `render`. `Scope::log` only copies the
+//! frame into a lock-free queue; a thread owned by the scope waits for the
+//! trigger, collects a frame width of samples and sends them to the IDE.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let scope = Scope::setup(context, 2)?;
+//! scope.set_trigger(Trigger {
+//!     mode: TriggerMode::Normal,
+//!     channel: 0,
+//!     direction: TriggerDirection::Rising,
+//!     level: 0.,
+//! });
+//! // In render:
+//! for frame in 0..context.audio_frames() {
+//!     scope.log(&[input[frame], output[frame]]);
+//! }
+//! ```
+//!
+//! The IDE is told the number of channels and the sample rate with an OSC
+//! `/scope-setup` message on `CONTROL_PORT`. Each trace is then sent to
+//! `DATA_PORT` as one UDP packet of little-endian `f32`s, the frame width of
+//! samples of the first channel followed by those of the next. Settings
+//! changed in the IDE are not read back; the trigger and frame width are set
+//! with `Scope::set_trigger` and `Scope::set_frame_width`.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time;
+
+use ring;
+use Context;
+
+/// Port the IDE reads `/scope-setup` messages from.
+pub const CONTROL_PORT: u16 = 8676;
+/// Port the IDE reads traces from.
+pub const DATA_PORT: u16 = 8677;
+/// Frames in a trace, unless changed with `Scope::set_frame_width`.
+pub const DEFAULT_FRAME_WIDTH: usize = 1280;
+
+/// Largest payload of a UDP packet.
+const MAX_PACKET: usize = 65_507;
+/// Frames the queue between render and the sending thread holds.
+const QUEUE_FRAMES: usize = 8192;
+
+/// When a trace starts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Start a trace as soon as the previous one is sent.
+    Off,
+    /// Start a trace on the trigger, or after a frame width without one.
+    Auto,
+    /// Only start a trace on the trigger.
+    Normal,
+}
+
+/// Which crossing of the trigger level starts a trace.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerDirection {
+    Rising,
+    Falling,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Trigger {
+    pub mode: TriggerMode,
+    /// Channel compared with the level. Channels the scope does not have are
+    /// treated as `TriggerMode::Off`.
+    pub channel: usize,
+    pub direction: TriggerDirection,
+    pub level: f32,
+}
+
+impl Default for Trigger {
+    fn default() -> Trigger {
+        Trigger {
+            mode: TriggerMode::Auto,
+            channel: 0,
+            direction: TriggerDirection::Rising,
+            level: 0.,
+        }
+    }
+}
+
+/// A scope, fed from the render thread.
+pub struct Scope {
+    channels: usize,
+    sample_rate: f32,
+    queue: ring::Producer<f32>,
+    settings: Arc<Settings>,
+    dropped: u64,
+    stop: Arc<AtomicBool>,
+    sender: Option<JoinHandle<()>>,
+}
+
+impl Scope {
+    /// Set up a scope showing `channels` signals at the audio sample rate of
+    /// `context`, streaming to the IDE on this board.
+    pub fn setup(context: &Context, channels: usize) -> io::Result<Scope> {
+        let localhost = Ipv4Addr::new(127, 0, 0, 1);
+        Scope::connect(
+            channels,
+            context.audio_sample_rate(),
+            SocketAddrV4::new(localhost, CONTROL_PORT).into(),
+            SocketAddrV4::new(localhost, DATA_PORT).into(),
+        )
+    }
+
+    /// Set up a scope sending its `/scope-setup` message to `control`, and its
+    /// traces to `data`.
+    pub fn connect(
+        channels: usize,
+        sample_rate: f32,
+        control: SocketAddr,
+        data: SocketAddr,
+    ) -> io::Result<Scope> {
+        if channels == 0 || channels * 4 > MAX_PACKET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a scope cannot show {} channels", channels),
+            ));
+        }
+
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.send_to(&setup_message(channels, sample_rate), control)?;
+
+        let (tx, rx) = ring::channel(QUEUE_FRAMES * channels);
+        let settings = Arc::new(Settings::new());
+        settings.frame_width.store(
+            DEFAULT_FRAME_WIDTH.min(max_frame_width(channels)),
+            Ordering::Relaxed,
+        );
+        let stop = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let settings = settings.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("bela-scope".to_owned())
+                .spawn(move || {
+                    let mut tracer = Tracer::new(channels);
+                    send_traces(&socket, data, rx, &mut tracer, &settings, &stop)
+                })?
+        };
+
+        Ok(Scope {
+            channels,
+            sample_rate,
+            queue: tx,
+            settings,
+            dropped: 0,
+            stop,
+            sender: Some(sender),
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    /// Log one frame, one value per channel. Never blocks or allocates.
+    ///
+    /// Channels missing from `frame` are logged as 0, and extra values are
+    /// ignored. Returns `false`, and counts the frame as dropped, if the
+    /// sending thread has fallen behind.
+    pub fn log(&mut self, frame: &[f32]) -> bool {
+        if self.queue.free() < self.channels {
+            self.dropped += 1;
+            return false;
+        }
+        for channel in 0..self.channels {
+            let _ = self.queue.push(frame.get(channel).cloned().unwrap_or(0.));
+        }
+        true
+    }
+
+    /// Number of frames dropped by `log`.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Change the trigger, from the next trace on.
+    pub fn set_trigger(&self, trigger: Trigger) {
+        self.settings.set_trigger(trigger);
+    }
+
+    pub fn trigger(&self) -> Trigger {
+        self.settings.trigger()
+    }
+
+    /// Change the number of frames in a trace, from the next trace on. It is
+    /// limited to what fits in one UDP packet.
+    pub fn set_frame_width(&self, frames: usize) {
+        let frames = frames.max(1).min(max_frame_width(self.channels));
+        self.settings.frame_width.store(frames, Ordering::Relaxed);
+    }
+
+    pub fn frame_width(&self) -> usize {
+        self.settings.frame_width.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            sender.thread().unpark();
+            let _ = sender.join();
+        }
+    }
+}
+
+/// Trigger and frame width, shared with the sending thread.
+struct Settings {
+    mode: AtomicUsize,
+    channel: AtomicUsize,
+    rising: AtomicBool,
+    level: AtomicU32,
+    frame_width: AtomicUsize,
+}
+
+impl Settings {
+    fn new() -> Settings {
+        let settings = Settings {
+            mode: AtomicUsize::new(0),
+            channel: AtomicUsize::new(0),
+            rising: AtomicBool::new(true),
+            level: AtomicU32::new(0),
+            frame_width: AtomicUsize::new(DEFAULT_FRAME_WIDTH),
+        };
+        settings.set_trigger(Trigger::default());
+        settings
+    }
+
+    fn set_trigger(&self, trigger: Trigger) {
+        let mode = match trigger.mode {
+            TriggerMode::Off => 0,
+            TriggerMode::Auto => 1,
+            TriggerMode::Normal => 2,
+        };
+        self.mode.store(mode, Ordering::Relaxed);
+        self.channel.store(trigger.channel, Ordering::Relaxed);
+        self.rising.store(
+            trigger.direction == TriggerDirection::Rising,
+            Ordering::Relaxed,
+        );
+        self.level.store(trigger.level.to_bits(), Ordering::Relaxed);
+    }
+
+    fn trigger(&self) -> Trigger {
+        Trigger {
+            mode: match self.mode.load(Ordering::Relaxed) {
+                0 => TriggerMode::Off,
+                1 => TriggerMode::Auto,
+                _ => TriggerMode::Normal,
+            },
+            channel: self.channel.load(Ordering::Relaxed),
+            direction: if self.rising.load(Ordering::Relaxed) {
+                TriggerDirection::Rising
+            } else {
+                TriggerDirection::Falling
+            },
+            level: f32::from_bits(self.level.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Collects traces from the logged frames.
+struct Tracer {
+    channels: usize,
+    trigger: Trigger,
+    width: usize,
+    /// Channel-major samples of the trace being collected.
+    trace: Vec<f32>,
+    /// Frames collected, or `None` while waiting for the trigger.
+    collected: Option<usize>,
+    /// Frames seen while waiting for the trigger.
+    waited: usize,
+    previous: f32,
+}
+
+impl Tracer {
+    fn new(channels: usize) -> Tracer {
+        Tracer {
+            channels,
+            trigger: Trigger::default(),
+            width: DEFAULT_FRAME_WIDTH,
+            trace: Vec::new(),
+            collected: None,
+            waited: 0,
+            previous: 0.,
+        }
+    }
+
+    /// Add a frame, returning the trace once it is complete.
+    ///
+    /// The settings are read while waiting for the trigger, so that changes
+    /// apply from the next trace even if the current trigger never comes.
+    fn feed(&mut self, frame: &[f32], settings: &Settings) -> Option<&[f32]> {
+        if self.collected.is_none() {
+            self.trigger = settings.trigger();
+            self.width = settings.frame_width.load(Ordering::Relaxed);
+            if !self.triggered(frame) {
+                return None;
+            }
+            self.trace.resize(self.width * self.channels, 0.);
+            self.collected = Some(0);
+            self.waited = 0;
+        }
+        let collected = self.collected?;
+        for (channel, &value) in frame.iter().enumerate() {
+            self.trace[channel * self.width + collected] = value;
+        }
+        if collected + 1 < self.width {
+            self.collected = Some(collected + 1);
+            None
+        } else {
+            self.collected = None;
+            Some(&self.trace)
+        }
+    }
+
+    fn triggered(&mut self, frame: &[f32]) -> bool {
+        let trigger = self.trigger;
+        if trigger.mode == TriggerMode::Off || trigger.channel >= self.channels {
+            return true;
+        }
+        let value = frame[trigger.channel];
+        let previous = self.previous;
+        self.previous = value;
+        let crossed = match trigger.direction {
+            TriggerDirection::Rising => previous < trigger.level && value >= trigger.level,
+            TriggerDirection::Falling => previous > trigger.level && value <= trigger.level,
+        };
+        self.waited += 1;
+        crossed || (trigger.mode == TriggerMode::Auto && self.waited > self.width)
+    }
+}
+
+fn send_traces(
+    socket: &UdpSocket,
+    data: SocketAddr,
+    mut queue: ring::Consumer<f32>,
+    tracer: &mut Tracer,
+    settings: &Settings,
+    stop: &AtomicBool,
+) {
+    let mut frame = vec![0.; tracer.channels];
+    let mut packet = Vec::new();
+    loop {
+        if queue.len() < frame.len() {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            thread::park_timeout(time::Duration::from_millis(5));
+            continue;
+        }
+        for value in frame.iter_mut() {
+            *value = queue.pop().unwrap_or(0.);
+        }
+        let trace = match tracer.feed(&frame, settings) {
+            Some(trace) => trace,
+            None => continue,
+        };
+        packet.clear();
+        for value in trace {
+            packet.extend_from_slice(&value.to_le_bytes());
+        }
+        // Traces are sent whether or not the IDE is listening.
+        let _ = socket.send_to(&packet, data);
+    }
+}
+
+/// Most frames of `channels` that fit in one UDP packet.
+fn max_frame_width(channels: usize) -> usize {
+    MAX_PACKET / (4 * channels)
+}
+
+/// The OSC message announcing the channels and sample rate to the IDE.
+fn setup_message(channels: usize, sample_rate: f32) -> Vec<u8> {
+    let mut message = Vec::new();
+    push_osc_string(&mut message, "/scope-setup");
+    push_osc_string(&mut message, ",if");
+    message.extend_from_slice(&(channels as i32).to_be_bytes());
+    message.extend_from_slice(&sample_rate.to_be_bytes());
+    message
+}
+
+/// Append `string` nul-terminated and padded to a multiple of 4 bytes.
+fn push_osc_string(message: &mut Vec<u8>, string: &str) {
+    message.extend_from_slice(string.as_bytes());
+    let padding = 4 - string.len() % 4;
+    let len = message.len() + padding;
+    message.resize(len, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener() -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    fn floats(packet: &[u8]) -> Vec<f32> {
+        packet
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn setup_message_is_osc() {
+        let message = setup_message(2, 44_100.);
+        assert_eq!(&message[..16], b"/scope-setup\0\0\0\0");
+        assert_eq!(&message[16..20], b",if\0");
+        assert_eq!(&message[20..24], &2i32.to_be_bytes());
+        assert_eq!(&message[24..], &44_100f32.to_be_bytes());
+    }
+
+    #[test]
+    fn sends_setup_and_traces() {
+        let control = listener();
+        let data = listener();
+        let mut scope = Scope::connect(
+            2,
+            44_100.,
+            control.local_addr().unwrap(),
+            data.local_addr().unwrap(),
+        )
+        .unwrap();
+
+        let mut buf = [0; MAX_PACKET];
+        let (len, _) = control.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &setup_message(2, 44_100.)[..]);
+
+        scope.set_trigger(Trigger {
+            mode: TriggerMode::Off,
+            ..Trigger::default()
+        });
+        scope.set_frame_width(4);
+        for frame in 0..4 {
+            assert!(scope.log(&[frame as f32, -(frame as f32)]));
+        }
+
+        let (len, _) = data.recv_from(&mut buf).unwrap();
+        assert_eq!(len, 4 * 2 * 4);
+        assert_eq!(floats(&buf[..len]), vec![0., 1., 2., 3., 0., -1., -2., -3.]);
+    }
+
+    #[test]
+    fn refuses_channels_that_do_not_fit() {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9).into();
+        assert!(Scope::connect(0, 44_100., addr, addr).is_err());
+        assert!(Scope::connect(MAX_PACKET, 44_100., addr, addr).is_err());
+    }
+
+    #[test]
+    fn frame_width_fits_a_packet() {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9).into();
+        let scope = Scope::connect(8, 44_100., addr, addr).unwrap();
+        scope.set_frame_width(0);
+        assert_eq!(scope.frame_width(), 1);
+        scope.set_frame_width(usize::MAX);
+        assert_eq!(scope.frame_width(), MAX_PACKET / 32);
+    }
+
+    #[test]
+    fn normal_trigger_waits_for_crossing() {
+        let settings = Settings::new();
+        settings.set_trigger(Trigger {
+            mode: TriggerMode::Normal,
+            channel: 1,
+            direction: TriggerDirection::Falling,
+            level: 0.5,
+        });
+        settings.frame_width.store(2, Ordering::Relaxed);
+        let mut tracer = Tracer::new(2);
+        assert!(tracer.feed(&[0., 1.], &settings).is_none());
+        assert!(tracer.feed(&[1., 0.8], &settings).is_none());
+        // Crosses the level, and starts the trace
+        assert!(tracer.feed(&[2., 0.2], &settings).is_none());
+        assert_eq!(
+            tracer.feed(&[3., 0.1], &settings),
+            Some(&[2., 3., 0.2, 0.1][..])
+        );
+    }
+}
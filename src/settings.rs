@@ -0,0 +1,698 @@
+use std::convert::TryInto;
+use std::{error, fmt};
+
+use error as bela_error;
+use hw::HwConfig;
+use {BelaHw, InitSettings};
+
+/// Range of the DAC level, in dB.
+pub(crate) const DAC_LEVEL: (f32, f32) = (-63.5, 0.);
+/// Range of the ADC level, in dB.
+pub(crate) const ADC_LEVEL: (f32, f32) = (-12., 0.);
+/// Range of the PGA gain, in dB.
+pub(crate) const PGA_GAIN: (f32, f32) = (0., 59.5);
+/// Range of the headphone level, in dB.
+pub(crate) const HEADPHONE_LEVEL: (f32, f32) = (-63.5, 0.);
+
+/// Channels available on a board, as far as the settings are concerned.
+struct Limits {
+    analog_in: usize,
+    analog_out: usize,
+    digital: usize,
+}
+
+fn limits(board: &BelaHw) -> Limits {
+    match *board {
+        // The board is detected by libbela, so only the largest one can be
+        // assumed.
+        BelaHw::NoHw => Limits {
+            analog_in: 8,
+            analog_out: 8,
+            digital: 16,
+        },
+        board => {
+            let hw = HwConfig::for_board(board);
+            Limits {
+                analog_in: hw.analog_in_channels,
+                analog_out: hw.analog_out_channels,
+                digital: hw.digital_channels,
+            }
+        }
+    }
+}
+
+/// A setting that was rejected, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidField {
+    /// Name of the setting, as in the `InitSettingsBuilder` method, followed
+    /// by the index for the two channels of `pga_gain`.
+    pub field: &'static str,
+    pub value: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} = {}: {}", self.field, self.value, self.reason)
+    }
+}
+
+/// Every invalid field found when validating settings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidSettings {
+    pub fields: Vec<InvalidField>,
+}
+
+impl InvalidSettings {
+    fn push<V: fmt::Display, R: Into<String>>(&mut self, field: &'static str, value: V, reason: R) {
+        self.fields.push(InvalidField {
+            field,
+            value: value.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    /// Check that `value` is within `range`, inclusive.
+    fn check_range(&mut self, field: &'static str, value: f32, range: (f32, f32)) {
+        if !(value >= range.0 && value <= range.1) {
+            self.push(
+                field,
+                value,
+                format!("must be between {}dB and {}dB", range.0, range.1),
+            );
+        }
+    }
+}
+
+/// Check a single codec level against its range, as when validating settings.
+pub(crate) fn check_level(
+    field: &'static str,
+    value: f32,
+    range: (f32, f32),
+) -> Result<(), InvalidSettings> {
+    let mut invalid = InvalidSettings { fields: Vec::new() };
+    invalid.check_range(field, value, range);
+    if invalid.fields.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid)
+    }
+}
+
+impl fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "invalid settings")?;
+        for (idx, field) in self.fields.iter().enumerate() {
+            write!(f, "{} {}", if idx == 0 { ":" } else { ";" }, field)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for InvalidSettings {}
+
+impl From<InvalidSettings> for bela_error::Error {
+    fn from(invalid: InvalidSettings) -> bela_error::Error {
+        bela_error::Error::new(bela_error::ErrorKind::Init).with_source(invalid)
+    }
+}
+
+impl InitSettings {
+    /// Start building settings from the defaults, to be validated before they
+    /// are used.
+    pub fn builder() -> InitSettingsBuilder {
+        InitSettingsBuilder::new()
+    }
+
+    /// Check the settings against the selected board, listing every setting
+    /// libbela would reject, or that the board cannot honour.
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut invalid = InvalidSettings { fields: Vec::new() };
+
+        let board = BelaHw::from_i32(self.settings.board);
+        if board.is_none() {
+            invalid.push("board", self.settings.board, "is not a known board");
+        }
+        let limits = limits(board.as_ref().unwrap_or(&BelaHw::NoHw));
+
+        let period_size = self.period_size();
+        if !period_size.is_power_of_two() || !(2..=128).contains(&period_size) {
+            invalid.push(
+                "period_size",
+                period_size,
+                "must be a power of two between 2 and 128",
+            );
+        }
+
+        if self.use_analog() {
+            let channels = [
+                (
+                    "num_analog_in_channels",
+                    self.num_analog_in_channels(),
+                    limits.analog_in,
+                ),
+                (
+                    "num_analog_out_channels",
+                    self.num_analog_out_channels(),
+                    limits.analog_out,
+                ),
+            ];
+            for &(field, num, max) in &channels {
+                if num > max {
+                    invalid.push(field, num, format!("the board has {} channels", max));
+                } else if num != 0 && num != 2 && num != 4 && num != 8 {
+                    invalid.push(field, num, "must be 0, 2, 4 or 8");
+                }
+            }
+
+            let analog_channels = self
+                .num_analog_in_channels()
+                .max(self.num_analog_out_channels());
+            // 2 analog channels run at twice the audio rate, which cannot be
+            // brought down to it
+            if self.uniform_sample_rate() && analog_channels == 2 {
+                invalid.push(
+                    "uniform_sample_rate",
+                    true,
+                    "requires 4 or 8 analog channels",
+                );
+            }
+
+            let mux = self.num_mux_channels();
+            if mux != 0 && mux != 2 && mux != 4 && mux != 8 {
+                invalid.push("num_mux_channels", mux, "must be 0, 2, 4 or 8");
+            } else if mux != 0 && self.num_analog_in_channels() != 8 {
+                invalid.push("num_mux_channels", mux, "requires 8 analog input channels");
+            }
+
+            let expanders = [
+                (
+                    "audio_expander_inputs",
+                    self.audio_expander_inputs(),
+                    self.num_analog_in_channels(),
+                ),
+                (
+                    "audio_expander_outputs",
+                    self.audio_expander_outputs(),
+                    self.num_analog_out_channels(),
+                ),
+            ];
+            for &(field, mask, channels) in &expanders {
+                if mask.checked_shr(channels as u32).unwrap_or(0) != 0 {
+                    invalid.push(
+                        field,
+                        format!("{:#x}", mask),
+                        format!("selects channels beyond the {} analog channels", channels),
+                    );
+                }
+            }
+        } else {
+            let unused = [
+                ("num_mux_channels", self.num_mux_channels()),
+                ("audio_expander_inputs", self.audio_expander_inputs()),
+                ("audio_expander_outputs", self.audio_expander_outputs()),
+            ];
+            for &(field, value) in &unused {
+                if value != 0 {
+                    invalid.push(field, value, "requires use_analog");
+                }
+            }
+        }
+
+        if self.use_digital() && self.num_digital_channels() > limits.digital {
+            invalid.push(
+                "num_digital_channels",
+                self.num_digital_channels(),
+                format!("the board has {} channels", limits.digital),
+            );
+        }
+
+        invalid.check_range("dac_level", self.dac_level(), DAC_LEVEL);
+        invalid.check_range("adc_level", self.adc_level(), ADC_LEVEL);
+        let pga_gain = self.pga_gain();
+        invalid.check_range("pga_gain[0]", pga_gain[0], PGA_GAIN);
+        invalid.check_range("pga_gain[1]", pga_gain[1], PGA_GAIN);
+        invalid.check_range("headphone_level", self.headphone_level(), HEADPHONE_LEVEL);
+
+        if invalid.fields.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid)
+        }
+    }
+}
+
+/// Builds `InitSettings` that are checked against the selected board before
+/// they are handed to libbela.
+///
+/// ```rust,ignore
+/// let mut settings = InitSettings::builder()
+///     .board(BelaHw::BelaMini)
+///     .period_size(32)
+///     .num_analog_out_channels(0)
+///     .build()?;
+/// ```
+pub struct InitSettingsBuilder {
+    settings: InitSettings,
+    period_size: usize,
+    num_analog_in_channels: usize,
+    num_analog_out_channels: usize,
+    num_digital_channels: usize,
+    num_mux_channels: usize,
+    audio_expander_inputs: usize,
+    audio_expander_outputs: usize,
+    pru_number: usize,
+    audio_thread_stack_size: usize,
+    auxiliary_task_stack_size: usize,
+}
+
+impl InitSettingsBuilder {
+    /// Start from the default settings of libbela.
+    pub fn new() -> InitSettingsBuilder {
+        InitSettingsBuilder::from_settings(InitSettings::default())
+    }
+
+    /// Start from existing settings.
+    pub fn from_settings(settings: InitSettings) -> InitSettingsBuilder {
+        InitSettingsBuilder {
+            period_size: settings.period_size(),
+            num_analog_in_channels: settings.num_analog_in_channels(),
+            num_analog_out_channels: settings.num_analog_out_channels(),
+            num_digital_channels: settings.num_digital_channels(),
+            num_mux_channels: settings.num_mux_channels(),
+            audio_expander_inputs: settings.audio_expander_inputs(),
+            audio_expander_outputs: settings.audio_expander_outputs(),
+            pru_number: settings.pru_number(),
+            audio_thread_stack_size: settings.audio_thread_stack_size(),
+            auxiliary_task_stack_size: settings.auxiliary_task_stack_size(),
+            settings,
+        }
+    }
+
+    /// The settings so far, without the channel counts and period size,
+    /// which are only applied by `build`.
+    pub(crate) fn settings(&self) -> &InitSettings {
+        &self.settings
+    }
+
+    pub fn period_size(mut self, size: usize) -> InitSettingsBuilder {
+        self.period_size = size;
+        self
+    }
+
+    pub fn use_analog(mut self, use_analog: bool) -> InitSettingsBuilder {
+        self.settings.set_use_analog(use_analog);
+        self
+    }
+
+    pub fn use_digital(mut self, use_digital: bool) -> InitSettingsBuilder {
+        self.settings.set_use_digital(use_digital);
+        self
+    }
+
+    pub fn num_analog_in_channels(mut self, num: usize) -> InitSettingsBuilder {
+        self.num_analog_in_channels = num;
+        self
+    }
+
+    pub fn num_analog_out_channels(mut self, num: usize) -> InitSettingsBuilder {
+        self.num_analog_out_channels = num;
+        self
+    }
+
+    /// Set both the analog input and output channel counts.
+    pub fn num_analog_channels(self, num: usize) -> InitSettingsBuilder {
+        self.num_analog_in_channels(num)
+            .num_analog_out_channels(num)
+    }
+
+    pub fn num_digital_channels(mut self, num: usize) -> InitSettingsBuilder {
+        self.num_digital_channels = num;
+        self
+    }
+
+    pub fn num_mux_channels(mut self, num: usize) -> InitSettingsBuilder {
+        self.num_mux_channels = num;
+        self
+    }
+
+    pub fn audio_expander_inputs(mut self, mask: usize) -> InitSettingsBuilder {
+        self.audio_expander_inputs = mask;
+        self
+    }
+
+    pub fn audio_expander_outputs(mut self, mask: usize) -> InitSettingsBuilder {
+        self.audio_expander_outputs = mask;
+        self
+    }
+
+    pub fn pru_number(mut self, val: usize) -> InitSettingsBuilder {
+        self.pru_number = val;
+        self
+    }
+
+    pub fn pru_filename(mut self, val: [u8; 256]) -> InitSettingsBuilder {
+        self.settings.set_pru_filename(val);
+        self
+    }
+
+    /// Set the PRU code file from a path, which must be shorter than 256
+    /// bytes and free of nul bytes. An empty path selects the built-in code.
+    pub(crate) fn pru_file(self, path: &str) -> Option<InitSettingsBuilder> {
+        // Leave room for the terminating nul.
+        let mut filename = [0u8; 256];
+        if path.len() >= filename.len() || path.contains('\0') {
+            return None;
+        }
+        filename[..path.len()].copy_from_slice(path.as_bytes());
+        Some(self.pru_filename(filename))
+    }
+
+    pub fn begin_muted(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_begin_muted(val);
+        self
+    }
+
+    pub fn dac_level(mut self, val: f32) -> InitSettingsBuilder {
+        self.settings.set_dac_level(val);
+        self
+    }
+
+    pub fn adc_level(mut self, val: f32) -> InitSettingsBuilder {
+        self.settings.set_adc_level(val);
+        self
+    }
+
+    pub fn pga_gain(mut self, val: [f32; 2]) -> InitSettingsBuilder {
+        self.settings.set_pga_gain(val);
+        self
+    }
+
+    pub fn headphone_level(mut self, val: f32) -> InitSettingsBuilder {
+        self.settings.set_headphone_level(val);
+        self
+    }
+
+    pub fn detect_underruns(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_detect_underruns(val);
+        self
+    }
+
+    pub fn verbose(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_verbose(val);
+        self
+    }
+
+    pub fn enable_led(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_enable_led(val);
+        self
+    }
+
+    pub fn stop_button_pin(mut self, val: Option<i8>) -> InitSettingsBuilder {
+        self.settings.set_stop_button_pin(val);
+        self
+    }
+
+    pub fn high_performance_mode(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_high_performance_mode(val);
+        self
+    }
+
+    pub fn interleave(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_interleave(val);
+        self
+    }
+
+    pub fn analog_outputs_persist(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_analog_outputs_persist(val);
+        self
+    }
+
+    pub fn uniform_sample_rate(mut self, val: bool) -> InitSettingsBuilder {
+        self.settings.set_uniform_sample_rate(val);
+        self
+    }
+
+    pub fn audio_thread_stack_size(mut self, num: usize) -> InitSettingsBuilder {
+        self.audio_thread_stack_size = num;
+        self
+    }
+
+    pub fn auxiliary_task_stack_size(mut self, num: usize) -> InitSettingsBuilder {
+        self.auxiliary_task_stack_size = num;
+        self
+    }
+
+    pub fn amp_mute_pin(mut self, val: Option<i8>) -> InitSettingsBuilder {
+        self.settings.set_amp_mute_pin(val);
+        self
+    }
+
+    /// Select the board to validate against, rather than having libbela
+    /// detect it.
+    pub fn board(mut self, board: BelaHw) -> InitSettingsBuilder {
+        self.settings.set_board(board);
+        self
+    }
+
+    /// Validate the settings, returning every invalid field at once.
+    pub fn build(self) -> Result<InitSettings, InvalidSettings> {
+        let InitSettingsBuilder { mut settings, .. } = self;
+        let mut invalid = InvalidSettings { fields: Vec::new() };
+
+        // A count that does not fit is reported and left at its previous
+        // value, so that the other fields are still checked
+        let counts: [(&'static str, usize, fn(&mut InitSettings, usize)); 10] = [
+            (
+                "period_size",
+                self.period_size,
+                InitSettings::set_period_size,
+            ),
+            (
+                "num_analog_in_channels",
+                self.num_analog_in_channels,
+                InitSettings::set_num_analog_in_channels,
+            ),
+            (
+                "num_analog_out_channels",
+                self.num_analog_out_channels,
+                InitSettings::set_num_analog_out_channels,
+            ),
+            (
+                "num_digital_channels",
+                self.num_digital_channels,
+                InitSettings::set_num_digital_channels,
+            ),
+            (
+                "num_mux_channels",
+                self.num_mux_channels,
+                InitSettings::set_num_mux_channels,
+            ),
+            (
+                "audio_expander_inputs",
+                self.audio_expander_inputs,
+                InitSettings::set_audio_expander_inputs,
+            ),
+            (
+                "audio_expander_outputs",
+                self.audio_expander_outputs,
+                InitSettings::set_audio_expander_outputs,
+            ),
+            ("pru_number", self.pru_number, InitSettings::set_pru_number),
+            (
+                "audio_thread_stack_size",
+                self.audio_thread_stack_size,
+                InitSettings::set_audio_thread_stack_size,
+            ),
+            (
+                "auxiliary_task_stack_size",
+                self.auxiliary_task_stack_size,
+                InitSettings::set_auxiliary_task_stack_size,
+            ),
+        ];
+        for &(field, value, set) in &counts {
+            let fits: Result<i32, _> = value.try_into();
+            if fits.is_ok() {
+                set(&mut settings, value);
+            } else {
+                invalid.push(field, value, "is out of range");
+            }
+        }
+
+        if let Err(rest) = settings.validate() {
+            for field in rest.fields {
+                if !invalid
+                    .fields
+                    .iter()
+                    .any(|known| known.field == field.field)
+                {
+                    invalid.fields.push(field);
+                }
+            }
+        }
+        if invalid.fields.is_empty() {
+            Ok(settings)
+        } else {
+            Err(invalid)
+        }
+    }
+}
+
+impl Default for InitSettingsBuilder {
+    fn default() -> InitSettingsBuilder {
+        InitSettingsBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> InitSettingsBuilder {
+        InitSettingsBuilder::from_settings(InitSettings::software_default())
+    }
+
+    fn invalid(res: Result<InitSettings, InvalidSettings>) -> InvalidSettings {
+        match res {
+            Ok(_) => InvalidSettings { fields: Vec::new() },
+            Err(invalid) => invalid,
+        }
+    }
+
+    fn fields(res: Result<InitSettings, InvalidSettings>) -> Vec<&'static str> {
+        let invalid = invalid(res);
+        invalid.fields.iter().map(|field| field.field).collect()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(InitSettings::software_default().validate().is_ok());
+        assert!(builder().build().is_ok());
+    }
+
+    #[test]
+    fn period_size() {
+        assert!(builder().period_size(128).build().is_ok());
+        assert_eq!(
+            fields(builder().period_size(3).build()),
+            vec!["period_size"]
+        );
+        assert_eq!(
+            fields(builder().period_size(256).build()),
+            vec!["period_size"]
+        );
+    }
+
+    #[test]
+    fn uniform_sample_rate() {
+        for &channels in &[4, 8] {
+            let settings = builder()
+                .num_analog_channels(channels)
+                .uniform_sample_rate(true)
+                .build();
+            assert!(settings.is_ok(), "{} channels", channels);
+        }
+        let settings = builder()
+            .num_analog_channels(2)
+            .uniform_sample_rate(true)
+            .build();
+        assert_eq!(fields(settings), vec!["uniform_sample_rate"]);
+    }
+
+    #[test]
+    fn channels_against_board() {
+        let settings = builder()
+            .board(BelaHw::BelaMini)
+            .num_analog_out_channels(8)
+            .build();
+        assert_eq!(fields(settings), vec!["num_analog_out_channels"]);
+        assert_eq!(
+            fields(builder().num_analog_in_channels(6).build()),
+            vec!["num_analog_in_channels"]
+        );
+        assert_eq!(
+            fields(builder().num_digital_channels(17).build()),
+            vec!["num_digital_channels"]
+        );
+    }
+
+    #[test]
+    fn multiplexer() {
+        assert!(builder().num_mux_channels(8).build().is_ok());
+        assert_eq!(
+            fields(builder().num_mux_channels(3).build()),
+            vec!["num_mux_channels"]
+        );
+        let settings = builder()
+            .num_analog_in_channels(4)
+            .num_mux_channels(2)
+            .build();
+        assert_eq!(fields(settings), vec!["num_mux_channels"]);
+        let settings = builder().use_analog(false).num_mux_channels(2).build();
+        assert_eq!(fields(settings), vec!["num_mux_channels"]);
+    }
+
+    #[test]
+    fn levels_name_each_pga_channel() {
+        let settings = builder()
+            .dac_level(1.)
+            .pga_gain([60., 10.])
+            .headphone_level(-70.)
+            .build();
+        assert_eq!(
+            fields(settings),
+            vec!["dac_level", "pga_gain[0]", "headphone_level"]
+        );
+        assert_eq!(
+            fields(builder().pga_gain([0., 59.6]).build()),
+            vec!["pga_gain[1]"]
+        );
+    }
+
+    #[test]
+    fn counts_out_of_range() {
+        let invalid = invalid(builder().period_size(usize::MAX).build());
+        assert_eq!(invalid.fields[0].reason, "is out of range");
+        assert_eq!(invalid.fields.len(), 1);
+
+        // Listed along with the other invalid fields, once each
+        let settings = builder()
+            .num_mux_channels(usize::MAX)
+            .period_size(usize::MAX)
+            .num_analog_in_channels(3)
+            .adc_level(3.)
+            .build();
+        assert_eq!(
+            fields(settings),
+            vec![
+                "period_size",
+                "num_mux_channels",
+                "num_analog_in_channels",
+                "adc_level"
+            ]
+        );
+    }
+
+    #[test]
+    fn analog_channel_counts() {
+        assert!(builder().num_analog_in_channels(0).build().is_ok());
+        assert!(builder().num_analog_out_channels(4).build().is_ok());
+        let invalid = invalid(builder().num_analog_out_channels(3).build());
+        assert_eq!(invalid.fields[0].reason, "must be 0, 2, 4 or 8");
+    }
+
+    #[test]
+    fn every_invalid_field_is_listed() {
+        let invalid = invalid(
+            builder()
+                .period_size(7)
+                .num_digital_channels(20)
+                .adc_level(3.)
+                .build(),
+        );
+        assert_eq!(invalid.fields.len(), 3);
+        assert!(invalid
+            .to_string()
+            .starts_with("invalid settings: period_size = 7"));
+    }
+}
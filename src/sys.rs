@@ -0,0 +1,100 @@
+//! The libbela types and constants shared by every backend.
+//!
+//! With the `libbela` feature they are those of `bela-sys`. Without it, the
+//! same definitions are given here, so that the software and offline backends
+//! build and link on machines without libbela.
+
+#[cfg(feature = "libbela")]
+pub(crate) use bela_sys::{
+    BelaContext, BelaHw_BelaHw_Bela, BelaHw_BelaHw_BelaMini, BelaHw_BelaHw_CtagBeast,
+    BelaHw_BelaHw_CtagBeastBela, BelaHw_BelaHw_CtagFace, BelaHw_BelaHw_CtagFaceBela,
+    BelaHw_BelaHw_NoHw, BelaHw_BelaHw_Salt, BelaInitSettings, BELA_FLAG_ANALOG_OUTPUTS_PERSIST,
+    BELA_FLAG_DETECT_UNDERRUNS, BELA_FLAG_INTERLEAVED,
+};
+
+#[cfg(not(feature = "libbela"))]
+pub(crate) use self::portable::*;
+
+#[cfg(not(feature = "libbela"))]
+#[allow(non_snake_case, non_upper_case_globals)]
+mod portable {
+    use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+    pub const BELA_FLAG_INTERLEAVED: u32 = 1;
+    pub const BELA_FLAG_ANALOG_OUTPUTS_PERSIST: u32 = 2;
+    pub const BELA_FLAG_DETECT_UNDERRUNS: u32 = 4;
+
+    pub const BelaHw_BelaHw_NoHw: c_int = -1;
+    pub const BelaHw_BelaHw_Bela: c_int = 0;
+    pub const BelaHw_BelaHw_BelaMini: c_int = 1;
+    pub const BelaHw_BelaHw_Salt: c_int = 2;
+    pub const BelaHw_BelaHw_CtagFace: c_int = 3;
+    pub const BelaHw_BelaHw_CtagBeast: c_int = 4;
+    pub const BelaHw_BelaHw_CtagFaceBela: c_int = 5;
+    pub const BelaHw_BelaHw_CtagBeastBela: c_int = 6;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct BelaContext {
+        pub audioIn: *const f32,
+        pub audioOut: *mut f32,
+        pub analogIn: *const f32,
+        pub analogOut: *mut f32,
+        pub digital: *mut u32,
+        pub audioFrames: u32,
+        pub audioInChannels: u32,
+        pub audioOutChannels: u32,
+        pub audioSampleRate: f32,
+        pub analogFrames: u32,
+        pub analogInChannels: u32,
+        pub analogOutChannels: u32,
+        pub analogSampleRate: f32,
+        pub digitalFrames: u32,
+        pub digitalChannels: u32,
+        pub digitalSampleRate: f32,
+        pub audioFramesElapsed: u64,
+        pub multiplexerChannels: u32,
+        pub multiplexerStartingChannel: u32,
+        pub multiplexerAnalogIn: *const f32,
+        pub audioExpanderEnabled: u32,
+        pub flags: u32,
+        pub projectName: [c_char; 256],
+        pub underrunCount: c_uint,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct BelaInitSettings {
+        pub periodSize: c_int,
+        pub useAnalog: c_int,
+        pub useDigital: c_int,
+        pub numAnalogInChannels: c_int,
+        pub numAnalogOutChannels: c_int,
+        pub numDigitalChannels: c_int,
+        pub beginMuted: c_int,
+        pub dacLevel: f32,
+        pub adcLevel: f32,
+        pub pgaGain: [f32; 2],
+        pub headphoneLevel: f32,
+        pub numMuxChannels: c_int,
+        pub audioExpanderInputs: c_uint,
+        pub audioExpanderOutputs: c_uint,
+        pub pruNumber: c_int,
+        pub pruFilename: [u8; 256],
+        pub detectUnderruns: c_int,
+        pub verbose: c_int,
+        pub enableLED: c_int,
+        pub stopButtonPin: c_int,
+        pub highPerformanceMode: c_int,
+        pub interleave: c_int,
+        pub analogOutputsPersist: c_int,
+        pub uniformSampleRate: c_int,
+        pub audioThreadStackSize: c_uint,
+        pub auxiliaryTaskStackSize: c_uint,
+        pub setup: Option<unsafe extern "C" fn(*mut BelaContext, *mut c_void) -> bool>,
+        pub render: Option<unsafe extern "C" fn(*mut BelaContext, *mut c_void)>,
+        pub cleanup: Option<unsafe extern "C" fn(*mut BelaContext, *mut c_void)>,
+        pub ampMutePin: c_int,
+        pub board: c_int,
+    }
+}
@@ -0,0 +1,1090 @@
+//! Reads Trill touch sensors over I2C, like the `Trill` library of C++ Bela
+//! projects.
+//!
+//! `Trill` speaks the sensor's protocol over any `I2cBus`: `LinuxI2c` for the
+//! `/dev/i2c-*` buses of the board, or the `MockBus` end of `mock`, with the
+//! `mock` feature, when testing without hardware. I2C transfers block for up to a few
+//! milliseconds, so a sensor is set up before audio starts and then read from
+//! an auxiliary task created with `Bela::create_trill_reader`, which publishes
+//! each reading to render without locking.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let bus = LinuxI2c::open(trill::DEFAULT_BUS)?;
+//! let bar = Trill::setup(bus, Device::Bar)?;
+//! let mut touches = App::create_trill_reader(bar, 50, &CString::new("trill").unwrap());
+//! // In render, every few blocks:
+//! touches.update();
+//! if let Some(touch) = touches.reading().touches().first() {
+//!     frequency = 200. + 800. * touch.position;
+//! }
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use triple;
+use {error, CreatedTask};
+
+/// The I2C bus the Trill sensors are connected to on Bela.
+pub const DEFAULT_BUS: u32 = 1;
+/// Most touches reported on one axis.
+pub const MAX_TOUCHES: usize = 5;
+/// Most channels of any sensor.
+pub const MAX_CHANNELS: usize = 30;
+
+const OFFSET_COMMAND: u8 = 0;
+const OFFSET_DATA: u8 = 4;
+
+const COMMAND_MODE: u8 = 1;
+const COMMAND_SCAN_SETTINGS: u8 = 2;
+const COMMAND_PRESCALER: u8 = 3;
+const COMMAND_NOISE_THRESHOLD: u8 = 4;
+const COMMAND_IDAC: u8 = 5;
+const COMMAND_BASELINE_UPDATE: u8 = 6;
+const COMMAND_MINIMUM_SIZE: u8 = 7;
+const COMMAND_AUTO_SCAN_INTERVAL: u8 = 16;
+const COMMAND_IDENTIFY: u8 = 255;
+
+/// How long the sensor takes to act on a command before it can be read.
+const COMMAND_DELAY: Duration = Duration::from_millis(15);
+/// First byte of the reply to an identify command.
+const IDENTIFY_HEADER: u8 = 0xfe;
+/// Location ending the list of touches.
+const NO_TOUCH: u16 = 0xffff;
+/// Positions are reported in 1/128ths of the distance between two pads.
+const POSITION_STEPS: u16 = 128;
+
+/// `ioctl` request selecting the address of later transfers on an i2c-dev bus.
+const I2C_SLAVE: libc::c_ulong = 0x0703;
+
+/// A kind of Trill sensor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Device {
+    Bar,
+    Square,
+    Craft,
+    Ring,
+    Hex,
+    Flex,
+}
+
+impl Device {
+    /// The device identified by the type byte of an identify reply.
+    pub fn from_id(id: u8) -> Option<Device> {
+        match id {
+            1 => Some(Device::Bar),
+            2 => Some(Device::Square),
+            3 => Some(Device::Craft),
+            4 => Some(Device::Ring),
+            5 => Some(Device::Hex),
+            6 => Some(Device::Flex),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            Device::Bar => 1,
+            Device::Square => 2,
+            Device::Craft => 3,
+            Device::Ring => 4,
+            Device::Hex => 5,
+            Device::Flex => 6,
+        }
+    }
+
+    /// The address the device answers at when its address pads are left
+    /// open.
+    pub fn default_address(self) -> u8 {
+        match self {
+            Device::Bar => 0x20,
+            Device::Square => 0x28,
+            Device::Craft => 0x30,
+            Device::Ring => 0x38,
+            Device::Hex => 0x40,
+            Device::Flex => 0x48,
+        }
+    }
+
+    /// The mode `Trill::setup` puts the device in.
+    pub fn default_mode(self) -> Mode {
+        match self {
+            Device::Craft => Mode::Diff,
+            _ => Mode::Centroid,
+        }
+    }
+
+    /// Number of sensing channels, each read as one value outside of
+    /// `Mode::Centroid`.
+    pub fn channels(self) -> usize {
+        match self {
+            Device::Bar => 26,
+            Device::Ring => 28,
+            _ => 30,
+        }
+    }
+
+    /// Whether touches are reported along two axes.
+    pub fn is_2d(self) -> bool {
+        matches!(self, Device::Square | Device::Hex)
+    }
+
+    /// Number of touches reported on each axis.
+    pub fn max_touches(self) -> usize {
+        if self.is_2d() {
+            4
+        } else {
+            MAX_TOUCHES
+        }
+    }
+
+    /// Number of buttons reported alongside the touches in
+    /// `Mode::Centroid`.
+    pub fn buttons(self) -> usize {
+        match self {
+            Device::Ring => 2,
+            _ => 0,
+        }
+    }
+
+    /// Number of bytes of a reading in `mode`.
+    fn data_len(self, mode: Mode) -> usize {
+        match mode {
+            Mode::Centroid => {
+                4 * self.max_touches() * if self.is_2d() { 2 } else { 1 } + 2 * self.buttons()
+            }
+            _ => 2 * self.channels(),
+        }
+    }
+
+    /// Highest location reported on the vertical, or only, axis, and on the
+    /// horizontal axis.
+    fn location_range(self) -> (u16, u16) {
+        let pads = match self {
+            Device::Bar => (25, 0),
+            Device::Square => (14, 14),
+            Device::Ring => (28, 0),
+            Device::Hex => (13, 15),
+            Device::Craft | Device::Flex => (29, 0),
+        };
+        (pads.0 * POSITION_STEPS, pads.1 * POSITION_STEPS)
+    }
+}
+
+/// What the device reports on each reading.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    /// The position and size of each touch.
+    Centroid,
+    /// The unprocessed value of each channel.
+    Raw,
+    /// The baseline of each channel, as set by the last baseline update.
+    Baseline,
+    /// The difference between each channel and its baseline.
+    Diff,
+}
+
+impl Mode {
+    fn id(self) -> u8 {
+        match self {
+            Mode::Centroid => 0,
+            Mode::Raw => 1,
+            Mode::Baseline => 2,
+            Mode::Diff => 3,
+        }
+    }
+
+    #[cfg(any(test, feature = "mock"))]
+    fn from_id(id: u8) -> Option<Mode> {
+        match id {
+            0 => Some(Mode::Centroid),
+            1 => Some(Mode::Raw),
+            2 => Some(Mode::Baseline),
+            3 => Some(Mode::Diff),
+            _ => None,
+        }
+    }
+}
+
+/// One touch reported in `Mode::Centroid`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Touch {
+    /// Position along the axis, from 0 to 1.
+    pub position: f32,
+    /// Size of the touch, in the device's own units.
+    pub size: u16,
+}
+
+/// One reading of a device.
+///
+/// Only the data of the mode it was read in is filled in; the rest is empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    mode: Mode,
+    touches: [Touch; MAX_TOUCHES],
+    touch_count: usize,
+    horizontal: [Touch; MAX_TOUCHES],
+    horizontal_count: usize,
+    buttons: [u16; 2],
+    button_count: usize,
+    values: [u16; MAX_CHANNELS],
+    value_count: usize,
+}
+
+impl Default for Reading {
+    fn default() -> Reading {
+        Reading {
+            mode: Mode::Centroid,
+            touches: [Touch::default(); MAX_TOUCHES],
+            touch_count: 0,
+            horizontal: [Touch::default(); MAX_TOUCHES],
+            horizontal_count: 0,
+            buttons: [0; 2],
+            button_count: 0,
+            values: [0; MAX_CHANNELS],
+            value_count: 0,
+        }
+    }
+}
+
+impl Reading {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The touches on the only axis of a 1D device, or the vertical axis of a
+    /// 2D one, in the order the device reports them.
+    pub fn touches(&self) -> &[Touch] {
+        &self.touches[..self.touch_count]
+    }
+
+    /// The touches on the horizontal axis of a 2D device.
+    pub fn horizontal_touches(&self) -> &[Touch] {
+        &self.horizontal[..self.horizontal_count]
+    }
+
+    /// The buttons of a Trill Ring.
+    pub fn buttons(&self) -> &[u16] {
+        &self.buttons[..self.button_count]
+    }
+
+    /// One value per channel, outside of `Mode::Centroid`.
+    pub fn values(&self) -> &[u16] {
+        &self.values[..self.value_count]
+    }
+
+    /// Whether any touch was reported.
+    pub fn is_touched(&self) -> bool {
+        self.touch_count > 0 || self.horizontal_count > 0
+    }
+
+    fn parse(&mut self, device: Device, mode: Mode, data: &[u8]) {
+        self.mode = mode;
+        self.touch_count = 0;
+        self.horizontal_count = 0;
+        self.button_count = 0;
+        self.value_count = 0;
+        if mode != Mode::Centroid {
+            for (value, bytes) in self.values.iter_mut().zip(data.chunks(2)) {
+                *value = u16::from_be_bytes([bytes[0], bytes[1]]);
+                self.value_count += 1;
+            }
+            return;
+        }
+        let max = device.max_touches();
+        let (vertical, horizontal) = device.location_range();
+        self.touch_count = parse_touches(&data[..4 * max], vertical, &mut self.touches);
+        if device.is_2d() {
+            self.horizontal_count =
+                parse_touches(&data[4 * max..8 * max], horizontal, &mut self.horizontal);
+        }
+        let buttons = &data[4 * max..];
+        for (button, bytes) in self.buttons[..device.buttons()]
+            .iter_mut()
+            .zip(buttons.chunks(2))
+        {
+            *button = u16::from_be_bytes([bytes[0], bytes[1]]);
+            self.button_count += 1;
+        }
+    }
+}
+
+/// Parse the locations, then the sizes, of up to `data.len() / 4` touches.
+fn parse_touches(data: &[u8], range: u16, touches: &mut [Touch]) -> usize {
+    let max = data.len() / 4;
+    let word = |index: usize| u16::from_be_bytes([data[2 * index], data[2 * index + 1]]);
+    let mut count = 0;
+    while count < max && word(count) != NO_TOUCH {
+        touches[count] = Touch {
+            position: (f32::from(word(count)) / f32::from(range)).min(1.),
+            size: word(max + count),
+        };
+        count += 1;
+    }
+    count
+}
+
+/// An I2C bus the sensor is connected to.
+pub trait I2cBus {
+    /// Write `bytes` to the device at `address` in a single transfer.
+    fn write(&mut self, address: u8, bytes: &[u8]) -> io::Result<()>;
+
+    /// Fill `buf` from the device at `address` in a single transfer.
+    fn read(&mut self, address: u8, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// A Linux i2c-dev bus, such as `/dev/i2c-1`.
+pub struct LinuxI2c {
+    file: File,
+    address: Option<u8>,
+}
+
+impl LinuxI2c {
+    /// Open `/dev/i2c-<bus>`.
+    pub fn open(bus: u32) -> io::Result<LinuxI2c> {
+        LinuxI2c::open_path(format!("/dev/i2c-{}", bus))
+    }
+
+    pub fn open_path<P: AsRef<Path>>(path: P) -> io::Result<LinuxI2c> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(LinuxI2c {
+            file,
+            address: None,
+        })
+    }
+
+    fn select(&mut self, address: u8) -> io::Result<()> {
+        if self.address == Some(address) {
+            return Ok(());
+        }
+        let result = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                I2C_SLAVE as _,
+                libc::c_ulong::from(address),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.address = Some(address);
+        Ok(())
+    }
+}
+
+impl I2cBus for LinuxI2c {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> io::Result<()> {
+        self.select(address)?;
+        // Each call is one transfer, so a short write is not retried.
+        if self.file.write(bytes)? != bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short I2C write to {:#04x}", address),
+            ));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, address: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.select(address)?;
+        if self.file.read(buf)? != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short I2C read from {:#04x}", address),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A Trill sensor on an I2C bus.
+pub struct Trill<B> {
+    bus: B,
+    address: u8,
+    device: Device,
+    firmware: u8,
+    mode: Mode,
+    /// Whether the device will be read from its data, rather than from
+    /// wherever the last command left it.
+    prepared: bool,
+    buf: [u8; 2 * MAX_CHANNELS],
+}
+
+impl<B: I2cBus> Trill<B> {
+    /// Set up the `device` at its default address.
+    pub fn setup(bus: B, device: Device) -> io::Result<Trill<B>> {
+        Trill::with_address(bus, device, device.default_address())
+    }
+
+    /// Check that the device at `address` is a `device`, then put it in its
+    /// default mode, with the default scan settings, and update its baseline,
+    /// as the C++ library does.
+    pub fn with_address(bus: B, device: Device, address: u8) -> io::Result<Trill<B>> {
+        let mut trill = Trill {
+            bus,
+            address,
+            device,
+            firmware: 0,
+            mode: device.default_mode(),
+            prepared: false,
+            buf: [0; 2 * MAX_CHANNELS],
+        };
+        let found = trill.identify()?;
+        if found != device {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected a Trill {:?} at {:#04x}, found a Trill {:?}",
+                    device, address, found
+                ),
+            ));
+        }
+        trill.set_mode(device.default_mode())?;
+        trill.set_scan_settings(0, 12)?;
+        trill.update_baseline()?;
+        Ok(trill)
+    }
+
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// The firmware version reported by the last identify.
+    pub fn firmware_version(&self) -> u8 {
+        self.firmware
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Ask the device what it is, and which firmware it runs.
+    pub fn identify(&mut self) -> io::Result<Device> {
+        self.command(&[COMMAND_IDENTIFY])?;
+        self.prepare()?;
+        let mut reply = [0; 4];
+        self.bus.read(self.address, &mut reply)?;
+        // The next read must skip back to the start of the data.
+        self.prepared = false;
+        match Device::from_id(reply[1]) {
+            Some(device) if reply[0] == IDENTIFY_HEADER => {
+                self.firmware = reply[2];
+                Ok(device)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no Trill sensor answered at {:#04x}", self.address),
+            )),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) -> io::Result<()> {
+        self.command(&[COMMAND_MODE, mode.id()])?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Set the scan `speed`, from 0 (fastest) to 3 (slowest), and the
+    /// resolution of each channel, from 9 to 16 bits.
+    pub fn set_scan_settings(&mut self, speed: u8, bits: u8) -> io::Result<()> {
+        if speed > 3 || !(9..=16).contains(&bits) {
+            return Err(invalid(format!(
+                "invalid scan settings: speed {}, {} bits",
+                speed, bits
+            )));
+        }
+        self.command(&[COMMAND_SCAN_SETTINGS, speed, bits])
+    }
+
+    /// Set the prescaler, from 1 to 8. Larger sensors need larger values.
+    pub fn set_prescaler(&mut self, prescaler: u8) -> io::Result<()> {
+        if !(1..=8).contains(&prescaler) {
+            return Err(invalid(format!("invalid prescaler {}", prescaler)));
+        }
+        self.command(&[COMMAND_PRESCALER, prescaler])
+    }
+
+    /// Set the threshold below which the difference from the baseline is
+    /// taken as noise.
+    pub fn set_noise_threshold(&mut self, threshold: u8) -> io::Result<()> {
+        self.command(&[COMMAND_NOISE_THRESHOLD, threshold])
+    }
+
+    /// Set the charging current of the sensing channels.
+    pub fn set_idac(&mut self, value: u8) -> io::Result<()> {
+        self.command(&[COMMAND_IDAC, value])
+    }
+
+    /// Set the size below which touches are not reported.
+    pub fn set_minimum_touch_size(&mut self, size: u16) -> io::Result<()> {
+        let [high, low] = size.to_be_bytes();
+        self.command(&[COMMAND_MINIMUM_SIZE, high, low])
+    }
+
+    /// Set how often the device scans on its own, in its own units, or 0 to
+    /// scan only when read.
+    pub fn set_auto_scan_interval(&mut self, interval: u16) -> io::Result<()> {
+        let [high, low] = interval.to_be_bytes();
+        self.command(&[COMMAND_AUTO_SCAN_INTERVAL, high, low])
+    }
+
+    /// Take the current value of each channel as its untouched baseline.
+    pub fn update_baseline(&mut self) -> io::Result<()> {
+        self.command(&[COMMAND_BASELINE_UPDATE])
+    }
+
+    /// Read the latest scan into `reading`.
+    pub fn read(&mut self, reading: &mut Reading) -> io::Result<()> {
+        self.prepare()?;
+        let len = self.device.data_len(self.mode);
+        self.bus.read(self.address, &mut self.buf[..len])?;
+        reading.parse(self.device, self.mode, &self.buf[..len]);
+        Ok(())
+    }
+
+    fn command(&mut self, command: &[u8]) -> io::Result<()> {
+        let mut bytes = [OFFSET_COMMAND; 4];
+        bytes[1..=command.len()].copy_from_slice(command);
+        self.prepared = false;
+        self.bus.write(self.address, &bytes[..=command.len()])?;
+        thread::sleep(COMMAND_DELAY);
+        Ok(())
+    }
+
+    /// Point the device at its data, which it then returns on every read.
+    fn prepare(&mut self) -> io::Result<()> {
+        if !self.prepared {
+            self.bus.write(self.address, &[OFFSET_DATA])?;
+            self.prepared = true;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// State shared between a `TrillReader` and its task.
+struct Shared {
+    /// Set when the task has been scheduled and has not finished reading.
+    scheduled: AtomicBool,
+    errors: AtomicU64,
+}
+
+/// The render end of a reader, until its task is created.
+pub(crate) struct Latest {
+    reader: triple::Reader<Reading>,
+    device: Device,
+    shared: Arc<Shared>,
+}
+
+/// Reads the sensor and publishes each reading, run by the auxiliary task.
+pub(crate) struct Poll<B> {
+    trill: Trill<B>,
+    writer: triple::Writer<Reading>,
+    shared: Arc<Shared>,
+}
+
+/// Split `trill` into the task reading it and the end render takes the
+/// readings from.
+pub(crate) fn split<B: I2cBus>(trill: Trill<B>) -> (Latest, Poll<B>) {
+    let (writer, reader) = triple::exchange(Reading::default);
+    let shared = Arc::new(Shared {
+        scheduled: AtomicBool::new(false),
+        errors: AtomicU64::new(0),
+    });
+    let latest = Latest {
+        reader,
+        device: trill.device(),
+        shared: shared.clone(),
+    };
+    let poll = Poll {
+        trill,
+        writer,
+        shared,
+    };
+    (latest, poll)
+}
+
+impl<B: I2cBus> Poll<B> {
+    pub(crate) fn run(&mut self) {
+        match self.trill.read(self.writer.slot()) {
+            Ok(()) => self.writer.publish(),
+            Err(_) => {
+                self.shared.errors.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        // Cleared last, so that render never schedules a read while one is
+        // still running.
+        self.shared.scheduled.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The readings of a sensor, taken by an auxiliary task, as returned by
+/// `Bela::create_trill_reader`.
+pub struct TrillReader {
+    latest: Latest,
+    task: CreatedTask,
+    schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+}
+
+impl TrillReader {
+    pub(crate) fn new(
+        latest: Latest,
+        task: CreatedTask,
+        schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+    ) -> TrillReader {
+        TrillReader {
+            latest,
+            task,
+            schedule,
+        }
+    }
+
+    /// Take the latest reading, and schedule the next one unless a read is
+    /// already running. Never blocks or allocates.
+    ///
+    /// Returns whether a new reading was taken. Calling this every block reads
+    /// the sensor as fast as the bus allows; calling it less often leaves the
+    /// bus free for other devices.
+    pub fn update(&mut self) -> bool {
+        let updated = self.latest.reader.update();
+        if !self.latest.shared.scheduled.swap(true, Ordering::SeqCst)
+            && (self.schedule)(&self.task).is_err()
+        {
+            self.latest.shared.scheduled.store(false, Ordering::SeqCst);
+        }
+        updated
+    }
+
+    /// The reading taken by the last `update`. Empty until the first reading
+    /// arrives.
+    pub fn reading(&self) -> &Reading {
+        self.latest.reader.get()
+    }
+
+    pub fn device(&self) -> Device {
+        self.latest.device
+    }
+
+    /// Number of reads that failed, and were skipped.
+    pub fn errors(&self) -> u64 {
+        self.latest.shared.errors.load(Ordering::SeqCst)
+    }
+
+    /// The task reading the sensor.
+    pub fn task(&self) -> &CreatedTask {
+        &self.task
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+pub use self::mock_sensor::{mock, MockBus, MockTrill};
+
+/// The in-memory sensor of `mock`, for tests and for the `mock` feature.
+#[cfg(any(test, feature = "mock"))]
+mod mock_sensor {
+    use std::sync::{Mutex, MutexGuard};
+
+    use super::*;
+
+    /// Create an in-memory sensor for testing: the `MockBus` is handed to
+    /// `Trill`, and the `MockTrill` sets what the sensor reports and inspects
+    /// what it was sent.
+    pub fn mock(device: Device, address: u8) -> (MockBus, MockTrill) {
+        let state = Arc::new(Mutex::new(MockState {
+            device,
+            address,
+            firmware: 3,
+            connected: true,
+            offset: OFFSET_COMMAND,
+            identified: false,
+            mode: Mode::Centroid,
+            commands: Vec::new(),
+            touches: Vec::new(),
+            horizontal: Vec::new(),
+            buttons: [0; 2],
+            values: Vec::new(),
+        }));
+        (
+            MockBus {
+                state: state.clone(),
+            },
+            MockTrill { state },
+        )
+    }
+
+    struct MockState {
+        device: Device,
+        address: u8,
+        firmware: u8,
+        connected: bool,
+        /// Register the next read starts at.
+        offset: u8,
+        /// Whether the data holds the reply to an identify command.
+        identified: bool,
+        mode: Mode,
+        commands: Vec<Vec<u8>>,
+        touches: Vec<(u16, u16)>,
+        horizontal: Vec<(u16, u16)>,
+        buttons: [u16; 2],
+        values: Vec<u16>,
+    }
+
+    impl MockState {
+        fn check(&self, address: u8) -> io::Result<()> {
+            if self.connected && address == self.address {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no device at {:#04x}", address),
+                ))
+            }
+        }
+
+        fn data(&self, buf: &mut [u8]) {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            if self.offset != OFFSET_DATA {
+                return;
+            }
+            if self.identified {
+                let reply = [IDENTIFY_HEADER, self.device.id(), self.firmware];
+                let len = reply.len().min(buf.len());
+                buf[..len].copy_from_slice(&reply[..len]);
+                return;
+            }
+            let mut words = Vec::new();
+            if self.mode == Mode::Centroid {
+                let max = self.device.max_touches();
+                push_touches(&mut words, &self.touches, max);
+                if self.device.is_2d() {
+                    push_touches(&mut words, &self.horizontal, max);
+                }
+                words.extend_from_slice(&self.buttons[..self.device.buttons()]);
+            } else {
+                words.extend_from_slice(&self.values);
+                words.resize(self.device.channels(), 0);
+            }
+            for (bytes, word) in buf.chunks_mut(2).zip(words) {
+                let word = word.to_be_bytes();
+                let len = bytes.len();
+                bytes.copy_from_slice(&word[..len]);
+            }
+        }
+    }
+
+    fn push_touches(words: &mut Vec<u16>, touches: &[(u16, u16)], max: usize) {
+        let touches = &touches[..touches.len().min(max)];
+        words.extend(touches.iter().map(|&(location, _)| location));
+        words.resize(words.len() + max - touches.len(), NO_TOUCH);
+        words.extend(touches.iter().map(|&(_, size)| size));
+        words.resize(words.len() + max - touches.len(), 0);
+    }
+
+    /// The bus end of a mock sensor, answering like a Trill with the firmware
+    /// 2 and 3 protocol.
+    pub struct MockBus {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    impl I2cBus for MockBus {
+        fn write(&mut self, address: u8, bytes: &[u8]) -> io::Result<()> {
+            let mut state = lock(&self.state);
+            state.check(address)?;
+            let offset = match bytes.first() {
+                Some(&offset) => offset,
+                None => return Ok(()),
+            };
+            state.offset = offset;
+            if offset != OFFSET_COMMAND || bytes.len() < 2 {
+                return Ok(());
+            }
+            state.identified = bytes[1] == COMMAND_IDENTIFY;
+            match bytes[1] {
+                COMMAND_MODE if bytes.len() > 2 => {
+                    if let Some(mode) = Mode::from_id(bytes[2]) {
+                        state.mode = mode;
+                    }
+                }
+                _ => (),
+            }
+            state.commands.push(bytes[1..].to_vec());
+            Ok(())
+        }
+
+        fn read(&mut self, address: u8, buf: &mut [u8]) -> io::Result<()> {
+            let mut state = lock(&self.state);
+            state.check(address)?;
+            state.data(buf);
+            // The next scan replaces the reply, as far as later reads go.
+            state.identified = false;
+            Ok(())
+        }
+    }
+
+    /// The control end of a mock sensor.
+    pub struct MockTrill {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    impl MockTrill {
+        fn lock(&self) -> MutexGuard<'_, MockState> {
+            lock(&self.state)
+        }
+
+        /// Report touches as `(location, size)` pairs, on the only axis of a 1D
+        /// device or the vertical axis of a 2D one. Locations are in 1/128ths of
+        /// the distance between two pads.
+        pub fn set_touches(&self, touches: &[(u16, u16)]) {
+            self.lock().touches = touches.to_vec();
+        }
+
+        /// Report touches on the horizontal axis of a 2D device.
+        pub fn set_horizontal_touches(&self, touches: &[(u16, u16)]) {
+            self.lock().horizontal = touches.to_vec();
+        }
+
+        /// Report the buttons of a Trill Ring.
+        pub fn set_buttons(&self, buttons: [u16; 2]) {
+            self.lock().buttons = buttons;
+        }
+
+        /// Report one value per channel outside of `Mode::Centroid`. Missing
+        /// channels read as 0.
+        pub fn set_values(&self, values: &[u16]) {
+            self.lock().values = values.to_vec();
+        }
+
+        pub fn set_firmware_version(&self, firmware: u8) {
+            self.lock().firmware = firmware;
+        }
+
+        /// Make every transfer fail, as if the sensor had been unplugged, or
+        /// succeed again.
+        pub fn set_connected(&self, connected: bool) {
+            self.lock().connected = connected;
+        }
+
+        /// The mode the sensor was last set to.
+        pub fn mode(&self) -> Mode {
+            self.lock().mode
+        }
+
+        /// Every command received, without the command offset: the command byte
+        /// followed by its arguments.
+        pub fn commands(&self) -> Vec<Vec<u8>> {
+            self.lock().commands.clone()
+        }
+    }
+
+    fn lock(state: &Mutex<MockState>) -> MutexGuard<'_, MockState> {
+        state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use backend::Offline;
+    use {Bela, OwnedAppData};
+
+    fn setup(device: Device) -> (Trill<MockBus>, MockTrill) {
+        let (bus, sensor) = mock(device, device.default_address());
+        (Trill::setup(bus, device).unwrap(), sensor)
+    }
+
+    fn read(trill: &mut Trill<MockBus>) -> Reading {
+        let mut reading = Reading::default();
+        trill.read(&mut reading).unwrap();
+        reading
+    }
+
+    #[test]
+    fn setup_identifies_and_configures() {
+        let (bus, sensor) = mock(Device::Craft, 0x31);
+        sensor.set_firmware_version(2);
+        let trill = Trill::with_address(bus, Device::Craft, 0x31).unwrap();
+        assert_eq!(trill.firmware_version(), 2);
+        assert_eq!(trill.mode(), Mode::Diff);
+        assert_eq!(sensor.mode(), Mode::Diff);
+        assert_eq!(
+            sensor.commands(),
+            vec![
+                vec![COMMAND_IDENTIFY],
+                vec![COMMAND_MODE, 3],
+                vec![COMMAND_SCAN_SETTINGS, 0, 12],
+                vec![COMMAND_BASELINE_UPDATE],
+            ]
+        );
+    }
+
+    #[test]
+    fn identify_finds_device() {
+        let (mut trill, _sensor) = setup(Device::Hex);
+        assert_eq!(trill.identify().unwrap(), Device::Hex);
+        // Reads the data again, rather than the identify reply
+        assert!(!read(&mut trill).is_touched());
+    }
+
+    #[test]
+    fn setup_refuses_other_device() {
+        let (bus, _sensor) = mock(Device::Square, Device::Bar.default_address());
+        let err = Trill::setup(bus, Device::Bar).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn setup_without_device() {
+        let (bus, sensor) = mock(Device::Bar, Device::Bar.default_address());
+        sensor.set_connected(false);
+        assert!(Trill::setup(bus, Device::Bar).is_err());
+
+        let (bus, _sensor) = mock(Device::Bar, 0x21);
+        let err = Trill::setup(bus, Device::Bar).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn set_mode_reads_values() {
+        let (mut trill, sensor) = setup(Device::Bar);
+        trill.set_mode(Mode::Raw).unwrap();
+        assert_eq!(sensor.mode(), Mode::Raw);
+        assert_eq!(sensor.commands().last(), Some(&vec![COMMAND_MODE, 1]));
+
+        sensor.set_values(&[10, 300, 65_535]);
+        let reading = read(&mut trill);
+        assert_eq!(reading.mode(), Mode::Raw);
+        assert_eq!(reading.values().len(), 26);
+        assert_eq!(&reading.values()[..4], &[10, 300, 65_535, 0]);
+        assert!(reading.touches().is_empty());
+    }
+
+    #[test]
+    fn invalid_settings_are_not_sent() {
+        let (mut trill, sensor) = setup(Device::Bar);
+        let sent = sensor.commands().len();
+        assert!(trill.set_scan_settings(4, 12).is_err());
+        assert!(trill.set_scan_settings(0, 17).is_err());
+        assert!(trill.set_prescaler(0).is_err());
+        assert_eq!(sensor.commands().len(), sent);
+    }
+
+    #[test]
+    fn centroids_1d() {
+        let (mut trill, sensor) = setup(Device::Bar);
+        let range = 25 * POSITION_STEPS;
+        sensor.set_touches(&[(range / 2, 200), (range, 50), (range * 2, 10)]);
+        let reading = read(&mut trill);
+        assert_eq!(
+            reading.touches(),
+            &[
+                Touch {
+                    position: 0.5,
+                    size: 200
+                },
+                Touch {
+                    position: 1.,
+                    size: 50
+                },
+                // Beyond the end of the sensor
+                Touch {
+                    position: 1.,
+                    size: 10
+                },
+            ]
+        );
+        assert!(reading.horizontal_touches().is_empty());
+        assert!(reading.buttons().is_empty());
+
+        sensor.set_touches(&[(0, 1); 6]);
+        assert_eq!(read(&mut trill).touches().len(), MAX_TOUCHES);
+        sensor.set_touches(&[]);
+        assert!(!read(&mut trill).is_touched());
+    }
+
+    #[test]
+    fn centroids_2d() {
+        let (mut trill, sensor) = setup(Device::Square);
+        let range = 14 * POSITION_STEPS;
+        sensor.set_touches(&[(range / 4, 120)]);
+        sensor.set_horizontal_touches(&[(range / 2, 80), (range, 90)]);
+        let reading = read(&mut trill);
+        assert_eq!(
+            reading.touches(),
+            &[Touch {
+                position: 0.25,
+                size: 120
+            }]
+        );
+        assert_eq!(
+            reading.horizontal_touches(),
+            &[
+                Touch {
+                    position: 0.5,
+                    size: 80
+                },
+                Touch {
+                    position: 1.,
+                    size: 90
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn centroids_ring() {
+        let (mut trill, sensor) = setup(Device::Ring);
+        let range = 28 * POSITION_STEPS;
+        sensor.set_touches(&[(range / 2, 300)]);
+        sensor.set_buttons([12, 900]);
+        let reading = read(&mut trill);
+        assert_eq!(
+            reading.touches(),
+            &[Touch {
+                position: 0.5,
+                size: 300
+            }]
+        );
+        assert_eq!(reading.buttons(), &[12, 900]);
+        assert!(reading.horizontal_touches().is_empty());
+    }
+
+    #[test]
+    fn reader_counts_errors() {
+        let (trill, sensor) = setup(Device::Bar);
+        sensor.set_touches(&[(0, 100)]);
+        let name = CString::new("trill").unwrap();
+        let mut reader = Bela::<OwnedAppData<()>, Offline>::create_trill_reader(trill, 0, &name);
+        assert!(reader.reading().touches().is_empty());
+        // The read runs as soon as it is scheduled, and is taken on the next
+        // update
+        assert!(!reader.update());
+        assert!(reader.update());
+        assert_eq!(reader.reading().touches().len(), 1);
+
+        sensor.set_connected(false);
+        reader.update();
+        assert!(!reader.update());
+        assert!(reader.errors() > 0);
+        assert_eq!(reader.reading().touches().len(), 1);
+    }
+}
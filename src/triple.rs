@@ -0,0 +1,86 @@
+//! Single-producer, single-consumer exchange of the latest value.
+//!
+//! Each end owns one of three slots and trades it for the spare one, so
+//! neither end waits for the other or allocates after the exchange is
+//! created. The reader only ever sees the most recently published value.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Set in `Shared::spare` when the spare slot holds a value the reader has
+/// not taken yet.
+const FRESH: usize = 4;
+
+struct Shared<T> {
+    slots: [UnsafeCell<T>; 3],
+    spare: AtomicUsize,
+}
+
+// Each slot is owned by exactly one end, or is the spare, as handed over by
+// the swaps of `spare`.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Create an exchange whose slots are initialized by `init`.
+pub(crate) fn exchange<T: Send, F: Fn() -> T>(init: F) -> (Writer<T>, Reader<T>) {
+    let shared = Arc::new(Shared {
+        slots: [
+            UnsafeCell::new(init()),
+            UnsafeCell::new(init()),
+            UnsafeCell::new(init()),
+        ],
+        spare: AtomicUsize::new(2),
+    });
+    (
+        Writer {
+            shared: shared.clone(),
+            index: 0,
+        },
+        Reader { shared, index: 1 },
+    )
+}
+
+/// Publishing end of an exchange.
+pub(crate) struct Writer<T> {
+    shared: Arc<Shared<T>>,
+    index: usize,
+}
+
+impl<T> Writer<T> {
+    /// The slot to fill before `publish`. It holds an older value, not
+    /// necessarily the last one published.
+    pub(crate) fn slot(&mut self) -> &mut T {
+        unsafe { &mut *self.shared.slots[self.index].get() }
+    }
+
+    /// Hand the slot over to the reader.
+    pub(crate) fn publish(&mut self) {
+        let spare = self.shared.spare.swap(self.index | FRESH, Ordering::AcqRel);
+        self.index = spare & !FRESH;
+    }
+}
+
+/// Receiving end of an exchange.
+pub(crate) struct Reader<T> {
+    shared: Arc<Shared<T>>,
+    index: usize,
+}
+
+impl<T> Reader<T> {
+    /// Take the value published last, if it has not been taken yet. Returns
+    /// whether there was one.
+    pub(crate) fn update(&mut self) -> bool {
+        if self.shared.spare.load(Ordering::Relaxed) & FRESH == 0 {
+            return false;
+        }
+        let spare = self.shared.spare.swap(self.index, Ordering::AcqRel);
+        self.index = spare & !FRESH;
+        true
+    }
+
+    /// The value taken by the last `update`.
+    pub(crate) fn get(&self) -> &T {
+        unsafe { &*self.shared.slots[self.index].get() }
+    }
+}
@@ -0,0 +1,409 @@
+//! Logs values from the render callback to a file, like the `WriteFile` of
+//! C++ Bela projects.
+//!
+//! Render only copies frames into a queue allocated up front; an auxiliary
+//! task created with `Bela::create_write_file` writes them to disk. When the
+//! queue is full, frames are dropped and counted rather than blocking render.
+//!
+//! ```rust,ignore
+//! // In setup:
+//! let file = FileWriter::create("sensors.csv", FileFormat::Csv, 8)?;
+//! let mut log = App::create_write_file(
+//!     file,
+//!     22_050,
+//!     5,
+//!     &CString::new("sensor-log").unwrap(),
+//! );
+//! // In render:
+//! for frame in 0..context.analog_frames() {
+//!     let start = frame * context.analog_in_channels();
+//!     log.log(&context.analog_in()[start..start + 8]);
+//! }
+//! ```
+//!
+//! The file is completed, and a WAV header written, once the task is deleted
+//! and its closure dropped: when the `WriteFile` and every other handle to its
+//! task are dropped, or when audio is cleaned up for a scoped task.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use hound;
+
+use files::{to_io_error, WavWriter};
+use ring;
+use {error, CreatedTask};
+
+/// How frames are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    /// One line per frame, with values separated by commas.
+    Csv,
+    /// Little-endian `f32`s, one frame after the other.
+    Binary,
+    /// A 32-bit float WAV file, with one channel per column.
+    Wav { sample_rate: u32 },
+}
+
+/// The file a `WriteFile` logs to, written from its auxiliary task.
+pub struct FileWriter {
+    columns: usize,
+    sink: Sink,
+}
+
+enum Sink {
+    Csv(BufWriter<File>),
+    Binary(BufWriter<File>),
+    Wav(WavWriter),
+}
+
+impl FileWriter {
+    /// Create the file at `path`, for frames of `columns` values.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: FileFormat,
+        columns: usize,
+    ) -> io::Result<FileWriter> {
+        let wav = matches!(format, FileFormat::Wav { .. });
+        if columns == 0 || (wav && columns > u16::MAX as usize) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot log frames of {} values", columns),
+            ));
+        }
+        let sink = match format {
+            FileFormat::Csv => Sink::Csv(BufWriter::new(File::create(path)?)),
+            FileFormat::Binary => Sink::Binary(BufWriter::new(File::create(path)?)),
+            FileFormat::Wav { sample_rate } => {
+                let spec = hound::WavSpec {
+                    channels: columns as u16,
+                    sample_rate,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                };
+                Sink::Wav(hound::WavWriter::create(path, spec).map_err(to_io_error)?)
+            }
+        };
+        Ok(FileWriter { columns, sink })
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    fn write_frame(&mut self, frame: &[f32]) -> io::Result<()> {
+        match self.sink {
+            Sink::Csv(ref mut file) => {
+                for (column, value) in frame.iter().enumerate() {
+                    if column > 0 {
+                        write!(file, ",")?;
+                    }
+                    write!(file, "{}", value)?;
+                }
+                writeln!(file)
+            }
+            Sink::Binary(ref mut file) => {
+                for value in frame {
+                    file.write_all(&value.to_le_bytes())?;
+                }
+                Ok(())
+            }
+            Sink::Wav(ref mut file) => {
+                for &value in frame {
+                    file.write_sample(value).map_err(to_io_error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Csv(mut file) | Sink::Binary(mut file) => file.flush(),
+            Sink::Wav(file) => file.finalize().map_err(to_io_error),
+        }
+    }
+}
+
+/// State shared between a `WriteFile` and its task.
+struct Shared {
+    /// Set when the task has been scheduled and has not started draining.
+    scheduled: AtomicBool,
+    /// Set once writing has failed. Later frames are discarded.
+    failed: AtomicBool,
+}
+
+/// The render end of a queue, until its task is created.
+pub(crate) struct Queue {
+    producer: ring::Producer<f32>,
+    columns: usize,
+    capacity: usize,
+    shared: Arc<Shared>,
+}
+
+/// Writes the frames waiting in the queue, run by the auxiliary task.
+pub(crate) struct Drain {
+    consumer: ring::Consumer<f32>,
+    file: Option<FileWriter>,
+    frame: Vec<f32>,
+    shared: Arc<Shared>,
+}
+
+impl Drain {
+    /// Split `file` into a queue of `frames` frames and the drain emptying it.
+    pub(crate) fn new(file: FileWriter, frames: usize) -> (Queue, Drain) {
+        let columns = file.columns();
+        let capacity = frames.max(1) * columns;
+        let (producer, consumer) = ring::channel(capacity);
+        let shared = Arc::new(Shared {
+            scheduled: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+        });
+        let queue = Queue {
+            producer,
+            columns,
+            capacity,
+            shared: shared.clone(),
+        };
+        let drain = Drain {
+            consumer,
+            file: Some(file),
+            frame: vec![0.; columns],
+            shared,
+        };
+        (queue, drain)
+    }
+
+    pub(crate) fn run(&mut self) {
+        // Cleared first, so that frames logged from now on schedule the task
+        // again.
+        self.shared.scheduled.store(false, Ordering::SeqCst);
+        while self.consumer.len() >= self.frame.len() {
+            for value in self.frame.iter_mut() {
+                *value = self.consumer.pop().unwrap_or(0.);
+            }
+            let written = match self.file {
+                Some(ref mut file) => file.write_frame(&self.frame),
+                None => continue,
+            };
+            if written.is_err() {
+                self.fail();
+            }
+        }
+    }
+
+    fn fail(&mut self) {
+        self.file = None;
+        self.shared.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Drain {
+    fn drop(&mut self) {
+        self.run();
+        if let Some(file) = self.file.take() {
+            if file.finish().is_err() {
+                self.shared.failed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// A file logged to from the render thread, as returned by
+/// `Bela::create_write_file`.
+pub struct WriteFile {
+    queue: Queue,
+    overflows: u64,
+    task: CreatedTask,
+    schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+}
+
+impl WriteFile {
+    pub(crate) fn new(
+        queue: Queue,
+        task: CreatedTask,
+        schedule: fn(&CreatedTask) -> Result<(), error::Error>,
+    ) -> WriteFile {
+        WriteFile {
+            queue,
+            overflows: 0,
+            task,
+            schedule,
+        }
+    }
+
+    /// Log one frame, one value per column. Never blocks or allocates.
+    ///
+    /// Columns missing from `frame` are logged as 0, and extra values are
+    /// ignored. Returns `false`, and counts an overflow, if there is no room
+    /// left for the frame. The task is scheduled once a quarter of the queue
+    /// is waiting.
+    pub fn log(&mut self, frame: &[f32]) -> bool {
+        let logged = if self.queue.producer.free() < self.queue.columns {
+            self.overflows += 1;
+            false
+        } else {
+            for column in 0..self.queue.columns {
+                let value = frame.get(column).cloned().unwrap_or(0.);
+                let _ = self.queue.producer.push(value);
+            }
+            true
+        };
+        if self.pending() >= self.queue.capacity / 4 {
+            let _ = self.flush();
+        }
+        logged
+    }
+
+    /// Schedule the task to write every frame logged so far.
+    pub fn flush(&mut self) -> Result<(), error::Error> {
+        if self.pending() == 0 || self.queue.shared.scheduled.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let scheduled = (self.schedule)(&self.task);
+        if scheduled.is_err() {
+            self.queue.shared.scheduled.store(false, Ordering::SeqCst);
+        }
+        scheduled
+    }
+
+    /// Number of frames dropped because the queue was full.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Whether writing to the file has failed. Frames logged after a failure
+    /// are discarded.
+    pub fn has_failed(&self) -> bool {
+        self.queue.shared.failed.load(Ordering::SeqCst)
+    }
+
+    /// The task writing the file.
+    pub fn task(&self) -> &CreatedTask {
+        &self.task
+    }
+
+    fn pending(&self) -> usize {
+        self.queue.capacity - self.queue.producer.free()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+    use std::{env, fs, process};
+
+    use super::*;
+    use backend::Offline;
+    use {Bela, OwnedAppData};
+
+    type App = Bela<OwnedAppData<()>, Offline>;
+
+    fn path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("bela-write-file-{}-{}", process::id(), name))
+    }
+
+    fn name() -> CString {
+        CString::new("test").unwrap()
+    }
+
+    /// Log `frames` to a new file of `format`, and read the file back.
+    fn write(name: &str, format: FileFormat, columns: usize, frames: &[&[f32]]) -> Vec<u8> {
+        let path = path(name);
+        let file = FileWriter::create(&path, format, columns).unwrap();
+        let mut log = App::create_write_file(file, 16, 0, &self::name());
+        for frame in frames {
+            assert!(log.log(frame));
+        }
+        assert!(!log.has_failed());
+        // Dropping the last handle drains the queue and completes the file
+        drop(log);
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        contents
+    }
+
+    #[test]
+    fn csv() {
+        let contents = write(
+            "csv",
+            FileFormat::Csv,
+            2,
+            &[&[1., 0.5], &[-2.], &[3., 4., 5.]],
+        );
+        assert_eq!(String::from_utf8(contents).unwrap(), "1,0.5\n-2,0\n3,4\n");
+    }
+
+    #[test]
+    fn binary() {
+        let contents = write("bin", FileFormat::Binary, 2, &[&[1., 0.5], &[-2., 0.]]);
+        let expected: Vec<u8> = [1f32, 0.5, -2., 0.]
+            .iter()
+            .flat_map(|value| value.to_le_bytes().to_vec())
+            .collect();
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn wav() {
+        let format = FileFormat::Wav {
+            sample_rate: 22_050,
+        };
+        let contents = write("wav", format, 2, &[&[1., 0.5], &[-2., 0.25]]);
+        let reader = hound::WavReader::new(&contents[..]).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 22_050);
+        let samples: Vec<f32> = reader.into_samples().map(Result::unwrap).collect();
+        assert_eq!(samples, vec![1., 0.5, -2., 0.25]);
+    }
+
+    #[test]
+    fn refuses_empty_frames() {
+        assert!(FileWriter::create(path("empty"), FileFormat::Csv, 0).is_err());
+    }
+
+    static SCHEDULED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts the times it is called, without running the task.
+    fn count_schedule(_task: &CreatedTask) -> Result<(), error::Error> {
+        SCHEDULED.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test]
+    fn drains_on_schedule_and_counts_overflows() {
+        let path = path("overflow");
+        let file = FileWriter::create(&path, FileFormat::Csv, 1).unwrap();
+        let (queue, mut drain) = Drain::new(file, 4);
+        let task = App::create_auxiliary_task(Box::new(|| {}), 0, &name());
+        let mut log = WriteFile::new(queue, task, count_schedule);
+
+        // Scheduled once a quarter of the queue is waiting, and not again
+        // until the task has run
+        assert!(log.log(&[0.]));
+        assert_eq!(SCHEDULED.load(Ordering::SeqCst), 1);
+        for value in 1..4 {
+            assert!(log.log(&[value as f32]));
+        }
+        assert_eq!(SCHEDULED.load(Ordering::SeqCst), 1);
+        assert!(!log.log(&[4.]));
+        assert_eq!(log.overflows(), 1);
+
+        drain.run();
+        assert!(log.log(&[5.]));
+        assert_eq!(SCHEDULED.load(Ordering::SeqCst), 2);
+        log.flush().unwrap();
+        assert_eq!(SCHEDULED.load(Ordering::SeqCst), 2);
+
+        drop(drain);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "0\n1\n2\n3\n5\n");
+        assert!(!log.has_failed());
+    }
+}
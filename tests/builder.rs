@@ -0,0 +1,153 @@
+//! Runs apps built from owned closures on the backends that need no board.
+extern crate bela;
+extern crate hound;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{env, fs, process, thread, time};
+
+use bela::backend::{Offline, Software};
+use bela::*;
+
+/// How many times each callback ran.
+#[derive(Clone, Default)]
+struct Calls {
+    setup: Arc<AtomicUsize>,
+    render: Arc<AtomicUsize>,
+    cleanup: Arc<AtomicUsize>,
+}
+
+impl Calls {
+    fn counts(&self) -> (usize, usize, usize) {
+        (
+            self.setup.load(Ordering::SeqCst),
+            self.render.load(Ordering::SeqCst),
+            self.cleanup.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// Builds, in a function of its own, an app counting its callbacks into
+/// `calls` and writing a ramp of `step` to every output.
+fn ramp<B: Backend>(calls: &Calls, step: f32, backend: B) -> Bela<OwnedAppData<f32>, B> {
+    let (setup, render, cleanup) = (calls.clone(), calls.clone(), calls.clone());
+    AppBuilder::new(0.)
+        .setup(move |_context, _value| {
+            setup.setup.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .render(move |context, value| {
+            render.render.fetch_add(1, Ordering::SeqCst);
+            for frame in context.audio_out_frames() {
+                for samp in frame {
+                    *samp = *value;
+                }
+                *value += step;
+            }
+        })
+        .cleanup(move |_context, _value| {
+            cleanup.cleanup.fetch_add(1, Ordering::SeqCst);
+        })
+        .build_with_backend(backend)
+}
+
+fn software(blocks: u64) -> Software {
+    let mut backend = Software::new();
+    backend.set_realtime(false);
+    backend.set_max_blocks(Some(blocks));
+    backend
+}
+
+#[test]
+fn builder_runs_on_software() {
+    let calls = Calls::default();
+    let mut bela = ramp(&calls, 1., software(4));
+    bela.run(&mut InitSettings::software_default()).unwrap();
+    assert_eq!(calls.counts(), (1, 4, 1));
+}
+
+#[test]
+fn builder_runs_on_offline() {
+    let path = env::temp_dir().join(format!("bela-builder-{}.wav", process::id()));
+    let calls = Calls::default();
+    let mut backend = Offline::new(time::Duration::from_millis(1));
+    backend.set_audio_out(&path);
+    let mut bela = ramp(&calls, 0.25, backend);
+    bela.run(&mut InitSettings::software_default()).unwrap();
+    // 44.1 frames, rounded up to two blocks of 32
+    assert_eq!(calls.counts(), (1, 2, 1));
+
+    let mut reader = hound::WavReader::open(&path).unwrap();
+    let samples: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(samples.len(), 2 * 64);
+    for (frame, samps) in samples.chunks(2).enumerate() {
+        assert_eq!(samps, [frame as f32 * 0.25; 2]);
+    }
+}
+
+#[test]
+fn owned_app_data_takes_owned_closures() {
+    let calls = Calls::default();
+    let mut user_data = OwnedAppData::new(0, |_context, _renders| {});
+    // Closures moved in after construction, capturing state of their own
+    let (setup, cleanup) = (calls.clone(), calls.clone());
+    let seen = String::from("abc");
+    user_data.set_render_fn(move |_context, renders: &mut usize| {
+        *renders += seen.len();
+    });
+    user_data.set_setup_fn(move |_context, _renders| {
+        setup.setup.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    user_data.set_cleanup_fn(move |_context, renders| {
+        cleanup.cleanup.store(*renders, Ordering::SeqCst);
+    });
+
+    let mut bela = Bela::with_backend(user_data, software(5));
+    bela.run(&mut InitSettings::software_default()).unwrap();
+    // Cleanup saw 5 renders of 3
+    assert_eq!(calls.counts(), (1, 0, 15));
+}
+
+#[test]
+fn owned_app_data_is_send() {
+    // Built on one thread, and run on another
+    let calls = Calls::default();
+    let user_data = AppBuilder::new(0.)
+        .render(|context, _value| {
+            for samp in context.audio_out().iter_mut() {
+                *samp = 0.;
+            }
+        })
+        .setup({
+            let calls = calls.clone();
+            move |_context, _value| {
+                calls.setup.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .build_user_data();
+    thread::spawn(move || {
+        let mut bela = Bela::with_backend(user_data, software(1));
+        bela.run(&mut InitSettings::software_default()).unwrap();
+    })
+    .join()
+    .unwrap();
+    assert_eq!(calls.counts(), (1, 0, 0));
+}
+
+#[test]
+fn app_data_takes_closures_that_are_not_send() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let renders = Rc::new(Cell::new(0));
+    let mut render = {
+        let renders = renders.clone();
+        move |_context: &mut Context, _data: &mut ()| renders.set(renders.get() + 1)
+    };
+    let mut bela = Bela::with_backend(AppData::new((), &mut render, None, None), software(2));
+    bela.run(&mut InitSettings::software_default()).unwrap();
+    assert_eq!(renders.get(), 2);
+}
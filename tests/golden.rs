@@ -0,0 +1,38 @@
+//! Checks the sawtooth of `examples/software.rs` against its golden output.
+//! Run with `BELA_UPDATE_GOLDEN=1` after changing it on purpose.
+extern crate bela;
+
+use bela::*;
+
+struct Phasor {
+    idx: usize,
+}
+
+#[test]
+fn software_saw_is_unchanged() {
+    // Generates a non-bandlimited sawtooth at 110Hz.
+    let mut render = |context: &mut Context, phasor: &mut Phasor| {
+        for samp in context.audio_out().iter_mut() {
+            let gain = 0.5;
+            *samp = 2. * (phasor.idx as f32 * 110. / 44100.) - 1.;
+            *samp *= gain;
+            phasor.idx += 1;
+            if phasor.idx as f32 > 44100. / 110. {
+                phasor.idx = 0;
+            }
+        }
+    };
+
+    let mut user_data = AppData::new(Phasor { idx: 0 }, &mut render, None, None);
+    // Only the audio output is of interest
+    let mut settings = InitSettings::software_default();
+    settings.set_use_analog(false);
+    settings.set_use_digital(false);
+    golden::Harness::new(ContextBuilder::from_settings(&settings, 2, 2, 44_100.), 32)
+        .tolerance(1e-6)
+        .check(
+            &mut user_data,
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/software_saw"),
+        )
+        .unwrap();
+}